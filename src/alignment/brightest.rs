@@ -0,0 +1,57 @@
+use crate::analysis::{DEFAULT_DETECTION_SIGMA, detect_stars};
+use crate::image::FitsImage;
+
+/// Estimate the translation between two frames from the centroid of their brightest star.
+///
+/// Returns `(dx, dy)` with the same meaning as `register_phase_correlation`. Much cheaper
+/// than star matching or phase correlation and works on single-star fields, but only
+/// recovers shifts, and is wrong if the brightest star differs between the frames (e.g.
+/// it drifted out of one of them). Gives `(0.0, 0.0)` when either frame has no star.
+pub fn align_by_brightest_star(reference: &FitsImage, target: &FitsImage) -> (f32, f32) {
+    // Stars come brightest first
+    let brightest = |image: &FitsImage| {
+        detect_stars(image, DEFAULT_DETECTION_SIGMA)
+            .first()
+            .copied()
+    };
+
+    match (brightest(reference), brightest(target)) {
+        (Some(reference), Some(target)) => (target.x - reference.x, target.y - reference.y),
+        _ => {
+            log::warn!("No star found to align by, assuming no shift");
+            (0.0, 0.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// A 64x64 frame holding a single star at `(x, y)`
+    fn one_star(x: f32, y: f32) -> FitsImage {
+        let data = ArrayD::from_shape_fn(vec![64, 64], |index| {
+            let r2 = (index[1] as f32 - x).powi(2) + (index[0] as f32 - y).powi(2);
+            100.0 + 1000.0 * (-r2 / (2.0 * 1.5f32.powi(2))).exp()
+        });
+        FitsImage::from_data(data)
+    }
+
+    #[test]
+    fn shifted_star_gives_the_offset() {
+        let (dx, dy) = align_by_brightest_star(&one_star(30.0, 25.0), &one_star(33.5, 22.25));
+        assert!((dx - 3.5).abs() < 0.1, "dx = {}", dx);
+        assert!((dy + 2.75).abs() < 0.1, "dy = {}", dy);
+    }
+
+    #[test]
+    fn starless_frame_gives_no_shift() {
+        let blank = FitsImage::from_data(ArrayD::from_elem(vec![64, 64], 100.0));
+        assert_eq!(
+            align_by_brightest_star(&one_star(30.0, 25.0), &blank),
+            (0.0, 0.0)
+        );
+    }
+}
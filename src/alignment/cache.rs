@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use super::AffineTransform;
+use crate::image::ImageError;
+
+/// Fingerprint of a frame file, used to tell whether a cached transform is still valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileFingerprint {
+    /// Size of the file in bytes
+    len: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    modified: u128,
+}
+
+impl FileFingerprint {
+    fn of(path: &Path) -> Result<Self, ImageError> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+
+        Ok(Self {
+            len: metadata.len(),
+            modified,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedTransform {
+    transform: AffineTransform,
+    fingerprint: FileFingerprint,
+}
+
+/// Computed registration transforms keyed by frame path, persisted as JSON
+///
+/// Re-stacking an already aligned set can reuse these instead of registering the frames
+/// again. An entry is only reused while the frame file and the reference frame it was
+/// registered against are unchanged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransformCache {
+    /// Frame the transforms map onto
+    reference: Option<CachedReference>,
+    entries: HashMap<PathBuf, CachedTransform>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedReference {
+    path: PathBuf,
+    fingerprint: FileFingerprint,
+}
+
+impl TransformCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a cache previously written with `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        let json = fs::read_to_string(path)?;
+        Self::from_json(&json)
+    }
+
+    /// Write the cache to disk as JSON
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ImageError> {
+        serde_json::from_str(json)
+            .map_err(|e| ImageError::FormatError(format!("Invalid transform cache: {}", e)))
+    }
+
+    pub fn to_json(&self) -> Result<String, ImageError> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| ImageError::FormatError(format!("Failed to serialize transforms: {}", e)))
+    }
+
+    /// Register the frames against `reference_path` from now on. Every cached transform is
+    /// dropped if it was computed against a different or since modified reference.
+    pub fn set_reference<P: AsRef<Path>>(&mut self, reference_path: P) -> Result<(), ImageError> {
+        let path = reference_path.as_ref();
+        let reference = CachedReference {
+            path: path.to_path_buf(),
+            fingerprint: FileFingerprint::of(path)?,
+        };
+        if self.reference.as_ref() != Some(&reference) {
+            self.entries.clear();
+            self.reference = Some(reference);
+        }
+        Ok(())
+    }
+
+    /// Record the transform computed for a frame
+    pub fn insert<P: AsRef<Path>>(
+        &mut self,
+        frame_path: P,
+        transform: AffineTransform,
+    ) -> Result<(), ImageError> {
+        let frame_path = frame_path.as_ref();
+        let fingerprint = FileFingerprint::of(frame_path)?;
+        self.entries.insert(
+            frame_path.to_path_buf(),
+            CachedTransform {
+                transform,
+                fingerprint,
+            },
+        );
+        Ok(())
+    }
+
+    /// The cached transform for a frame, if present and the file hasn't changed since
+    pub fn get<P: AsRef<Path>>(&self, frame_path: P) -> Option<AffineTransform> {
+        let frame_path = frame_path.as_ref();
+        let cached = self.entries.get(frame_path)?;
+        let fingerprint = FileFingerprint::of(frame_path).ok()?;
+        (cached.fingerprint == fingerprint).then_some(cached.transform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to a file in the temp directory, replacing any earlier one
+    fn write_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("eventide-test-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn transforms_round_trip_and_are_reused_until_the_frame_changes() {
+        let reference = write_file("cache-reference.fits", b"reference");
+        let frame = write_file("cache-frame.fits", b"frame");
+        let shift = AffineTransform::translation(-2.5, 1.25);
+
+        let mut cache = TransformCache::new();
+        cache.set_reference(&reference).unwrap();
+        cache.insert(&frame, shift).unwrap();
+        let mut cache = TransformCache::from_json(&cache.to_json().unwrap()).unwrap();
+        assert_eq!(cache.get(&frame), Some(shift));
+
+        // Setting the same reference again keeps the entries
+        cache.set_reference(&reference).unwrap();
+        assert_eq!(cache.get(&frame), Some(shift));
+
+        // A rewritten frame has to be registered again
+        write_file("cache-frame.fits", b"a different frame");
+        assert_eq!(cache.get(&frame), None);
+        cache.insert(&frame, AffineTransform::identity()).unwrap();
+
+        // As is every frame once the reference changes
+        cache.set_reference(&frame).unwrap();
+        assert_eq!(cache.get(&frame), None);
+
+        fs::remove_file(&reference).unwrap();
+        fs::remove_file(&frame).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::{FitsImage, ImageError};
+
+mod brightest;
+mod cache;
+mod phase;
+mod resample;
+
+pub use brightest::align_by_brightest_star;
+pub use cache::TransformCache;
+pub use phase::{MIN_DITHER_AMPLITUDE, detect_dithering, register_phase_correlation};
+use resample::apply_affine;
+pub use resample::derotate;
+
+/// File in the lights folder the registration transforms are cached in between runs
+pub const TRANSFORM_CACHE_FILE_NAME: &str = "eventide-transforms.json";
+
+/// Affine transform mapping a frame's pixel coordinates onto the reference frame
+///
+/// x' = a * x + b * y + tx
+/// y' = c * x + d * y + ty
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AffineTransform {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+    pub tx: f64,
+    pub ty: f64,
+}
+
+impl Default for AffineTransform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl AffineTransform {
+    /// The transform that leaves every point in place
+    pub fn identity() -> Self {
+        Self {
+            a: 1.0,
+            b: 0.0,
+            c: 0.0,
+            d: 1.0,
+            tx: 0.0,
+            ty: 0.0,
+        }
+    }
+
+    /// A pure translation
+    pub fn translation(tx: f64, ty: f64) -> Self {
+        Self {
+            tx,
+            ty,
+            ..Self::identity()
+        }
+    }
+
+    /// A rotation by `degrees` (counterclockwise with the y axis pointing up) about `center`
+    pub fn rotation(degrees: f64, center: (f64, f64)) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (cx, cy) = center;
+        Self {
+            a: cos,
+            b: -sin,
+            c: sin,
+            d: cos,
+            tx: cx - cos * cx + sin * cy,
+            ty: cy - sin * cx - cos * cy,
+        }
+    }
+
+    /// Map a point from frame coordinates to reference coordinates
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.a * x + self.b * y + self.tx,
+            self.c * x + self.d * y + self.ty,
+        )
+    }
+
+    /// The transform mapping reference coordinates back to frame coordinates, or `None`
+    /// if this one collapses the plane
+    pub fn inverse(&self) -> Option<Self> {
+        let det = self.a * self.d - self.b * self.c;
+        if det.abs() < f64::EPSILON {
+            return None;
+        }
+
+        let (a, b, c, d) = (self.d / det, -self.b / det, -self.c / det, self.a / det);
+        Some(Self {
+            a,
+            b,
+            c,
+            d,
+            tx: -(a * self.tx + b * self.ty),
+            ty: -(c * self.tx + d * self.ty),
+        })
+    }
+}
+
+/// Register every frame onto the first by phase correlation and resample it into the first
+/// frame's pixel grid, so the frames can be stacked. Pixels shifted in from outside a frame
+/// are missing (NaN) and left out of the stack.
+///
+/// Transforms are looked up in `cache` by file path and reused while neither the frame nor
+/// the reference changed on disk; newly computed ones are added to it. Frames that weren't
+/// read from a file are always registered.
+pub fn align_to_first(
+    frames: &[FitsImage],
+    cache: &mut TransformCache,
+) -> Result<Vec<FitsImage>, ImageError> {
+    let Some((reference, rest)) = frames.split_first() else {
+        return Ok(Vec::new());
+    };
+    let reference_path = reference.metadata.file_path.as_ref();
+    if let Some(path) = reference_path {
+        cache.set_reference(path)?;
+    }
+
+    // A feature at (x, y) in the reference is at (x + dx, y + dy) in the frame
+    let register = |frame: &FitsImage| {
+        let (dx, dy) = register_phase_correlation(reference, frame);
+        AffineTransform::translation(-dx as f64, -dy as f64)
+    };
+
+    let mut reused = 0;
+    let mut aligned = vec![reference.clone()];
+    for frame in rest {
+        let transform = match (&frame.metadata.file_path, reference_path) {
+            (Some(path), Some(_)) => match cache.get(path) {
+                Some(transform) => {
+                    reused += 1;
+                    transform
+                }
+                None => {
+                    let transform = register(frame);
+                    cache.insert(path, transform)?;
+                    transform
+                }
+            },
+            _ => register(frame),
+        };
+        aligned.push(apply_affine(frame, &transform)?);
+    }
+
+    log::info!(
+        "Aligned {} frames, reusing {} cached transforms",
+        aligned.len(),
+        reused
+    );
+    Ok(aligned)
+}
+
+/// `align_to_first` with the transforms cached in `TRANSFORM_CACHE_FILE_NAME` in `folder`,
+/// usually the lights folder, so stacking the same frames again skips their registration.
+///
+/// A cache that can't be read is started over and one that can't be written is only
+/// logged, as neither affects the aligned frames.
+pub fn align_to_first_cached(
+    frames: &[FitsImage],
+    folder: &Path,
+) -> Result<Vec<FitsImage>, ImageError> {
+    let cache_path = folder.join(TRANSFORM_CACHE_FILE_NAME);
+    let mut cache = if cache_path.exists() {
+        TransformCache::load(&cache_path).unwrap_or_else(|e| {
+            log::warn!("Ignoring transform cache {}: {}", cache_path.display(), e);
+            TransformCache::new()
+        })
+    } else {
+        TransformCache::new()
+    };
+
+    let aligned = align_to_first(frames, &mut cache)?;
+
+    if let Err(e) = cache.save(&cache_path) {
+        log::warn!(
+            "Failed to save transform cache {}: {}",
+            cache_path.display(),
+            e
+        );
+    }
+    Ok(aligned)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::{FrameType, temp_path};
+
+    /// Write a 64x64 frame of Gaussian stars shifted by `(dx, dy)` and read it back
+    fn star_frame(name: &str, dx: f32, dy: f32) -> FitsImage {
+        let stars = [(20.0, 18.0), (40.0, 30.0), (28.0, 45.0)];
+        let data = ArrayD::from_shape_fn(vec![64, 64], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            100.0
+                + stars
+                    .iter()
+                    .map(|&(sx, sy)| {
+                        let r2 = (x - sx - dx).powi(2) + (y - sy - dy).powi(2);
+                        800.0 * (-r2 / (2.0 * 1.5f32.powi(2))).exp()
+                    })
+                    .sum::<f32>()
+        });
+        let path = temp_path(name);
+        let _ = std::fs::remove_file(&path);
+        FitsImage::from_data(data).to_file(&path).unwrap();
+        FitsImage::from_file(&path, FrameType::Light).unwrap()
+    }
+
+    #[test]
+    fn frames_are_aligned_and_cached_transforms_reused() {
+        let frames = [
+            star_frame("align-reference.fits", 0.0, 0.0),
+            star_frame("align-shifted.fits", 3.0, -2.0),
+        ];
+        let star_at_reference = |image: &FitsImage| image.data()[[18, 20]];
+
+        let mut cache = TransformCache::new();
+        let aligned = align_to_first(&frames, &mut cache).unwrap();
+        assert!(
+            star_at_reference(&aligned[1]) > 800.0,
+            "{}",
+            star_at_reference(&aligned[1])
+        );
+
+        // A cached transform is used as is, without registering the frame again
+        let shifted_path = frames[1].metadata.file_path.clone().unwrap();
+        let cached = cache.get(&shifted_path).unwrap();
+        assert!((cached.tx + 3.0).abs() < 0.1 && (cached.ty - 2.0).abs() < 0.1);
+        cache
+            .insert(&shifted_path, AffineTransform::identity())
+            .unwrap();
+        let unaligned = align_to_first(&frames, &mut cache).unwrap();
+        assert!(star_at_reference(&unaligned[1]) < 200.0);
+
+        for frame in &frames {
+            std::fs::remove_file(frame.metadata.file_path.as_ref().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn transforms_are_cached_in_the_folder() {
+        let folder = temp_path("align-cache-folder");
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        let frames = [
+            star_frame("align-cached-reference.fits", 0.0, 0.0),
+            star_frame("align-cached-shifted.fits", 3.0, -2.0),
+        ];
+
+        align_to_first_cached(&frames, &folder).unwrap();
+        let cache = TransformCache::load(folder.join(TRANSFORM_CACHE_FILE_NAME)).unwrap();
+        let shifted_path = frames[1].metadata.file_path.clone().unwrap();
+        assert!(cache.get(&shifted_path).is_some());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+        for frame in &frames {
+            std::fs::remove_file(frame.metadata.file_path.as_ref().unwrap()).unwrap();
+        }
+    }
+}
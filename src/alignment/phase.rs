@@ -0,0 +1,244 @@
+use std::borrow::Borrow;
+
+use ndarray::Array2;
+
+use crate::analysis::detection_plane;
+use crate::fft::{Complex, fft2, ifft2};
+use crate::image::FitsImage;
+
+/// Estimate the translation between two frames by phase correlation.
+///
+/// Returns `(dx, dy)` such that a feature at `(x, y)` in `reference` appears at
+/// `(x + dx, y + dy)` in `target`, to sub-pixel accuracy. Unlike star matching this
+/// needs no detectable stars, but only recovers shifts (no rotation), up to half the
+/// frame size. Frames of different sizes can't be compared and give `(0.0, 0.0)`.
+pub fn register_phase_correlation(reference: &FitsImage, target: &FitsImage) -> (f32, f32) {
+    let reference = detection_plane(reference);
+    let target = detection_plane(target);
+
+    if reference.dim() != target.dim() || reference.is_empty() {
+        log::warn!(
+            "Phase correlation needs frames of the same size, got {:?} and {:?}",
+            reference.dim(),
+            target.dim()
+        );
+        return (0.0, 0.0);
+    }
+
+    let mut reference = windowed_spectrum(&reference);
+    let target = windowed_spectrum(&target);
+
+    // Normalized cross-power spectrum: keeps only the phase difference, whose inverse
+    // transform is a sharp peak at the shift
+    ndarray::Zip::from(&mut reference)
+        .and(&target)
+        .for_each(|r, &t| {
+            let cross = t * r.conj();
+            let magnitude = cross.norm();
+            *r = if magnitude > f32::EPSILON {
+                cross / magnitude
+            } else {
+                Complex::new(0.0, 0.0)
+            };
+        });
+    // Both spectra are padded the same way, so shifts wrap around the padded size
+    let correlation = ifft2(&reference, reference.dim());
+    let (height, width) = correlation.dim();
+
+    // Integer peak, then refine each axis from its neighbours
+    let (peak_y, peak_x) = correlation
+        .indexed_iter()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(index, _)| index)
+        .unwrap_or((0, 0));
+
+    let at = |y: usize, x: usize| correlation[[y % height, x % width]];
+    let dx = peak_x as f32
+        + peak_offset(
+            at(peak_y, peak_x + width - 1),
+            at(peak_y, peak_x),
+            at(peak_y, peak_x + 1),
+        );
+    let dy = peak_y as f32
+        + peak_offset(
+            at(peak_y + height - 1, peak_x),
+            at(peak_y, peak_x),
+            at(peak_y + 1, peak_x),
+        );
+
+    // Peaks past the middle are negative shifts wrapped around
+    (wrap_shift(dx, width), wrap_shift(dy, height))
+}
+
+/// Mean-subtract a plane, taper it with a Hann window to suppress edge effects, and
+/// take its 2D FFT. The taper makes the zero padding `fft2` adds seamless.
+fn windowed_spectrum(plane: &Array2<f32>) -> Array2<Complex<f32>> {
+    let (height, width) = plane.dim();
+    let mean = plane.iter().filter(|v| v.is_finite()).sum::<f32>() / plane.len() as f32;
+    let hann = |i: usize, n: usize| {
+        if n <= 1 {
+            1.0
+        } else {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (n - 1) as f32).cos()
+        }
+    };
+
+    let windowed = Array2::from_shape_fn((height, width), |(y, x)| {
+        let value = plane[[y, x]];
+        let value = if value.is_finite() { value - mean } else { 0.0 };
+        value * hann(y, height) * hann(x, width)
+    });
+    fft2(&windowed.into_dyn())
+}
+
+/// Sub-pixel position of a peak from the values just before, at and after it.
+///
+/// The phase correlation of a sub-pixel shift is a sampled sinc, for which the shift
+/// towards the larger neighbour is `side / (side + peak)` (Foroosh et al., 2002); a
+/// parabola through the three values pulls the estimate towards the whole pixel.
+fn peak_offset(before: f32, peak: f32, after: f32) -> f32 {
+    let (side, direction) = if after >= before {
+        (after, 1.0)
+    } else {
+        (before, -1.0)
+    };
+    if side <= 0.0 || peak <= 0.0 {
+        return 0.0;
+    }
+    direction * (side / (side + peak)).min(0.5)
+}
+
+/// Map a peak position in [0, size) to a signed shift in [-size/2, size/2)
+fn wrap_shift(position: f32, size: usize) -> f32 {
+    let size = size as f32;
+    if position >= size / 2.0 {
+        position - size
+    } else {
+        position
+    }
+}
+
+/// RMS offset below which frames are considered undithered, in pixels
+pub const MIN_DITHER_AMPLITUDE: f32 = 0.5;
+
+/// Measure how much a set of frames was dithered, to judge whether drizzle is worthwhile.
+///
+/// Every frame is registered against the first by phase correlation and the RMS distance
+/// of the offsets from their mean is returned, in pixels. Returns `None` when there are
+/// fewer than two frames or the amplitude is below `MIN_DITHER_AMPLITUDE`.
+///
+/// Takes the frames owned or shared (e.g. `Arc<FitsImage>`), so callers holding shared
+/// frames don't need to copy them.
+pub fn detect_dithering<F: Borrow<FitsImage> + Sync>(frames: &[F]) -> Option<f32> {
+    use rayon::prelude::*;
+
+    let (reference, rest) = frames.split_first()?;
+    if rest.is_empty() {
+        return None;
+    }
+
+    // The reference sits at the origin
+    let mut offsets = vec![(0.0, 0.0)];
+    offsets.par_extend(
+        rest.par_iter()
+            .map(|frame| register_phase_correlation(reference.borrow(), frame.borrow())),
+    );
+
+    let count = offsets.len() as f32;
+    let mean_x = offsets.iter().map(|&(dx, _)| dx).sum::<f32>() / count;
+    let mean_y = offsets.iter().map(|&(_, dy)| dy).sum::<f32>() / count;
+    let rms = (offsets
+        .iter()
+        .map(|&(dx, dy)| (dx - mean_x).powi(2) + (dy - mean_y).powi(2))
+        .sum::<f32>()
+        / count)
+        .sqrt();
+
+    (rms >= MIN_DITHER_AMPLITUDE).then_some(rms)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// A 64x64 frame of Gaussian stars on a flat background, shifted by `(dx, dy)`
+    fn star_field(dx: f32, dy: f32) -> FitsImage {
+        let stars = [
+            (20.0, 18.0, 800.0),
+            (40.0, 30.0, 500.0),
+            (28.0, 45.0, 650.0),
+        ];
+        let data = ArrayD::from_shape_fn(vec![64, 64], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            100.0
+                + stars
+                    .iter()
+                    .map(|&(sx, sy, peak)| {
+                        let r2 = (x - sx - dx).powi(2) + (y - sy - dy).powi(2);
+                        peak * (-r2 / (2.0 * 1.5f32.powi(2))).exp()
+                    })
+                    .sum::<f32>()
+        });
+        FitsImage::from_data(data)
+    }
+
+    #[test]
+    fn sub_pixel_shift_is_recovered() {
+        for (sx, sy) in [(2.3, -1.6), (0.5, 0.25), (-3.7, 4.9)] {
+            let (dx, dy) = register_phase_correlation(&star_field(0.0, 0.0), &star_field(sx, sy));
+            assert!((dx - sx).abs() < 0.1, "dx = {} for {}", dx, sx);
+            assert!((dy - sy).abs() < 0.1, "dy = {} for {}", dy, sy);
+        }
+    }
+
+    #[test]
+    fn frames_of_different_sizes_give_no_shift() {
+        let small = FitsImage::from_data(ArrayD::zeros(vec![32, 32]));
+        assert_eq!(
+            register_phase_correlation(&star_field(0.0, 0.0), &small),
+            (0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn undithered_frames_are_not_dithered() {
+        let frames: Vec<FitsImage> = (0..4).map(|_| star_field(0.0, 0.0)).collect();
+        assert_eq!(detect_dithering(&frames), None);
+    }
+
+    #[test]
+    fn shifted_frames_report_their_rms_offset() {
+        let shifts = [(0.0, 0.0), (3.0, -2.0), (-4.0, 1.0), (1.0, 4.0)];
+        let frames: Vec<FitsImage> = shifts.iter().map(|&(dx, dy)| star_field(dx, dy)).collect();
+
+        let count = shifts.len() as f32;
+        let mean_x = shifts.iter().map(|s| s.0).sum::<f32>() / count;
+        let mean_y = shifts.iter().map(|s| s.1).sum::<f32>() / count;
+        let expected = (shifts
+            .iter()
+            .map(|&(dx, dy)| (dx - mean_x).powi(2) + (dy - mean_y).powi(2))
+            .sum::<f32>()
+            / count)
+            .sqrt();
+
+        let amplitude = detect_dithering(&frames).expect("frames were dithered");
+        assert!(
+            (amplitude - expected).abs() < 0.2,
+            "{} vs {}",
+            amplitude,
+            expected
+        );
+    }
+
+    #[test]
+    fn shared_frames_are_accepted_too() {
+        let frames: Vec<std::sync::Arc<FitsImage>> = [(0.0, 0.0), (5.0, 0.0)]
+            .iter()
+            .map(|&(dx, dy)| std::sync::Arc::new(star_field(dx, dy)))
+            .collect();
+        assert!(detect_dithering(&frames).is_some());
+        assert_eq!(detect_dithering(&frames[..1]), None);
+    }
+}
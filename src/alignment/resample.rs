@@ -0,0 +1,147 @@
+use ndarray::{Array2, ArrayView2, Ix2};
+
+use super::AffineTransform;
+use crate::image::{FitsImage, ImageError};
+
+/// Resample an image through an affine transform with bilinear interpolation.
+///
+/// `transform` maps the image's pixel coordinates to the output's, which keeps the input's
+/// size. Output pixels that map from outside the image are missing (NaN).
+pub fn apply_affine(
+    image: &FitsImage,
+    transform: &AffineTransform,
+) -> Result<FitsImage, ImageError> {
+    let inverse = transform.inverse().ok_or_else(|| {
+        ImageError::UnsupportedOperation("Transform is not invertible".to_string())
+    })?;
+
+    let mut output = image.clone();
+    let data = output.data_mut();
+    if data.ndim() == 3 {
+        for mut plane in data.outer_iter_mut() {
+            let resampled = resample_plane(
+                plane
+                    .view()
+                    .into_dimensionality::<Ix2>()
+                    .map_err(|e| ImageError::DimensionError(e.to_string()))?,
+                &inverse,
+            );
+            plane.assign(&resampled.into_dyn());
+        }
+    } else {
+        let resampled = resample_plane(
+            data.view()
+                .into_dimensionality::<Ix2>()
+                .map_err(|e| ImageError::DimensionError(e.to_string()))?,
+            &inverse,
+        );
+        *data = resampled.into_dyn();
+    }
+
+    Ok(output)
+}
+
+/// Undo the field rotation recorded in an image's CROTA2 keyword, so frames taken on
+/// either side of a meridian flip line up. Images without a rotation are returned as is.
+pub fn derotate(image: &FitsImage) -> Result<FitsImage, ImageError> {
+    match image.metadata.rotation {
+        Some(degrees) if degrees != 0.0 => {
+            let (width, height) = image.dimensions();
+            let center = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+            apply_affine(image, &AffineTransform::rotation(-degrees, center))
+        }
+        _ => Ok(image.clone()),
+    }
+}
+
+/// Fill every output pixel by sampling `plane` where `inverse` maps it
+fn resample_plane(plane: ArrayView2<f32>, inverse: &AffineTransform) -> Array2<f32> {
+    Array2::from_shape_fn(plane.dim(), |(y, x)| {
+        let (sx, sy) = inverse.apply(x as f64, y as f64);
+        bilinear(plane, sx, sy)
+    })
+}
+
+/// How far outside the plane, in pixels, a sample still counts as on its edge. Rotations
+/// by whole quarter turns land edge pixels a rounding error past the border.
+const EDGE_TOLERANCE: f64 = 1e-6;
+
+/// Bilinearly interpolated value at a fractional position, NaN outside the plane
+fn bilinear(plane: ArrayView2<f32>, x: f64, y: f64) -> f32 {
+    let (height, width) = plane.dim();
+    let (max_x, max_y) = ((width - 1) as f64, (height - 1) as f64);
+    if x < -EDGE_TOLERANCE
+        || y < -EDGE_TOLERANCE
+        || x > max_x + EDGE_TOLERANCE
+        || y > max_y + EDGE_TOLERANCE
+    {
+        return f32::NAN;
+    }
+    let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+
+    let x0 = x.floor() as usize;
+    let y0 = y.floor() as usize;
+    let x1 = (x0 + 1).min(width - 1);
+    let y1 = (y0 + 1).min(height - 1);
+    let fx = (x - x0 as f64) as f32;
+    let fy = (y - y0 as f64) as f32;
+
+    let top = plane[[y0, x0]] * (1.0 - fx) + plane[[y0, x1]] * fx;
+    let bottom = plane[[y1, x0]] * (1.0 - fx) + plane[[y1, x1]] * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::temp_path;
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9
+    }
+
+    #[test]
+    fn rotation_matrix_turns_about_its_center() {
+        let transform = AffineTransform::rotation(90.0, (2.0, 1.0));
+        assert!(close((transform.a, transform.b), (0.0, -1.0)));
+        assert!(close((transform.c, transform.d), (1.0, 0.0)));
+        assert!(close(transform.apply(2.0, 1.0), (2.0, 1.0)));
+        assert!(close(transform.apply(3.0, 1.0), (2.0, 2.0)));
+
+        let inverse = transform.inverse().unwrap();
+        assert!(close(inverse.apply(2.0, 2.0), (3.0, 1.0)));
+    }
+
+    #[test]
+    fn crota2_is_read_and_undone() {
+        let mut image = FitsImage::from_data(
+            ArrayD::from_shape_vec(vec![3, 3], (0..9).map(|v| v as f32).collect()).unwrap(),
+        );
+        image.metadata.rotation = Some(180.0);
+
+        let path = temp_path("crota2.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        let reloaded = FitsImage::from_file_detect_type(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.metadata.rotation, Some(180.0));
+
+        // Half a turn about the center reverses the pixel order
+        let derotated = derotate(&reloaded).unwrap();
+        let values: Vec<f32> = derotated.data().iter().map(|v| v.round()).collect();
+        assert_eq!(values, [8.0, 7.0, 6.0, 5.0, 4.0, 3.0, 2.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn collapsed_transform_is_rejected() {
+        let image = FitsImage::from_data(ArrayD::zeros(vec![2, 2]));
+        let flat = AffineTransform {
+            a: 0.0,
+            d: 0.0,
+            ..AffineTransform::identity()
+        };
+        assert!(apply_affine(&image, &flat).is_err());
+    }
+}
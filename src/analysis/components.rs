@@ -0,0 +1,109 @@
+use ndarray::Array2;
+
+/// Which neighbors of a pixel count as touching it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Pixels sharing an edge: left, right, up and down
+    Four,
+    /// Pixels sharing an edge or a corner
+    Eight,
+}
+
+impl Connectivity {
+    /// Offsets `(dy, dx)` of the neighbors of a pixel
+    fn offsets(self) -> &'static [(isize, isize)] {
+        match self {
+            Connectivity::Four => &[(-1, 0), (0, -1), (0, 1), (1, 0)],
+            Connectivity::Eight => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+        }
+    }
+}
+
+/// Label the connected regions of set pixels in `mask`.
+///
+/// Returns an array of the mask's shape holding 0 for unset pixels and the region's label,
+/// from 1 up to the returned count, for set ones. Regions are numbered in scan order of
+/// their first pixel (row by row, top to bottom).
+pub fn label_components(mask: &Array2<bool>, connectivity: Connectivity) -> (Array2<u32>, usize) {
+    let (height, width) = mask.dim();
+    let mut labels = Array2::<u32>::zeros((height, width));
+    let mut count = 0;
+    let mut stack = Vec::new();
+
+    for y in 0..height {
+        for x in 0..width {
+            if !mask[[y, x]] || labels[[y, x]] != 0 {
+                continue;
+            }
+
+            // Flood fill the region starting at this pixel
+            count += 1;
+            let label = count as u32;
+            labels[[y, x]] = label;
+            stack.push((y, x));
+            while let Some((cy, cx)) = stack.pop() {
+                for &(dy, dx) in connectivity.offsets() {
+                    let (Some(ny), Some(nx)) =
+                        (cy.checked_add_signed(dy), cx.checked_add_signed(dx))
+                    else {
+                        continue;
+                    };
+                    if ny < height && nx < width && mask[[ny, nx]] && labels[[ny, nx]] == 0 {
+                        labels[[ny, nx]] = label;
+                        stack.push((ny, nx));
+                    }
+                }
+            }
+        }
+    }
+
+    (labels, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn separated_blobs_get_their_own_labels() {
+        let mask = array![
+            [1, 1, 0, 0, 0, 1],
+            [1, 0, 0, 0, 0, 1],
+            [0, 0, 0, 1, 0, 0],
+            [0, 0, 1, 1, 0, 0],
+            [0, 0, 0, 0, 0, 0],
+        ]
+        .mapv(|v| v == 1);
+
+        let (labels, count) = label_components(&mask, Connectivity::Four);
+        assert_eq!(count, 3);
+        assert_eq!(
+            labels,
+            array![
+                [1, 1, 0, 0, 0, 2],
+                [1, 0, 0, 0, 0, 2],
+                [0, 0, 0, 3, 0, 0],
+                [0, 0, 3, 3, 0, 0],
+                [0, 0, 0, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn diagonal_neighbors_touch_only_with_eight_connectivity() {
+        let mask = array![[true, false], [false, true]];
+        assert_eq!(label_components(&mask, Connectivity::Four).1, 2);
+        assert_eq!(label_components(&mask, Connectivity::Eight).1, 1);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::image::{FitsImage, FrameType};
+
+use super::{DEFAULT_DETECTION_SIGMA, detection_plane, estimate_background, stars_in_plane};
+
+/// Frames with at least this many detected stars are taken to be lights
+const LIGHT_MIN_STARS: usize = 10;
+/// Median level, as a fraction of full scale, above which a starless frame is a flat
+const FLAT_MIN_LEVEL: f32 = 0.15;
+/// Background noise, as a fraction of full scale, above which a starless frame is still
+/// treated as a light (e.g. cloudy or badly focused) rather than a dark or bias
+const LIGHT_MIN_NOISE: f32 = 0.01;
+/// Exposures this short (seconds) are taken to be bias frames
+const BIAS_MAX_EXPOSURE: f64 = 0.01;
+
+/// Guess a frame's type from its contents, for files without a FRAME or IMAGETYP keyword.
+///
+/// Lights have stars; flats are starless with a high median level; darks and bias
+/// frames have a low median and little spread, and are told apart by exposure time
+/// (frames with no recorded exposure are assumed to be darks).
+pub fn infer_frame_type(image: &FitsImage) -> FrameType {
+    let plane = detection_plane(image);
+    let background = estimate_background(&plane);
+
+    if stars_in_plane(&plane, &background, DEFAULT_DETECTION_SIGMA).len() >= LIGHT_MIN_STARS {
+        return FrameType::Light;
+    }
+
+    let full_scale = full_scale(image);
+    if background.level / full_scale >= FLAT_MIN_LEVEL {
+        return FrameType::Flat;
+    }
+    if background.noise / full_scale >= LIGHT_MIN_NOISE {
+        return FrameType::Light;
+    }
+
+    match image.metadata.exposure_time {
+        Some(exposure) if exposure <= BIAS_MAX_EXPOSURE => FrameType::Bias,
+        _ => FrameType::Dark,
+    }
+}
+
+/// Largest value the frame's pixel type can hold. Float frames are assumed to be
+/// normalized to [0, 1] unless their values say otherwise.
+fn full_scale(image: &FitsImage) -> f32 {
+    image.metadata.pixel_type.max_value().unwrap_or_else(|| {
+        if image.calculate_statistics().max <= 1.0 {
+            1.0
+        } else {
+            u16::MAX as f32
+        }
+    })
+}
@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use super::{
+    DEFAULT_DETECTION_SIGMA, detection_plane, eccentricity_in_plane, estimate_background,
+    fwhm_in_plane, stars_in_plane,
+};
+use crate::image::FitsImage;
+
+/// Per-frame quality metrics used in session reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameMetrics {
+    pub file_name: String,
+    /// Exposure time in seconds
+    pub exposure_time: Option<f64>,
+    /// Sensor temperature in degrees Celsius
+    pub temperature: Option<f64>,
+    /// Median star FWHM in pixels
+    pub fwhm: Option<f32>,
+    /// Median star eccentricity, 0 for round stars
+    pub eccentricity: Option<f32>,
+    /// Number of stars detected
+    pub star_count: usize,
+    /// Robust (median) sky background level
+    pub background: f32,
+    /// Background noise estimated from the median absolute deviation
+    pub noise: f32,
+    /// Mean signal above the background relative to the background noise
+    pub snr: f32,
+}
+
+impl FrameMetrics {
+    pub const CSV_HEADER: &'static str = "filename,exposure,temperature,fwhm,background,snr";
+
+    /// Measure the metrics of a loaded frame
+    pub fn measure(image: &FitsImage) -> Self {
+        let plane = detection_plane(image);
+        let background = estimate_background(&plane);
+        let stars = stars_in_plane(&plane, &background, DEFAULT_DETECTION_SIGMA);
+        let fwhm = fwhm_in_plane(&plane, &background, &stars);
+        let eccentricity = eccentricity_in_plane(&plane, &background, &stars);
+
+        let mean = image.calculate_statistics().mean;
+        let snr = if background.noise > 0.0 {
+            (mean - background.level) / background.noise
+        } else {
+            0.0
+        };
+
+        let file_name = image
+            .metadata
+            .file_path
+            .as_deref()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Self {
+            file_name,
+            exposure_time: image.metadata.exposure_time,
+            temperature: image.metadata.temperature,
+            fwhm,
+            eccentricity,
+            star_count: stars.len(),
+            background: background.level,
+            noise: background.noise,
+            snr,
+        }
+    }
+
+    /// One CSV row matching `CSV_HEADER`; unknown values are left empty
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{:.3},{:.3}",
+            csv_escape(&self.file_name),
+            optional(self.exposure_time.map(|v| format!("{:.3}", v))),
+            optional(self.temperature.map(|v| format!("{:.1}", v))),
+            optional(self.fwhm.map(|v| format!("{:.3}", v))),
+            self.background,
+            self.snr,
+        )
+    }
+}
+
+fn optional(value: Option<String>) -> String {
+    value.unwrap_or_default()
+}
+
+/// Quote a CSV field if it contains a delimiter, quote, or line break
+pub fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// A 64 x 64 frame at `name` with a background of 100, a little pixel-to-pixel
+    /// variation, and a round star at each of `stars`
+    fn frame(name: &str, stars: &[(f32, f32)]) -> FitsImage {
+        let mut image = FitsImage::from_data(ArrayD::from_shape_fn(vec![64, 64], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            let hash = ((index[0] * 64 + index[1]) as u32).wrapping_mul(2_654_435_761) >> 28;
+            stars
+                .iter()
+                .map(|&(sx, sy)| 900.0 * (-((x - sx).powi(2) + (y - sy).powi(2)) / 5.0).exp())
+                .sum::<f32>()
+                + 100.0
+                + hash as f32
+        }));
+        image.metadata.file_path = Some(format!("/session/{}", name).into());
+        image
+    }
+
+    #[test]
+    fn csv_rows_match_the_measured_metrics() {
+        let mut starry = frame("light, 1.fits", &[(16.0, 20.0), (44.0, 40.0)]);
+        starry.metadata.exposure_time = Some(120.0);
+        starry.metadata.temperature = Some(-9.96);
+        let empty = frame("light_2.fits", &[]);
+
+        let rows: Vec<(FrameMetrics, String)> = [starry, empty]
+            .iter()
+            .map(|image| {
+                let metrics = FrameMetrics::measure(image);
+                let row = metrics.to_csv_row();
+                (metrics, row)
+            })
+            .collect();
+
+        let (metrics, row) = &rows[0];
+        assert_eq!(metrics.star_count, 2);
+        let fwhm = metrics.fwhm.unwrap();
+        assert_eq!(
+            *row,
+            format!(
+                "\"light, 1.fits\",120.000,-10.0,{:.3},{:.3},{:.3}",
+                fwhm, metrics.background, metrics.snr
+            )
+        );
+
+        let (metrics, row) = &rows[1];
+        assert_eq!((metrics.star_count, metrics.fwhm), (0, None));
+        assert_eq!(
+            *row,
+            format!(
+                "light_2.fits,,,,{:.3},{:.3}",
+                metrics.background, metrics.snr
+            )
+        );
+        assert_eq!(
+            row.split(',').count(),
+            FrameMetrics::CSV_HEADER.split(',').count()
+        );
+    }
+}
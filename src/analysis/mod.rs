@@ -0,0 +1,490 @@
+use ndarray::Array2;
+
+use crate::image::{FitsImage, fit_weighted_polynomial, median_of};
+
+mod components;
+mod frame_type;
+mod metrics;
+mod outliers;
+mod trails;
+
+pub use components::{Connectivity, label_components};
+pub use frame_type::infer_frame_type;
+pub use metrics::{FrameMetrics, csv_escape};
+pub use outliers::{flag_outlier_frames, flag_outlier_metrics};
+pub use trails::detect_trails;
+
+/// Connected regions smaller than this are treated as hot pixels or noise, not stars
+const MIN_STAR_PIXELS: usize = 3;
+
+/// A star found by `detect_stars`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Star {
+    /// Intensity-weighted centroid, in pixel coordinates
+    pub x: f32,
+    pub y: f32,
+    /// Sum of the background-subtracted pixel values
+    pub flux: f32,
+    /// Brightest background-subtracted pixel value
+    pub peak: f32,
+    /// Number of pixels above the detection threshold
+    pub area: usize,
+}
+
+/// Detection threshold, in background noise sigmas, used when measuring frame quality
+pub const DEFAULT_DETECTION_SIGMA: f32 = 5.0;
+
+/// Maximum number of stars (brightest first) measured when estimating the FWHM
+const MAX_FWHM_STARS: usize = 50;
+
+/// Ratio between the FWHM and the standard deviation of a Gaussian profile
+const FWHM_PER_SIGMA: f32 = 2.354_82;
+
+/// Only pixels this many background noise sigmas above the background are fitted when
+/// measuring a star's shape; fainter ones are mostly noise once their log is taken
+const FIT_MIN_SIGMA: f32 = 3.0;
+
+/// Frames whose median star eccentricity exceeds this have visibly elongated stars,
+/// usually from tracking errors or wind. Round stars measure below 0.4 with typical noise.
+pub const MAX_ECCENTRICITY: f32 = 0.6;
+
+/// Detect stars as connected groups of pixels above `threshold_sigma` times the background noise.
+///
+/// Stars are returned brightest (by flux) first.
+pub fn detect_stars(image: &FitsImage, threshold_sigma: f32) -> Vec<Star> {
+    let plane = detection_plane(image);
+    let background = estimate_background(&plane);
+    stars_in_plane(&plane, &background, threshold_sigma)
+}
+
+/// Median eccentricity of the brightest stars detected in `image`, from 0 for round stars
+/// towards 1 for trails, or `None` if no star could be measured. Each star is fitted with
+/// an elliptical Gaussian; frames above `MAX_ECCENTRICITY` likely suffered from tracking
+/// errors or wind.
+pub fn measure_eccentricity(image: &FitsImage) -> Option<f32> {
+    let plane = detection_plane(image);
+    let background = estimate_background(&plane);
+    let stars = stars_in_plane(&plane, &background, DEFAULT_DETECTION_SIGMA);
+    eccentricity_in_plane(&plane, &background, &stars)
+}
+
+/// Median eccentricity of the brightest detected stars, or `None` if no star could be
+/// measured. Each star's is `sqrt(1 - minor² / major²)` of the axes of the elliptical
+/// Gaussian `fit_gaussian` fits to it.
+fn eccentricity_in_plane(
+    plane: &Array2<f32>,
+    background: &Background,
+    stars: &[Star],
+) -> Option<f32> {
+    let mut eccentricities: Vec<f32> = stars
+        .iter()
+        .take(MAX_FWHM_STARS)
+        .filter_map(|star| fit_gaussian(plane, background, star))
+        .filter_map(|fit| fit.eccentricity())
+        .collect();
+
+    if eccentricities.is_empty() {
+        None
+    } else {
+        Some(median_of(&mut eccentricities))
+    }
+}
+
+/// Median FWHM in pixels of the brightest detected stars, or `None` if no star could be measured
+fn fwhm_in_plane(plane: &Array2<f32>, background: &Background, stars: &[Star]) -> Option<f32> {
+    let mut widths: Vec<f32> = stars
+        .iter()
+        .take(MAX_FWHM_STARS)
+        .filter_map(|star| star_moments(plane, background, star))
+        .map(|moments| FWHM_PER_SIGMA * ((moments.xx + moments.yy) / 2.0).sqrt())
+        .filter(|fwhm| fwhm.is_finite() && *fwhm > 0.0)
+        .collect();
+
+    if widths.is_empty() {
+        None
+    } else {
+        Some(median_of(&mut widths))
+    }
+}
+
+fn stars_in_plane(plane: &Array2<f32>, background: &Background, threshold_sigma: f32) -> Vec<Star> {
+    let mut stars: Vec<Star> = find_star_regions(plane, background, threshold_sigma)
+        .into_iter()
+        .map(|pixels| {
+            let mut flux = 0.0;
+            let mut peak = f32::MIN;
+            let mut sum_x = 0.0;
+            let mut sum_y = 0.0;
+            for &(y, x) in &pixels {
+                let value = plane[[y, x]] - background.level;
+                flux += value;
+                peak = peak.max(value);
+                sum_x += value * x as f32;
+                sum_y += value * y as f32;
+            }
+
+            Star {
+                x: sum_x / flux,
+                y: sum_y / flux,
+                flux,
+                peak,
+                area: pixels.len(),
+            }
+        })
+        .collect();
+
+    stars.sort_by(|a, b| {
+        b.flux
+            .partial_cmp(&a.flux)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    stars
+}
+
+/// Central second moments of a star's profile along the axes
+struct Moments {
+    xx: f32,
+    yy: f32,
+}
+
+/// Pixels `(y, x)` in a window around the star's centroid large enough to include the
+/// profile wings beyond the detection footprint
+fn star_window(plane: &Array2<f32>, star: &Star) -> impl Iterator<Item = (usize, usize)> {
+    let (height, width) = plane.dim();
+    let half = ((star.area as f32 / std::f32::consts::PI).sqrt() * 2.0).ceil() as usize + 2;
+
+    let cx = star.x.round() as usize;
+    let cy = star.y.round() as usize;
+    let (x0, x1) = (cx.saturating_sub(half), (cx + half).min(width - 1));
+    let (y0, y1) = (cy.saturating_sub(half), (cy + half).min(height - 1));
+    (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| (y, x)))
+}
+
+/// Intensity-weighted second moments in a window around the star's centroid
+fn star_moments(plane: &Array2<f32>, background: &Background, star: &Star) -> Option<Moments> {
+    let mut total = 0.0;
+    let mut xx = 0.0;
+    let mut yy = 0.0;
+    for (y, x) in star_window(plane, star) {
+        let value = plane[[y, x]] - background.level;
+        if value <= 0.0 {
+            continue;
+        }
+        let dx = x as f32 - star.x;
+        let dy = y as f32 - star.y;
+        total += value;
+        xx += value * dx * dx;
+        yy += value * dy * dy;
+    }
+
+    (total > 0.0).then(|| Moments {
+        xx: xx / total,
+        yy: yy / total,
+    })
+}
+
+/// Elliptical Gaussian fitted to a star, `peak * exp(-(a·dx² + 2b·dx·dy + c·dy²) / 2)`
+/// around its center. `[[a, b], [b, c]]` is the inverse of the profile's covariance.
+struct GaussianFit {
+    a: f64,
+    b: f64,
+    c: f64,
+}
+
+impl GaussianFit {
+    /// Eccentricity of the fitted ellipse. The eigenvalues of the inverse covariance are
+    /// `1 / minor²` and `1 / major²`, so `minor² / major²` is the small over the large one.
+    fn eccentricity(&self) -> Option<f32> {
+        let mean = (self.a + self.c) / 2.0;
+        let spread = (((self.a - self.c) / 2.0).powi(2) + self.b * self.b).sqrt();
+        let (large, small) = (mean + spread, mean - spread);
+        // Not a peak: the fit failed, e.g. on a saturated or blended star
+        if !large.is_finite() || small <= 0.0 {
+            return None;
+        }
+        Some((1.0 - small / large).sqrt() as f32)
+    }
+}
+
+/// Fit an elliptical Gaussian to the star's pixels by least squares.
+///
+/// The log of a Gaussian is a quadratic in x and y, so the fit is a linear least squares
+/// fit of a quadratic surface to the log of the background-subtracted pixels above
+/// `FIT_MIN_SIGMA`. Each pixel is weighted by its squared value, which undoes the way the
+/// log magnifies the noise of the faint wings.
+fn fit_gaussian(plane: &Array2<f32>, background: &Background, star: &Star) -> Option<GaussianFit> {
+    let threshold = (FIT_MIN_SIGMA * background.noise).max(0.0);
+    let samples = star_window(plane, star).filter_map(|(y, x)| {
+        let value = plane[[y, x]] - background.level;
+        (value > threshold).then(|| {
+            let dx = (x as f32 - star.x) as f64;
+            let dy = (y as f32 - star.y) as f64;
+            let value = value as f64;
+            (dx, dy, value.ln(), value * value)
+        })
+    });
+
+    // ln(value) = k0 + k1·dx + k2·dy + k3·dx² + k4·dx·dy + k5·dy²
+    let k = fit_weighted_polynomial(samples, 6)?;
+    Some(GaussianFit {
+        a: -2.0 * k[3],
+        b: -k[4],
+        c: -2.0 * k[5],
+    })
+}
+
+impl FitsImage {
+    /// Build a 0..1 mask that is 1 over detected stars, grown by `dilation` pixels.
+    ///
+    /// Useful to protect stars during stretching or to restrict sharpening to them.
+    pub fn generate_star_mask(&self, threshold_sigma: f32, dilation: usize) -> FitsImage {
+        let plane = detection_plane(self);
+        let background = estimate_background(&plane);
+        let (height, width) = plane.dim();
+
+        let mut mask = FitsImage::new(width, height);
+        mask.metadata = self.metadata.clone();
+        mask.frame_type = self.frame_type;
+
+        let radius = dilation as isize;
+        let radius_sq = radius * radius;
+        let data = mask.data_mut();
+
+        for pixels in find_star_regions(&plane, &background, threshold_sigma) {
+            for (y, x) in pixels {
+                // Stamp a disk of the dilation radius around each star pixel
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        if dx * dx + dy * dy > radius_sq {
+                            continue;
+                        }
+                        let (ny, nx) = (y as isize + dy, x as isize + dx);
+                        if ny >= 0 && nx >= 0 && (ny as usize) < height && (nx as usize) < width {
+                            data[[ny as usize, nx as usize]] = 1.0;
+                        }
+                    }
+                }
+            }
+        }
+
+        mask
+    }
+}
+
+/// Robust background level and noise of a plane
+struct Background {
+    level: f32,
+    noise: f32,
+}
+
+/// Median background and noise from the median absolute deviation, which stars barely affect
+fn estimate_background(plane: &Array2<f32>) -> Background {
+    let mut values: Vec<f32> = plane.iter().copied().filter(|v| v.is_finite()).collect();
+    if values.is_empty() {
+        return Background {
+            level: 0.0,
+            noise: 0.0,
+        };
+    }
+
+    let level = median_of(&mut values);
+    let mut deviations: Vec<f32> = values.iter().map(|&v| (v - level).abs()).collect();
+    // Scale the MAD to match a Gaussian standard deviation
+    let noise = 1.4826 * median_of(&mut deviations);
+
+    Background { level, noise }
+}
+
+/// Group the pixels above the detection threshold into 8-connected regions.
+///
+/// Noise spikes that only touch at their corners don't add up to a star: a region is kept
+/// only if at least `MIN_STAR_PIXELS` of its pixels are 4-connected, sharing edges.
+fn find_star_regions(
+    plane: &Array2<f32>,
+    background: &Background,
+    threshold_sigma: f32,
+) -> Vec<Vec<(usize, usize)>> {
+    let threshold = background.level + threshold_sigma * background.noise;
+    let above = plane.mapv(|v| v > threshold);
+    let (labels, count) = label_components(&above, Connectivity::Eight);
+    let (cores, core_count) = label_components(&above, Connectivity::Four);
+
+    let mut core_sizes = vec![0; core_count];
+    for &core in cores.iter().filter(|&&core| core != 0) {
+        core_sizes[core as usize - 1] += 1;
+    }
+
+    let mut regions = vec![Vec::new(); count];
+    let mut compact = vec![false; count];
+    for (((y, x), &label), &core) in labels.indexed_iter().zip(&cores) {
+        if label != 0 {
+            regions[label as usize - 1].push((y, x));
+            compact[label as usize - 1] |= core_sizes[core as usize - 1] >= MIN_STAR_PIXELS;
+        }
+    }
+
+    regions
+        .into_iter()
+        .zip(compact)
+        .filter_map(|(pixels, compact)| compact.then_some(pixels))
+        .collect()
+}
+
+/// The single plane stars are detected on: the image itself, or its luminance for color images
+pub fn detection_plane(image: &FitsImage) -> Array2<f32> {
+    let plane = if image.is_color() {
+        image
+            .to_luminance()
+            .and_then(|luminance| luminance.view2d().map(|plane| plane.to_owned()))
+    } else {
+        image.view2d().map(|plane| plane.to_owned())
+    };
+
+    plane.unwrap_or_else(|_| Array2::zeros((0, 0)))
+}
+
+/// Deterministic noise in [-3, 3] without the straight-line structure of a periodic
+/// pattern, for the tests' synthetic frames
+#[cfg(test)]
+fn noise(y: usize, x: usize) -> f32 {
+    let mut h = (y as u64) << 32 | x as u64;
+    h = (h ^ (h >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    (h % 7) as f32 - 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::FrameType;
+
+    /// A noisy 100 x 100 background with round gaussian stars at the given (x, y) positions
+    fn star_field(stars: &[(f32, f32)]) -> FitsImage {
+        FitsImage::from_data(ArrayD::from_shape_fn(vec![100, 100], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            stars
+                .iter()
+                .map(|&(sx, sy)| 800.0 * (-((x - sx).powi(2) + (y - sy).powi(2)) / 6.0).exp())
+                .sum::<f32>()
+                + 100.0
+                + noise(index[0], index[1])
+        }))
+    }
+
+    #[test]
+    fn star_mask_covers_dilated_stars_and_not_the_background() {
+        let image = star_field(&[(20.0, 30.0), (70.0, 60.0)]);
+        let mask = image.generate_star_mask(5.0, 3);
+        let undilated = image.generate_star_mask(5.0, 0);
+        let data = mask.data();
+
+        assert_eq!(mask.dimensions(), (100, 100));
+        assert!(data.iter().all(|&v| v == 0.0 || v == 1.0));
+        for (x, y) in [(20, 30), (70, 60)] {
+            assert_eq!(data[[y, x]], 1.0);
+            // The dilation reaches past the pixels bright enough to be detected
+            assert_eq!(undilated.data()[[y, x + 6]], 0.0);
+            assert_eq!(data[[y, x + 6]], 1.0);
+        }
+        for (x, y) in [(5, 5), (50, 45), (90, 10)] {
+            assert_eq!(data[[y, x]], 0.0);
+        }
+        let covered = data.iter().filter(|&&v| v == 1.0).count();
+        assert!(covered < 500, "{} pixels masked", covered);
+    }
+
+    #[test]
+    fn pixels_touching_only_at_corners_are_not_a_star() {
+        let mut image = FitsImage::from_data(ArrayD::from_shape_fn(vec![40, 40], |index| {
+            100.0 + noise(index[0], index[1])
+        }));
+        let data = image.data_mut();
+        // A diagonal run of spikes, and the same number of pixels sharing edges
+        for (y, x) in [(10, 10), (11, 11), (12, 12), (30, 10), (30, 11), (31, 11)] {
+            data[[y, x]] = 1000.0;
+        }
+
+        let stars = detect_stars(&image, DEFAULT_DETECTION_SIGMA);
+        assert_eq!(stars.len(), 1);
+        assert!(stars[0].y > 29.0 && stars[0].area == 3, "{:?}", stars[0]);
+    }
+
+    #[test]
+    fn frame_type_is_inferred_from_synthetic_frames() {
+        let uniform = |level: f32, exposure: Option<f64>| {
+            let mut image = FitsImage::from_data(ArrayD::from_shape_fn(vec![100, 100], |index| {
+                level + noise(index[0], index[1])
+            }));
+            image.metadata.exposure_time = exposure;
+            image
+        };
+        let grid: Vec<(f32, f32)> = [15.0, 38.0, 61.0, 84.0]
+            .iter()
+            .flat_map(|&x| [20.0, 50.0, 80.0].map(|y| (x, y)))
+            .collect();
+
+        let frames = [
+            (uniform(300.0, Some(0.0)), FrameType::Bias),
+            (uniform(300.0, Some(120.0)), FrameType::Dark),
+            (uniform(30000.0, Some(2.0)), FrameType::Flat),
+            (star_field(&grid), FrameType::Light),
+        ];
+        for (image, expected) in &frames {
+            assert_eq!(infer_frame_type(image), *expected);
+        }
+    }
+
+    #[test]
+    fn eccentricity_tells_round_stars_from_trailed_ones() {
+        // Gaussian stars with variances `var_x` and `var_y`, spaced well apart
+        let field = |var_x: f32, var_y: f32| {
+            FitsImage::from_data(ArrayD::from_shape_fn(vec![100, 100], |index| {
+                let (y, x) = (index[0] as f32, index[1] as f32);
+                let star = |sx: f32, sy: f32| {
+                    let r2 = (x - sx).powi(2) / var_x + (y - sy).powi(2) / var_y;
+                    800.0 * (-r2 / 2.0).exp()
+                };
+                [20.0, 50.0, 80.0]
+                    .iter()
+                    .flat_map(|&sx| [25.0, 75.0].map(|sy| star(sx, sy)))
+                    .sum::<f32>()
+                    + 100.0
+                    + noise(index[0], index[1])
+            }))
+        };
+
+        let round = FrameMetrics::measure(&field(3.0, 3.0))
+            .eccentricity
+            .unwrap();
+        // Trailed along x to twice the width: sqrt(1 - 1/4) = 0.87 without noise
+        let trailed = FrameMetrics::measure(&field(12.0, 3.0))
+            .eccentricity
+            .unwrap();
+        assert!(round < 0.4, "round stars: {}", round);
+        assert!(trailed > 0.6, "trailed stars: {}", trailed);
+    }
+
+    #[test]
+    fn gaussian_fit_recovers_the_elongation_of_a_tilted_star() {
+        // A star with axis variances 12 and 3, turned 30° from the x axis
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        let image = FitsImage::from_data(ArrayD::from_shape_fn(vec![60, 60], |index| {
+            let (dx, dy) = (index[1] as f32 - 30.0, index[0] as f32 - 30.0);
+            let (u, v) = (dx * cos + dy * sin, dy * cos - dx * sin);
+            100.0
+                + 1000.0 * (-(u * u / 12.0 + v * v / 3.0) / 2.0).exp()
+                + noise(index[0], index[1]) / 3.0
+        }));
+
+        let eccentricity = measure_eccentricity(&image).unwrap();
+        let expected = (1.0f32 - 3.0 / 12.0).sqrt();
+        assert!(
+            (eccentricity - expected).abs() < 0.03,
+            "{} != {}",
+            eccentricity,
+            expected
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use super::{FrameMetrics, MAX_ECCENTRICITY, median_of};
+use crate::image::FitsImage;
+
+/// A frame whose background is more than this many robust standard deviations (scaled MAD)
+/// from the session median is flagged
+const BACKGROUND_OUTLIER_SIGMA: f32 = 3.0;
+/// A frame with fewer than this fraction of the session's median star count is flagged
+const MIN_STAR_FRACTION: f32 = 0.5;
+
+/// Flag frames that stand out from the rest of the session, e.g. from clouds, car
+/// headlights or dew.
+///
+/// Measures every frame and passes the metrics to `flag_outlier_metrics`.
+pub fn flag_outlier_frames(frames: &[FitsImage]) -> Vec<bool> {
+    use rayon::prelude::*;
+
+    let metrics: Vec<FrameMetrics> = frames.par_iter().map(FrameMetrics::measure).collect();
+    flag_outlier_metrics(&metrics)
+}
+
+/// Flag the frames whose metrics stand out from the rest of the session.
+///
+/// A frame is flagged when its median background is an outlier (beyond
+/// `BACKGROUND_OUTLIER_SIGMA` scaled MADs from the session median), when it has far
+/// fewer stars than the session's median frame, or when its stars are elongated (median
+/// eccentricity above `MAX_ECCENTRICITY`). The background spread is never taken below the
+/// session's median pixel noise, so near-identical frames don't flag each other over
+/// differences the noise swamps. Works from the frames' `FrameMetrics`, so a session can
+/// be measured one frame at a time. Needs at least three frames to say anything; smaller
+/// sets are never flagged.
+pub fn flag_outlier_metrics(metrics: &[FrameMetrics]) -> Vec<bool> {
+    if metrics.len() < 3 {
+        return vec![false; metrics.len()];
+    }
+
+    let mut levels: Vec<f32> = metrics.iter().map(|m| m.background).collect();
+    let session_level = median_of(&mut levels);
+    let mut deviations: Vec<f32> = levels.iter().map(|&l| (l - session_level).abs()).collect();
+    let mut noise: Vec<f32> = metrics.iter().map(|m| m.noise).collect();
+    let spread = (1.4826 * median_of(&mut deviations)).max(median_of(&mut noise));
+
+    let mut star_counts: Vec<f32> = metrics.iter().map(|m| m.star_count as f32).collect();
+    let session_stars = median_of(&mut star_counts);
+
+    metrics
+        .iter()
+        .map(|m| {
+            let background_outlier =
+                (m.background - session_level).abs() > BACKGROUND_OUTLIER_SIGMA * spread;
+            let few_stars = (m.star_count as f32) < MIN_STAR_FRACTION * session_stars;
+            let elongated = m.eccentricity.is_some_and(|e| e > MAX_ECCENTRICITY);
+            background_outlier || few_stars || elongated
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// A 96x96 frame of Gaussian stars on `sky`, with a little deterministic noise
+    fn frame(sky: f32, seed: usize) -> FitsImage {
+        let stars = [
+            (20.0, 20.0),
+            (70.0, 25.0),
+            (45.0, 50.0),
+            (15.0, 75.0),
+            (75.0, 78.0),
+        ];
+        let data = ArrayD::from_shape_fn(vec![96, 96], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            let noise = ((index[0] * 31 + index[1] * 17 + seed * 7) % 11) as f32 - 5.0;
+            sky + noise
+                + stars
+                    .iter()
+                    .map(|&(sx, sy)| {
+                        let r2 = (x - sx).powi(2) + (y - sy).powi(2);
+                        900.0 * (-r2 / (2.0 * 1.8f32.powi(2))).exp()
+                    })
+                    .sum::<f32>()
+        });
+        FitsImage::from_data(data)
+    }
+
+    #[test]
+    fn clouded_frame_is_flagged() {
+        let mut frames: Vec<FitsImage> = (0..5)
+            .map(|seed| frame(100.0 + seed as f32, seed))
+            .collect();
+        frames.insert(2, frame(400.0, 9));
+
+        let flags = flag_outlier_frames(&frames);
+        assert_eq!(flags, [false, false, true, false, false, false]);
+    }
+
+    #[test]
+    fn differences_below_the_noise_are_not_flagged() {
+        // The backgrounds barely differ, so their MAD alone would be zero
+        let mut metrics: Vec<FrameMetrics> = (0..5)
+            .map(|seed| FrameMetrics::measure(&frame(100.0, seed)))
+            .collect();
+        for m in &mut metrics {
+            m.background = 100.0;
+        }
+        metrics[3].background = 101.0;
+
+        assert_eq!(flag_outlier_metrics(&metrics), [false; 5]);
+    }
+
+    #[test]
+    fn small_sessions_are_never_flagged() {
+        let metrics = [
+            FrameMetrics::measure(&frame(100.0, 0)),
+            FrameMetrics::measure(&frame(400.0, 1)),
+        ];
+        assert_eq!(flag_outlier_metrics(&metrics), [false, false]);
+    }
+}
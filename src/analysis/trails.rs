@@ -0,0 +1,170 @@
+use super::{detection_plane, estimate_background};
+use crate::image::FitsImage;
+
+/// Number of angle bins in the Hough accumulator (1 degree each)
+const ANGLE_BINS: usize = 180;
+/// Maximum number of trails reported per frame
+const MAX_TRAILS: usize = 10;
+/// Distance from the line, in pixels, within which a pixel supports it
+const LINE_TOLERANCE: f32 = 1.5;
+/// Shortest feature reported as a trail, in pixels
+const MIN_TRAIL_LENGTH: f32 = 30.0;
+/// Minimum fraction of the segment's length covered by supporting pixels, so a chance
+/// alignment of separate stars isn't reported as a trail
+const MIN_TRAIL_DENSITY: f32 = 0.5;
+
+/// A straight linear feature, such as a satellite or aircraft trail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineSegment {
+    pub x0: f32,
+    pub y0: f32,
+    pub x1: f32,
+    pub y1: f32,
+    /// Direction of the segment in radians, in [0, π), measured from the x axis towards y
+    pub angle: f32,
+    /// Number of above-threshold pixels along the segment
+    pub pixels: usize,
+}
+
+impl LineSegment {
+    pub fn length(&self) -> f32 {
+        (self.x1 - self.x0).hypot(self.y1 - self.y0)
+    }
+}
+
+/// Detect linear features among the pixels above `threshold_sigma` times the background noise.
+///
+/// Uses a Hough transform: every bright pixel votes for the lines through it, the strongest
+/// line is extracted as a segment and its pixels removed, and the search repeats. Stars only
+/// contribute a handful of votes to any single line, so they aren't reported.
+pub fn detect_trails(image: &FitsImage, threshold_sigma: f32) -> Vec<LineSegment> {
+    let plane = detection_plane(image);
+    let background = estimate_background(&plane);
+    let threshold = background.level + threshold_sigma * background.noise;
+
+    let (height, width) = plane.dim();
+    let mut points: Vec<(f32, f32)> = plane
+        .indexed_iter()
+        .filter(|&(_, &value)| value > threshold)
+        .map(|((y, x), _)| (x as f32, y as f32))
+        .collect();
+
+    let diagonal = (width as f32).hypot(height as f32);
+    let rho_bins = 2 * diagonal.ceil() as usize + 1;
+    let angles: Vec<(f32, f32)> = (0..ANGLE_BINS)
+        .map(|i| {
+            let theta = i as f32 * std::f32::consts::PI / ANGLE_BINS as f32;
+            (theta.cos(), theta.sin())
+        })
+        .collect();
+
+    let mut trails = Vec::new();
+    let mut accumulator = vec![0u32; ANGLE_BINS * rho_bins];
+
+    while trails.len() < MAX_TRAILS && points.len() as f32 >= MIN_TRAIL_LENGTH * MIN_TRAIL_DENSITY {
+        // Vote for every line (theta, rho) through each point: rho = x cos θ + y sin θ
+        accumulator.fill(0);
+        for &(x, y) in &points {
+            for (i, &(cos, sin)) in angles.iter().enumerate() {
+                let rho = (x * cos + y * sin + diagonal).round() as usize;
+                accumulator[i * rho_bins + rho] += 1;
+            }
+        }
+
+        let Some((best, &votes)) = accumulator.iter().enumerate().max_by_key(|&(_, v)| *v) else {
+            break;
+        };
+        if (votes as f32) < MIN_TRAIL_LENGTH * MIN_TRAIL_DENSITY {
+            break;
+        }
+
+        let (cos, sin) = angles[best / rho_bins];
+        let rho = (best % rho_bins) as f32 - diagonal;
+
+        // Split the points into those supporting the line and the rest
+        let (support, rest): (Vec<(f32, f32)>, Vec<(f32, f32)>) = points
+            .iter()
+            .partition(|&&(x, y)| (x * cos + y * sin - rho).abs() <= LINE_TOLERANCE);
+        points = rest;
+
+        // Extent of the supporting pixels along the line direction (-sin θ, cos θ)
+        let along = |&(x, y): &(f32, f32)| -x * sin + y * cos;
+        let (start, end) = support
+            .iter()
+            .map(along)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), t| {
+                (lo.min(t), hi.max(t))
+            });
+        let length = end - start;
+        if length < MIN_TRAIL_LENGTH {
+            continue;
+        }
+
+        // Count the 1px steps along the segment holding a supporting pixel, so a couple of
+        // wide stars that happen to line up don't pass for a trail
+        let mut covered = vec![false; length as usize + 1];
+        for point in &support {
+            covered[(along(point) - start) as usize] = true;
+        }
+        let coverage = covered.iter().filter(|&&c| c).count();
+        if (coverage as f32) < length * MIN_TRAIL_DENSITY {
+            continue;
+        }
+
+        // Foot of the normal from the origin, then step along the line to both ends
+        let (foot_x, foot_y) = (rho * cos, rho * sin);
+        let segment_direction =
+            (sin.atan2(cos) + std::f32::consts::FRAC_PI_2).rem_euclid(std::f32::consts::PI);
+        trails.push(LineSegment {
+            x0: foot_x - start * sin,
+            y0: foot_y + start * cos,
+            x1: foot_x - end * sin,
+            y1: foot_y + end * cos,
+            angle: segment_direction,
+            pixels: support.len(),
+        });
+    }
+
+    trails
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::analysis::noise;
+
+    #[test]
+    fn injected_trail_is_found_with_its_orientation() {
+        // A faint star and a bright line from (10, 20) to (110, 70), about 26.6 degrees
+        let data = ArrayD::from_shape_fn(vec![100, 120], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            let star = 500.0 * (-((x - 90.0).powi(2) + (y - 20.0).powi(2)) / 4.0).exp();
+            let on_line =
+                (10.0..=110.0).contains(&x) && (y - (20.0 + (x - 10.0) / 2.0)).abs() < 1.0;
+            100.0 + noise(index[0], index[1]) + star + if on_line { 400.0 } else { 0.0 }
+        });
+        let image = FitsImage::from_data(data);
+
+        let trails = detect_trails(&image, 5.0);
+        assert_eq!(trails.len(), 1);
+        let expected = 0.5f32.atan();
+        assert!((trails[0].angle - expected).abs() < 2f32.to_radians());
+        assert!(trails[0].length() > 90.0);
+    }
+
+    #[test]
+    fn stars_alone_are_not_trails() {
+        let data = ArrayD::from_shape_fn(vec![100, 100], |index| {
+            let (y, x) = (index[0] as f32, index[1] as f32);
+            [(20.0, 30.0), (60.0, 70.0), (80.0, 15.0)]
+                .iter()
+                .map(|&(sx, sy)| 800.0 * (-((x - sx).powi(2) + (y - sy).powi(2)) / 6.0).exp())
+                .sum::<f32>()
+                + 100.0
+                + noise(index[0], index[1])
+        });
+        assert!(detect_trails(&FitsImage::from_data(data), 5.0).is_empty());
+    }
+}
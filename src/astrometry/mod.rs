@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::image::{FitsImage, ImageError};
+
+/// FITS header records are fixed 80-character cards
+const CARD_LENGTH: usize = 80;
+
+/// Tangent-plane (TAN) world coordinate solution, as produced by a plate solver.
+///
+/// Pixel coordinates follow the FITS convention: 1-based, so the centre of the first
+/// pixel is (1, 1). Angles are in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wcs {
+    /// Reference pixel
+    pub crpix: [f64; 2],
+    /// Right ascension and declination of the reference pixel
+    pub crval: [f64; 2],
+    /// Linear transform from pixel offsets to intermediate world coordinates
+    pub cd: [[f64; 2]; 2],
+}
+
+impl Wcs {
+    /// Parse a WCS from FITS header text, either newline-separated or as raw 80-character
+    /// cards (the `.wcs` files written by ASTAP and astrometry.net respectively).
+    ///
+    /// Uses the CD matrix when present, otherwise CDELT with an optional CROTA2 rotation.
+    pub fn from_header(text: &str) -> Result<Self, ImageError> {
+        let mut keys = HashMap::new();
+        for card in header_cards(text) {
+            if let Some((key, value)) = parse_card(card) {
+                keys.insert(key, value);
+            }
+        }
+
+        let get = |key: &str| keys.get(key).copied();
+        let require = |key: &str| {
+            get(key).ok_or_else(|| ImageError::FormatError(format!("WCS is missing {}", key)))
+        };
+
+        let crpix = [require("CRPIX1")?, require("CRPIX2")?];
+        let crval = [require("CRVAL1")?, require("CRVAL2")?];
+
+        let cd = match (get("CD1_1"), get("CD1_2"), get("CD2_1"), get("CD2_2")) {
+            (Some(cd11), cd12, cd21, Some(cd22)) => {
+                [[cd11, cd12.unwrap_or(0.0)], [cd21.unwrap_or(0.0), cd22]]
+            }
+            _ => {
+                let cdelt1 = require("CDELT1")?;
+                let cdelt2 = require("CDELT2")?;
+                let (sin, cos) = get("CROTA2").unwrap_or(0.0).to_radians().sin_cos();
+                [[cdelt1 * cos, -cdelt2 * sin], [cdelt1 * sin, cdelt2 * cos]]
+            }
+        };
+
+        Ok(Self { crpix, crval, cd })
+    }
+
+    /// Sky position (RA, Dec in degrees) of a 1-based pixel coordinate
+    pub fn pixel_to_sky(&self, x: f64, y: f64) -> (f64, f64) {
+        let dx = x - self.crpix[0];
+        let dy = y - self.crpix[1];
+        let xi = (self.cd[0][0] * dx + self.cd[0][1] * dy).to_radians();
+        let eta = (self.cd[1][0] * dx + self.cd[1][1] * dy).to_radians();
+
+        // Inverse gnomonic projection around the reference point
+        let ra0 = self.crval[0].to_radians();
+        let dec0 = self.crval[1].to_radians();
+        let denominator = dec0.cos() - eta * dec0.sin();
+        let ra = ra0 + xi.atan2(denominator);
+        let dec = (dec0.sin() + eta * dec0.cos()).atan2(xi.hypot(denominator));
+
+        (ra.to_degrees().rem_euclid(360.0), dec.to_degrees())
+    }
+
+    /// Image scale in arcseconds per pixel
+    pub fn pixel_scale(&self) -> f64 {
+        let determinant = self.cd[0][0] * self.cd[1][1] - self.cd[0][1] * self.cd[1][0];
+        determinant.abs().sqrt() * 3600.0
+    }
+}
+
+/// Split header text into cards, accepting both line-based and fixed-width layouts
+fn header_cards(text: &str) -> Vec<&str> {
+    if text.contains('\n') {
+        return text.lines().collect();
+    }
+
+    let mut cards = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let split = rest
+            .char_indices()
+            .nth(CARD_LENGTH)
+            .map_or(rest.len(), |(index, _)| index);
+        let (card, tail) = rest.split_at(split);
+        cards.push(card);
+        rest = tail;
+    }
+    cards
+}
+
+/// Parse a numeric `KEY = value / comment` card
+fn parse_card(card: &str) -> Option<(String, f64)> {
+    let (key, value) = card.split_once('=')?;
+    let value = value.split('/').next()?.trim().trim_matches('\'').trim();
+    let value = value.replace(['D', 'd'], "E").parse().ok()?;
+    Some((key.trim().to_string(), value))
+}
+
+/// Something that can plate-solve a FITS file on disk
+pub trait PlateSolver {
+    fn solve_file(&self, path: &Path) -> Result<Wcs, ImageError>;
+}
+
+/// The external solvers `ExternalSolver` knows how to drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolverKind {
+    Astap,
+    AstrometryNet,
+}
+
+/// Parses `astap` or `astrometry-net`, as taken on the command line
+impl FromStr for SolverKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "astap" => Ok(SolverKind::Astap),
+            "astrometry-net" | "astrometry.net" => Ok(SolverKind::AstrometryNet),
+            _ => Err(format!(
+                "Unknown plate solver '{}', expected astap or astrometry-net",
+                s
+            )),
+        }
+    }
+}
+
+/// Plate solver that runs a locally installed `astap` or `solve-field` binary and reads
+/// back the `.wcs` file it writes next to the input
+#[derive(Debug, Clone)]
+pub struct ExternalSolver {
+    pub kind: SolverKind,
+    pub binary: PathBuf,
+}
+
+impl ExternalSolver {
+    /// Use the solver's usual binary name, looked up on `PATH`
+    pub fn new(kind: SolverKind) -> Self {
+        let binary = match kind {
+            SolverKind::Astap => "astap",
+            SolverKind::AstrometryNet => "solve-field",
+        };
+        Self {
+            kind,
+            binary: PathBuf::from(binary),
+        }
+    }
+}
+
+impl PlateSolver for ExternalSolver {
+    fn solve_file(&self, path: &Path) -> Result<Wcs, ImageError> {
+        let mut command = Command::new(&self.binary);
+        match self.kind {
+            SolverKind::Astap => command.arg("-f").arg(path),
+            SolverKind::AstrometryNet => command
+                .args(["--overwrite", "--no-plots", "--new-fits", "none"])
+                .arg(path),
+        };
+
+        log::debug!("Running plate solver: {:?}", command);
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(ImageError::UnsupportedOperation(format!(
+                "{:?} failed: {}",
+                self.binary,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let wcs_path = path.with_extension("wcs");
+        let header = fs::read(&wcs_path).map_err(|_| {
+            ImageError::UnsupportedOperation(format!("No solution found for {:?}", path))
+        })?;
+        Wcs::from_header(&String::from_utf8_lossy(&header))
+    }
+}
+
+/// Plate-solve an image by writing it to a temporary FITS file and handing that to `solver`
+pub fn plate_solve(image: &FitsImage, solver: &dyn PlateSolver) -> Result<Wcs, ImageError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let dir = std::env::temp_dir().join(format!("eventide-solve-{}-{}", std::process::id(), nanos));
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join("image.fits");
+    let result = image.to_file(&path).and_then(|()| solver.solve_file(&path));
+
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        log::warn!("Could not remove {:?}: {}", dir, e);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// Part of a `.wcs` file written by ASTAP, one card per line
+    const ASTAP_WCS: &str = "\
+SIMPLE  =                    T / file does conform to FITS standard
+NAXIS   =                    0 / number of data axes
+CTYPE1  = 'RA---TAN'           / first parameter RA  ,  projection TANgential
+CRPIX1  =  2.3205000000000E+003 / X of reference pixel
+CRPIX2  =  1.7405000000000E+003 / Y of reference pixel
+CRVAL1  =  1.0684579000000E+001 / RA of reference pixel (deg)
+CRVAL2  =  4.1268750000000E+001 / DEC of reference pixel (deg)
+CDELT1  = -3.0575000000000E-004 / X pixel size (deg)
+CDELT2  =  3.0575000000000E-004 / Y pixel size (deg)
+CROTA2  =  1.7960000000000E+002 / Image twist Y axis (deg)
+CD1_1   =  3.0574255023000E-004 / CD matrix to convert (x,y) to (Ra, Dec)
+CD1_2   =  2.1344770345000E-006 / CD matrix to convert (x,y) to (Ra, Dec)
+CD2_1   =  2.1344770345000E-006 / CD matrix to convert (x,y) to (Ra, Dec)
+CD2_2   = -3.0574255023000E-004 / CD matrix to convert (x,y) to (Ra, Dec)
+COMMENT 7  Solved in 0.2 sec. Offset was 0.004 deg.
+END";
+
+    #[test]
+    fn astap_solution_is_parsed_from_its_cd_matrix() {
+        let wcs = Wcs::from_header(ASTAP_WCS).unwrap();
+        assert_eq!(wcs.crpix, [2320.5, 1740.5]);
+        assert_eq!(wcs.crval, [10.684579, 41.26875]);
+        assert_eq!(wcs.cd[0][1], 2.1344770345e-6);
+
+        let (ra, dec) = wcs.pixel_to_sky(2320.5, 1740.5);
+        assert!((ra - 10.684579).abs() < 1e-9 && (dec - 41.26875).abs() < 1e-9);
+        assert!((wcs.pixel_scale() - 1.1007).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fixed_width_cards_with_cdelt_and_rotation_are_parsed() {
+        // astrometry.net writes raw 80-character cards without line breaks
+        let header: String = [
+            "CRPIX1  =                  512 / X reference pixel",
+            "CRPIX2  =                  384 / Y reference pixel",
+            "CRVAL1  =               83.822 / RA  of reference point",
+            "CRVAL2  =               -5.391 / DEC of reference point",
+            "CDELT1  =              -0.0003 / X pixel scale",
+            "CDELT2  =               0.0003 / Y pixel scale",
+            "CROTA2  =                   90 / Rotation",
+            "END",
+        ]
+        .iter()
+        .map(|card| format!("{:<80}", card))
+        .collect();
+
+        let wcs = Wcs::from_header(&header).unwrap();
+        assert_eq!((wcs.crpix, wcs.crval), ([512.0, 384.0], [83.822, -5.391]));
+        let expected = [[0.0, -0.0003], [-0.0003, 0.0]];
+        for (row, expected_row) in wcs.cd.iter().zip(expected) {
+            for (value, expected) in row.iter().zip(expected_row) {
+                assert!((value - expected).abs() < 1e-12, "{:?}", wcs.cd);
+            }
+        }
+        assert!((wcs.pixel_scale() - 1.08).abs() < 1e-9);
+
+        assert!(matches!(
+            Wcs::from_header("CRPIX1  = 1\nCRPIX2  = 1"),
+            Err(ImageError::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn solvers_parse_from_their_command_line_names() {
+        assert_eq!("astap".parse(), Ok(SolverKind::Astap));
+        assert_eq!("astrometry-net".parse(), Ok(SolverKind::AstrometryNet));
+        assert_eq!("Astrometry.net".parse(), Ok(SolverKind::AstrometryNet));
+        assert!("platesolve2".parse::<SolverKind>().is_err());
+    }
+
+    /// Answers with the ASTAP sample, remembering the file it was asked to solve
+    struct MockSolver {
+        solved: RefCell<Option<PathBuf>>,
+    }
+
+    impl PlateSolver for MockSolver {
+        fn solve_file(&self, path: &Path) -> Result<Wcs, ImageError> {
+            FitsImage::validate(path)?;
+            *self.solved.borrow_mut() = Some(path.to_owned());
+            Wcs::from_header(ASTAP_WCS)
+        }
+    }
+
+    #[test]
+    fn plate_solve_hands_a_temporary_file_to_the_solver() {
+        let image = FitsImage::from_data(ArrayD::from_elem(vec![8, 8], 100.0));
+        let solver = MockSolver {
+            solved: RefCell::new(None),
+        };
+
+        let wcs = plate_solve(&image, &solver).unwrap();
+        assert_eq!(wcs, Wcs::from_header(ASTAP_WCS).unwrap());
+        let solved = solver.solved.into_inner().unwrap();
+        assert!(!solved.exists(), "temporary file {:?} left behind", solved);
+    }
+}
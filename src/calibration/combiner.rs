@@ -0,0 +1,282 @@
+use crate::image::{FitsImage, FrameType, ImageError};
+
+use super::{RejectionFallback, RejectionSummary, SigmaClipOptions};
+
+/// A method of combining a set of aligned frames into a single image
+pub trait Combiner: Send + Sync {
+    /// Combine the frames into one image
+    fn combine(&self, images: &[FitsImage]) -> Result<FitsImage, ImageError>;
+
+    /// Combine the frames, also returning which values were rejected for combiners that
+    /// reject outliers
+    fn combine_with_summary(
+        &self,
+        images: &[FitsImage],
+    ) -> Result<(FitsImage, Option<RejectionSummary>), ImageError> {
+        Ok((self.combine(images)?, None))
+    }
+
+    /// Human readable name, used in the UI and logs
+    fn name(&self) -> &'static str;
+
+    /// Name and parameters, recorded in the processing history of combined images
+    fn description(&self) -> String {
+        self.name().to_string()
+    }
+}
+
+/// Record the combiner used in a combined image's processing history
+fn record_combine(
+    combiner: &dyn Combiner,
+    result: Result<FitsImage, ImageError>,
+) -> Result<FitsImage, ImageError> {
+    result.map(|mut image| {
+        image
+            .metadata
+            .history
+            .record(format!("Combine method: {}", combiner.description()));
+        image
+    })
+}
+
+/// Per-pixel mean, see `average`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Average;
+
+impl Combiner for Average {
+    fn combine(&self, images: &[FitsImage]) -> Result<FitsImage, ImageError> {
+        record_combine(self, super::average(images))
+    }
+
+    fn name(&self) -> &'static str {
+        "Average"
+    }
+}
+
+/// Per-pixel median, see `median`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Median;
+
+impl Combiner for Median {
+    fn combine(&self, images: &[FitsImage]) -> Result<FitsImage, ImageError> {
+        record_combine(self, super::median(images))
+    }
+
+    fn name(&self) -> &'static str {
+        "Median"
+    }
+}
+
+/// Per-pixel mean after iteratively rejecting outliers, see `sigma_clipping`
+#[derive(Debug, Clone, Copy)]
+pub struct SigmaClip {
+    pub sigma: f32,
+    pub iterations: usize,
+    /// Value written where every frame was rejected
+    pub fallback: RejectionFallback,
+    /// Fewest frames accepted, see `SigmaClipOptions::min_frames`
+    pub min_frames: usize,
+}
+
+impl Default for SigmaClip {
+    fn default() -> Self {
+        let options = SigmaClipOptions::default();
+        Self {
+            sigma: options.sigma,
+            iterations: options.max_iterations,
+            fallback: options.fallback,
+            min_frames: options.min_frames,
+        }
+    }
+}
+
+impl SigmaClip {
+    fn options(&self) -> SigmaClipOptions {
+        SigmaClipOptions {
+            sigma: self.sigma,
+            max_iterations: self.iterations,
+            fallback: self.fallback,
+            min_frames: self.min_frames,
+        }
+    }
+}
+
+impl Combiner for SigmaClip {
+    fn combine(&self, images: &[FitsImage]) -> Result<FitsImage, ImageError> {
+        // `sigma_clipping` uses the default options and can run on the GPU
+        let defaults = SigmaClipOptions::default();
+        if self.fallback == defaults.fallback && self.min_frames == defaults.min_frames {
+            return record_combine(
+                self,
+                super::sigma_clipping(images, self.sigma, self.iterations),
+            );
+        }
+
+        let result =
+            super::sigma_clipping_with_options(images, &self.options()).map(|stack| stack.image);
+        record_combine(self, result)
+    }
+
+    fn combine_with_summary(
+        &self,
+        images: &[FitsImage],
+    ) -> Result<(FitsImage, Option<RejectionSummary>), ImageError> {
+        let stack = super::sigma_clipping_with_options(images, &self.options())?;
+        let image = record_combine(self, Ok(stack.image))?;
+        Ok((image, Some(stack.summary)))
+    }
+
+    fn name(&self) -> &'static str {
+        "Sigma Clipping"
+    }
+
+    fn description(&self) -> String {
+        format!(
+            "{} (sigma {}, {} iterations, {:?} fallback)",
+            self.name(),
+            self.sigma,
+            self.iterations,
+            self.fallback
+        )
+    }
+}
+
+/// The combine methods users can pick from, with default parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CombineMethod {
+    Average,
+    Median,
+    SigmaClip { sigma: f32, iterations: usize },
+}
+
+impl CombineMethod {
+    /// All methods with their default parameters, in display order
+    pub fn all() -> [CombineMethod; 3] {
+        let clip = SigmaClip::default();
+        [
+            CombineMethod::Average,
+            CombineMethod::Median,
+            CombineMethod::SigmaClip {
+                sigma: clip.sigma,
+                iterations: clip.iterations,
+            },
+        ]
+    }
+
+    /// The recommended method for building a master of the given frame type
+    pub fn default_for(frame_type: FrameType) -> Self {
+        match frame_type {
+            // Median rejects cosmic rays and other outliers in the small bias/dark sets
+            FrameType::Bias | FrameType::Dark | FrameType::DarkFlat => CombineMethod::Median,
+            // Flats are smooth and well exposed, so averaging gives the lowest noise
+            FrameType::Flat => CombineMethod::Average,
+            // Sigma clipping removes satellites and planes while keeping the mean's SNR
+            FrameType::Light => {
+                let clip = SigmaClip::default();
+                CombineMethod::SigmaClip {
+                    sigma: clip.sigma,
+                    iterations: clip.iterations,
+                }
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CombineMethod::Average => Average.name(),
+            CombineMethod::Median => Median.name(),
+            CombineMethod::SigmaClip { .. } => SigmaClip::default().name(),
+        }
+    }
+
+    pub fn combiner(&self) -> Box<dyn Combiner> {
+        match *self {
+            CombineMethod::Average => Box::new(Average),
+            CombineMethod::Median => Box::new(Median),
+            CombineMethod::SigmaClip { sigma, iterations } => Box::new(SigmaClip {
+                sigma,
+                iterations,
+                ..SigmaClip::default()
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::calibration::{average, median, sigma_clipping, sigma_clipping_with_map};
+
+    fn frames() -> Vec<FitsImage> {
+        [1.0, 2.0, 4.0, 3.0, 40.0]
+            .iter()
+            .map(|&value| FitsImage::from_data(ArrayD::from_elem(vec![3, 4], value)))
+            .collect()
+    }
+
+    #[test]
+    fn each_frame_type_has_its_recommended_default() {
+        let clip = SigmaClip::default();
+        let expected = [
+            (FrameType::Bias, CombineMethod::Median),
+            (FrameType::Dark, CombineMethod::Median),
+            (FrameType::DarkFlat, CombineMethod::Median),
+            (FrameType::Flat, CombineMethod::Average),
+            (
+                FrameType::Light,
+                CombineMethod::SigmaClip {
+                    sigma: clip.sigma,
+                    iterations: clip.iterations,
+                },
+            ),
+        ];
+
+        for (frame_type, method) in expected {
+            let default = CombineMethod::default_for(frame_type);
+            assert_eq!(default, method, "{:?}", frame_type);
+            assert_eq!(default.combiner().name(), method.name());
+        }
+    }
+
+    #[test]
+    fn trait_dispatch_matches_the_free_functions() {
+        let frames = frames();
+        let clip = SigmaClip::default();
+        let cases: [(Box<dyn Combiner>, FitsImage); 3] = [
+            (Box::new(Average), average(&frames).unwrap()),
+            (Box::new(Median), median(&frames).unwrap()),
+            (
+                Box::new(clip),
+                sigma_clipping(&frames, clip.sigma, clip.iterations).unwrap(),
+            ),
+        ];
+
+        for (combiner, expected) in cases {
+            let combined = combiner.combine(&frames).unwrap();
+            assert_eq!(combined.data(), expected.data(), "{}", combiner.name());
+            assert!(
+                combined
+                    .metadata
+                    .history
+                    .entries()
+                    .iter()
+                    .any(|entry| entry.contains(combiner.name()))
+            );
+        }
+    }
+
+    #[test]
+    fn only_sigma_clipping_reports_rejections() {
+        let frames = frames();
+        let clip = SigmaClip::default();
+        let (combined, summary) = clip.combine_with_summary(&frames).unwrap();
+        let expected = sigma_clipping_with_map(&frames, clip.sigma, clip.iterations).unwrap();
+        assert_eq!(combined.data(), expected.image.data());
+        assert_eq!(summary, Some(expected.summary));
+
+        let (_, summary) = Average.combine_with_summary(&frames).unwrap();
+        assert_eq!(summary, None);
+    }
+}
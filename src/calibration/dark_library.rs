@@ -0,0 +1,284 @@
+use crate::image::FitsImage;
+
+/// Largest sensor temperature difference in °C at which a dark still matches a light
+pub const DARK_TEMPERATURE_TOLERANCE: f64 = 2.0;
+
+/// Largest exposure difference, relative to the light's exposure, at which a dark still
+/// matches a light
+pub const DARK_EXPOSURE_TOLERANCE: f64 = 0.1;
+
+/// Tolerances used by `DarkMatchStrategy::Exact`: a thousandth of a degree, and a
+/// thousandth of the light's exposure
+const EXACT_EPSILON: f64 = 1e-3;
+
+/// How strictly a dark has to match the light it calibrates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DarkMatchStrategy {
+    /// Same exposure and temperature
+    Exact,
+    /// The closest dark within both tolerances
+    #[default]
+    Nearest,
+    /// The closest dark within the temperature tolerance, whatever its exposure. When it's
+    /// outside the exposure tolerance the match carries the factor to scale it by, which
+    /// is only valid for bias-subtracted darks.
+    ScaleIfNeeded,
+}
+
+impl DarkMatchStrategy {
+    pub const ALL: [DarkMatchStrategy; 3] = [
+        DarkMatchStrategy::Exact,
+        DarkMatchStrategy::Nearest,
+        DarkMatchStrategy::ScaleIfNeeded,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DarkMatchStrategy::Exact => "Exact match",
+            DarkMatchStrategy::Nearest => "Nearest within tolerance",
+            DarkMatchStrategy::ScaleIfNeeded => "Scale if needed",
+        }
+    }
+}
+
+/// Settings for `match_dark_with`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DarkMatchOptions {
+    pub strategy: DarkMatchStrategy,
+    /// Largest sensor temperature difference in °C
+    pub temp_tolerance: f64,
+    /// Largest exposure difference relative to the light's exposure
+    pub exposure_tolerance: f64,
+}
+
+impl Default for DarkMatchOptions {
+    fn default() -> Self {
+        Self {
+            strategy: DarkMatchStrategy::default(),
+            temp_tolerance: DARK_TEMPERATURE_TOLERANCE,
+            exposure_tolerance: DARK_EXPOSURE_TOLERANCE,
+        }
+    }
+}
+
+/// A dark picked from a library by `match_dark_with`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DarkMatch {
+    /// Index of the dark in the library
+    pub index: usize,
+    /// Factor to multiply the dark by before subtracting it: the ratio of the light's
+    /// exposure to the dark's. 1.0 unless `ScaleIfNeeded` picked a dark outside the
+    /// exposure tolerance.
+    pub scale: f64,
+}
+
+/// Index of the dark in `dark_library` closest to `light` in exposure time and temperature,
+/// or `None` if no dark is within the default tolerances. See `match_dark_with` for the
+/// other strategies and custom tolerances.
+pub fn match_dark(light: &FitsImage, dark_library: &[FitsImage]) -> Option<usize> {
+    match_dark_with(light, dark_library, &DarkMatchOptions::default()).map(|m| m.index)
+}
+
+/// Pick the dark in `dark_library` that best matches `light` under the given strategy and
+/// tolerances, or `None` if no dark is close enough.
+///
+/// Darks are ranked by the sum of their temperature and exposure differences, each
+/// divided by its tolerance, so with the default tolerances one degree off weighs the same
+/// as a 5% longer exposure. A difference that can't be computed because either frame lacks
+/// the keyword (e.g. DSLRs don't report temperature) counts as a match.
+pub fn match_dark_with(
+    light: &FitsImage,
+    dark_library: &[FitsImage],
+    options: &DarkMatchOptions,
+) -> Option<DarkMatch> {
+    let (temp_tolerance, exposure_tolerance) = match options.strategy {
+        DarkMatchStrategy::Exact => (EXACT_EPSILON, EXACT_EPSILON),
+        DarkMatchStrategy::Nearest | DarkMatchStrategy::ScaleIfNeeded => {
+            (options.temp_tolerance, options.exposure_tolerance)
+        }
+    };
+
+    // (index, temperature difference, exposure difference) of the darks within the
+    // temperature tolerance, differences in units of their tolerance
+    let candidates: Vec<(usize, f64, f64)> = dark_library
+        .iter()
+        .enumerate()
+        .map(|(index, dark)| {
+            let (temperature, exposure) =
+                differences(light, dark, temp_tolerance, exposure_tolerance);
+            (index, temperature, exposure)
+        })
+        .filter(|&(_, temperature, _)| temperature <= 1.0)
+        .collect();
+
+    let nearest = candidates
+        .iter()
+        .filter(|&&(_, _, exposure)| exposure <= 1.0)
+        .min_by(|a, b| (a.1 + a.2).total_cmp(&(b.1 + b.2)));
+    if let Some(&(index, _, _)) = nearest {
+        return Some(DarkMatch { index, scale: 1.0 });
+    }
+    if options.strategy != DarkMatchStrategy::ScaleIfNeeded {
+        return None;
+    }
+
+    // Nothing close in exposure: take the closest exposure and scale it
+    let light_exposure = light.metadata.exposure_time?;
+    candidates
+        .iter()
+        .filter_map(|&(index, temperature, _)| {
+            let dark_exposure = dark_library[index].metadata.exposure_time?;
+            (dark_exposure > 0.0).then_some((index, temperature, dark_exposure))
+        })
+        .min_by(|a, b| {
+            let ratio = |exposure: f64| (light_exposure / exposure).ln().abs();
+            ratio(a.2).total_cmp(&ratio(b.2)).then(a.1.total_cmp(&b.1))
+        })
+        .map(|(index, _, dark_exposure)| DarkMatch {
+            index,
+            scale: light_exposure / dark_exposure,
+        })
+}
+
+/// Temperature and exposure differences between a light and a dark, each divided by its
+/// tolerance. Differences that can't be computed are 0.
+fn differences(
+    light: &FitsImage,
+    dark: &FitsImage,
+    temp_tolerance: f64,
+    exposure_tolerance: f64,
+) -> (f64, f64) {
+    let light = &light.metadata;
+    let dark = &dark.metadata;
+    let temp_tolerance = temp_tolerance.max(f64::EPSILON);
+    let exposure_tolerance = exposure_tolerance.max(f64::EPSILON);
+
+    let temperature = match (light.temperature, dark.temperature) {
+        (Some(light), Some(dark)) => (light - dark).abs() / temp_tolerance,
+        _ => 0.0,
+    };
+    let exposure = match (light.exposure_time, dark.exposure_time) {
+        (Some(light), Some(dark)) if light > 0.0 => {
+            (light - dark).abs() / light / exposure_tolerance
+        }
+        _ => 0.0,
+    };
+
+    (temperature, exposure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(exposure: f64, temperature: f64) -> FitsImage {
+        let mut frame = FitsImage::new(0, 0);
+        frame.metadata.exposure_time = Some(exposure);
+        frame.metadata.temperature = Some(temperature);
+        frame
+    }
+
+    #[test]
+    fn nearest_dark_is_chosen() {
+        let library = [
+            frame(300.0, -10.0),
+            frame(120.0, -10.0),
+            frame(120.0, -5.5),
+            frame(120.0, -4.0),
+            frame(60.0, -5.0),
+        ];
+        let options = DarkMatchOptions::default();
+
+        let matched = match_dark_with(&frame(120.0, -5.0), &library, &options).unwrap();
+        assert_eq!(
+            matched,
+            DarkMatch {
+                index: 2,
+                scale: 1.0
+            }
+        );
+        assert_eq!(match_dark(&frame(300.0, -9.0), &library), Some(0));
+    }
+
+    #[test]
+    fn no_dark_within_tolerance_is_no_match() {
+        let library = [frame(120.0, -10.0), frame(60.0, -5.0)];
+
+        assert_eq!(match_dark(&frame(120.0, -5.0), &library), None);
+        assert_eq!(match_dark(&frame(120.0, -5.0), &[]), None);
+    }
+
+    #[test]
+    fn missing_temperature_counts_as_a_match() {
+        let mut light = frame(120.0, 0.0);
+        light.metadata.temperature = None;
+        let library = [frame(300.0, -10.0), frame(118.0, 25.0)];
+
+        assert_eq!(match_dark(&light, &library), Some(1));
+    }
+
+    #[test]
+    fn each_strategy_selects_as_documented() {
+        let library = [frame(120.0, -4.0), frame(120.0, -5.0), frame(60.0, -5.0)];
+        let with = |strategy| DarkMatchOptions {
+            strategy,
+            ..DarkMatchOptions::default()
+        };
+
+        // Exact wants the same exposure and temperature
+        let exact = with(DarkMatchStrategy::Exact);
+        assert_eq!(
+            match_dark_with(&frame(120.0, -5.0), &library, &exact).map(|m| m.index),
+            Some(1)
+        );
+        assert_eq!(match_dark_with(&frame(120.0, -4.5), &library, &exact), None);
+
+        // Nearest accepts the closest one within the tolerances
+        let nearest = with(DarkMatchStrategy::Nearest);
+        assert_eq!(
+            match_dark_with(&frame(120.0, -4.2), &library, &nearest).map(|m| m.index),
+            Some(0)
+        );
+        assert_eq!(
+            match_dark_with(&frame(30.0, -5.0), &library, &nearest),
+            None
+        );
+
+        // Scale if needed falls back to the closest exposure, with the factor to apply
+        let scale = with(DarkMatchStrategy::ScaleIfNeeded);
+        assert_eq!(
+            match_dark_with(&frame(30.0, -5.0), &library, &scale),
+            Some(DarkMatch {
+                index: 2,
+                scale: 0.5
+            })
+        );
+        assert_eq!(
+            match_dark_with(&frame(120.0, -5.0), &library, &scale),
+            Some(DarkMatch {
+                index: 1,
+                scale: 1.0
+            })
+        );
+    }
+
+    #[test]
+    fn tolerances_widen_and_narrow_the_match() {
+        let library = [frame(120.0, -10.0)];
+        let light = frame(130.0, -7.0);
+        assert_eq!(
+            match_dark_with(&light, &library, &DarkMatchOptions::default()),
+            None
+        );
+
+        let loose = DarkMatchOptions {
+            temp_tolerance: 5.0,
+            exposure_tolerance: 0.2,
+            ..DarkMatchOptions::default()
+        };
+        assert_eq!(
+            match_dark_with(&light, &library, &loose).map(|m| m.index),
+            Some(0)
+        );
+    }
+}
@@ -0,0 +1,493 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use std::sync::mpsc;
+
+use wgpu::util::DeviceExt;
+
+use crate::image::{FitsImage, ImageError};
+
+/// Threads per compute workgroup, matching `@workgroup_size` in the shader
+const WORKGROUP_SIZE: u32 = 64;
+/// Largest number of workgroups a single dispatch may use along one dimension
+const MAX_WORKGROUPS: u32 = 65_535;
+
+/// Each invocation combines one pixel across all frames. Frames are laid out one after
+/// another in `input`, so the value of pixel `p` in frame `f` is `input[f * pixels + p]`.
+///
+/// Like the CPU versions, NaN and infinite values are treated as missing: they are left
+/// out of every sum and count, and a pixel with no finite value at all comes out NaN.
+/// Finiteness is tested on the bits, since shader compilers may assume floats are never
+/// NaN and fold away comparisons.
+const SHADER: &str = r#"
+struct Params {
+    pixels: u32,
+    frames: u32,
+    iterations: u32,
+    sigma: f32,
+}
+
+@group(0) @binding(0) var<storage, read> input: array<f32>;
+@group(0) @binding(1) var<storage, read_write> output: array<f32>;
+@group(0) @binding(2) var<uniform> params: Params;
+
+fn is_finite(v: f32) -> bool {
+    return (bitcast<u32>(v) & 0x7f800000u) != 0x7f800000u;
+}
+
+// Written for pixels without any finite value. A variable rather than a constant, so it
+// isn't evaluated (and rejected) at compile time.
+fn missing() -> f32 {
+    var nan_bits = 0x7fc00000u;
+    return bitcast<f32>(nan_bits);
+}
+
+@compute @workgroup_size(64)
+fn average(@builtin(global_invocation_id) id: vec3<u32>) {
+    let p = id.x;
+    if (p >= params.pixels) {
+        return;
+    }
+
+    var sum = 0.0;
+    var count = 0u;
+    for (var f = 0u; f < params.frames; f++) {
+        let v = input[f * params.pixels + p];
+        if (is_finite(v)) {
+            sum += v;
+            count++;
+        }
+    }
+    if (count == 0u) {
+        output[p] = missing();
+    } else {
+        output[p] = sum / f32(count);
+    }
+}
+
+@compute @workgroup_size(64)
+fn sigma_clip(@builtin(global_invocation_id) id: vec3<u32>) {
+    let p = id.x;
+    if (p >= params.pixels) {
+        return;
+    }
+
+    // Values kept so far are exactly those inside [lo, hi]: each pass intersects
+    // the interval with the new bounds, like the CPU version's `retain`
+    var lo = -3.4e38;
+    var hi = 3.4e38;
+    var available = 0u;
+    for (var f = 0u; f < params.frames; f++) {
+        if (is_finite(input[f * params.pixels + p])) {
+            available++;
+        }
+    }
+    if (available == 0u) {
+        output[p] = missing();
+        return;
+    }
+    var count = available;
+
+    for (var i = 0u; i < params.iterations; i++) {
+        if (count <= 2u) {
+            break;
+        }
+
+        var sum = 0.0;
+        for (var f = 0u; f < params.frames; f++) {
+            let v = input[f * params.pixels + p];
+            if (is_finite(v) && v >= lo && v <= hi) {
+                sum += v;
+            }
+        }
+        let mean = sum / f32(count);
+
+        var sq = 0.0;
+        for (var f = 0u; f < params.frames; f++) {
+            let v = input[f * params.pixels + p];
+            if (is_finite(v) && v >= lo && v <= hi) {
+                sq += (v - mean) * (v - mean);
+            }
+        }
+        let std_dev = sqrt(sq / f32(count));
+
+        lo = max(lo, mean - params.sigma * std_dev);
+        hi = min(hi, mean + params.sigma * std_dev);
+
+        let before = count;
+        count = 0u;
+        for (var f = 0u; f < params.frames; f++) {
+            let v = input[f * params.pixels + p];
+            if (is_finite(v) && v >= lo && v <= hi) {
+                count++;
+            }
+        }
+
+        // Converged: nothing was rejected this pass
+        if (count == before) {
+            break;
+        }
+    }
+
+    // If everything was rejected, fall back to the mean of all finite values like the
+    // CPU version's default `RejectionFallback::Mean`
+    if (count == 0u) {
+        lo = -3.4e38;
+        hi = 3.4e38;
+        count = available;
+    }
+
+    var sum = 0.0;
+    for (var f = 0u; f < params.frames; f++) {
+        let v = input[f * params.pixels + p];
+        if (is_finite(v) && v >= lo && v <= hi) {
+            sum += v;
+        }
+    }
+    output[p] = sum / f32(count);
+}
+"#;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+    pixels: u32,
+    frames: u32,
+    iterations: u32,
+    sigma: f32,
+}
+
+/// The device and compiled pipelines, created once on first use
+struct GpuContext {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    average: wgpu::ComputePipeline,
+    sigma_clip: wgpu::ComputePipeline,
+}
+
+static CONTEXT: OnceLock<Option<GpuContext>> = OnceLock::new();
+
+fn context() -> Result<&'static GpuContext, ImageError> {
+    CONTEXT
+        .get_or_init(|| match pollster::block_on(GpuContext::new()) {
+            Ok(context) => Some(context),
+            Err(e) => {
+                log::warn!("GPU backend unavailable: {}", e);
+                None
+            }
+        })
+        .as_ref()
+        .ok_or_else(|| ImageError::UnsupportedOperation("No usable GPU adapter".to_string()))
+}
+
+impl GpuContext {
+    async fn new() -> Result<Self, String> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await
+            .ok_or_else(|| "no adapter found".to_string())?;
+
+        // Ask for the adapter's own limits so large storage buffers are allowed
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("eventide stacking"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: adapter.limits(),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("stacking shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER)),
+        });
+        let pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let average = pipeline("average");
+        let sigma_clip = pipeline("sigma_clip");
+
+        Ok(Self {
+            device,
+            queue,
+            average,
+            sigma_clip,
+        })
+    }
+
+    /// Run a combine pipeline over `frames`, splitting the pixels into chunks that fit
+    /// the device's buffer and dispatch limits
+    fn combine(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        frames: &[Cow<[f32]>],
+        iterations: u32,
+        sigma: f32,
+    ) -> Result<Vec<f32>, ImageError> {
+        let pixels = frames[0].len();
+        let frame_count = frames.len();
+
+        let max_binding = self.device.limits().max_storage_buffer_binding_size as usize;
+        let chunk = (max_binding / (frame_count * std::mem::size_of::<f32>()))
+            .min((MAX_WORKGROUPS * WORKGROUP_SIZE) as usize);
+        if chunk == 0 {
+            return Err(ImageError::UnsupportedOperation(
+                "Too many frames for the GPU's buffer limits".to_string(),
+            ));
+        }
+
+        let mut output = Vec::with_capacity(pixels);
+        let mut staging_input = Vec::with_capacity(chunk * frame_count);
+        for start in (0..pixels).step_by(chunk) {
+            let end = (start + chunk).min(pixels);
+            let len = end - start;
+
+            staging_input.clear();
+            for frame in frames {
+                staging_input.extend_from_slice(&frame[start..end]);
+            }
+
+            output.extend(self.dispatch(
+                pipeline,
+                &staging_input,
+                len,
+                frame_count,
+                iterations,
+                sigma,
+            )?);
+        }
+
+        Ok(output)
+    }
+
+    fn dispatch(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        input: &[f32],
+        pixels: usize,
+        frames: usize,
+        iterations: u32,
+        sigma: f32,
+    ) -> Result<Vec<f32>, ImageError> {
+        let output_size = (pixels * std::mem::size_of::<f32>()) as u64;
+        let params = Params {
+            pixels: pixels as u32,
+            frames: frames as u32,
+            iterations,
+            sigma,
+        };
+
+        let input_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("frames"),
+                contents: bytemuck::cast_slice(input),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let output_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("combined"),
+            size: output_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let params_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("params"),
+                contents: bytemuck::bytes_of(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let readback_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: output_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: input_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: output_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups((pixels as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&output_buffer, 0, &readback_buffer, 0, output_size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(|e| ImageError::UnsupportedOperation(format!("GPU readback failed: {}", e)))?
+            .map_err(|e| ImageError::UnsupportedOperation(format!("GPU readback failed: {}", e)))?;
+
+        let values = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range()).to_vec();
+        readback_buffer.unmap();
+
+        Ok(values)
+    }
+}
+
+/// Flatten each frame's pixels in standard order, borrowing when already contiguous
+fn frame_slices(images: &[FitsImage]) -> Vec<Cow<'_, [f32]>> {
+    images
+        .iter()
+        .map(|img| match img.data().as_slice() {
+            Some(slice) => Cow::Borrowed(slice),
+            None => Cow::Owned(img.data().iter().copied().collect()),
+        })
+        .collect()
+}
+
+/// Build the result image from combined pixels, with the stack's metadata
+fn result_image(images: &[FitsImage], values: Vec<f32>) -> Result<FitsImage, ImageError> {
+    let first = &images[0];
+    let data = ndarray::ArrayD::from_shape_vec(first.data().raw_dim(), values)
+        .map_err(|e| ImageError::DimensionError(e.to_string()))?;
+
+    let mut result = FitsImage::new(0, 0);
+    result.metadata = super::stack_metadata(images);
+    result.frame_type = first.frame_type;
+    *result.data_mut() = data;
+
+    Ok(result)
+}
+
+/// GPU version of `average`. Frames must already have matching dimensions.
+pub fn average(images: &[FitsImage]) -> Result<FitsImage, ImageError> {
+    let context = context()?;
+    let frames = frame_slices(images);
+    let values = context.combine(&context.average, &frames, 0, 0.0)?;
+    result_image(images, values)
+}
+
+/// GPU version of `sigma_clipping`. Frames must already have matching dimensions.
+pub fn sigma_clipping(
+    images: &[FitsImage],
+    sigma: f32,
+    iterations: usize,
+) -> Result<FitsImage, ImageError> {
+    let context = context()?;
+    let frames = frame_slices(images);
+    let values = context.combine(&context.sigma_clip, &frames, iterations as u32, sigma)?;
+    result_image(images, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::calibration::{
+        SigmaClipOptions, average_with_chunk_rows, sigma_clipping_with_options,
+    };
+
+    const TOLERANCE: f32 = 1e-4;
+
+    fn frame(values: &[f32]) -> FitsImage {
+        FitsImage::from_data(
+            ArrayD::from_shape_vec(vec![1, values.len()], values.to_vec()).unwrap(),
+        )
+    }
+
+    /// Five frames of four pixels: a plain pixel, one with an outlier, one with a NaN and
+    /// an infinity among its values, and one with no finite value at all
+    fn frames() -> Vec<FitsImage> {
+        [
+            [10.0, 10.0, 5.0, f32::NAN],
+            [11.0, 11.0, f32::NAN, f32::NAN],
+            [12.0, 10.5, 6.0, f32::INFINITY],
+            [13.0, 500.0, f32::INFINITY, f32::NAN],
+            [14.0, 10.2, 7.0, f32::NAN],
+        ]
+        .iter()
+        .map(|values| frame(values))
+        .collect()
+    }
+
+    fn assert_matches(gpu: &FitsImage, cpu: &FitsImage) {
+        for (&g, &c) in gpu.data().iter().zip(cpu.data().iter()) {
+            if c.is_nan() {
+                assert!(g.is_nan(), "GPU gave {} where the CPU gave NaN", g);
+            } else {
+                assert!((g - c).abs() <= TOLERANCE, "GPU gave {}, CPU {}", g, c);
+            }
+        }
+    }
+
+    #[test]
+    fn average_matches_cpu() {
+        let frames = frames();
+        let cpu = average_with_chunk_rows(&frames, 1).unwrap();
+        assert_eq!(cpu.data()[[0, 2]], 6.0);
+        assert!(cpu.data()[[0, 3]].is_nan());
+
+        // Machines without a usable adapter only check the CPU side
+        let Ok(gpu) = average(&frames) else {
+            return;
+        };
+        assert_matches(&gpu, &cpu);
+    }
+
+    #[test]
+    fn sigma_clipping_matches_cpu() {
+        let frames = frames();
+        let options = SigmaClipOptions {
+            sigma: 1.5,
+            max_iterations: 3,
+            ..SigmaClipOptions::default()
+        };
+        let cpu = sigma_clipping_with_options(&frames, &options)
+            .unwrap()
+            .image;
+        assert!(cpu.data()[[0, 1]] < 11.0, "the outlier should be clipped");
+        assert!(cpu.data()[[0, 3]].is_nan());
+
+        let Ok(gpu) = sigma_clipping(&frames, options.sigma, options.max_iterations) else {
+            return;
+        };
+        assert_matches(&gpu, &cpu);
+    }
+}
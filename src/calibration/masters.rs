@@ -0,0 +1,192 @@
+use crate::image::{FitsImage, FrameType, ImageError};
+
+use super::{
+    CombineMethod, DarkMatchOptions, MasterFlatOptions, create_master_flat, match_dark_with,
+};
+
+/// The calibration frames of a session by type. Any of them may be empty.
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationFrames {
+    pub bias: Vec<FitsImage>,
+    pub darks: Vec<FitsImage>,
+    pub flats: Vec<FitsImage>,
+    pub dark_flats: Vec<FitsImage>,
+}
+
+/// Master frames the lights are calibrated with, built by `CalibrationMasters::build`
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationMasters {
+    /// Master bias, subtracted from the lights and darks
+    pub bias: Option<FitsImage>,
+    /// Master darks, one per exposure time, bias-subtracted when there is a master bias
+    pub darks: Vec<FitsImage>,
+    /// Master flat normalized to a mean of 1
+    pub flat: Option<FitsImage>,
+}
+
+impl CalibrationMasters {
+    /// Combine each type of calibration frame into its master, with the method `method_for`
+    /// gives for the type (e.g. `CombineMethod::default_for`).
+    ///
+    /// Darks are combined per exposure time, so a dark library holding several exposures
+    /// gives a master for each, matched to the lights by `calibrate`. Flats have the
+    /// master dark flat (or, without dark flats, the master bias) subtracted before they
+    /// are combined into the normalized master flat.
+    pub fn build(
+        frames: CalibrationFrames,
+        method_for: impl Fn(FrameType) -> CombineMethod,
+    ) -> Result<Self, ImageError> {
+        let CalibrationFrames {
+            bias,
+            mut darks,
+            mut flats,
+            dark_flats,
+        } = frames;
+        let combine = |frames: &[FitsImage], frame_type: FrameType| {
+            let mut master = method_for(frame_type).combiner().combine(frames)?;
+            master.frame_type = frame_type;
+            Ok::<_, ImageError>(master)
+        };
+
+        let bias = match bias.is_empty() {
+            true => None,
+            false => Some(combine(&bias, FrameType::Bias)?),
+        };
+
+        // A missing exposure time sorts and groups as NaN
+        let exposure = |frame: &FitsImage| frame.metadata.exposure_time.unwrap_or(f64::NAN);
+        darks.sort_by(|a, b| exposure(a).total_cmp(&exposure(b)));
+        let mut master_darks = Vec::new();
+        for group in darks.chunk_by(|a, b| exposure(a).to_bits() == exposure(b).to_bits()) {
+            let mut master = combine(group, FrameType::Dark)?;
+            if let Some(bias) = &bias {
+                master.subtract(bias, 1.0)?;
+            }
+            master_darks.push(master);
+        }
+
+        let flat = if flats.is_empty() {
+            None
+        } else {
+            let flat_offset = match dark_flats.is_empty() {
+                true => bias.clone(),
+                false => Some(combine(&dark_flats, FrameType::DarkFlat)?),
+            };
+            if let Some(offset) = &flat_offset {
+                for flat in &mut flats {
+                    flat.subtract(offset, 1.0)?;
+                }
+            }
+            let options = MasterFlatOptions {
+                combine_method: method_for(FrameType::Flat),
+                ..MasterFlatOptions::default()
+            };
+            Some(create_master_flat(&flats, &options)?)
+        };
+
+        Ok(Self {
+            bias,
+            darks: master_darks,
+            flat,
+        })
+    }
+
+    /// Whether there is no master to calibrate with
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_none() && self.darks.is_empty() && self.flat.is_none()
+    }
+
+    /// Calibrate `light` in place: subtract the master bias and the master dark
+    /// `match_dark_with` picks for it, scaled as the match asks, then divide by the master flat.
+    ///
+    /// A light no dark matches is left without dark subtraction; callers warn about those
+    /// beforehand.
+    pub fn calibrate(
+        &self,
+        light: &mut FitsImage,
+        dark_matching: &DarkMatchOptions,
+    ) -> Result<(), ImageError> {
+        if let Some(bias) = &self.bias {
+            light.subtract(bias, 1.0)?;
+        }
+        if let Some(dark) = match_dark_with(light, &self.darks, dark_matching) {
+            light.subtract(&self.darks[dark.index], dark.scale as f32)?;
+        }
+        if let Some(flat) = &self.flat {
+            light.divide(flat)?;
+        }
+        Ok(())
+    }
+
+    /// Calibrate every light in place, see `calibrate`
+    pub fn calibrate_all(
+        &self,
+        lights: &mut [FitsImage],
+        dark_matching: &DarkMatchOptions,
+    ) -> Result<(), ImageError> {
+        use rayon::prelude::*;
+
+        lights
+            .par_iter_mut()
+            .try_for_each(|light| self.calibrate(light, dark_matching))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    fn frame(frame_type: FrameType, exposure: f64, values: [f32; 2]) -> FitsImage {
+        let mut frame =
+            FitsImage::from_data(ArrayD::from_shape_vec(vec![1, 2], values.to_vec()).unwrap());
+        frame.frame_type = frame_type;
+        frame.metadata.exposure_time = Some(exposure);
+        frame
+    }
+
+    #[test]
+    fn lights_are_calibrated_with_bias_the_matching_dark_and_flat() {
+        // Bias 100, dark current 10 per 60s, flat response 1.0 on the left, 0.5 on the right
+        let frames = CalibrationFrames {
+            bias: vec![frame(FrameType::Bias, 0.0, [100.0, 100.0]); 3],
+            darks: vec![
+                frame(FrameType::Dark, 60.0, [110.0, 110.0]),
+                frame(FrameType::Dark, 120.0, [120.0, 120.0]),
+                frame(FrameType::Dark, 60.0, [110.0, 110.0]),
+            ],
+            flats: vec![frame(FrameType::Flat, 1.0, [1100.0, 600.0]); 2],
+            dark_flats: Vec::new(),
+        };
+        let masters = CalibrationMasters::build(frames, CombineMethod::default_for).unwrap();
+        assert_eq!(masters.darks.len(), 2);
+
+        // 400 of signal on the left, 200 behind the vignetting on the right
+        let mut light = frame(FrameType::Light, 120.0, [520.0, 320.0]);
+        masters
+            .calibrate(&mut light, &DarkMatchOptions::default())
+            .unwrap();
+
+        let flat_mean = (1000.0 + 500.0) / 2.0;
+        let expected = [400.0 / (1000.0 / flat_mean), 200.0 / (500.0 / flat_mean)];
+        for (value, expected) in light.data().iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-3, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn lights_without_a_matching_dark_keep_their_dark_current() {
+        let frames = CalibrationFrames {
+            darks: vec![frame(FrameType::Dark, 300.0, [50.0, 50.0])],
+            ..CalibrationFrames::default()
+        };
+        let masters = CalibrationMasters::build(frames, CombineMethod::default_for).unwrap();
+
+        let mut light = frame(FrameType::Light, 60.0, [80.0, 80.0]);
+        masters
+            .calibrate(&mut light, &DarkMatchOptions::default())
+            .unwrap();
+        assert_eq!(light.data().as_slice().unwrap(), [80.0, 80.0]);
+    }
+}
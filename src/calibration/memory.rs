@@ -0,0 +1,113 @@
+/// Bytes of one f32 pixel value
+const BYTES_PER_VALUE: usize = 4;
+/// Bytes of one RGBA preview pixel
+const BYTES_PER_PREVIEW_PIXEL: usize = 4;
+
+/// Estimate the peak memory of loading and stacking `frame_count` mono frames of
+/// `(width, height)` pixels in bytes: every frame's f32 data and its RGBA preview, plus
+/// the stacked result.
+pub fn estimate_stack_memory(frame_count: usize, dimensions: (usize, usize)) -> usize {
+    let pixels = dimensions.0.saturating_mul(dimensions.1);
+    let per_frame = pixels.saturating_mul(BYTES_PER_VALUE + BYTES_PER_PREVIEW_PIXEL);
+    per_frame
+        .saturating_mul(frame_count)
+        .saturating_add(pixels.saturating_mul(BYTES_PER_VALUE))
+}
+
+/// Memory currently available to new allocations in bytes. Only Linux reports it (through
+/// /proc/meminfo); elsewhere this is `None` and memory checks are skipped.
+#[cfg(target_os = "linux")]
+pub fn available_memory() -> Option<usize> {
+    // MemAvailable is reported in kB, e.g. "MemAvailable:   16297364 kB"
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kilobytes: usize = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes.saturating_mul(1024))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn available_memory() -> Option<usize> {
+    None
+}
+
+/// How much memory stacking `frame_count` frames of `dimensions` needs, compared to what
+/// is available when the platform reports it, e.g. "about 3.2 GB of 15.5 GB available"
+pub fn memory_summary(frame_count: usize, dimensions: (usize, usize)) -> String {
+    let needed = estimate_stack_memory(frame_count, dimensions);
+    match available_memory() {
+        Some(available) => format!(
+            "about {:.1} GB of {:.1} GB available",
+            gigabytes(needed),
+            gigabytes(available)
+        ),
+        None => format!("about {:.1} GB", gigabytes(needed)),
+    }
+}
+
+/// A warning when stacking would need more memory than is available, or `None` when it
+/// fits or the available memory is unknown
+pub fn memory_warning(frame_count: usize, dimensions: (usize, usize)) -> Option<String> {
+    let needed = estimate_stack_memory(frame_count, dimensions);
+    let available = available_memory()?;
+    exceeds_memory(needed, available).then(|| {
+        format!(
+            "Stacking {} frames needs about {:.1} GB but only {:.1} GB is available; \
+             deselect some frames in the Registration step or stack them in smaller batches",
+            frame_count,
+            gigabytes(needed),
+            gigabytes(available)
+        )
+    })
+}
+
+/// Whether `needed` bytes don't fit in `available`
+pub fn exceeds_memory(needed: usize, available: usize) -> bool {
+    needed > available
+}
+
+fn gigabytes(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_counts_frames_previews_and_the_result() {
+        // 10 frames of 100x50: 4 bytes of data and 4 of preview per pixel, plus the stack
+        let pixels = 100 * 50;
+        assert_eq!(
+            estimate_stack_memory(10, (100, 50)),
+            10 * pixels * 8 + pixels * 4
+        );
+        assert_eq!(estimate_stack_memory(0, (100, 50)), pixels * 4);
+    }
+
+    #[test]
+    fn estimate_saturates_instead_of_overflowing() {
+        assert_eq!(
+            estimate_stack_memory(usize::MAX, (100_000, 100_000)),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn threshold_is_exclusive() {
+        assert!(!exceeds_memory(1024, 1024));
+        assert!(exceeds_memory(1025, 1024));
+        assert!(!exceeds_memory(0, 0));
+    }
+
+    #[test]
+    fn summary_reports_the_estimate() {
+        let summary = memory_summary(1000, (6000, 4000));
+        assert!(summary.starts_with("about 178.9 GB"), "{}", summary);
+    }
+}
@@ -1,4 +1,284 @@
-use crate::image::{FitsImage, ImageError};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use ndarray::ArrayD;
+
+use crate::image::{
+    FitsImage, FrameType, GradientModel, ImageError, ImageMetadata, ProcessingHistory, median_of,
+};
+
+mod combiner;
+mod dark_library;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod masters;
+mod memory;
+mod pixel_stack;
+mod simd;
+
+use pixel_stack::{RowStack, frame_rows, row_count};
+
+pub use combiner::{Average, CombineMethod, Combiner};
+pub use dark_library::{DarkMatchOptions, DarkMatchStrategy, match_dark, match_dark_with};
+pub use masters::{CalibrationFrames, CalibrationMasters};
+pub use memory::{memory_summary, memory_warning};
+
+/// Where `average` and `sigma_clipping` do their work
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Cpu,
+    /// Compute shaders via wgpu. Needs the `gpu` feature; falls back to the CPU otherwise.
+    Gpu,
+}
+
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::Cpu as u8);
+
+/// Select the backend used by subsequent stacking calls
+pub fn set_backend(backend: Backend) {
+    if backend == Backend::Gpu && !cfg!(feature = "gpu") {
+        log::warn!("Built without the `gpu` feature, stacking will run on the CPU");
+    }
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+}
+
+/// The currently selected stacking backend
+pub fn backend() -> Backend {
+    match BACKEND.load(Ordering::Relaxed) {
+        1 => Backend::Gpu,
+        _ => Backend::Cpu,
+    }
+}
+
+/// Median levels differing by more than this factor are reported as a likely scale mismatch
+const MEDIAN_RATIO_WARNING: f32 = 10.0;
+
+/// Check that frames about to be combined are comparable.
+///
+/// Returns a warning for each frame whose pixel type differs from the first frame, or whose
+/// median level is wildly different (e.g. an integer frame stacked with a normalized float one).
+pub fn combine_warnings(images: &[FitsImage]) -> Vec<String> {
+    let Some(first) = images.first() else {
+        return Vec::new();
+    };
+
+    let frame_name = |index: usize, img: &FitsImage| {
+        img.metadata
+            .file_path
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| format!("frame {}", index))
+    };
+
+    let reference_type = first.metadata.pixel_type;
+    let reference_median = first.calculate_statistics().median;
+    let mut warnings = Vec::new();
+
+    for (index, img) in images.iter().enumerate().skip(1) {
+        if img.metadata.pixel_type != reference_type {
+            warnings.push(format!(
+                "{} has pixel type {:?} but the first frame is {:?}",
+                frame_name(index, img),
+                img.metadata.pixel_type,
+                reference_type
+            ));
+        }
+
+        let median = img.calculate_statistics().median;
+        let (low, high) = if median.abs() < reference_median.abs() {
+            (median.abs(), reference_median.abs())
+        } else {
+            (reference_median.abs(), median.abs())
+        };
+        if high > 0.0 && (low == 0.0 || high / low > MEDIAN_RATIO_WARNING) {
+            warnings.push(format!(
+                "{} has median level {} but the first frame has {}",
+                frame_name(index, img),
+                median,
+                reference_median
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Scale every frame so its median level matches the first frame's.
+///
+/// Use this to combine frames whose values are on different scales, e.g. after
+/// `combine_warnings` reports a mismatch.
+pub fn normalize_median_levels(frames: &mut [FitsImage]) {
+    // Only fails for an empty slice, where there is nothing to normalize
+    let _ = normalize_to_reference(frames, 0);
+}
+
+/// Scale every frame by the ratio of the reference frame's median to its own, equalizing
+/// the overall level before combining flats or lights taken under varying brightness.
+///
+/// Frames with a zero median are left untouched.
+pub fn normalize_to_reference(
+    frames: &mut [FitsImage],
+    reference_index: usize,
+) -> Result<(), ImageError> {
+    let reference = frames
+        .get(reference_index)
+        .map(|f| f.calculate_statistics().median)
+        .ok_or_else(|| {
+            ImageError::DimensionError(format!(
+                "Reference frame {} out of range for {} frames",
+                reference_index,
+                frames.len()
+            ))
+        })?;
+
+    for (index, frame) in frames.iter_mut().enumerate() {
+        if index == reference_index {
+            continue;
+        }
+        let median = frame.calculate_statistics().median;
+        if median != 0.0 {
+            let scale = reference / median;
+            frame.data_mut().mapv_inplace(|v| v * scale);
+            frame.metadata.history.record(format!(
+                "Scaled by {} to match frame {}'s median",
+                scale, reference_index
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that all images share the first image's dimensions, returning the common (width, height)
+pub fn check_same_dimensions(images: &[FitsImage]) -> Result<(usize, usize), ImageError> {
+    let Some(first) = images.first() else {
+        return Err(ImageError::FormatError("No images provided".to_string()));
+    };
+    let (width, height) = first.dimensions();
+
+    for (index, img) in images.iter().enumerate().skip(1) {
+        if img.dimensions() != (width, height) || img.data().shape() != first.data().shape() {
+            let (w, h) = img.dimensions();
+            return Err(ImageError::DimensionError(format!(
+                "All images must have the same dimensions: image {} is {}x{}, expected {}x{}",
+                index, w, h, width, height
+            )));
+        }
+    }
+
+    Ok((width, height))
+}
+
+/// Metadata for a stack of `images`: the first frame's, with the frame count recorded as
+/// NCOMBINE and a new history listing the combined frames.
+///
+/// A stack of lights gets the total integration as its exposure time. Calibration masters
+/// keep the first frame's exposure: a master dark still represents one exposure of that
+/// length, which is what dark matching compares against.
+pub fn stack_metadata(images: &[FitsImage]) -> ImageMetadata {
+    let mut metadata = images
+        .first()
+        .map(|img| img.metadata.clone())
+        .unwrap_or_default();
+
+    let is_light = images
+        .first()
+        .is_some_and(|img| img.frame_type == FrameType::Light);
+    if is_light {
+        sum_exposures(images, &mut metadata);
+    }
+
+    metadata
+        .extra
+        .insert("NCOMBINE".to_string(), images.len().to_string());
+
+    // The first frame's history describes that frame alone (its own scaling, bias shift,
+    // ...), so the stack's starts afresh
+    metadata.history = ProcessingHistory::default();
+    metadata
+        .history
+        .record(format!("Combined {} frames", images.len()));
+    for name in images
+        .iter()
+        .filter_map(|img| img.metadata.file_path.as_ref()?.file_name())
+    {
+        metadata
+            .history
+            .record(format!("Frame: {}", name.to_string_lossy()));
+    }
+    metadata
+}
+
+/// Set the stack's exposure time to the total integration of `images`
+fn sum_exposures(images: &[FitsImage], metadata: &mut ImageMetadata) {
+    let exposures: Vec<f64> = images
+        .iter()
+        .filter_map(|img| img.metadata.exposure_time)
+        .collect();
+    if !exposures.is_empty() {
+        metadata.exposure_time = Some(exposures.iter().sum());
+    }
+    if exposures.len() != images.len() && !exposures.is_empty() {
+        log::warn!(
+            "{} of {} frames have no exposure time; total integration only counts the rest",
+            images.len() - exposures.len(),
+            images.len()
+        );
+    }
+}
+
+/// Split frames into groups by their FILTER value, in filter name order.
+///
+/// Frames without a filter form their own group, keyed `None`, which sorts first.
+pub fn group_by_filter(images: Vec<FitsImage>) -> Vec<(Option<String>, Vec<FitsImage>)> {
+    let mut groups: std::collections::BTreeMap<Option<String>, Vec<FitsImage>> =
+        std::collections::BTreeMap::new();
+    for image in images {
+        let filter = image
+            .metadata
+            .filter
+            .as_deref()
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(str::to_string);
+        groups.entry(filter).or_default().push(image);
+    }
+    groups.into_iter().collect()
+}
+
+/// Center-crop every frame to the smallest width and height among them.
+///
+/// An opt-in alternative to the strict `check_same_dimensions` for sessions whose frames
+/// differ by a few pixels, e.g. from different capture software.
+pub fn crop_to_common_size(images: &[FitsImage]) -> Result<Vec<FitsImage>, ImageError> {
+    let width = images
+        .iter()
+        .map(|img| img.dimensions().0)
+        .min()
+        .unwrap_or(0);
+    let height = images
+        .iter()
+        .map(|img| img.dimensions().1)
+        .min()
+        .unwrap_or(0);
+
+    images
+        .iter()
+        .map(|img| {
+            if img.dimensions() == (width, height) {
+                Ok(img.clone())
+            } else {
+                img.crop_center(width, height)
+            }
+        })
+        .collect()
+}
+
+/// Log the compatibility warnings for a set of frames about to be combined
+fn report_combine_warnings(images: &[FitsImage]) {
+    for warning in combine_warnings(images) {
+        log::warn!("{}", warning);
+    }
+}
 
 /// Combine multiple FITS images by calculating the average value for each pixel
 pub fn average(images: &[FitsImage]) -> Result<FitsImage, ImageError> {
@@ -8,242 +288,1912 @@ pub fn average(images: &[FitsImage]) -> Result<FitsImage, ImageError> {
         ));
     }
 
-    // Use the first image as a template
-    let first = &images[0];
-    let (width, height) = first.dimensions();
+    let (width, height) = check_same_dimensions(images)?;
 
-    // Check that all images have the same dimensions
-    for img in images.iter().skip(1) {
-        if img.dimensions() != (width, height) {
-            return Err(ImageError::DimensionError(
-                "All images must have the same dimensions for averaging".to_string(),
-            ));
+    report_combine_warnings(images);
+
+    log::debug!("Image dimensions: {} x {}", width, height);
+
+    #[cfg(feature = "gpu")]
+    if backend() == Backend::Gpu {
+        match gpu::average(images) {
+            Ok(result) => return Ok(result),
+            Err(e) => log::warn!("GPU averaging failed, falling back to CPU: {}", e),
         }
     }
 
-    println!("Image dimensions: {} x {}", width, height);
-    println!("Creating average image...");
+    average_cpu(images, auto_chunk_rows(row_count(&images[0])))
+}
+
+/// `average` on the CPU with each parallel task handling `chunk_rows` rows.
+///
+/// The result doesn't depend on the chunk size. Larger chunks mean fewer tasks and less
+/// scheduling overhead, smaller ones balance better across threads; `average` picks one
+/// with `auto_chunk_rows`.
+pub fn average_with_chunk_rows(
+    images: &[FitsImage],
+    chunk_rows: usize,
+) -> Result<FitsImage, ImageError> {
+    if images.is_empty() {
+        return Err(ImageError::FormatError(
+            "No images provided for averaging".to_string(),
+        ));
+    }
+    check_same_dimensions(images)?;
+    report_combine_warnings(images);
+
+    average_cpu(images, chunk_rows)
+}
+
+fn average_cpu(images: &[FitsImage], chunk_rows: usize) -> Result<FitsImage, ImageError> {
+    let first = &images[0];
+    let chunk_rows = chunk_rows.max(1);
+    let (width, height) = first.dimensions();
+    // Color cubes are averaged plane by plane, as one tall stack of rows
+    let rows = row_count(first);
+
+    log::debug!("Creating average image...");
 
     // Create a new image to hold the average
     let mut result = FitsImage::new(width, height);
 
-    // Copy metadata from the first image
-    result.metadata = first.metadata.clone();
+    // Copy metadata from the first image, with the total exposure and frame count
+    result.metadata = stack_metadata(images);
     result.frame_type = first.frame_type;
 
-    println!("Calculating average pixel values in parallel...");
+    log::debug!(
+        "Calculating average pixel values in parallel, {} rows per task...",
+        chunk_rows
+    );
 
     use rayon::prelude::*;
 
-    // Calculate averages in parallel
-    let pixel_values: Vec<((usize, usize), f32)> = (0..height)
-        .into_par_iter()
-        .flat_map(|y| {
-            let mut row_results = Vec::with_capacity(width);
-            for x in 0..width {
-                let sum: f32 = images.iter().map(|img| img.data[[y, x]]).sum();
-                let avg = sum / images.len() as f32;
-                row_results.push(((y, x), avg));
-            }
-            println!("Processed row {} of {}", y, height);
-            row_results
-        })
-        .collect();
-
-    // Fill the result array
-    let result_data = result.data_mut();
-    for ((y, x), avg) in pixel_values {
-        result_data[[y, x]] = avg;
+    // Sum each row across frames in SIMD lanes, then divide by the number of frames
+    // contributing to each pixel; NaN and infinite pixels count as missing
+    let mut data = vec![0.0f32; width * rows];
+    let chunk_len = width * chunk_rows;
+    if chunk_len > 0 {
+        data.par_chunks_mut(chunk_len)
+            .enumerate()
+            .for_each(|(chunk, output)| {
+                let mut row_count = vec![0.0f32; width];
+                for (i, row_sum) in output.chunks_exact_mut(width).enumerate() {
+                    let y = chunk * chunk_rows + i;
+                    row_count.fill(0.0);
+                    for row in frame_rows(images, y) {
+                        simd::add_finite(row_sum, &mut row_count, &row);
+                    }
+                    simd::divide_by_counts(row_sum, &row_count);
+                }
+            });
     }
 
+    *result.data_mut() = ArrayD::from_shape_vec(first.data().raw_dim(), data)
+        .map_err(|e| ImageError::DimensionError(e.to_string()))?;
+
     Ok(result)
 }
 
-/// Combine multiple FITS images by calculating the median value for each pixel
-pub fn median(images: &[FitsImage]) -> Result<FitsImage, ImageError> {
+/// Parallel tasks per thread when splitting rows between tasks
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// Rows per parallel task for an image of `height` rows: a few tasks per thread, enough
+/// to balance uneven progress without paying task overhead for every row
+pub fn auto_chunk_rows(height: usize) -> usize {
+    let tasks = rayon::current_num_threads() * CHUNKS_PER_THREAD;
+    height.div_ceil(tasks).max(1)
+}
+
+/// Combine multiple FITS images by a per-frame weighted average of each pixel
+pub fn weighted_average(images: &[FitsImage], weights: &[f32]) -> Result<FitsImage, ImageError> {
     if images.is_empty() {
         return Err(ImageError::FormatError(
-            "No images provided for median".to_string(),
+            "No images provided for weighted averaging".to_string(),
+        ));
+    }
+
+    if weights.len() != images.len() {
+        return Err(ImageError::FormatError(format!(
+            "Expected {} weights for weighted averaging, got {}",
+            images.len(),
+            weights.len()
+        )));
+    }
+
+    let total_weight: f32 = weights.iter().sum();
+    if total_weight <= 0.0 || weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+        return Err(ImageError::FormatError(
+            "Weights must be non-negative with a positive sum".to_string(),
         ));
     }
 
     // Use the first image as a template
     let first = &images[0];
-    let (width, height) = first.dimensions();
+    check_same_dimensions(images)?;
 
-    // Check that all images have the same dimensions
-    for img in images.iter().skip(1) {
-        if img.dimensions() != (width, height) {
-            return Err(ImageError::DimensionError(
-                "All images must have the same dimensions for median".to_string(),
-            ));
-        }
+    report_combine_warnings(images);
+
+    // Accumulate per pixel so frames with a missing (non-finite) value there are left out
+    // of both the sum and the total weight
+    let mut weight_sums = ArrayD::<f32>::zeros(first.data().raw_dim());
+    let mut result = first.clone();
+    result.metadata = stack_metadata(images);
+    let result_data = result.data_mut();
+    result_data.fill(0.0);
+    for (img, &weight) in images.iter().zip(weights) {
+        ndarray::Zip::from(&mut *result_data)
+            .and(&mut weight_sums)
+            .and(img.data())
+            .for_each(|sum, weight_sum, &value| {
+                if value.is_finite() {
+                    *sum += weight * value;
+                    *weight_sum += weight;
+                }
+            });
     }
+    ndarray::Zip::from(result_data)
+        .and(&weight_sums)
+        .for_each(|value, &weight_sum| {
+            *value = if weight_sum > 0.0 {
+                *value / weight_sum
+            } else {
+                f32::NAN
+            };
+        });
 
-    // Create a new image to hold the median
-    let mut result = FitsImage::new(width, height);
+    Ok(result)
+}
 
-    // Copy metadata from the first image
-    result.metadata = first.metadata.clone();
-    result.frame_type = first.frame_type;
+/// Combine frames by a per-pixel weighted average, where each frame's weights come from a
+/// mask image of the same size with values in 0..1.
+///
+/// A mask value of 0 excludes that frame's pixel entirely (e.g. a plane crossing one
+/// corner), 1 counts it fully. Pixels with no weight in any frame become NaN.
+pub fn masked_average(images: &[FitsImage], masks: &[FitsImage]) -> Result<FitsImage, ImageError> {
+    if images.is_empty() {
+        return Err(ImageError::FormatError(
+            "No images provided for masked averaging".to_string(),
+        ));
+    }
 
-    // Calculate the median pixel value for each position
-    let result_data = result.data_mut();
+    if masks.len() != images.len() {
+        return Err(ImageError::FormatError(format!(
+            "Expected {} masks for masked averaging, got {}",
+            images.len(),
+            masks.len()
+        )));
+    }
 
-    for y in 0..height {
-        for x in 0..width {
-            let mut values: Vec<f32> = images.iter().map(|img| img.data[[y, x]]).collect();
+    let first = &images[0];
+    check_same_dimensions(images)?;
+    if let Some(index) = masks
+        .iter()
+        .position(|mask| mask.data().shape() != first.data().shape())
+    {
+        return Err(ImageError::DimensionError(format!(
+            "Mask {} doesn't match the frame dimensions",
+            index
+        )));
+    }
 
-            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    report_combine_warnings(images);
 
-            let median = if values.len() % 2 == 0 {
-                let mid = values.len() / 2;
-                (values[mid - 1] + values[mid]) / 2.0
+    let mut weight_sums = ArrayD::<f32>::zeros(first.data().raw_dim());
+    let mut result = first.clone();
+    result.metadata = stack_metadata(images);
+    let result_data = result.data_mut();
+    result_data.fill(0.0);
+    for (img, mask) in images.iter().zip(masks) {
+        ndarray::Zip::from(&mut *result_data)
+            .and(&mut weight_sums)
+            .and(img.data())
+            .and(mask.data())
+            .for_each(|sum, weight_sum, &value, &weight| {
+                let weight = if weight.is_finite() {
+                    weight.clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                if weight > 0.0 && value.is_finite() {
+                    *sum += weight * value;
+                    *weight_sum += weight;
+                }
+            });
+    }
+    ndarray::Zip::from(result_data)
+        .and(&weight_sums)
+        .for_each(|value, &weight_sum| {
+            *value = if weight_sum > 0.0 {
+                *value / weight_sum
             } else {
-                values[values.len() / 2]
+                f32::NAN
             };
+        });
+
+    Ok(result)
+}
 
-            result_data[[y, x]] = median;
+/// Weight of a frame from its airmass: frames taken low on the horizon see through more
+/// atmosphere, so the weight falls off as 1 / airmass². Missing airmass weighs 1.0.
+pub fn airmass_weight(airmass: Option<f64>) -> f32 {
+    match airmass {
+        Some(airmass) if airmass.is_finite() && airmass >= 1.0 => {
+            (1.0 / (airmass * airmass)) as f32
         }
+        _ => 1.0,
     }
+}
+
+/// Airmass weights for each frame, to pass to `weighted_average`
+pub fn airmass_weights(images: &[FitsImage]) -> Vec<f32> {
+    images
+        .iter()
+        .map(|img| airmass_weight(img.metadata.airmass))
+        .collect()
+}
+
+/// Combine multiple FITS images by calculating the median value for each pixel
+pub fn median(images: &[FitsImage]) -> Result<FitsImage, ImageError> {
+    if images.is_empty() {
+        return Err(ImageError::FormatError(
+            "No images provided for median".to_string(),
+        ));
+    }
+
+    // Use the first image as a template
+    let first = &images[0];
+    let (width, height) = check_same_dimensions(images)?;
+
+    report_combine_warnings(images);
+
+    // Create a new image to hold the median
+    let mut result = FitsImage::new(width, height);
+
+    // Copy metadata from the first image, with the total exposure and frame count
+    result.metadata = stack_metadata(images);
+    result.frame_type = first.frame_type;
+
+    *result.data_mut() = ArrayD::from_shape_vec(
+        first.data().raw_dim(),
+        median_rows(images, row_count(first)),
+    )
+    .map_err(|e| ImageError::DimensionError(e.to_string()))?;
 
     Ok(result)
 }
 
+/// Per-pixel medians of the first `rows` rows of `images` (see `row_count`), row after row
+fn median_rows(images: &[FitsImage], rows: usize) -> Vec<f32> {
+    use rayon::prelude::*;
+
+    // Transpose one row at a time so every pixel's values are read from contiguous memory
+    let rows: Vec<Vec<f32>> = (0..rows)
+        .into_par_iter()
+        .map(|y| {
+            let stack = RowStack::load(images, y);
+            let mut values = Vec::with_capacity(images.len());
+            stack
+                .pixels()
+                .map(|pixel| {
+                    values.clear();
+                    values.extend(pixel.iter().copied().filter(|v| v.is_finite()));
+                    if values.is_empty() {
+                        f32::NAN
+                    } else {
+                        median_of(&mut values)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    rows.concat()
+}
+
 /// Apply sigma clipping to combine multiple FITS images
 pub fn sigma_clipping(
     images: &[FitsImage],
     sigma: f32,
     iterations: usize,
 ) -> Result<FitsImage, ImageError> {
+    #[cfg(feature = "gpu")]
+    if backend() == Backend::Gpu && !images.is_empty() {
+        check_same_dimensions(images)?;
+        report_combine_warnings(images);
+        match gpu::sigma_clipping(images, sigma, iterations) {
+            Ok(result) => return Ok(result),
+            Err(e) => log::warn!("GPU sigma clipping failed, falling back to CPU: {}", e),
+        }
+    }
+
+    sigma_clipping_with_map(images, sigma, iterations).map(|stack| stack.image)
+}
+
+/// Aggregate rejection statistics of a clipped stack
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RejectionSummary {
+    /// Number of pixel values rejected across all frames
+    pub total_rejected: usize,
+    /// Number of pixel values considered (pixels x frames)
+    pub total_samples: usize,
+    /// Number of rejected pixel values per input frame
+    pub per_frame: Vec<usize>,
+}
+
+impl RejectionSummary {
+    /// Build the summary from per-frame rejection counts for images of `pixels` pixels
+    pub fn new(per_frame: Vec<usize>, pixels: usize) -> Self {
+        Self {
+            total_rejected: per_frame.iter().sum(),
+            total_samples: pixels * per_frame.len(),
+            per_frame,
+        }
+    }
+
+    /// Percentage of all pixel values that were rejected
+    pub fn percentage(&self) -> f64 {
+        if self.total_samples == 0 {
+            0.0
+        } else {
+            self.total_rejected as f64 / self.total_samples as f64 * 100.0
+        }
+    }
+
+    /// The `count` frames with the most rejections as (frame index, rejections), most first
+    pub fn worst_frames(&self, count: usize) -> Vec<(usize, usize)> {
+        let mut frames: Vec<(usize, usize)> = self.per_frame.iter().copied().enumerate().collect();
+        frames.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        frames.truncate(count);
+        frames
+    }
+}
+
+/// What sigma clipping writes at a pixel where every value was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectionFallback {
+    /// Write 0.0, leaving a black hole in the stack
+    Zero,
+    /// Mean of all the pixel's original values, as if no clipping had happened
+    #[default]
+    Mean,
+    /// Median of all the pixel's original values
+    Median,
+    /// Mean of the values that survived until the pass that rejected everything
+    LastSurvivors,
+}
+
+/// Parameters of a sigma-clipped combine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SigmaClipOptions {
+    /// Values further than this many standard deviations from the mean are rejected
+    pub sigma: f32,
+    /// Upper limit on clipping passes; clipping stops earlier once a pass rejects nothing
+    pub max_iterations: usize,
+    pub fallback: RejectionFallback,
+    /// Fewest frames the combine accepts, 0 (the default) for no minimum. Clipping needs
+    /// several values per pixel to tell outliers apart, and too few frames often means
+    /// some failed to load.
+    pub min_frames: usize,
+}
+
+impl Default for SigmaClipOptions {
+    fn default() -> Self {
+        Self {
+            sigma: 3.0,
+            max_iterations: 3,
+            fallback: RejectionFallback::default(),
+            min_frames: 0,
+        }
+    }
+}
+
+/// Fail a clipped combine of fewer than `min_frames` frames
+fn check_clip_frame_count(images: &[FitsImage], min_frames: usize) -> Result<(), ImageError> {
+    if images.len() < min_frames {
+        return Err(ImageError::FormatError(format!(
+            "Sigma clipping needs at least {} frames, got {}",
+            min_frames,
+            images.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Result of a clipped combine, with a record of what was rejected
+#[derive(Debug, Clone)]
+pub struct ClippedStack {
+    /// The combined image
+    pub image: FitsImage,
+    /// Number of frames rejected at each pixel
+    pub rejection_map: FitsImage,
+    pub summary: RejectionSummary,
+    /// Whether every pixel stopped rejecting before reaching the iteration limit
+    pub converged: bool,
+}
+
+/// Apply sigma clipping to combine multiple FITS images, recording the rejected values.
+///
+/// Each pixel is clipped for at most `max_iterations` passes, stopping early once a pass
+/// rejects nothing.
+pub fn sigma_clipping_with_map(
+    images: &[FitsImage],
+    sigma: f32,
+    max_iterations: usize,
+) -> Result<ClippedStack, ImageError> {
+    sigma_clipping_with_options(
+        images,
+        &SigmaClipOptions {
+            sigma,
+            max_iterations,
+            ..SigmaClipOptions::default()
+        },
+    )
+}
+
+/// Sigma-clipped combine with full control over the clipping, recording the rejected values
+pub fn sigma_clipping_with_options(
+    images: &[FitsImage],
+    options: &SigmaClipOptions,
+) -> Result<ClippedStack, ImageError> {
+    let SigmaClipOptions {
+        sigma,
+        max_iterations,
+        fallback,
+        min_frames,
+    } = *options;
+
     if images.is_empty() {
         return Err(ImageError::FormatError(
             "No images provided for sigma clipping".to_string(),
         ));
     }
+    check_clip_frame_count(images, min_frames)?;
 
     // Use the first image as a template
     let first = &images[0];
-    let (width, height) = first.dimensions();
+    let (width, height) = check_same_dimensions(images)?;
+
+    report_combine_warnings(images);
+
+    // Create a new image to hold the result
+    let mut result = FitsImage::new(width, height);
+
+    // Copy metadata from the first image, with the total exposure and frame count
+    result.metadata = stack_metadata(images);
+    result.frame_type = first.frame_type;
+
+    let mut rejection_map = FitsImage::new(width, height);
+    rejection_map.metadata = first.metadata.clone();
+
+    use rayon::prelude::*;
+
+    // Apply sigma clipping for each pixel position, one row per task
+    // Color cubes are clipped plane by plane, as one tall stack of rows
+    let pixels = first.data().len();
+    let rows: Vec<(Vec<f32>, Vec<f32>, Vec<usize>, bool)> = (0..row_count(first))
+        .into_par_iter()
+        .map(|y| {
+            let stack = RowStack::load(images, y);
+            let mut row_values = Vec::with_capacity(width);
+            let mut row_rejections = Vec::with_capacity(width);
+            let mut per_frame = vec![0usize; images.len()];
+            let mut row_converged = true;
+
+            for pixel in stack.pixels() {
+                // Get values for this pixel from all images, remembering their frame.
+                // Missing (non-finite) values are dropped rather than counted as rejected.
+                let mut values: Vec<(usize, f32)> = pixel
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .filter(|&(_, v)| v.is_finite())
+                    .collect();
+                let available = values.len();
+
+                // Apply sigma clipping iterations
+                let clip = clip_pixel(&mut values, sigma, max_iterations, &mut per_frame);
+                row_converged &= clip.converged;
+
+                // Calculate mean of remaining values, falling back when nothing survived
+                let value = if available == 0 {
+                    f32::NAN
+                } else if clip.all_rejected {
+                    match fallback {
+                        RejectionFallback::Zero => 0.0,
+                        RejectionFallback::LastSurvivors => mean_of_values(&values),
+                        RejectionFallback::Mean | RejectionFallback::Median => {
+                            let mut original: Vec<f32> =
+                                pixel.iter().copied().filter(|v| v.is_finite()).collect();
+                            if fallback == RejectionFallback::Mean {
+                                original.iter().sum::<f32>() / original.len() as f32
+                            } else {
+                                median_of(&mut original)
+                            }
+                        }
+                    }
+                } else {
+                    mean_of_values(&values)
+                };
+                row_values.push(value);
+                let survivors = if clip.all_rejected { 0 } else { values.len() };
+                row_rejections.push((available - survivors) as f32);
+            }
+
+            (row_values, row_rejections, per_frame, row_converged)
+        })
+        .collect();
+
+    // Fill the result arrays
+    let mut per_frame = vec![0usize; images.len()];
+    let mut converged = true;
+    let mut values = Vec::with_capacity(pixels);
+    let mut rejections = Vec::with_capacity(pixels);
+    for (row_values, row_rejections, row_per_frame, row_converged) in rows {
+        converged &= row_converged;
+        values.extend(row_values);
+        rejections.extend(row_rejections);
+        for (total, count) in per_frame.iter_mut().zip(row_per_frame) {
+            *total += count;
+        }
+    }
+    *result.data_mut() = ArrayD::from_shape_vec(first.data().raw_dim(), values)
+        .map_err(|e| ImageError::DimensionError(e.to_string()))?;
+    *rejection_map.data_mut() = ArrayD::from_shape_vec(first.data().raw_dim(), rejections)
+        .map_err(|e| ImageError::DimensionError(e.to_string()))?;
+
+    if !converged {
+        log::debug!(
+            "Sigma clipping hit the {} iteration limit before converging",
+            max_iterations
+        );
+    }
+
+    Ok(ClippedStack {
+        image: result,
+        rejection_map,
+        summary: RejectionSummary::new(per_frame, pixels),
+        converged,
+    })
+}
+
+/// How clipping one pixel's stack ended
+struct PixelClip {
+    /// Clipping stopped on its own rather than running out of iterations
+    converged: bool,
+    /// The last pass rejected every value. The values it started from are left in place.
+    all_rejected: bool,
+}
+
+/// Iteratively reject the values more than `sigma` standard deviations from the mean of
+/// one pixel's stack, counting each rejection against its frame in `per_frame`.
+///
+/// Stops early once an iteration rejects nothing, or when a pass would reject everything.
+fn clip_pixel(
+    values: &mut Vec<(usize, f32)>,
+    sigma: f32,
+    max_iterations: usize,
+    per_frame: &mut [usize],
+) -> PixelClip {
+    for _ in 0..max_iterations {
+        if values.len() <= 2 {
+            return PixelClip {
+                converged: true,
+                all_rejected: false,
+            };
+        }
+
+        // Calculate mean and standard deviation
+        let mean: f32 = values.iter().map(|&(_, v)| v).sum::<f32>() / values.len() as f32;
+        let variance: f32 =
+            values.iter().map(|&(_, v)| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+        let std_dev = variance.sqrt();
+
+        // Reject outliers
+        let lower_bound = mean - sigma * std_dev;
+        let upper_bound = mean + sigma * std_dev;
+
+        let in_bounds = |v: f32| v >= lower_bound && v <= upper_bound;
+        if !values.iter().any(|&(_, v)| in_bounds(v)) {
+            for &(frame, _) in values.iter() {
+                per_frame[frame] += 1;
+            }
+            return PixelClip {
+                converged: true,
+                all_rejected: true,
+            };
+        }
+
+        let before = values.len();
+        values.retain(|&(frame, v)| {
+            let keep = in_bounds(v);
+            if !keep {
+                per_frame[frame] += 1;
+            }
+            keep
+        });
+
+        if values.len() == before {
+            return PixelClip {
+                converged: true,
+                all_rejected: false,
+            };
+        }
+    }
+
+    PixelClip {
+        converged: false,
+        all_rejected: false,
+    }
+}
+
+/// Mean of a pixel's surviving (frame, value) pairs
+fn mean_of_values(values: &[(usize, f32)]) -> f32 {
+    values.iter().map(|&(_, v)| v).sum::<f32>() / values.len() as f32
+}
+
+/// Incrementally combines frames into a running mean, optionally tracking the variance.
+///
+/// Frames can be added one at a time as they become available, so a stack doesn't
+/// need every frame loaded up front. NaN and infinite pixels are treated as missing.
+#[derive(Debug, Clone, Default)]
+pub struct StackAccumulator {
+    /// Number of frames added so far
+    count: usize,
+    /// Running mean, carrying the metadata of the first frame
+    mean: Option<FitsImage>,
+    /// Number of frames with a finite value at each pixel
+    counts: Option<ArrayD<f32>>,
+    /// Running sum of squared differences from the mean (Welford's algorithm)
+    m2: Option<ArrayD<f32>>,
+    /// Whether to track the per-pixel variance
+    track_variance: bool,
+    /// Sum of the exposure times of the frames added so far
+    total_exposure: Option<f64>,
+}
+
+impl StackAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an accumulator that also tracks the per-pixel variance
+    pub fn with_variance() -> Self {
+        Self {
+            track_variance: true,
+            ..Self::default()
+        }
+    }
 
-    // Check that all images have the same dimensions
-    for img in images.iter().skip(1) {
-        if img.dimensions() != (width, height) {
+    /// Number of frames added so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Add a frame to the running stack
+    pub fn add_frame(&mut self, img: &FitsImage) -> Result<(), ImageError> {
+        if let Some(exposure) = img.metadata.exposure_time {
+            *self.total_exposure.get_or_insert(0.0) += exposure;
+        }
+
+        let Some(mean) = self.mean.as_mut() else {
+            // The first frame becomes the initial mean
+            self.mean = Some(img.clone());
+            self.counts = Some(img.data().mapv(|v| if v.is_finite() { 1.0 } else { 0.0 }));
+            if self.track_variance {
+                self.m2 = Some(ArrayD::<f32>::zeros(img.data().raw_dim()));
+            }
+            self.count = 1;
+            return Ok(());
+        };
+
+        if mean.data().shape() != img.data().shape() {
             return Err(ImageError::DimensionError(
-                "All images must have the same dimensions for sigma clipping".to_string(),
+                "All images must have the same dimensions for stacking".to_string(),
             ));
         }
+
+        self.count += 1;
+        let counts = self
+            .counts
+            .get_or_insert_with(|| ArrayD::<f32>::zeros(img.data().raw_dim()));
+
+        match self.m2.as_mut() {
+            Some(m2) => {
+                ndarray::Zip::from(mean.data_mut())
+                    .and(counts)
+                    .and(m2)
+                    .and(img.data())
+                    .for_each(|mean, n, m2, &value| {
+                        if !value.is_finite() {
+                            return;
+                        }
+                        *n += 1.0;
+                        if *n == 1.0 {
+                            *mean = value;
+                            return;
+                        }
+                        let delta = value - *mean;
+                        *mean += delta / *n;
+                        *m2 += delta * (value - *mean);
+                    });
+            }
+            None => {
+                ndarray::Zip::from(mean.data_mut())
+                    .and(counts)
+                    .and(img.data())
+                    .for_each(|mean, n, &value| {
+                        if !value.is_finite() {
+                            return;
+                        }
+                        *n += 1.0;
+                        if *n == 1.0 {
+                            *mean = value;
+                        } else {
+                            *mean += (value - *mean) / *n;
+                        }
+                    });
+            }
+        }
+
+        Ok(())
     }
 
-    // Create a new image to hold the result
-    let mut result = FitsImage::new(width, height);
+    /// The mean of all frames added so far (an empty image if none were added).
+    /// Pixels missing from every frame are NaN.
+    ///
+    /// Like the other combines, the metadata carries NCOMBINE, and the total exposure for
+    /// a stack of lights (see `stack_metadata`).
+    pub fn result(&self) -> FitsImage {
+        let Some(mut result) = self.mean.clone() else {
+            return FitsImage::new(0, 0);
+        };
 
-    // Copy metadata from the first image
-    result.metadata = first.metadata.clone();
-    result.frame_type = first.frame_type;
+        if result.frame_type == FrameType::Light && self.total_exposure.is_some() {
+            result.metadata.exposure_time = self.total_exposure;
+        }
+        result
+            .metadata
+            .extra
+            .insert("NCOMBINE".to_string(), self.count.to_string());
+        result
+    }
 
-    // Apply sigma clipping for each pixel position
-    let result_data = result.data_mut();
+    /// The per-pixel population variance, if variance tracking is enabled
+    pub fn variance(&self) -> Option<FitsImage> {
+        let mean = self.mean.as_ref()?;
+        let m2 = self.m2.as_ref()?;
+        let counts = self.counts.as_ref()?;
 
-    for y in 0..height {
-        for x in 0..width {
-            // Get values for this pixel from all images
-            let mut values: Vec<f32> = images.iter().map(|img| img.data[[y, x]]).collect();
+        let mut variance = mean.clone();
+        let mut values = m2.clone();
+        ndarray::Zip::from(&mut values)
+            .and(counts)
+            .for_each(|v, &n| *v = if n > 0.0 { *v / n } else { f32::NAN });
+        *variance.data_mut() = values;
 
-            // Apply sigma clipping iterations
-            for _ in 0..iterations {
-                if values.len() <= 2 {
-                    break;
-                }
+        Some(variance)
+    }
+}
 
-                // Calculate mean and standard deviation
-                let mean: f32 = values.iter().sum::<f32>() / values.len() as f32;
-                let variance: f32 =
-                    values.iter().map(|&v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
-                let std_dev = variance.sqrt();
+/// Correct slow bias drift across a session by bringing every frame to a common median level.
+///
+/// Each frame's median is measured and the difference to the median of all frame medians
+/// is subtracted, leaving the frames at a shared background level.
+pub fn normalize_bias_level(frames: &mut [FitsImage]) {
+    if frames.is_empty() {
+        return;
+    }
 
-                // Reject outliers
-                let lower_bound = mean - sigma * std_dev;
-                let upper_bound = mean + sigma * std_dev;
+    use rayon::prelude::*;
 
-                values.retain(|&v| v >= lower_bound && v <= upper_bound);
+    let levels: Vec<f32> = frames
+        .par_iter()
+        .map(|frame| frame.calculate_statistics().median)
+        .collect();
+
+    let reference = median_of(&mut levels.clone());
+
+    frames
+        .par_iter_mut()
+        .zip(levels.par_iter())
+        .for_each(|(frame, &level)| {
+            let offset = level - reference;
+            frame.data_mut().mapv_inplace(|v| v - offset);
+            frame
+                .metadata
+                .history
+                .record(format!("Bias level shifted by {}", -offset));
+        });
+}
+
+/// Remove the background gradient from every frame before stacking.
+///
+/// Gradients that rotate with the field between subs don't line up after registration,
+/// so removing them per frame gives a flatter stack than removing them afterwards.
+pub fn subtract_background_all(
+    frames: &mut [FitsImage],
+    model: GradientModel,
+) -> Result<(), ImageError> {
+    use rayon::prelude::*;
+
+    frames
+        .par_iter_mut()
+        .try_for_each(|frame| frame.remove_gradient(model))
+}
+
+impl FitsImage {
+    /// Remove CMOS amp glow by subtracting a scaled (dark - bias) glow model.
+    ///
+    /// Unlike plain dark subtraction, `scale` can be tuned to match lights taken at a
+    /// different exposure or temperature than the master dark.
+    pub fn remove_amp_glow(
+        &mut self,
+        master_dark: &FitsImage,
+        master_bias: &FitsImage,
+        scale: f32,
+    ) -> Result<(), ImageError> {
+        if master_dark.data().shape() != self.data().shape()
+            || master_bias.data().shape() != self.data().shape()
+        {
+            return Err(ImageError::DimensionError(
+                "Master dark and bias must match the image dimensions for amp glow removal"
+                    .to_string(),
+            ));
+        }
+
+        ndarray::Zip::from(self.data_mut())
+            .and(master_dark.data())
+            .and(master_bias.data())
+            .for_each(|value, &dark, &bias| {
+                *value -= scale * (dark - bias);
+            });
+
+        Ok(())
+    }
+}
+
+/// Percentile of a flat's pixel values taken as its background pedestal
+const FLAT_PEDESTAL_PERCENTILE: f32 = 0.01;
+
+/// Options for `create_master_flat`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MasterFlatOptions {
+    /// Subtract a measured background pedestal (scattered light that isn't part of the
+    /// optical response) before normalizing. The pedestal is the flat's 1st percentile.
+    pub subtract_pedestal: bool,
+    /// Normalized flat values below this are raised to it, so nearly dead pixels or
+    /// heavy vignetting don't blow up the lights divided by the flat
+    pub min_value: f32,
+    /// How the flats are combined before normalizing
+    pub combine_method: CombineMethod,
+}
+
+impl Default for MasterFlatOptions {
+    fn default() -> Self {
+        Self {
+            subtract_pedestal: false,
+            min_value: 0.05,
+            combine_method: CombineMethod::default_for(FrameType::Flat),
+        }
+    }
+}
+
+/// Healthy range of a flat's median, as fractions of the pixel type's full scale. Below it
+/// the flat is noisy, above it the sensor is leaving its linear range.
+pub const FLAT_LEVEL_RANGE: (f32, f32) = (0.2, 0.7);
+
+/// A flat's median as a fraction of its pixel type's full scale, or `None` for floating
+/// point frames, whose full scale is unknown
+pub fn flat_level(flat: &FitsImage) -> Option<f32> {
+    let max = flat.metadata.pixel_type.max_value()?;
+    Some(flat.calculate_statistics().median / max)
+}
+
+/// Warn when any of the flats has a median outside `FLAT_LEVEL_RANGE`
+pub fn flat_level_warning<'a>(flats: impl IntoIterator<Item = &'a FitsImage>) -> Option<String> {
+    let (low, high) = FLAT_LEVEL_RANGE;
+    let mut total = 0;
+    let (mut dim, mut bright) = (0, 0);
+    for level in flats.into_iter().filter_map(flat_level) {
+        total += 1;
+        if level < low {
+            dim += 1;
+        } else if level > high {
+            bright += 1;
+        }
+    }
+
+    let problems: Vec<String> = [(dim, "too dim"), (bright, "near saturation")]
+        .into_iter()
+        .filter(|&(count, _)| count > 0)
+        .map(|(count, problem)| format!("{} of {} flats are {}", count, total, problem))
+        .collect();
+    (!problems.is_empty()).then(|| {
+        format!(
+            "{} (median should be {:.0}-{:.0}% of full scale)",
+            problems.join(", "),
+            low * 100.0,
+            high * 100.0
+        )
+    })
+}
+
+/// Create a master flat by combining flat frames and normalizing the result to a mean of 1
+///
+/// Logs a warning when flats are exposed outside `FLAT_LEVEL_RANGE`.
+pub fn create_master_flat(
+    flat_frames: &[FitsImage],
+    options: &MasterFlatOptions,
+) -> Result<FitsImage, ImageError> {
+    if let Some(warning) = flat_level_warning(flat_frames) {
+        log::warn!("{}", warning);
+    }
+
+    let mut master_flat = options.combine_method.combiner().combine(flat_frames)?;
+    master_flat.frame_type = FrameType::Flat;
+
+    let mut values: Vec<f32> = master_flat
+        .data()
+        .iter()
+        .copied()
+        .filter(|v| v.is_finite())
+        .collect();
+    if values.is_empty() {
+        return Err(ImageError::FormatError(
+            "Master flat has no valid pixels".to_string(),
+        ));
+    }
+
+    let pedestal = if options.subtract_pedestal {
+        let index = ((values.len() - 1) as f32 * FLAT_PEDESTAL_PERCENTILE) as usize;
+        *values.select_nth_unstable_by(index, f32::total_cmp).1
+    } else {
+        0.0
+    };
+
+    let mean = values.iter().map(|v| v - pedestal).sum::<f32>() / values.len() as f32;
+    if mean <= 0.0 {
+        return Err(ImageError::FormatError(format!(
+            "Master flat has no signal above its pedestal ({})",
+            pedestal
+        )));
+    }
+    log::debug!("Master flat pedestal {}, mean level {}", pedestal, mean);
+
+    let min_value = options.min_value;
+    master_flat.data_mut().mapv_inplace(|v| {
+        if v.is_finite() {
+            ((v - pedestal) / mean).max(min_value)
+        } else {
+            v
+        }
+    });
+    master_flat.metadata.history.record(format!(
+        "Master flat: pedestal {} subtracted, normalized by mean {}, floor {}",
+        pedestal, mean, min_value
+    ));
+
+    Ok(master_flat)
+}
+
+/// Build a synthetic flat from a stacked light for sessions without flat frames.
+///
+/// The stack is blurred with a large Gaussian so stars and most nebulosity wash out,
+/// leaving an approximation of the vignetting and gradients, and each plane is normalized
+/// to a mean of 1. Divide the stack by it with `FitsImage::divide`. Large extended
+/// targets get partly flattened too, so this is a fallback rather than a real flat.
+pub fn synthetic_flat(stack: &FitsImage, blur_sigma: f32) -> Result<FitsImage, ImageError> {
+    let mut flat = stack.clone();
+    flat.frame_type = FrameType::Flat;
+    flat.gaussian_blur(blur_sigma)?;
+    flat.metadata.history.record(format!(
+        "Synthetic flat: blurred with sigma {} and normalized",
+        blur_sigma
+    ));
+
+    let min_value = MasterFlatOptions::default().min_value;
+    let data = flat.data_mut();
+    let planes = if data.ndim() == 3 {
+        data.outer_iter_mut().collect()
+    } else {
+        vec![data.view_mut()]
+    };
+    for mut plane in planes {
+        let (sum, count) = plane
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold((0.0f64, 0usize), |(sum, count), &v| {
+                (sum + v as f64, count + 1)
+            });
+        let mean = if count > 0 {
+            (sum / count as f64) as f32
+        } else {
+            0.0
+        };
+        if mean <= 0.0 {
+            return Err(ImageError::FormatError(
+                "Synthetic flat has no signal to normalize".to_string(),
+            ));
+        }
+        plane.mapv_inplace(|v| {
+            if v.is_finite() {
+                (v / mean).max(min_value)
+            } else {
+                v
             }
+        });
+    }
+
+    Ok(flat)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::{PixelType, temp_path};
+
+    fn frame(value: f32, name: &str) -> FitsImage {
+        let mut image =
+            FitsImage::from_data(ArrayD::from_shape_vec(vec![2, 2], vec![value; 4]).unwrap());
+        image.metadata.file_path = Some(name.into());
+        image
+    }
+
+    #[test]
+    fn stack_history_lists_the_frames_without_the_first_frames_steps() {
+        let mut first = frame(1.0, "a.fits");
+        first.metadata.history.record("Bias level shifted by 3");
+        let frames = [first, frame(2.0, "b.fits")];
+
+        let metadata = stack_metadata(&frames);
+        assert_eq!(
+            metadata.history.entries(),
+            ["Combined 2 frames", "Frame: a.fits", "Frame: b.fits"]
+        );
+    }
+
+    #[test]
+    fn stack_history_is_written_as_history_cards() {
+        let frames = [frame(1.0, "a.fits"), frame(3.0, "b.fits")];
+        let stacked = Average.combine(&frames).unwrap();
+
+        let path = temp_path("history.fits");
+        let _ = std::fs::remove_file(&path);
+        stacked.to_file(&path).unwrap();
+        let header = String::from_utf8_lossy(&std::fs::read(&path).unwrap()).into_owned();
+        std::fs::remove_file(&path).unwrap();
+
+        for line in ["Combined 2 frames", "Frame: a.fits", "Frame: b.fits"] {
+            assert!(
+                header.contains(&format!("HISTORY {}", line)),
+                "{} missing",
+                line
+            );
+        }
+        assert!(header.contains("HISTORY Combine method: Average"));
+    }
+
+    /// Coefficient of variation of the finite pixels
+    fn relative_spread(image: &FitsImage) -> f32 {
+        let stats = image.calculate_statistics();
+        stats.std_dev / stats.mean
+    }
+
+    #[test]
+    fn synthetic_flat_flattens_vignetting() {
+        // Brightest in the middle, 40% darker in the corners
+        let data = ArrayD::from_shape_fn(vec![64, 64], |index| {
+            let (y, x) = (index[0] as f32 - 31.5, index[1] as f32 - 31.5);
+            1000.0 * (1.0 - 0.4 * (x * x + y * y) / (2.0 * 31.5 * 31.5))
+        });
+        let mut stack = FitsImage::from_data(data);
+        let before = relative_spread(&stack);
+
+        let flat = synthetic_flat(&stack, 8.0).unwrap();
+        assert_eq!(flat.frame_type, FrameType::Flat);
+        stack.divide(&flat).unwrap();
+
+        let after = relative_spread(&stack);
+        assert!(after < before / 2.0, "spread {} -> {}", before, after);
+    }
+
+    #[test]
+    fn sigma_clipping_below_min_frames_errors() {
+        let frames = [frame(1.0, "a.fits"), frame(2.0, "b.fits")];
+        let options = SigmaClipOptions {
+            min_frames: 3,
+            ..SigmaClipOptions::default()
+        };
+
+        let result = sigma_clipping_with_options(&frames, &options);
+        assert!(matches!(result, Err(ImageError::FormatError(_))));
+    }
+
+    #[test]
+    fn min_frames_is_off_by_default() {
+        let frames = [frame(1.0, "a.fits"), frame(2.0, "b.fits")];
+        assert_eq!(SigmaClipOptions::default().min_frames, 0);
+
+        let stack = sigma_clipping_with_options(&frames, &SigmaClipOptions::default()).unwrap();
+        assert!(stack.image.data().iter().all(|&v| v == 1.5));
+        assert!(sigma_clipping(&frames[..1], 3.0, 3).is_ok());
+    }
+
+    /// `count` frames of hashed noise with a few missing pixels. The last frame is stored
+    /// column-major, so combines also see a frame whose rows aren't contiguous.
+    fn noisy_frames(count: usize, width: usize, height: usize) -> Vec<FitsImage> {
+        (0..count)
+            .map(|i| {
+                let data = ArrayD::from_shape_fn(vec![width, height], |index| {
+                    let seed = ((i * height + index[1]) * width + index[0]) as u32;
+                    let hash = seed.wrapping_mul(2_654_435_761) >> 8;
+                    if hash % 97 == 0 {
+                        f32::NAN
+                    } else {
+                        (hash % 4096) as f32
+                    }
+                });
+                let data = if i + 1 == count {
+                    data.reversed_axes()
+                } else {
+                    data.reversed_axes().as_standard_layout().into_owned()
+                };
+                FitsImage::from_data(data)
+            })
+            .collect()
+    }
+
+    /// Whether two images hold the same pixels, NaN matching NaN
+    fn same_pixels(a: &FitsImage, b: &FitsImage) -> bool {
+        a.data().shape() == b.data().shape()
+            && a.data()
+                .iter()
+                .zip(b.data().iter())
+                .all(|(x, y)| x == y || (x.is_nan() && y.is_nan()))
+    }
+
+    /// Average by indexing every frame at `[[y, x]]`, one parallel task per row, as the
+    /// combine did before it read contiguous rows
+    fn indexed_average(images: &[FitsImage]) -> FitsImage {
+        use rayon::prelude::*;
+
+        let (width, height) = images[0].dimensions();
+        let rows: Vec<Vec<f32>> = (0..height)
+            .into_par_iter()
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let (mut sum, mut count) = (0.0, 0.0);
+                        for img in images {
+                            let v = img.data()[[y, x]];
+                            if v.is_finite() {
+                                sum += v;
+                                count += 1.0;
+                            }
+                        }
+                        if count > 0.0 { sum / count } else { f32::NAN }
+                    })
+                    .collect()
+            })
+            .collect();
+        FitsImage::from_data(ArrayD::from_shape_vec(vec![height, width], rows.concat()).unwrap())
+    }
+
+    #[test]
+    fn average_matches_pixel_by_pixel_indexing() {
+        let frames = noisy_frames(5, 37, 11);
+        assert!(frames[4].data().as_slice().is_none());
+
+        let average = average_cpu(&frames, auto_chunk_rows(11)).unwrap();
+        assert!(same_pixels(&average, &indexed_average(&frames)));
+    }
+
+    /// 12 frames of 1024 x 1024. In release mode on a single-core x86_64 machine reading
+    /// contiguous rows took about 46ms against about 76ms for `[[y, x]]` indexing.
+    #[test]
+    #[ignore = "timing comparison, run with --release -- --ignored --nocapture"]
+    fn contiguous_average_is_faster_than_indexing() {
+        let frames = noisy_frames(12, 1024, 1024);
+
+        let start = std::time::Instant::now();
+        let contiguous = average_cpu(&frames, auto_chunk_rows(1024)).unwrap();
+        let contiguous_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let indexed = indexed_average(&frames);
+        let indexed_time = start.elapsed();
+
+        println!(
+            "contiguous: {:?}, indexed: {:?}",
+            contiguous_time, indexed_time
+        );
+        assert!(same_pixels(&contiguous, &indexed));
+        assert!(contiguous_time < indexed_time);
+    }
 
-            // Calculate mean of remaining values
-            if values.is_empty() {
-                result_data[[y, x]] = 0.0;
+    /// Gather `[[y, x]]` from every frame for each pixel, one parallel task per row, and
+    /// reduce the finite values with `combine`, as the combines did before `RowStack`
+    fn gathered(images: &[FitsImage], combine: impl Fn(Vec<f32>) -> f32 + Sync) -> FitsImage {
+        use rayon::prelude::*;
+
+        let (width, height) = images[0].dimensions();
+        let rows: Vec<Vec<f32>> = (0..height)
+            .into_par_iter()
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let values: Vec<f32> = images
+                            .iter()
+                            .map(|img| img.data()[[y, x]])
+                            .filter(|v| v.is_finite())
+                            .collect();
+                        if values.is_empty() {
+                            f32::NAN
+                        } else {
+                            combine(values)
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        FitsImage::from_data(ArrayD::from_shape_vec(vec![height, width], rows.concat()).unwrap())
+    }
+
+    #[test]
+    fn row_stack_combines_match_a_per_pixel_gather() {
+        let frames = noisy_frames(7, 29, 9);
+
+        let median = median(&frames).unwrap();
+        let expected = gathered(&frames, |mut values| median_of(&mut values));
+        assert!(same_pixels(&median, &expected));
+
+        let options = SigmaClipOptions {
+            sigma: 1.5,
+            fallback: RejectionFallback::LastSurvivors,
+            ..SigmaClipOptions::default()
+        };
+        let clipped = sigma_clipping_with_options(&frames, &options).unwrap();
+        assert!(clipped.summary.total_rejected > 0);
+        let expected = gathered(&frames, |values| {
+            let mut values: Vec<(usize, f32)> = values.into_iter().enumerate().collect();
+            let mut per_frame = vec![0; values.len()];
+            clip_pixel(
+                &mut values,
+                options.sigma,
+                options.max_iterations,
+                &mut per_frame,
+            );
+            mean_of_values(&values)
+        });
+        assert!(same_pixels(&clipped.image, &expected));
+    }
+
+    /// 12 frames of 1024 x 1024. In release mode on a single-core x86_64 machine the
+    /// `RowStack` median rows took about 70ms against about 190ms for gathering `[[y, x]]`
+    /// from every frame.
+    #[test]
+    #[ignore = "timing comparison, run with --release -- --ignored --nocapture"]
+    fn row_stack_median_is_faster_than_gathering() {
+        let frames = noisy_frames(12, 1024, 1024);
+
+        let start = std::time::Instant::now();
+        let stacked = median_rows(&frames, 1024);
+        let stack_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let expected = gathered(&frames, |mut values| median_of(&mut values));
+        let gather_time = start.elapsed();
+
+        println!("row stack: {:?}, gather: {:?}", stack_time, gather_time);
+        assert!(
+            stacked
+                .iter()
+                .zip(expected.data().iter())
+                .all(|(a, b)| a == b || (a.is_nan() && b.is_nan()))
+        );
+        assert!(stack_time < gather_time);
+    }
+
+    #[test]
+    fn average_does_not_depend_on_the_chunk_size() {
+        let frames = noisy_frames(4, 23, 17);
+        let expected = average_with_chunk_rows(&frames, 1).unwrap();
+
+        for chunk_rows in [0, 2, 3, 16, 17, 100, auto_chunk_rows(17)] {
+            let average = average_with_chunk_rows(&frames, chunk_rows).unwrap();
+            assert!(
+                same_pixels(&average, &expected),
+                "chunk of {} rows",
+                chunk_rows
+            );
+        }
+    }
+
+    /// Ten averages of 8 frames of 16 x 65536, narrow so per-task overhead shows. In
+    /// release mode on a single-core x86_64 machine one row per task took 480-520ms
+    /// against about 470ms with `auto_chunk_rows`; on 256 x 4096 frames the two were
+    /// within noise of each other. Rayon already splits `par_chunks_mut` adaptively, so
+    /// the gain is a few percent.
+    #[test]
+    #[ignore = "timing comparison, run with --release -- --ignored --nocapture"]
+    fn time_average_chunk_sizes() {
+        let frames = noisy_frames(8, 16, 65536);
+        let time = |chunk_rows| {
+            let start = std::time::Instant::now();
+            for _ in 0..10 {
+                std::hint::black_box(average_cpu(&frames, chunk_rows).unwrap());
+            }
+            start.elapsed()
+        };
+
+        let per_row = time(1);
+        let auto = time(auto_chunk_rows(65536));
+        println!("one row per task: {:?}, auto: {:?}", per_row, auto);
+    }
+
+    #[test]
+    fn bias_drift_is_brought_to_a_common_median() {
+        let ramp = |offset: f32| {
+            let data = ArrayD::from_shape_fn(vec![3, 3], |i| (i[0] * 3 + i[1]) as f32 + offset);
+            FitsImage::from_data(data)
+        };
+        let mut frames = [ramp(0.0), ramp(10.0), ramp(-4.0)];
+
+        normalize_bias_level(&mut frames);
+        for frame in &frames {
+            assert_eq!(frame.calculate_statistics().median, 4.0);
+            assert_eq!(frame.data(), ramp(0.0).data());
+        }
+        assert_eq!(
+            frames[1].metadata.history.entries(),
+            ["Bias level shifted by -10"]
+        );
+    }
+
+    #[test]
+    fn amp_glow_gradient_is_removed_with_the_matching_scale() {
+        let glow = |x: usize| if x < 4 { 40.0 - 10.0 * x as f32 } else { 0.0 };
+        let bias = FitsImage::from_data(ArrayD::from_elem(vec![4, 8], 100.0));
+        let dark = FitsImage::from_data(ArrayD::from_shape_fn(vec![4, 8], |i| 100.0 + glow(i[1])));
+        let mut light = FitsImage::from_data(ArrayD::from_shape_fn(vec![4, 8], |i| {
+            500.0 + 0.5 * glow(i[1])
+        }));
+        let before = relative_spread(&light);
+
+        light.remove_amp_glow(&dark, &bias, 0.5).unwrap();
+        assert!(before > 0.0);
+        assert!(light.data().iter().all(|&v| v == 500.0));
+
+        let small = FitsImage::from_data(ArrayD::from_elem(vec![2, 2], 100.0));
+        assert!(matches!(
+            light.remove_amp_glow(&small, &bias, 0.5),
+            Err(ImageError::DimensionError(_))
+        ));
+    }
+
+    #[test]
+    fn running_mean_matches_the_batch_average() {
+        let frames = noisy_frames(5, 9, 7);
+        let mut accumulator = StackAccumulator::with_variance();
+        for frame in &frames {
+            accumulator.add_frame(frame).unwrap();
+        }
+        assert_eq!(accumulator.count(), 5);
+
+        let running = accumulator.result();
+        let batch = average(&frames).unwrap();
+        assert_eq!(running.metadata.extra["NCOMBINE"], "5");
+        for (&r, &b) in running.data().iter().zip(batch.data().iter()) {
+            assert!((r - b).abs() <= 1e-3 * b.abs().max(1.0), "{} vs {}", r, b);
+        }
+
+        // Population variance of the finite values at the first pixel
+        let values: Vec<f32> = frames
+            .iter()
+            .map(|frame| frame.data()[[0, 0]])
+            .filter(|v| v.is_finite())
+            .collect();
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let expected = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+        let variance = accumulator.variance().unwrap().data()[[0, 0]];
+        assert!(
+            (variance - expected).abs() <= 1e-3 * expected,
+            "{} vs {}",
+            variance,
+            expected
+        );
+
+        let small = FitsImage::from_data(ArrayD::zeros(vec![2, 2]));
+        assert!(matches!(
+            accumulator.add_frame(&small),
+            Err(ImageError::DimensionError(_))
+        ));
+    }
+
+    #[test]
+    fn per_frame_background_removal_flattens_the_stack() {
+        // Gradients pointing a different way in each frame, as the field rotates
+        let frames: Vec<FitsImage> = [(1.0, 0.0), (0.0, 1.0), (-0.7, 0.7)]
+            .into_iter()
+            .map(|(gx, gy)| {
+                FitsImage::from_data(ArrayD::from_shape_fn(vec![40, 60], |index| {
+                    let (y, x) = (index[0] as f32 - 19.5, index[1] as f32 - 29.5);
+                    1000.0 + 5.0 * (gx * x + gy * y)
+                }))
+            })
+            .collect();
+        let raw_stack = average(&frames).unwrap();
+
+        let mut corrected = frames.clone();
+        subtract_background_all(&mut corrected, GradientModel::Linear).unwrap();
+        for (before, after) in frames.iter().zip(&corrected) {
+            let (before, after) = (
+                before.calculate_statistics().std_dev,
+                after.calculate_statistics().std_dev,
+            );
+            assert!(after < before / 10.0, "{} -> {}", before, after);
+        }
+
+        let stack = average(&corrected).unwrap();
+        let (raw, flat) = (
+            raw_stack.calculate_statistics().std_dev,
+            stack.calculate_statistics().std_dev,
+        );
+        assert!(flat < raw / 10.0, "{} -> {}", raw, flat);
+    }
+
+    #[test]
+    fn mixed_pixel_types_and_scales_are_reported() {
+        let mut frames = [
+            frame(1000.0, "a.fits"),
+            frame(0.015, "b.fits"),
+            frame(1100.0, "c.fits"),
+        ];
+        frames[0].metadata.pixel_type = PixelType::U16;
+        frames[1].metadata.pixel_type = PixelType::F32;
+        frames[2].metadata.pixel_type = PixelType::U16;
+
+        assert_eq!(
+            combine_warnings(&frames),
+            [
+                "b.fits has pixel type F32 but the first frame is U16",
+                "b.fits has median level 0.015 but the first frame has 1000",
+            ]
+        );
+        assert!(combine_warnings(&[frame(1.0, "x.fits"), frame(2.0, "y.fits")]).is_empty());
+
+        normalize_median_levels(&mut frames);
+        assert!((frames[1].calculate_statistics().median - 1000.0).abs() < 0.01);
+        assert_eq!(combine_warnings(&frames).len(), 1);
+    }
+
+    #[test]
+    fn dimension_check_returns_the_common_size_or_names_the_odd_frame() {
+        let wide = FitsImage::from_data(ArrayD::zeros(vec![2, 3]));
+        let frames = [frame(1.0, "a.fits"), frame(2.0, "b.fits"), wide];
+
+        assert_eq!(check_same_dimensions(&frames[..2]).unwrap(), (2, 2));
+        match check_same_dimensions(&frames) {
+            Err(ImageError::DimensionError(message)) => assert_eq!(
+                message,
+                "All images must have the same dimensions: image 2 is 3x2, expected 2x2"
+            ),
+            other => panic!("expected a dimension error, got {:?}", other.map(|_| ())),
+        }
+        assert!(matches!(
+            median(&frames),
+            Err(ImageError::DimensionError(_))
+        ));
+        assert!(matches!(
+            check_same_dimensions(&[]),
+            Err(ImageError::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn parallel_sigma_clipping_matches_a_serial_loop() {
+        // Six frames of a gentle ramp, with a hot pixel and a satellite row in two of them
+        let frames: Vec<FitsImage> = (0..6)
+            .map(|i| {
+                FitsImage::from_data(ArrayD::from_shape_fn(vec![5, 7], |index| {
+                    let (y, x) = (index[0], index[1]);
+                    let value = 100.0 + (x + y) as f32 + (i * 7 % 5) as f32;
+                    match (i, y, x) {
+                        (2, 1, 3) => 60000.0,
+                        (4, 3, _) => value + 5000.0,
+                        _ => value,
+                    }
+                }))
+            })
+            .collect();
+
+        let clipped = sigma_clipping(&frames, 1.5, 3).unwrap();
+
+        for y in 0..5 {
+            for x in 0..7 {
+                let mut values: Vec<(usize, f32)> = frames
+                    .iter()
+                    .enumerate()
+                    .map(|(i, frame)| (i, frame.data()[[y, x]]))
+                    .collect();
+                let mut per_frame = vec![0; frames.len()];
+                clip_pixel(&mut values, 1.5, 3, &mut per_frame);
+                assert_eq!(
+                    clipped.data()[[y, x]],
+                    mean_of_values(&values),
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+        // The outliers are gone from the stack
+        assert!(clipped.data()[[1, 3]] < 200.0);
+        assert!(
+            clipped
+                .data()
+                .index_axis(ndarray::Axis(0), 3)
+                .iter()
+                .all(|&v| v < 200.0)
+        );
+    }
+
+    /// Keeps every logged message with the thread that logged it, so a test can read back
+    /// its own messages while other tests log concurrently
+    struct CaptureLogger(std::sync::Mutex<Vec<(std::thread::ThreadId, String)>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = (std::thread::current().id(), record.args().to_string());
+            self.0.lock().unwrap().push(message);
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger(std::sync::Mutex::new(Vec::new()));
+
+    #[test]
+    fn combine_progress_goes_to_the_log() {
+        // The only test installing a logger, as there can be one per process
+        log::set_logger(&CAPTURE_LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let frames = [
+            frame(100.0, "a.fits"),
+            frame(110.0, "b.fits"),
+            frame(105.0, "c.fits"),
+        ];
+        average(&frames).unwrap();
+
+        let thread = std::thread::current().id();
+        let logged: Vec<String> = CAPTURE_LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| *id == thread)
+            .map(|(_, message)| message.clone())
+            .collect();
+        for expected in ["Image dimensions: 2 x 2", "Creating average image..."] {
+            assert!(logged.iter().any(|m| m == expected), "{:?}", logged);
+        }
+    }
+
+    #[test]
+    fn airmass_weights_fall_off_towards_the_horizon() {
+        assert_eq!(airmass_weight(Some(1.0)), 1.0);
+        assert_eq!(airmass_weight(Some(2.0)), 0.25);
+        assert!(airmass_weight(Some(1.2)) > airmass_weight(Some(1.5)));
+        // Missing and impossible values leave the frame at full weight
+        for airmass in [None, Some(0.5), Some(f64::NAN)] {
+            assert_eq!(airmass_weight(airmass), 1.0);
+        }
+
+        let mut frames = [frame(100.0, "high.fits"), frame(200.0, "low.fits")];
+        frames[0].metadata.airmass = Some(1.0);
+        frames[1].metadata.airmass = Some(2.0);
+        let stack = weighted_average(&frames, &airmass_weights(&frames)).unwrap();
+        assert!(stack.data().iter().all(|&v| (v - 120.0).abs() < 1e-3));
+    }
+
+    #[test]
+    fn rejection_summary_totals_and_ranks_the_frames() {
+        let summary = RejectionSummary::new(vec![3, 0, 7, 1], 10);
+        assert_eq!((summary.total_rejected, summary.total_samples), (11, 40));
+        assert!((summary.percentage() - 27.5).abs() < 1e-9);
+        assert_eq!(summary.worst_frames(2), [(2, 7), (0, 3)]);
+        assert_eq!(RejectionSummary::default().percentage(), 0.0);
+
+        // A hot pixel in frame 1 and a bright row in frame 3
+        let frames: Vec<FitsImage> = (0..5)
+            .map(|i| {
+                FitsImage::from_data(ArrayD::from_shape_fn(vec![3, 4], |index| {
+                    match (i, index[0], index[1]) {
+                        (1, 0, 2) => 9000.0,
+                        (3, 2, _) => 5000.0,
+                        _ => 100.0,
+                    }
+                }))
+            })
+            .collect();
+        let stack = sigma_clipping_with_map(&frames, 1.5, 3).unwrap();
+
+        assert_eq!(stack.summary.per_frame, [0, 1, 0, 4, 0]);
+        assert_eq!(stack.summary.total_samples, 60);
+        let mapped: f32 = stack.rejection_map.data().iter().sum();
+        assert_eq!(mapped as usize, stack.summary.total_rejected);
+        assert_eq!(stack.summary.worst_frames(1), [(3, 4)]);
+    }
+
+    #[test]
+    fn crop_mode_stacks_frames_of_different_sizes() {
+        let small = FitsImage::from_data(ArrayD::from_elem(vec![100, 100], 1.0));
+        // One pixel wider on each side, with a border that must be cropped away
+        let large = FitsImage::from_data(ArrayD::from_shape_fn(vec![102, 102], |index| {
+            if index[0] % 101 == 0 || index[1] % 101 == 0 {
+                1000.0
             } else {
-                result_data[[y, x]] = values.iter().sum::<f32>() / values.len() as f32;
+                3.0
             }
+        }));
+        let frames = [small, large];
+        assert!(matches!(
+            average(&frames),
+            Err(ImageError::DimensionError(_))
+        ));
+
+        let cropped = crop_to_common_size(&frames).unwrap();
+        let stack = average(&cropped).unwrap();
+        assert_eq!(stack.dimensions(), (100, 100));
+        assert!(stack.data().iter().all(|&v| v == 2.0));
+    }
+
+    #[test]
+    fn nan_pixels_are_left_out_of_statistics_and_stacks() {
+        let with_gaps = |value: f32, gaps: &[usize]| {
+            let mut image = frame(value, "gaps.fits");
+            for &i in gaps {
+                image.data_mut().as_slice_mut().unwrap()[i] = f32::NAN;
+            }
+            image
+        };
+
+        let stats = with_gaps(10.0, &[0]).calculate_statistics();
+        assert_eq!((stats.mean, stats.min, stats.max), (10.0, 10.0, 10.0));
+
+        // Pixel 0 is missing from one frame, pixel 3 from all of them
+        let frames = [
+            with_gaps(10.0, &[0, 3]),
+            with_gaps(20.0, &[3]),
+            with_gaps(30.0, &[3]),
+        ];
+        for stack in [
+            average(&frames).unwrap(),
+            median(&frames).unwrap(),
+            sigma_clipping(&frames, 3.0, 3).unwrap(),
+        ] {
+            let data = stack.data().as_slice().unwrap().to_vec();
+            assert_eq!(data[..3], [25.0, 20.0, 20.0]);
+            assert!(data[3].is_nan());
         }
     }
 
-    Ok(result)
-}
+    #[test]
+    fn sigma_clipping_stops_once_nothing_is_rejected() {
+        let stack_of = |values: &[f32]| -> Vec<FitsImage> {
+            values.iter().map(|&v| frame(v, "clip.fits")).collect()
+        };
+
+        // Without outliers the first pass rejects nothing, so one pass is enough
+        let clean = stack_of(&[10.0, 11.0, 9.0, 10.0, 12.0, 8.0]);
+        let stack = sigma_clipping_with_map(&clean, 3.0, 1).unwrap();
+        assert!(stack.converged);
+        assert_eq!(stack.summary.total_rejected, 0);
+
+        // 1000 hides 30 until it's gone: two passes reject, a third confirms the rest
+        let mut values = vec![10.0; 10];
+        values.extend([30.0, 1000.0]);
+        let noisy = stack_of(&values);
+        let cut_short = sigma_clipping_with_map(&noisy, 2.0, 2).unwrap();
+        assert!(!cut_short.converged);
+        let stack = sigma_clipping_with_map(&noisy, 2.0, 10).unwrap();
+        assert!(stack.converged);
+        assert_eq!(stack.summary.per_frame[10..], [4, 4]);
+        assert!(stack.image.data().iter().all(|&v| v == 10.0));
+    }
+
+    #[test]
+    fn rejection_fallback_fills_pixels_where_everything_was_rejected() {
+        // Every value is further than 0.1 sigma from the mean of 4.8
+        let frames: Vec<FitsImage> = [0.0, 0.0, 10.0, 10.0, 4.0]
+            .iter()
+            .map(|&v| frame(v, "reject.fits"))
+            .collect();
+
+        for (fallback, expected) in [
+            (RejectionFallback::Zero, 0.0),
+            (RejectionFallback::Mean, 4.8),
+            (RejectionFallback::Median, 4.0),
+            (RejectionFallback::LastSurvivors, 4.8),
+        ] {
+            let options = SigmaClipOptions {
+                sigma: 0.1,
+                fallback,
+                ..SigmaClipOptions::default()
+            };
+            let stack = sigma_clipping_with_options(&frames, &options).unwrap();
+            assert_eq!(stack.summary.total_rejected, 5 * 4, "{:?}", fallback);
+            assert!(
+                stack
+                    .image
+                    .data()
+                    .iter()
+                    .all(|&v| (v - expected).abs() < 1e-5),
+                "{:?}",
+                fallback
+            );
+        }
+        assert_eq!(
+            SigmaClipOptions::default().fallback,
+            RejectionFallback::Mean
+        );
+    }
+
+    #[test]
+    fn masked_average_excludes_masked_regions() {
+        let plane = |value: f32| FitsImage::from_data(ArrayD::from_elem(vec![4, 4], value));
+        let set_corner = |image: &mut FitsImage, value: f32| {
+            image
+                .data_mut()
+                .slice_mut(ndarray::s![..2, ..2])
+                .fill(value);
+        };
+        // The third frame has a bright trail across its top-left corner
+        let mut trailed = plane(100.0);
+        set_corner(&mut trailed, 1000.0);
+        let frames = [plane(100.0), plane(100.0), trailed];
+
+        let mut trail_mask = plane(1.0);
+        set_corner(&mut trail_mask, 0.0);
+        let masks = [plane(1.0), plane(1.0), trail_mask];
+
+        let stack = masked_average(&frames, &masks).unwrap();
+        assert!(stack.data().iter().all(|&v| (v - 100.0).abs() < 1e-3));
+
+        // Without masking, the trail leaks into the corner
+        let unmasked = masked_average(&frames, &[plane(1.0), plane(1.0), plane(1.0)]).unwrap();
+        assert!((unmasked.data()[[0, 0]] - 400.0).abs() < 1e-3);
+
+        assert!(matches!(
+            masked_average(&frames, &masks[..2]),
+            Err(ImageError::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn stacks_record_total_exposure_and_ncombine() {
+        let exposed = |exposure: f64, frame_type: FrameType| {
+            let mut image = frame(100.0, "exposed.fits");
+            image.metadata.exposure_time = Some(exposure);
+            image.frame_type = frame_type;
+            image
+        };
+
+        let lights: Vec<FitsImage> = [60.0, 120.0, 300.0]
+            .iter()
+            .map(|&exposure| exposed(exposure, FrameType::Light))
+            .collect();
+        for stack in [
+            average(&lights).unwrap(),
+            median(&lights).unwrap(),
+            sigma_clipping(&lights, 3.0, 3).unwrap(),
+        ] {
+            assert_eq!(stack.metadata.exposure_time, Some(480.0));
+            assert_eq!(stack.metadata.extra["NCOMBINE"], "3");
+        }
+
+        // A master dark still stands for one exposure of its frames' length
+        let darks = [
+            exposed(300.0, FrameType::Dark),
+            exposed(300.0, FrameType::Dark),
+        ];
+        let master = average(&darks).unwrap();
+        assert_eq!(master.metadata.exposure_time, Some(300.0));
+        assert_eq!(master.metadata.extra["NCOMBINE"], "2");
+    }
+
+    #[test]
+    fn master_flat_pedestal_is_subtracted_before_normalizing() {
+        // 1000 of scattered light on top of an optical response that halves on the right.
+        // The unlit corner pixel sees only the scattered light.
+        let mut flat = FitsImage::from_data(ArrayD::from_shape_fn(vec![10, 10], |index| {
+            let response = if index[1] < 5 { 1.0 } else { 0.5 };
+            1000.0 + 10000.0 * response
+        }));
+        flat.data_mut()[[0, 0]] = 1000.0;
+        flat.frame_type = FrameType::Flat;
+        let flats = [flat];
+
+        let plain = create_master_flat(&flats, &MasterFlatOptions::default()).unwrap();
+        let options = MasterFlatOptions {
+            subtract_pedestal: true,
+            ..MasterFlatOptions::default()
+        };
+        let corrected = create_master_flat(&flats, &options).unwrap();
 
-// TODO: Implement the following functions
-// /// Create a master dark frame from a list of dark frames
-// pub fn create_master_dark(dark_frames: &[FitsImage]) -> Result<FitsImage, ImageError> {
-//     // Use median stacking for dark frames
-//     let mut master_dark = FitsImage::median(dark_frames)?;
-//     master_dark.frame_type = FrameType::Dark;
-
-//     // Update metadata
-//     if let Some(first_exposure) = dark_frames.first().and_then(|f| f.metadata.exposure_time) {
-//         master_dark.metadata.exposure_time = Some(first_exposure);
-//     }
-
-//     if let Some(first_temp) = dark_frames.first().and_then(|f| f.metadata.temperature) {
-//         master_dark.metadata.temperature = Some(first_temp);
-//     }
-
-//     Ok(master_dark)
-// }
-
-// /// Create a master flat frame from a list of flat frames
-// pub fn create_master_flat(flat_frames: &[FitsImage]) -> Result<FitsImage, ImageError> {
-//     // Use average stacking for flat frames
-//     let mut master_flat = FitsImage::average(flat_frames)?;
-//     master_flat.frame_type = FrameType::Flat;
-
-//     // Normalize the master flat
-//     let stats = master_flat.calculate_statistics();
-//     if stats.max > 0.0 {
-//         let (width, height) = master_flat.dimensions();
-//         for y in 0..height {
-//             for x in 0..width {
-//                 master_flat.data[[y, x]] /= stats.mean;
-//             }
-//         }
-//     }
-
-//     Ok(master_flat)
-// }
-
-// /// Create a master bias frame from a list of bias frames
-// pub fn create_master_bias(bias_frames: &[FitsImage]) -> Result<FitsImage, ImageError> {
-//     // Use median stacking for bias frames
-//     let mut master_bias = FitsImage::median(bias_frames)?;
-//     master_bias.frame_type = FrameType::Bias;
-
-//     Ok(master_bias)
-// }
-
-// /// Calibrate a light frame using master dark and master flat frames
-// pub fn calibrate(
-//     &mut self,
-//     master_dark: Option<&FitsImage>,
-//     master_flat: Option<&FitsImage>,
-// ) -> Result<(), ImageError> {
-//     // Apply dark frame subtraction if provided
-//     if let Some(dark) = master_dark {
-//         self.subtract(dark)?;
-//     }
-
-//     // Apply flat field correction if provided
-//     if let Some(flat) = master_flat {
-//         self.divide(flat)?;
-//     }
-
-//     Ok(())
-// }
+        // Only with the pedestal removed does the flat show the true 2:1 response
+        let ratio = |flat: &FitsImage| flat.data()[[5, 2]] / flat.data()[[5, 7]];
+        assert!((ratio(&plain) - 11.0 / 6.0).abs() < 1e-4);
+        assert!((ratio(&corrected) - 2.0).abs() < 1e-4);
+        // The unlit pixel would divide by 0, so it's raised to the floor
+        assert_eq!(corrected.data()[[0, 0]], options.min_value);
+        assert!(plain.data()[[0, 0]] > options.min_value);
+    }
+
+    #[test]
+    fn normalize_to_reference_brings_frames_to_a_common_median() {
+        let scaled = |scale: f32| {
+            let data = vec![1.0, 2.0, 3.0, 4.0].into_iter().map(|v| v * scale);
+            FitsImage::from_data(ArrayD::from_shape_vec(vec![2, 2], data.collect()).unwrap())
+        };
+        let mut frames = [scaled(100.0), scaled(250.0), scaled(50.0), scaled(0.0)];
+
+        normalize_to_reference(&mut frames, 1).unwrap();
+        for frame in &frames[..3] {
+            assert_eq!(frame.calculate_statistics().median, 625.0);
+            let expected = [250.0, 500.0, 750.0, 1000.0];
+            for (&value, expected) in frame.data().iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-3);
+            }
+        }
+        // A frame with a zero median can't be scaled to match
+        assert!(frames[3].data().iter().all(|&v| v == 0.0));
+
+        assert!(matches!(
+            normalize_to_reference(&mut frames, 4),
+            Err(ImageError::DimensionError(_))
+        ));
+    }
+
+    #[test]
+    fn flats_near_saturation_are_flagged() {
+        let good = frame(30000.0, "flat_1.fits");
+        let saturated = frame(62000.0, "flat_2.fits");
+        let mut float = frame(62000.0, "flat_3.fits");
+        float.metadata.pixel_type = PixelType::F32;
+
+        assert_eq!(flat_level_warning([&good]), None);
+        assert_eq!(flat_level_warning([&good, &float]), None);
+        assert_eq!(
+            flat_level_warning([&good, &saturated]).as_deref(),
+            Some("1 of 2 flats are near saturation (median should be 20-70% of full scale)")
+        );
+    }
+
+    #[test]
+    fn color_cubes_are_combined_plane_by_plane() {
+        let base = |index: ndarray::IxDyn| (10 * (index[0] + 1) + index[1] * 2 + index[2]) as f32;
+        let frames: Vec<FitsImage> = [0.0, 2.0, 10.0]
+            .iter()
+            .map(|&offset| {
+                FitsImage::from_data(ArrayD::from_shape_fn(vec![3, 2, 2], |index| {
+                    base(index) + offset
+                }))
+            })
+            .collect();
+        let expected =
+            |offset: f32| ArrayD::from_shape_fn(vec![3, 2, 2], |index| base(index) + offset);
+
+        let averaged = average(&frames).unwrap();
+        assert!(averaged.is_color());
+        assert_eq!(*averaged.data(), expected(4.0));
+        assert_eq!(*median(&frames).unwrap().data(), expected(2.0));
+        let clipped = sigma_clipping_with_map(&frames, 3.0, 3).unwrap();
+        assert_eq!(*clipped.image.data(), expected(4.0));
+        assert_eq!(clipped.rejection_map.data().shape(), [3, 2, 2]);
+    }
+}
@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+
+use ndarray::Axis;
+
+use crate::image::FitsImage;
+
+/// Number of rows of a frame across all of its planes: the height of a mono frame, three
+/// times that for a color cube. Row-wise combines walk `0..row_count` so every plane is
+/// stacked separately.
+pub fn row_count(image: &FitsImage) -> usize {
+    let shape = image.data().shape();
+    shape[..shape.len().saturating_sub(1)].iter().product()
+}
+
+/// Row `y` of every frame as a contiguous slice, counting rows through the planes of color
+/// cubes in order (see `row_count`). Rows of standard layout frames are borrowed; only
+/// frames stored in another layout are copied.
+pub fn frame_rows(images: &[FitsImage], y: usize) -> Vec<Cow<'_, [f32]>> {
+    images
+        .iter()
+        .map(|img| {
+            let row = if img.data().ndim() == 3 {
+                let height = img.data().len_of(Axis(1));
+                img.data()
+                    .index_axis(Axis(0), y / height)
+                    .index_axis_move(Axis(0), y % height)
+            } else {
+                img.data().index_axis(Axis(0), y)
+            };
+            match row.to_slice() {
+                Some(values) => Cow::Borrowed(values),
+                None => Cow::Owned(row.iter().copied().collect()),
+            }
+        })
+        .collect()
+}
+
+/// One row of a set of frames, transposed so the values of each pixel across all frames
+/// sit next to each other. Per-pixel combines (median, clipping) then read one contiguous
+/// slice per pixel instead of striding through every frame.
+pub struct RowStack {
+    frames: usize,
+    /// Pixel-major values: pixel `x` of frame `i` is at `x * frames + i`
+    values: Vec<f32>,
+}
+
+impl RowStack {
+    /// Gather row `y` of every frame. The frames must share the same dimensions.
+    pub fn load(images: &[FitsImage], y: usize) -> Self {
+        let rows = frame_rows(images, y);
+        let frames = rows.len();
+        let width = rows.first().map_or(0, |row| row.len());
+
+        let mut values = vec![0.0; width * frames];
+        for (frame, row) in rows.iter().enumerate() {
+            for (pixel, &value) in values.chunks_exact_mut(frames).zip(row.iter()) {
+                pixel[frame] = value;
+            }
+        }
+
+        Self { frames, values }
+    }
+
+    /// Every pixel's values, left to right, each indexed by frame. Missing (non-finite)
+    /// values are included, so indices always match the frame order.
+    pub fn pixels(&self) -> impl Iterator<Item = &[f32]> {
+        self.values.chunks_exact(self.frames.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    #[test]
+    fn row_stack_groups_each_pixel_across_frames() {
+        let row_major = ArrayD::from_shape_vec(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        // Same layout in value terms, but stored column-major so its rows aren't contiguous
+        let column_major =
+            ArrayD::from_shape_vec(vec![3, 2], vec![10.0, 40.0, f32::NAN, 50.0, 30.0, 60.0]);
+        let frames = [
+            FitsImage::from_data(row_major.unwrap()),
+            FitsImage::from_data(column_major.unwrap().reversed_axes()),
+        ];
+
+        assert!(matches!(frame_rows(&frames, 1)[1], Cow::Owned(_)));
+        let stack = RowStack::load(&frames, 0);
+        let pixels: Vec<&[f32]> = stack.pixels().collect();
+        assert_eq!(pixels.len(), 3);
+        assert_eq!(pixels[0], [1.0, 10.0]);
+        assert!(pixels[1][0] == 2.0 && pixels[1][1].is_nan());
+        assert_eq!(pixels[2], [3.0, 30.0]);
+
+        let stack = RowStack::load(&frames, 1);
+        let pixels: Vec<&[f32]> = stack.pixels().collect();
+        assert_eq!(pixels, [[4.0, 40.0], [5.0, 50.0], [6.0, 60.0]]);
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+
+use crate::alignment::align_by_brightest_star;
+use crate::analysis::{DEFAULT_DETECTION_SIGMA, FrameMetrics, detect_trails, flag_outlier_metrics};
+use crate::image::{FitsImage, FrameType};
+
+/// What the analyze command reports about a frame beyond its `FrameMetrics`
+struct FrameReport {
+    /// Number of satellite or aircraft trails and the length of the longest one
+    trails: usize,
+    longest_trail: f32,
+    /// Shift from the first frame, by its brightest star
+    drift: (f32, f32),
+}
+
+/// Read the FITS files in a folder one at a time and print per-frame quality metrics,
+/// optionally writing them to a CSV file as well
+pub fn run_analyze_command(folder: String, csv_path: Option<String>) {
+    let mut metrics = Vec::new();
+    let mut reports = Vec::new();
+    // Only the first frame is kept, to measure the drift against
+    let mut reference: Option<FitsImage> = None;
+    let mut total = 0;
+    for result in FitsImage::iter_folder(&folder, FrameType::Light) {
+        total += 1;
+        match result {
+            Ok(image) => {
+                metrics.push(FrameMetrics::measure(&image));
+                let segments = detect_trails(&image, DEFAULT_DETECTION_SIGMA);
+                let drift = reference.as_ref().map_or((0.0, 0.0), |reference| {
+                    align_by_brightest_star(reference, &image)
+                });
+                reports.push(FrameReport {
+                    trails: segments.len(),
+                    longest_trail: segments.iter().map(|s| s.length()).fold(0.0, f32::max),
+                    drift,
+                });
+                reference.get_or_insert(image);
+            }
+            Err(e) => eprintln!("Skipping frame: {}", e),
+        }
+    }
+
+    let outliers = flag_outlier_metrics(&metrics);
+
+    println!(
+        "{:<40} {:>10} {:>8} {:>8} {:>12} {:>8} {:>6} {:>7} {:>14} {:>8}",
+        "File",
+        "Exposure",
+        "Temp",
+        "FWHM",
+        "Background",
+        "SNR",
+        "Stars",
+        "Trails",
+        "Drift (px)",
+        "Outlier"
+    );
+    for ((m, report), &outlier) in metrics.iter().zip(&reports).zip(&outliers) {
+        println!(
+            "{:<40} {:>10} {:>8} {:>8} {:>12.2} {:>8.2} {:>6} {:>7} {:>14} {:>8}",
+            m.file_name,
+            m.exposure_time
+                .map(|v| format!("{:.1}s", v))
+                .unwrap_or_else(|| "-".to_string()),
+            m.temperature
+                .map(|v| format!("{:.1}C", v))
+                .unwrap_or_else(|| "-".to_string()),
+            m.fwhm
+                .map(|v| format!("{:.2}", v))
+                .unwrap_or_else(|| "-".to_string()),
+            m.background,
+            m.snr,
+            m.star_count,
+            report.trails,
+            format!("{:+.1}, {:+.1}", report.drift.0, report.drift.1),
+            if outlier { "yes" } else { "" },
+        );
+    }
+    println!("Analyzed {} of {} files", metrics.len(), total);
+    for (m, report) in metrics.iter().zip(&reports) {
+        if report.trails > 0 {
+            println!(
+                "{}: {} satellite or aircraft trail(s), longest {:.0}px",
+                m.file_name, report.trails, report.longest_trail
+            );
+        }
+    }
+    let flagged = outliers.iter().filter(|&&outlier| outlier).count();
+    if flagged > 0 {
+        println!(
+            "{} frame(s) stand out from the session (background, star count or elongated stars)",
+            flagged
+        );
+    }
+
+    if let Some(csv_path) = csv_path {
+        let mut csv = String::from(FrameMetrics::CSV_HEADER);
+        csv.push('\n');
+        for m in &metrics {
+            csv.push_str(&m.to_csv_row());
+            csv.push('\n');
+        }
+
+        match fs::write(&csv_path, csv) {
+            Ok(()) => println!("Metrics written to: {}", csv_path),
+            Err(e) => eprintln!("Error writing CSV {}: {}", csv_path, e),
+        }
+    }
+}
@@ -1,5 +1,13 @@
-// Declare the stack module
+// Declare the command modules
+mod analyze;
+mod organize;
+#[cfg(feature = "plate-solve")]
+mod solve;
 mod stack;
 
-// Re-export the run_stack_command function so it can be used as commands::run_stack_command
-pub use stack::run_stack_command;
+// Re-export the command functions so they can be used as commands::run_*_command
+pub use analyze::run_analyze_command;
+pub use organize::run_organize_command;
+#[cfg(feature = "plate-solve")]
+pub use solve::run_solve_command;
+pub use stack::{StackOutput, StackSteps, run_stack_command};
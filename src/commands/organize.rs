@@ -0,0 +1,24 @@
+use std::path::Path;
+
+use crate::image::{FITS_EXTENSIONS, FitsImage, organize_frames};
+
+/// Copy the FITS files of a folder into `dest`, laid out by `template`
+pub fn run_organize_command(folder: String, dest: String, template: String) {
+    let paths = match FitsImage::list_folder(&folder, FITS_EXTENSIONS) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", folder, e);
+            return;
+        }
+    };
+
+    match organize_frames(&paths, Path::new(&dest), &template) {
+        Ok(organized) => {
+            for (from, to) in paths.iter().zip(&organized) {
+                println!("{} -> {}", from.display(), to.display());
+            }
+            println!("Organized {} frames into {}", organized.len(), dest);
+        }
+        Err(e) => eprintln!("Error organizing frames: {}", e),
+    }
+}
@@ -0,0 +1,33 @@
+use crate::astrometry::{ExternalSolver, SolverKind, plate_solve};
+use crate::image::FitsImage;
+
+/// Plate-solve a FITS file with a locally installed solver and print where it points
+pub fn run_solve_command(file: String, solver: SolverKind, binary: Option<String>) {
+    let image = match FitsImage::from_file_detect_type(&file) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return;
+        }
+    };
+
+    let mut solver = ExternalSolver::new(solver);
+    if let Some(binary) = binary {
+        solver.binary = binary.into();
+    }
+    println!("Solving {} with {:?}", file, solver.binary);
+
+    let wcs = match plate_solve(&image, &solver) {
+        Ok(wcs) => wcs,
+        Err(e) => {
+            eprintln!("Error solving {}: {}", file, e);
+            return;
+        }
+    };
+
+    // FITS pixel coordinates are 1-based, so the centre of the frame is at (n + 1) / 2
+    let (width, height) = image.metadata.dimensions;
+    let (ra, dec) = wcs.pixel_to_sky((width as f64 + 1.0) / 2.0, (height as f64 + 1.0) / 2.0);
+    println!("Center: RA {:.5}, Dec {:+.5} (degrees)", ra, dec);
+    println!("Scale: {:.3} arcsec/px", wcs.pixel_scale());
+}
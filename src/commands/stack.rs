@@ -1,6 +1,10 @@
 // There are multiple ways to use modules from other parts of your crate:
 
 // Method 1: Import specific items from a module
+use std::path::Path;
+
+use crate::alignment;
+use crate::analysis;
 use crate::calibration;
 use crate::image;
 
@@ -8,12 +12,35 @@ use crate::image;
 // (Uncomment below to use this approach instead)
 // use crate::image;
 
+/// Where and how the stacked result is written
+pub struct StackOutput {
+    /// Folder the stacked image is written to
+    pub folder: String,
+    /// File name template, see `ImageMetadata::resolve_template`. With
+    /// `OutputLayout::ByFilter` it is resolved for each filter's stack in its folder.
+    pub name_template: String,
+    pub layout: image::OutputLayout,
+}
+
+/// Optional processing applied to the lights before they are combined
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StackSteps {
+    /// Register the frames onto the first one, reusing the transforms cached in
+    /// `alignment::TRANSFORM_CACHE_FILE_NAME` in the lights folder from an earlier run
+    pub align: bool,
+    /// Leave out the frames `analysis::flag_outlier_frames` flags, e.g. clouded ones
+    pub reject_outliers: bool,
+    /// Remove this background model from every light before it is aligned and combined
+    pub subtract_background: Option<image::GradientModel>,
+}
+
 pub fn run_stack_command(
     lights_folder: String,
     darks_folder: Option<String>,
     flats_folder: Option<String>,
     bias_folder: Option<String>,
-    output_folder: String,
+    output: StackOutput,
+    steps: StackSteps,
     threads: Option<usize>,
 ) {
     println!("Running stack command with the following parameters:");
@@ -21,10 +48,17 @@ pub fn run_stack_command(
     println!("Darks folder: {:?}", darks_folder);
     println!("Flats folder: {:?}", flats_folder);
     println!("Bias folder: {:?}", bias_folder);
-    println!("Output folder: {}", output_folder);
+    println!("Output folder: {}", output.folder);
+    println!("Output name template: {}", output.name_template);
+    println!("Output layout: {:?}", output.layout);
+    println!("Steps: {:?}", steps);
     println!("Threads: {:?}", threads);
 
-    let result = image::FitsImage::from_folder(&lights_folder, image::FrameType::Light);
+    let result = image::FitsImage::from_folder(
+        &lights_folder,
+        image::FrameType::Light,
+        image::FITS_EXTENSIONS,
+    );
 
     // Check if the result is an error
     if let Err(e) = result {
@@ -36,12 +70,155 @@ pub fn run_stack_command(
 
     // Unwrap the result to get the FitsImage
     // This is safe because we already checked for errors
-    let fits_images = result.unwrap();
+    let mut fits_images = result.unwrap();
 
     println!("Number of images read: {}", fits_images.len());
 
-    // Stack the images
-    let stacked_image = calibration::average(&fits_images);
+    // Before anything else touches the pixel values
+    let masters = match build_masters(darks_folder, flats_folder, bias_folder) {
+        Ok(masters) => masters,
+        Err(e) => {
+            eprintln!("Error building calibration masters: {}", e);
+            return;
+        }
+    };
+    if !masters.darks.is_empty() {
+        for light in &fits_images {
+            if calibration::match_dark(light, &masters.darks).is_none() {
+                let path = light.metadata.file_path.as_deref();
+                println!(
+                    "No dark within tolerance, not dark-subtracting: {}",
+                    path.unwrap_or(Path::new("(unnamed)")).display()
+                );
+            }
+        }
+    }
+    if !masters.is_empty() {
+        let dark_matching = calibration::DarkMatchOptions::default();
+        if let Err(e) = masters.calibrate_all(&mut fits_images, &dark_matching) {
+            eprintln!("Error calibrating images: {}", e);
+            return;
+        }
+        println!("Successfully calibrated images.");
+    }
+
+    // Before removing the background, which would level out the clouded frames
+    if steps.reject_outliers {
+        let outliers = analysis::flag_outlier_frames(&fits_images);
+        fits_images = fits_images
+            .into_iter()
+            .zip(outliers)
+            .filter_map(|(image, outlier)| {
+                if outlier {
+                    let path = image.metadata.file_path.as_deref();
+                    println!(
+                        "Leaving out outlier frame: {}",
+                        path.unwrap_or(Path::new("(unnamed)")).display()
+                    );
+                }
+                (!outlier).then_some(image)
+            })
+            .collect();
+        println!("Number of images kept: {}", fits_images.len());
+    }
+
+    // Before aligning, so the empty borders left by the shifts don't skew the fit
+    if let Some(model) = steps.subtract_background {
+        if let Err(e) = calibration::subtract_background_all(&mut fits_images, model) {
+            eprintln!("Error removing background: {}", e);
+            return;
+        }
+        println!("Successfully removed background.");
+    }
+
+    let fits_images = if steps.align {
+        match alignment::align_to_first_cached(&fits_images, Path::new(&lights_folder)) {
+            Ok(aligned) => {
+                println!("Successfully aligned images.");
+                aligned
+            }
+            Err(e) => {
+                eprintln!("Error aligning images: {}", e);
+                return;
+            }
+        }
+    } else {
+        fits_images
+    };
+
+    let combiner: Box<dyn calibration::Combiner> = Box::new(calibration::Average);
+    println!("Combining with: {}", combiner.name());
+
+    // Either stack everything together, or stack each filter separately into its own folder
+    let layout = output.layout;
+    let groups = match layout {
+        image::OutputLayout::Flat => vec![(None, fits_images)],
+        image::OutputLayout::ByFilter => calibration::group_by_filter(fits_images),
+    };
+
+    for (filter, images) in groups {
+        if layout == image::OutputLayout::ByFilter {
+            println!(
+                "Stacking {} frames with filter {}",
+                images.len(),
+                filter.as_deref().unwrap_or("(none)")
+            );
+        }
+
+        let output_dir = match layout.directory(Path::new(&output.folder), filter.as_deref()) {
+            Ok(dir) => dir,
+            Err(e) => {
+                eprintln!("Error creating output folder: {}", e);
+                return;
+            }
+        };
+
+        stack_and_save(
+            combiner.as_ref(),
+            &images,
+            &output_dir,
+            &output.name_template,
+        );
+    }
+}
+
+/// Load the calibration folders that were given and combine each into its masters with the
+/// recommended method for the frame type
+fn build_masters(
+    darks_folder: Option<String>,
+    flats_folder: Option<String>,
+    bias_folder: Option<String>,
+) -> Result<calibration::CalibrationMasters, image::ImageError> {
+    let frames = calibration::CalibrationFrames {
+        bias: load_folder(bias_folder, image::FrameType::Bias)?,
+        darks: load_folder(darks_folder, image::FrameType::Dark)?,
+        flats: load_folder(flats_folder, image::FrameType::Flat)?,
+        dark_flats: Vec::new(),
+    };
+    calibration::CalibrationMasters::build(frames, calibration::CombineMethod::default_for)
+}
+
+/// Load the frames of an optional calibration folder, none when it wasn't given
+fn load_folder(
+    folder: Option<String>,
+    frame_type: image::FrameType,
+) -> Result<Vec<image::FitsImage>, image::ImageError> {
+    let Some(folder) = folder else {
+        return Ok(Vec::new());
+    };
+    let frames = image::FitsImage::from_folder(&folder, frame_type, image::FITS_EXTENSIONS)?;
+    println!("Number of {:?} frames read: {}", frame_type, frames.len());
+    Ok(frames)
+}
+
+/// Combine one group of frames, print its statistics and save it to `output_dir`
+fn stack_and_save(
+    combiner: &dyn calibration::Combiner,
+    fits_images: &[image::FitsImage],
+    output_dir: &Path,
+    name_template: &str,
+) {
+    let stacked_image = combiner.combine(fits_images);
 
     // Check if the stacking was successful
     if let Err(e) = stacked_image {
@@ -62,12 +239,82 @@ pub fn run_stack_command(
     println!("Standard Deviation: {}", image_statistics.std_dev);
     println!("Minimum: {}", image_statistics.min);
     println!("Maximum: {}", image_statistics.max);
+    // Elongated stars in the stack point at misaligned or trailed frames
+    if let Some(eccentricity) = analysis::measure_eccentricity(&stacked_image) {
+        println!("Star eccentricity: {:.2}", eccentricity);
+    }
 
     // Save the stacked image
-    let output_path = format!("{}/stacked_image.fits", output_folder);
-    stacked_image.to_file(&output_path).unwrap_or_else(|e| {
-        eprintln!("Error saving stacked image: {}", e);
-    });
+    // The stack's exposure is the total integration, so name it from a single frame's
+    let file_name = fits_images[0]
+        .metadata
+        .resolve_template(name_template, fits_images.len());
+    let output_path = output_dir.join(file_name);
+    match stacked_image.to_file_with_options(&output_path, &image::SaveOptions::processed()) {
+        Ok(()) => println!("Stacked image saved to: {}", output_path.display()),
+        Err(e) => eprintln!("Error saving stacked image: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::temp_path;
+
+    #[test]
+    fn split_by_filter_names_each_stack_from_the_template() {
+        let lights = temp_path("split-lights");
+        let output = temp_path("split-output");
+        let _ = fs::remove_dir_all(&lights);
+        let _ = fs::remove_dir_all(&output);
+        fs::create_dir_all(&lights).unwrap();
 
-    println!("Stacked image saved to: {}", output_path);
+        for (name, filter) in [("a", "Ha"), ("b", "OIII"), ("c", "Ha"), ("d", "")] {
+            let mut image = image::FitsImage::from_data(ArrayD::from_elem(vec![4, 4], 100.0));
+            if !filter.is_empty() {
+                image.set_metadata_key("FILTER", filter).unwrap();
+            }
+            let path = lights.join(format!("{}.fits", name));
+            image.to_file(&path).unwrap();
+        }
+
+        run_stack_command(
+            lights.display().to_string(),
+            None,
+            None,
+            None,
+            StackOutput {
+                folder: output.display().to_string(),
+                name_template: "{filter}_{count}x.fits".to_string(),
+                layout: image::OutputLayout::ByFilter,
+            },
+            StackSteps::default(),
+            None,
+        );
+
+        let mut tree: Vec<PathBuf> = fs::read_dir(&output)
+            .unwrap()
+            .flat_map(|entry| fs::read_dir(entry.unwrap().path()).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .map(|path| path.strip_prefix(&output).unwrap().to_path_buf())
+            .collect();
+        tree.sort();
+        fs::remove_dir_all(&lights).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+
+        assert_eq!(
+            tree,
+            [
+                PathBuf::from("Ha/Ha_2x.fits"),
+                PathBuf::from("OIII/OIII_1x.fits"),
+                // Frames without a filter are stacked together too
+                PathBuf::from("unknown/unknown_1x.fits"),
+            ]
+        );
+    }
 }
@@ -0,0 +1,114 @@
+use ndarray::{Array2, ArrayD, Ix2};
+use rustfft::FftPlanner;
+
+pub use rustfft::num_complex::Complex;
+
+/// Forward 2D FFT of a `[height, width]` plane.
+///
+/// The plane is zero-padded up to `efficient_size` on each axis first, so the spectrum
+/// may be larger than the input; pass the original size to `ifft2` to undo it. Non-finite
+/// pixels are treated as 0, and data that isn't 2D gives an empty spectrum.
+pub fn fft2(data: &ArrayD<f32>) -> Array2<Complex<f32>> {
+    let Ok(plane) = data.view().into_dimensionality::<Ix2>() else {
+        return Array2::zeros((0, 0));
+    };
+
+    let (height, width) = plane.dim();
+    let mut spectrum = Array2::zeros((efficient_size(height), efficient_size(width)));
+    for ((y, x), &value) in plane.indexed_iter() {
+        if value.is_finite() {
+            spectrum[[y, x]] = Complex::new(value, 0.0);
+        }
+    }
+
+    fft2_in_place(&mut spectrum, false);
+    spectrum
+}
+
+/// Inverse of `fft2`: transform back, normalize, and crop to the original `(height, width)`
+pub fn ifft2(spectrum: &Array2<Complex<f32>>, (height, width): (usize, usize)) -> Array2<f32> {
+    let mut data = spectrum.clone();
+    fft2_in_place(&mut data, true);
+
+    let scale = 1.0 / data.len().max(1) as f32;
+    let height = height.min(data.nrows());
+    let width = width.min(data.ncols());
+    Array2::from_shape_fn((height, width), |(y, x)| data[[y, x]].re * scale)
+}
+
+/// Unnormalized 2D FFT of complex data in place, transforming every row and then every
+/// column. An inverse transform leaves the values scaled by the number of elements.
+pub fn fft2_in_place(data: &mut Array2<Complex<f32>>, inverse: bool) {
+    let (height, width) = data.dim();
+    let mut planner = FftPlanner::new();
+    let (row_fft, column_fft) = if inverse {
+        (
+            planner.plan_fft_inverse(width),
+            planner.plan_fft_inverse(height),
+        )
+    } else {
+        (
+            planner.plan_fft_forward(width),
+            planner.plan_fft_forward(height),
+        )
+    };
+
+    let mut buffer = Vec::with_capacity(width.max(height));
+    for mut row in data.rows_mut() {
+        buffer.clear();
+        buffer.extend(row.iter().copied());
+        row_fft.process(&mut buffer);
+        row.iter_mut().zip(&buffer).for_each(|(v, &b)| *v = b);
+    }
+    for mut column in data.columns_mut() {
+        buffer.clear();
+        buffer.extend(column.iter().copied());
+        column_fft.process(&mut buffer);
+        column.iter_mut().zip(&buffer).for_each(|(v, &b)| *v = b);
+    }
+}
+
+/// The smallest length of at least `n` whose only prime factors are 2, 3 and 5, which
+/// FFTs handle much faster than lengths with large prime factors
+pub fn efficient_size(n: usize) -> usize {
+    let mut size = n.max(1);
+    loop {
+        let mut rest = size;
+        for factor in [2, 3, 5] {
+            while rest % factor == 0 {
+                rest /= factor;
+            }
+        }
+        if rest == 1 {
+            return size;
+        }
+        size += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ifft2_undoes_fft2() {
+        // 7 x 11 is padded to 8 x 12 for the transform
+        let data = ArrayD::from_shape_fn(vec![7, 11], |index| {
+            ((index[0] * 31 + index[1] * 17) % 23) as f32 - 4.5
+        });
+        let spectrum = fft2(&data);
+        assert_eq!(spectrum.dim(), (8, 12));
+
+        let restored = ifft2(&spectrum, (7, 11));
+        assert_eq!(restored.dim(), (7, 11));
+        for (&original, &value) in data.iter().zip(restored.iter()) {
+            assert!((original - value).abs() < 1e-4, "{} vs {}", original, value);
+        }
+    }
+
+    #[test]
+    fn efficient_sizes_only_have_small_prime_factors() {
+        let sizes: Vec<usize> = [0, 1, 7, 11, 13, 97, 128].map(efficient_size).to_vec();
+        assert_eq!(sizes, [1, 1, 8, 12, 15, 100, 128]);
+    }
+}
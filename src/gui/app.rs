@@ -1,10 +1,21 @@
 use eframe::egui;
 use rfd::FileDialog;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::alignment::{MIN_DITHER_AMPLITUDE, align_to_first_cached, detect_dithering};
+use crate::calibration::{
+    CalibrationFrames, CalibrationMasters, CombineMethod, DarkMatchOptions, DarkMatchStrategy,
+    RejectionSummary, crop_to_common_size, group_by_filter, memory_summary, memory_warning,
+    subtract_background_all, synthetic_flat,
+};
 use crate::gui::registration::RegistrationView;
-use crate::image::FrameType;
+use crate::gui::task_worker::TaskWorker;
+use crate::image::{
+    FITS_EXTENSIONS, FitsImage, FrameType, GradientModel, OutputLayout, SaveOptions,
+};
 
 /// Represents a frame set that can contain:
 /// - A directory path where the frames are located
@@ -15,6 +26,17 @@ pub struct FrameSet {
     pub directory: Option<PathBuf>,
     pub file_paths: Vec<PathBuf>,
     pub is_required: bool,
+    /// How the frames are combined into a master, defaulting to the recommendation for the type
+    pub combine_method: CombineMethod,
+    /// Files that failed validation during the last scan, with the reason
+    pub invalid_files: HashMap<PathBuf, String>,
+    /// File extensions picked up when scanning the directory (without the leading dot)
+    pub extensions: Vec<String>,
+    /// Comma separated text being edited in the extensions field
+    extensions_input: String,
+    /// Image size of the first readable file, from its header, to estimate memory before
+    /// anything is loaded
+    frame_dimensions: Option<(usize, usize)>,
 }
 
 impl FrameSet {
@@ -24,6 +46,11 @@ impl FrameSet {
             directory: None,
             file_paths: Vec::new(),
             is_required,
+            combine_method: CombineMethod::default_for(frame_type),
+            invalid_files: HashMap::new(),
+            extensions: FITS_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            extensions_input: FITS_EXTENSIONS.join(", "),
+            frame_dimensions: None,
         }
     }
 
@@ -37,33 +64,73 @@ impl FrameSet {
         }
     }
 
+    /// Parse the extensions field into the accepted extension list
+    fn apply_extensions_input(&mut self) {
+        self.extensions = self
+            .extensions_input
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+    }
+
     fn scan_directory(&mut self) {
         if let Some(dir) = &self.directory {
             match fs::read_dir(dir) {
                 Ok(entries) => {
                     self.file_paths.clear();
+                    self.invalid_files.clear();
                     for entry in entries.flatten() {
                         let path = entry.path();
                         if path.is_file() {
-                            let extension =
-                                path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
-                            // Filter for common astrophotography image formats
-                            if ["fit", "fits", "fts"].contains(&extension.to_lowercase().as_str()) {
+                            // Filter for the accepted image formats
+                            if FitsImage::has_extension(&path, &self.extensions) {
+                                // Flag truncated or otherwise corrupt files up front
+                                if let Err(e) = FitsImage::validate(&path) {
+                                    self.invalid_files.insert(path.clone(), e.to_string());
+                                }
                                 self.file_paths.push(path);
                             }
                         }
                     }
                     // Sort the files by name
                     self.file_paths.sort();
+                    self.frame_dimensions = self
+                        .file_paths
+                        .iter()
+                        .filter(|path| !self.invalid_files.contains_key(*path))
+                        .find_map(|path| FitsImage::read_dimensions(path).ok());
                 }
                 Err(e) => {
-                    eprintln!("Error reading directory {}: {}", dir.display(), e);
+                    log::error!("Error reading directory {}: {}", dir.display(), e);
                 }
             }
         }
     }
 }
 
+/// Outcome of a processing run, shown in the Results step: one stack per filter when
+/// splitting by filter, otherwise a single stack
+struct ProcessingOutput {
+    stacks: Vec<StackResult>,
+}
+
+/// One stacked image written by a processing run
+struct StackResult {
+    /// Filter of the stacked frames, when stacks are split by filter
+    filter: Option<String>,
+    /// Where the stacked image was saved
+    output_path: PathBuf,
+    /// File names of the stacked frames, in stacking order
+    frame_names: Vec<String>,
+    /// Rejection statistics, for clipped combines
+    rejection: Option<RejectionSummary>,
+}
+
+/// Default blur of a synthetic flat, in pixels; wide enough to wash out stars and small
+/// nebulosity while following vignetting
+const SYNTHETIC_FLAT_SIGMA: f32 = 64.0;
+
 /// Represents the current step in the processing workflow
 #[derive(PartialEq)]
 enum WorkflowStep {
@@ -80,6 +147,34 @@ pub struct EventideApp {
     current_step: WorkflowStep,
     // Registration view
     registration_view: RegistrationView,
+    // Register the lights onto the first one before stacking
+    align_frames: bool,
+    // Center-crop frames of slightly different sizes to a common size before stacking
+    crop_to_common: bool,
+    // Background model removed from every light before stacking, if any
+    subtract_background: Option<GradientModel>,
+    // Whether stacks go straight into the output directory or into per-filter subfolders
+    output_layout: OutputLayout,
+    // Result of the last processing run
+    processing_result: Option<Result<ProcessingOutput, String>>,
+    // The processing run while it calibrates and stacks
+    processing_worker: Option<TaskWorker<Result<ProcessingOutput, String>>>,
+    // RMS dither amplitude of the selected lights once checked, `None` inside when undithered
+    dither_check: Option<Option<f32>>,
+    // The dither measurement while it runs
+    dither_worker: Option<TaskWorker<Option<f32>>>,
+    // Save processed results as 32-bit float rather than the frames' integer type
+    keep_float: bool,
+    // How strictly darks must match the lights they calibrate
+    dark_matching: DarkMatchOptions,
+    // Blur of the synthetic flat offered when no flats were provided
+    synthetic_flat_sigma: f32,
+    // Outcome of the last synthetic flat correction
+    synthetic_flat_status: Option<Result<Vec<PathBuf>, String>>,
+    // The synthetic flat correction while it runs
+    synthetic_flat_worker: Option<TaskWorker<Result<Vec<PathBuf>, String>>>,
+    // Why watching the lights folder couldn't start
+    live_watch_error: Option<String>,
 }
 
 impl Default for EventideApp {
@@ -95,6 +190,20 @@ impl Default for EventideApp {
             output_directory: None,
             current_step: WorkflowStep::FolderSelection,
             registration_view: RegistrationView::new(),
+            align_frames: false,
+            crop_to_common: false,
+            subtract_background: None,
+            output_layout: OutputLayout::default(),
+            processing_result: None,
+            processing_worker: None,
+            dither_check: None,
+            dither_worker: None,
+            keep_float: true,
+            dark_matching: DarkMatchOptions::default(),
+            synthetic_flat_sigma: SYNTHETIC_FLAT_SIGMA,
+            synthetic_flat_status: None,
+            synthetic_flat_worker: None,
+            live_watch_error: None,
         }
     }
 }
@@ -157,11 +266,31 @@ impl EventideApp {
                         let frame_set = &mut self.frame_sets[index];
                         frame_set.directory = None;
                         frame_set.file_paths.clear();
+                        frame_set.invalid_files.clear();
+                    }
+                });
+
+                // Accepted file extensions
+                ui.horizontal(|ui| {
+                    ui.label("Extensions:");
+                    let frame_set = &mut self.frame_sets[index];
+                    let response = ui
+                        .text_edit_singleline(&mut frame_set.extensions_input)
+                        .on_hover_text("Comma separated, e.g. fits, fit, fits.gz, fz");
+                    if response.lost_focus() {
+                        frame_set.apply_extensions_input();
+                        frame_set.scan_directory();
+                    }
+                    if ui.button("Default").clicked() {
+                        frame_set.extensions_input = FITS_EXTENSIONS.join(", ");
+                        frame_set.apply_extensions_input();
+                        frame_set.scan_directory();
                     }
                 });
 
                 // Display file table if directory is selected
                 let file_paths_clone = self.frame_sets[index].file_paths.clone();
+                let invalid_files = &self.frame_sets[index].invalid_files;
                 if !file_paths_clone.is_empty() {
                     ui.add_space(8.0);
 
@@ -174,6 +303,7 @@ impl EventideApp {
                                 .show(ui, |ui| {
                                     // Header row
                                     ui.strong("File Name");
+                                    ui.strong("Status");
                                     ui.end_row();
 
                                     // File rows
@@ -182,6 +312,12 @@ impl EventideApp {
                                             path.file_name().and_then(|f| f.to_str())
                                         {
                                             ui.label(file_name);
+                                            if let Some(reason) = invalid_files.get(path) {
+                                                ui.colored_label(egui::Color32::RED, "Corrupt")
+                                                    .on_hover_text(reason.as_str());
+                                            } else {
+                                                ui.label("OK");
+                                            }
                                             ui.end_row();
                                         }
                                     }
@@ -189,6 +325,12 @@ impl EventideApp {
                         });
 
                     ui.label(format!("Total files: {}", file_paths_clone.len()));
+                    if !invalid_files.is_empty() {
+                        ui.colored_label(
+                            egui::Color32::RED,
+                            format!("Corrupt files: {}", invalid_files.len()),
+                        );
+                    }
                 } else if has_directory {
                     ui.label("No compatible files found in the selected directory");
                 }
@@ -197,17 +339,15 @@ impl EventideApp {
 }
 
 impl EventideApp {
-    // Move frames from folder selection to registration view
-    fn load_frames_for_registration(&mut self) {
-        for frame_set in &self.frame_sets {
-            if !frame_set.file_paths.is_empty() {
-                // Clone paths to ensure they're accessible for loading
-                let paths: Vec<PathBuf> = frame_set.file_paths.clone();
-                // Load frames into registration view
-                self.registration_view
-                    .load_frames_from_paths(frame_set.frame_type, paths);
-            }
-        }
+    // Move frames from folder selection to registration view, loading them in the background
+    fn load_frames_for_registration(&mut self, ctx: &egui::Context) {
+        let batches: Vec<(FrameType, Vec<PathBuf>)> = self
+            .frame_sets
+            .iter()
+            .filter(|frame_set| !frame_set.file_paths.is_empty())
+            .map(|frame_set| (frame_set.frame_type, frame_set.file_paths.clone()))
+            .collect();
+        self.registration_view.start_loading(ctx, batches);
     }
 
     fn render_workflow_navbar(&mut self, ui: &mut egui::Ui) {
@@ -232,7 +372,7 @@ impl EventideApp {
         ui.separator();
     }
 
-    fn render_folder_selection_step(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
+    fn render_folder_selection_step(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.horizontal(|ui| {
             ui.strong("Output directory:");
 
@@ -263,12 +403,26 @@ impl EventideApp {
 
         ui.add_space(16.0);
 
+        // Warn before loading a session that won't fit in memory
+        let lights = &self.frame_sets[0];
+        if let Some(dimensions) = lights.frame_dimensions {
+            let count = lights.file_paths.len();
+            ui.label(format!(
+                "Stacking {} light frames needs {} of memory",
+                count,
+                memory_summary(count, dimensions)
+            ));
+            if let Some(warning) = memory_warning(count, dimensions) {
+                ui.colored_label(egui::Color32::YELLOW, format!("⚠ {}", warning));
+            }
+        }
+
         // Next step button
         let can_proceed = self.frame_sets[0].directory.is_some() && self.output_directory.is_some();
 
         ui.add_enabled_ui(can_proceed, |ui| {
             if ui.button("Continue to Registration").clicked() {
-                self.load_frames_for_registration();
+                self.load_frames_for_registration(ctx);
                 self.current_step = WorkflowStep::Registration;
             }
         });
@@ -279,6 +433,8 @@ impl EventideApp {
     }
 
     fn render_registration_step(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        self.render_live_watch(ctx, ui);
+
         // Display the registration view
         self.registration_view.ui(ctx, ui);
 
@@ -306,9 +462,174 @@ impl EventideApp {
         });
     }
 
+    /// Offer to keep watching the lights folder, so frames show up while the session is
+    /// still being captured
+    fn render_live_watch(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        let Some(folder) = self.frame_sets[0].directory.clone() else {
+            return;
+        };
+
+        ui.horizontal(|ui| {
+            let mut watching = self.registration_view.watched_count().is_some();
+            if ui
+                .checkbox(&mut watching, "Watch for new lights")
+                .on_hover_text("Add light frames as the capture software saves them to the folder")
+                .changed()
+            {
+                self.live_watch_error = None;
+                if watching {
+                    if let Err(e) = self.registration_view.start_watching(ctx, &folder) {
+                        self.live_watch_error =
+                            Some(format!("Can't watch {}: {}", folder.display(), e));
+                    }
+                } else {
+                    self.registration_view.stop_watching();
+                }
+            }
+
+            if let Some(count) = self.registration_view.watched_count() {
+                ui.spinner();
+                ui.label(format!(
+                    "Watching {}: {} new frames",
+                    folder.display(),
+                    count
+                ));
+            }
+            if let Some(error) = &self.live_watch_error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+    }
+
     fn render_processing_step(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
+        if let Some(result) = self.processing_worker.as_ref().and_then(TaskWorker::poll) {
+            self.processing_result = Some(result);
+            self.processing_worker = None;
+            self.current_step = WorkflowStep::Results;
+        }
+
         ui.heading("Processing");
-        ui.label("Processing options will be implemented here");
+
+        // Warn when calibration exposures don't match the frames they calibrate
+        for warning in self.registration_view.exposure_warnings() {
+            ui.colored_label(egui::Color32::YELLOW, format!("⚠ {}", warning));
+        }
+        if let Some(warning) = self.registration_view.memory_warning() {
+            ui.colored_label(egui::Color32::YELLOW, format!("⚠ {}", warning));
+        }
+
+        ui.add_space(8.0);
+        ui.strong("Combine method");
+
+        egui::Grid::new("combine_methods_grid")
+            .striped(true)
+            .show(ui, |ui| {
+                for frame_set in &mut self.frame_sets {
+                    if frame_set.file_paths.is_empty() {
+                        continue;
+                    }
+
+                    let frame_type = frame_set.frame_type;
+                    ui.label(format!("{} Frames", frame_set.frame_type_name()));
+                    egui::ComboBox::from_id_salt(format!("combine_{:?}", frame_type))
+                        .selected_text(frame_set.combine_method.name())
+                        .show_ui(ui, |ui| {
+                            for method in CombineMethod::all() {
+                                ui.selectable_value(
+                                    &mut frame_set.combine_method,
+                                    method,
+                                    method.name(),
+                                );
+                            }
+                        });
+
+                    if let CombineMethod::SigmaClip { sigma, iterations } =
+                        &mut frame_set.combine_method
+                    {
+                        ui.add(egui::Slider::new(sigma, 1.0..=5.0).text("Sigma"));
+                        ui.add(egui::Slider::new(iterations, 1..=10).text("Iterations"));
+                    }
+
+                    if frame_set.combine_method != CombineMethod::default_for(frame_type)
+                        && ui.button("Reset").clicked()
+                    {
+                        frame_set.combine_method = CombineMethod::default_for(frame_type);
+                    }
+                    ui.end_row();
+                }
+            });
+
+        ui.add_space(8.0);
+        ui.checkbox(&mut self.align_frames, "Align frames")
+            .on_hover_text(
+                "Register the lights onto the first one, reusing the transforms cached in the \
+                 lights folder by earlier runs",
+            );
+        ui.checkbox(&mut self.crop_to_common, "Crop frames to a common size")
+            .on_hover_text(
+                "Center-crop frames that differ by a few pixels instead of failing the stack",
+            );
+
+        ui.horizontal(|ui| {
+            let mut subtract = self.subtract_background.is_some();
+            if ui
+                .checkbox(&mut subtract, "Remove background from each frame")
+                .on_hover_text(
+                    "Subtract a fitted gradient from every light before stacking, so gradients \
+                     that move between frames don't blur into the stack",
+                )
+                .changed()
+            {
+                self.subtract_background = subtract.then_some(GradientModel::Linear);
+            }
+
+            if let Some(model) = &mut self.subtract_background {
+                egui::ComboBox::from_id_salt("background_model")
+                    .selected_text(model.name())
+                    .show_ui(ui, |ui| {
+                        for option in GradientModel::all() {
+                            let selected = model.name() == option.name();
+                            if ui.selectable_label(selected, option.name()).clicked() && !selected {
+                                *model = option;
+                            }
+                        }
+                    });
+                if let GradientModel::Tiled { tile } = model {
+                    ui.add(egui::Slider::new(tile, 16..=512).text("Tile size (px)"));
+                }
+            }
+        });
+
+        let mut split_by_filter = self.output_layout == OutputLayout::ByFilter;
+        if ui
+            .checkbox(&mut split_by_filter, "Split output by filter")
+            .on_hover_text("Stack each filter separately into {filter}/master_light.fits")
+            .changed()
+        {
+            self.output_layout = if split_by_filter {
+                OutputLayout::ByFilter
+            } else {
+                OutputLayout::Flat
+            };
+        }
+
+        ui.checkbox(&mut self.keep_float, "Save as 32-bit float")
+            .on_hover_text(
+                "Keep the fractional values of the stack instead of rounding back to the \
+                 frames' integer type",
+            );
+
+        if self
+            .frame_sets
+            .iter()
+            .any(|set| set.frame_type == FrameType::Dark && !set.file_paths.is_empty())
+        {
+            ui.add_space(8.0);
+            self.render_dark_matching(ui);
+        }
+
+        ui.add_space(8.0);
+        self.render_dither_check(ui);
 
         ui.add_space(16.0);
 
@@ -317,17 +638,224 @@ impl EventideApp {
                 self.current_step = WorkflowStep::Registration;
             }
 
-            if ui.button("Start Processing").clicked() {
-                println!("Processing images...");
-                // TODO: Implement actual processing
-                self.current_step = WorkflowStep::Results;
+            let running = self.processing_worker.is_some();
+            if ui
+                .add_enabled(!running, egui::Button::new("Start Processing"))
+                .clicked()
+            {
+                log::info!("Processing images...");
+                match self.processing_job() {
+                    Ok(job) => {
+                        self.processing_worker =
+                            Some(TaskWorker::spawn(ui.ctx(), move || job.run()));
+                    }
+                    Err(error) => {
+                        self.processing_result = Some(Err(error));
+                        self.current_step = WorkflowStep::Results;
+                    }
+                }
+            }
+            if running {
+                ui.spinner();
+                ui.label("Calibrating and stacking...");
             }
         });
     }
 
+    /// Strategy and tolerances used to pair lights with darks
+    fn render_dark_matching(&mut self, ui: &mut egui::Ui) {
+        ui.strong("Dark matching");
+
+        let options = &mut self.dark_matching;
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("dark_match_strategy")
+                .selected_text(options.strategy.name())
+                .show_ui(ui, |ui| {
+                    for strategy in DarkMatchStrategy::ALL {
+                        ui.selectable_value(&mut options.strategy, strategy, strategy.name());
+                    }
+                })
+                .response
+                .on_hover_text(
+                    "Scale if needed picks the closest exposure and scales it, which \
+                     requires bias-subtracted darks",
+                );
+
+            if options.strategy != DarkMatchStrategy::Exact {
+                ui.label("Temperature ±");
+                ui.add(
+                    egui::DragValue::new(&mut options.temp_tolerance)
+                        .range(0.1..=20.0)
+                        .speed(0.1)
+                        .suffix(" °C"),
+                );
+
+                let mut percent = options.exposure_tolerance * 100.0;
+                ui.label("Exposure ±");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut percent)
+                            .range(0.1..=100.0)
+                            .speed(0.5)
+                            .suffix(" %"),
+                    )
+                    .changed()
+                {
+                    options.exposure_tolerance = percent / 100.0;
+                }
+            }
+        });
+
+        if let Some(warning) = self
+            .registration_view
+            .dark_match_warning(&self.dark_matching)
+        {
+            ui.colored_label(egui::Color32::YELLOW, format!("⚠ {}", warning));
+        }
+    }
+
+    /// How processed results are written
+    fn save_options(&self) -> SaveOptions {
+        SaveOptions {
+            force_float: self.keep_float,
+            ..SaveOptions::default()
+        }
+    }
+
+    /// Measure the dithering of the selected lights on request and recommend for or against
+    /// drizzle
+    fn render_dither_check(&mut self, ui: &mut egui::Ui) {
+        if let Some(amplitude) = self.dither_worker.as_ref().and_then(TaskWorker::poll) {
+            self.dither_check = Some(amplitude);
+            self.dither_worker = None;
+        }
+
+        let selected = self.registration_view.selected_count(FrameType::Light);
+        ui.horizontal(|ui| {
+            let running = self.dither_worker.is_some();
+            if ui
+                .add_enabled(
+                    selected >= 2 && !running,
+                    egui::Button::new("Check dithering"),
+                )
+                .on_hover_text("Measure the offsets between light frames by phase correlation")
+                .clicked()
+            {
+                // The frames are shared with the worker, not copied
+                let images: Vec<_> = self
+                    .registration_view
+                    .selected_images(FrameType::Light)
+                    .into_iter()
+                    .map(|(_, image)| image)
+                    .collect();
+                self.dither_check = None;
+                self.dither_worker = Some(TaskWorker::spawn(ui.ctx(), move || {
+                    detect_dithering(&images)
+                }));
+            }
+
+            if running {
+                ui.spinner();
+                ui.label("Measuring frame offsets...");
+                return;
+            }
+            if selected < 2 {
+                ui.label("Select two or more light frames to check their dithering");
+                return;
+            }
+
+            match self.dither_check {
+                Some(Some(amplitude)) => {
+                    ui.label(format!(
+                        "Dithered by {:.1}px RMS: drizzle can recover extra resolution",
+                        amplitude
+                    ));
+                }
+                Some(None) => {
+                    ui.label(format!(
+                        "Frames moved less than {:.1}px: drizzle won't help",
+                        MIN_DITHER_AMPLITUDE
+                    ));
+                }
+                None => {}
+            }
+        });
+    }
+
+    /// Everything a processing run needs from the current selection and settings. The
+    /// frames are shared with the registration view, not copied.
+    fn processing_job(&self) -> Result<ProcessingJob, String> {
+        let output_directory = self
+            .output_directory
+            .clone()
+            .ok_or_else(|| "No output directory selected".to_string())?;
+
+        let selected = |frame_type| -> Vec<Arc<FitsImage>> {
+            self.registration_view
+                .selected_images(frame_type)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect()
+        };
+        // The transforms are cached next to the lights, where the stack command keeps them
+        let align_cached_in = if self.align_frames {
+            let lights_directory = self
+                .frame_sets
+                .iter()
+                .find(|set| set.frame_type == FrameType::Light)
+                .and_then(|set| set.directory.clone())
+                .ok_or_else(|| "No lights folder to cache the alignment in".to_string())?;
+            Some(lights_directory)
+        } else {
+            None
+        };
+        let combine_methods = self
+            .frame_sets
+            .iter()
+            .map(|set| (set.frame_type, set.combine_method))
+            .collect();
+
+        Ok(ProcessingJob {
+            lights: selected(FrameType::Light),
+            bias: selected(FrameType::Bias),
+            darks: selected(FrameType::Dark),
+            flats: selected(FrameType::Flat),
+            dark_flats: selected(FrameType::DarkFlat),
+            combine_methods,
+            dark_matching: self.dark_matching,
+            align_cached_in,
+            crop_to_common: self.crop_to_common,
+            subtract_background: self.subtract_background,
+            output_layout: self.output_layout,
+            output_directory,
+            save_options: self.save_options(),
+        })
+    }
+
     fn render_results_step(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.heading("Results");
-        ui.label("Results will be displayed here");
+
+        match &self.processing_result {
+            None => {
+                ui.label("No processing has been run yet");
+            }
+            Some(Err(error)) => {
+                ui.colored_label(egui::Color32::RED, format!("Processing failed: {}", error));
+            }
+            Some(Ok(output)) => {
+                for (index, stack) in output.stacks.iter().enumerate() {
+                    if index > 0 {
+                        ui.separator();
+                    }
+                    render_stack_result(ui, index, stack);
+                }
+            }
+        }
+
+        if self.flats_missing() {
+            ui.add_space(8.0);
+            self.render_synthetic_flat(ui);
+        }
 
         ui.add_space(16.0);
 
@@ -335,6 +863,237 @@ impl EventideApp {
             self.current_step = WorkflowStep::Processing;
         }
     }
+
+    /// Whether the last successful run had no flat frames to correct vignetting with
+    fn flats_missing(&self) -> bool {
+        matches!(self.processing_result, Some(Ok(_)))
+            && self
+                .frame_sets
+                .iter()
+                .filter(|set| set.frame_type == FrameType::Flat)
+                .all(|set| set.file_paths.is_empty())
+    }
+
+    /// Offer to flatten the stacks with a synthetic flat when no flats were provided
+    fn render_synthetic_flat(&mut self, ui: &mut egui::Ui) {
+        if let Some(status) = self
+            .synthetic_flat_worker
+            .as_ref()
+            .and_then(TaskWorker::poll)
+        {
+            self.synthetic_flat_status = Some(status);
+            self.synthetic_flat_worker = None;
+        }
+
+        ui.strong("Synthetic flat");
+        ui.label(
+            "No flat frames were used. A heavily blurred copy of the stack can approximate \
+             the vignetting; it may also dim large nebulae.",
+        );
+        ui.horizontal(|ui| {
+            ui.add(egui::Slider::new(&mut self.synthetic_flat_sigma, 8.0..=256.0).text("Blur"));
+            let running = self.synthetic_flat_worker.is_some();
+            if ui
+                .add_enabled(!running, egui::Button::new("Apply synthetic flat"))
+                .clicked()
+            {
+                self.start_synthetic_flat(ui.ctx());
+            }
+            if running {
+                ui.spinner();
+            }
+        });
+
+        match &self.synthetic_flat_status {
+            Some(Ok(paths)) => {
+                for path in paths {
+                    ui.label(format!("Flattened stack saved to {}", path.display()));
+                }
+            }
+            Some(Err(error)) => {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+            None => {}
+        }
+    }
+
+    /// Divide every saved stack by its own synthetic flat on a background thread; the blur
+    /// takes seconds on a full size stack
+    fn start_synthetic_flat(&mut self, ctx: &egui::Context) {
+        let Some(Ok(output)) = &self.processing_result else {
+            return;
+        };
+
+        let paths: Vec<PathBuf> = output
+            .stacks
+            .iter()
+            .map(|stack| stack.output_path.clone())
+            .collect();
+        let sigma = self.synthetic_flat_sigma;
+        let options = self.save_options();
+        self.synthetic_flat_status = None;
+        self.synthetic_flat_worker = Some(TaskWorker::spawn(ctx, move || {
+            apply_synthetic_flat(&paths, sigma, &options)
+        }));
+    }
+}
+
+/// A processing run, moved to a background thread: calibrate the selected lights with
+/// masters built from the selected calibration frames, then stack and save them
+struct ProcessingJob {
+    lights: Vec<Arc<FitsImage>>,
+    bias: Vec<Arc<FitsImage>>,
+    darks: Vec<Arc<FitsImage>>,
+    flats: Vec<Arc<FitsImage>>,
+    dark_flats: Vec<Arc<FitsImage>>,
+    combine_methods: HashMap<FrameType, CombineMethod>,
+    dark_matching: DarkMatchOptions,
+    /// Align the lights, caching their transforms in this folder
+    align_cached_in: Option<PathBuf>,
+    crop_to_common: bool,
+    subtract_background: Option<GradientModel>,
+    output_layout: OutputLayout,
+    output_directory: PathBuf,
+    save_options: SaveOptions,
+}
+
+impl ProcessingJob {
+    fn combine_method(&self, frame_type: FrameType) -> CombineMethod {
+        self.combine_methods
+            .get(&frame_type)
+            .copied()
+            .unwrap_or_else(|| CombineMethod::default_for(frame_type))
+    }
+
+    fn run(self) -> Result<ProcessingOutput, String> {
+        if self.lights.is_empty() {
+            return Err("No light frames selected".to_string());
+        }
+
+        // Calibration modifies the frames, so this is the one working copy of each, made
+        // here rather than on the UI thread
+        let owned = |frames: &[Arc<FitsImage>]| -> Vec<FitsImage> {
+            frames.iter().map(|image| image.as_ref().clone()).collect()
+        };
+        let frames = CalibrationFrames {
+            bias: owned(&self.bias),
+            darks: owned(&self.darks),
+            flats: owned(&self.flats),
+            dark_flats: owned(&self.dark_flats),
+        };
+        let masters =
+            CalibrationMasters::build(frames, |frame_type| self.combine_method(frame_type))
+                .map_err(|e| format!("Failed to build calibration masters: {}", e))?;
+
+        let mut images = owned(&self.lights);
+        masters
+            .calibrate_all(&mut images, &self.dark_matching)
+            .map_err(|e| format!("Failed to calibrate lights: {}", e))?;
+
+        if self.crop_to_common {
+            images = crop_to_common_size(&images).map_err(|e| e.to_string())?;
+        }
+
+        if let Some(model) = self.subtract_background {
+            subtract_background_all(&mut images, model).map_err(|e| e.to_string())?;
+        }
+
+        // After removing the background, so the empty borders left by the shifts don't
+        // skew the fit
+        if let Some(folder) = &self.align_cached_in {
+            images = align_to_first_cached(&images, folder)
+                .map_err(|e| format!("Failed to align lights: {}", e))?;
+        }
+
+        let groups = match self.output_layout {
+            OutputLayout::Flat => vec![(None, images)],
+            OutputLayout::ByFilter => group_by_filter(images),
+        };
+
+        let mut stacks = Vec::with_capacity(groups.len());
+        for (filter, images) in groups {
+            let directory = self
+                .output_layout
+                .directory(&self.output_directory, filter.as_deref())
+                .map_err(|e| format!("Failed to create output folder: {}", e))?;
+            let stem = match self.output_layout {
+                OutputLayout::Flat => "stacked_light",
+                OutputLayout::ByFilter => "master_light",
+            };
+            stacks.push(self.stack_group(&images, filter, &directory, stem)?);
+        }
+
+        Ok(ProcessingOutput { stacks })
+    }
+
+    /// Stack one group of calibrated light frames and save it in `directory`
+    fn stack_group(
+        &self,
+        images: &[FitsImage],
+        filter: Option<String>,
+        directory: &Path,
+        stem: &str,
+    ) -> Result<StackResult, String> {
+        let frame_names = images
+            .iter()
+            .map(|image| {
+                image
+                    .metadata
+                    .file_path
+                    .as_ref()
+                    .and_then(|path| path.file_name())
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+
+        let (stacked, rejection) = self
+            .combine_method(FrameType::Light)
+            .combiner()
+            .combine_with_summary(images)
+            .map_err(|e| e.to_string())?;
+
+        let output_path = unique_output_path(directory, stem, "fits");
+        stacked
+            .to_file_with_options(&output_path, &self.save_options)
+            .map_err(|e| format!("Failed to save {}: {}", output_path.display(), e))?;
+        log::info!("Stacked image saved to {}", output_path.display());
+
+        Ok(StackResult {
+            filter,
+            output_path,
+            frame_names,
+            rejection,
+        })
+    }
+}
+
+/// Divide each stack in `paths` by its own synthetic flat, saving the results next to it
+fn apply_synthetic_flat(
+    paths: &[PathBuf],
+    sigma: f32,
+    options: &SaveOptions,
+) -> Result<Vec<PathBuf>, String> {
+    let mut saved = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut image = FitsImage::from_file(path, FrameType::Light)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let flat = synthetic_flat(&image, sigma)
+            .map_err(|e| format!("Failed to build synthetic flat: {}", e))?;
+        image.divide(&flat).map_err(|e| e.to_string())?;
+
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let output_path = unique_output_path(directory, &format!("{}_flattened", stem), "fits");
+        image
+            .to_file_with_options(&output_path, options)
+            .map_err(|e| format!("Failed to save {}: {}", output_path.display(), e))?;
+        log::info!("Flattened stack saved to {}", output_path.display());
+        saved.push(output_path);
+    }
+
+    Ok(saved)
 }
 
 impl eframe::App for EventideApp {
@@ -359,3 +1118,62 @@ impl eframe::App for EventideApp {
         });
     }
 }
+
+/// Show where one stack was saved and, for clipped combines, which frames lost the most pixels
+fn render_stack_result(ui: &mut egui::Ui, index: usize, stack: &StackResult) {
+    if let Some(filter) = &stack.filter {
+        ui.strong(format!("Filter {}", filter));
+    }
+    ui.label(format!("Stacked {} frames", stack.frame_names.len()));
+    ui.horizontal(|ui| {
+        ui.label("Saved to:");
+        ui.monospace(stack.output_path.display().to_string());
+    });
+
+    if let Some(summary) = &stack.rejection {
+        ui.add_space(8.0);
+        ui.strong("Rejection");
+        ui.label(format!(
+            "Rejected {} of {} pixel values ({:.2}%)",
+            summary.total_rejected,
+            summary.total_samples,
+            summary.percentage()
+        ));
+
+        let pixels_per_frame = summary
+            .total_samples
+            .checked_div(summary.per_frame.len())
+            .unwrap_or(0);
+        egui::Grid::new(("rejection_grid", index))
+            .striped(true)
+            .show(ui, |ui| {
+                ui.strong("Frame");
+                ui.strong("Rejected");
+                ui.strong("Share of frame");
+                ui.end_row();
+
+                for (index, rejected) in summary.worst_frames(5) {
+                    ui.label(&stack.frame_names[index]);
+                    ui.label(rejected.to_string());
+                    let share = if pixels_per_frame > 0 {
+                        rejected as f64 / pixels_per_frame as f64 * 100.0
+                    } else {
+                        0.0
+                    };
+                    ui.label(format!("{:.2}%", share));
+                    ui.end_row();
+                }
+            });
+    }
+}
+
+/// A path in `directory` named `stem.extension`, numbered if that file already exists
+fn unique_output_path(directory: &Path, stem: &str, extension: &str) -> PathBuf {
+    let mut path = directory.join(format!("{}.{}", stem, extension));
+    let mut counter = 1;
+    while path.exists() {
+        path = directory.join(format!("{}_{}.{}", stem, counter, extension));
+        counter += 1;
+    }
+    path
+}
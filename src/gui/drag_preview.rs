@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+/// How long a slider must stay untouched after a keyboard or click change before the
+/// full resolution preview is rendered
+pub const SETTLE_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest side, in pixels, of the preview shown while a slider is being adjusted
+pub const DRAFT_PREVIEW_SIZE: usize = 512;
+
+/// Resolution the preview should be rendered at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewQuality {
+    /// A downsampled preview, cheap enough to redo every frame
+    Draft,
+    /// The full resolution preview
+    Full,
+}
+
+/// Where a stretch slider is in its adjustment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DragState {
+    /// Not being adjusted; the full resolution preview is current
+    #[default]
+    Idle,
+    /// The slider is held down
+    Dragging,
+    /// Changed without a drag (keyboard, click on the track), waiting for further changes
+    Settling { changed_at: Instant },
+}
+
+/// Tracks a stretch slider so the preview is rendered at low resolution while it moves
+/// and at full resolution once it comes to rest.
+///
+/// Releasing a drag renders the full preview straight away; other changes are debounced
+/// by `SETTLE_DELAY` so a run of key presses doesn't render one full preview each.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DragPreview {
+    state: DragState,
+}
+
+impl DragPreview {
+    /// Advance the state with this frame's slider response: whether it is being dragged
+    /// and whether its value changed
+    pub fn update(&mut self, dragging: bool, changed: bool, now: Instant) -> PreviewQuality {
+        self.state = match self.state {
+            _ if dragging => DragState::Dragging,
+            // The drag was released: the value is final
+            DragState::Dragging => DragState::Idle,
+            _ if changed => DragState::Settling { changed_at: now },
+            DragState::Settling { changed_at } if now.duration_since(changed_at) < SETTLE_DELAY => {
+                self.state
+            }
+            DragState::Settling { .. } | DragState::Idle => DragState::Idle,
+        };
+        self.quality()
+    }
+
+    /// The resolution to render the preview at in the current state
+    pub fn quality(&self) -> PreviewQuality {
+        match self.state {
+            DragState::Idle => PreviewQuality::Full,
+            DragState::Dragging | DragState::Settling { .. } => PreviewQuality::Draft,
+        }
+    }
+
+    /// How long until a settling slider comes to rest, so the UI can schedule a repaint
+    pub fn time_to_settle(&self, now: Instant) -> Option<Duration> {
+        match self.state {
+            DragState::Settling { changed_at } => {
+                Some(SETTLE_DELAY.saturating_sub(now.duration_since(changed_at)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Factor to downsample an image of the given size by so its longest side fits in
+/// `DRAFT_PREVIEW_SIZE`
+pub fn draft_factor(width: usize, height: usize) -> usize {
+    width.max(height).div_ceil(DRAFT_PREVIEW_SIZE).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drag_renders_drafts_until_released() {
+        let start = Instant::now();
+        let mut drag = DragPreview::default();
+        assert_eq!(drag.quality(), PreviewQuality::Full);
+
+        assert_eq!(drag.update(true, true, start), PreviewQuality::Draft);
+        assert_eq!(drag.state, DragState::Dragging);
+        // Holding the slider still keeps the draft
+        let later = start + SETTLE_DELAY * 4;
+        assert_eq!(drag.update(true, false, later), PreviewQuality::Draft);
+
+        // Releasing renders the full preview at once, without waiting to settle
+        assert_eq!(drag.update(false, false, later), PreviewQuality::Full);
+        assert_eq!(drag.state, DragState::Idle);
+    }
+
+    #[test]
+    fn changes_without_a_drag_are_debounced() {
+        let start = Instant::now();
+        let mut drag = DragPreview::default();
+
+        assert_eq!(drag.update(false, true, start), PreviewQuality::Draft);
+        let step = SETTLE_DELAY / 2;
+        // A second key press restarts the wait
+        assert_eq!(
+            drag.update(false, true, start + step),
+            PreviewQuality::Draft
+        );
+        assert_eq!(
+            drag.update(false, false, start + step * 2),
+            PreviewQuality::Draft
+        );
+        assert_eq!(drag.time_to_settle(start + step * 2), Some(step));
+
+        assert_eq!(
+            drag.update(false, false, start + step * 3),
+            PreviewQuality::Full
+        );
+        assert_eq!(drag.time_to_settle(start + step * 3), None);
+    }
+
+    #[test]
+    fn idle_slider_stays_full_resolution() {
+        let start = Instant::now();
+        let mut drag = DragPreview::default();
+        for frame in 0..3 {
+            let now = start + SETTLE_DELAY * frame;
+            assert_eq!(drag.update(false, false, now), PreviewQuality::Full);
+        }
+    }
+
+    #[test]
+    fn draft_fits_the_draft_size() {
+        assert_eq!(draft_factor(300, 200), 1);
+        assert_eq!(draft_factor(6000, 4000), 12);
+        assert!(6000 / draft_factor(6000, 4000) <= DRAFT_PREVIEW_SIZE);
+    }
+}
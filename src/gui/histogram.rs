@@ -0,0 +1,142 @@
+use eframe::egui::{Color32, Ui};
+use egui_plot::{Line, Plot, PlotPoints};
+use ndarray::Axis;
+
+use crate::image::FitsImage;
+
+/// Number of bins in the preview histogram
+pub const HISTOGRAM_BINS: usize = 256;
+
+/// Height of the histogram plot
+const HISTOGRAM_HEIGHT: f32 = 100.0;
+
+/// Pixel counts of each channel of an image over a shared value range
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelHistograms {
+    /// Value at the lower edge of the first bin
+    pub min: f32,
+    /// Value at the upper edge of the last bin
+    pub max: f32,
+    /// One histogram per channel: a single one for mono images, red, green and blue for
+    /// color images
+    pub channels: Vec<Vec<u32>>,
+}
+
+impl ChannelHistograms {
+    /// Bin the finite pixels of every channel into `bins` bins spanning the finite range of
+    /// the whole image, so the channels can be compared on one axis
+    pub fn compute(image: &FitsImage, bins: usize) -> Self {
+        let (min, max) = image
+            .data()
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold((f32::MAX, f32::MIN), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            });
+        if bins == 0 || min > max {
+            return Self {
+                min: 0.0,
+                max: 0.0,
+                channels: Vec::new(),
+            };
+        }
+
+        let range = max - min;
+        let scale = if range > 0.0 {
+            bins as f32 / range
+        } else {
+            0.0
+        };
+        let bin_channel = |values: &mut dyn Iterator<Item = f32>| {
+            let mut histogram = vec![0u32; bins];
+            for value in values.filter(|v| v.is_finite()) {
+                let bin = (((value - min) * scale) as usize).min(bins - 1);
+                histogram[bin] += 1;
+            }
+            histogram
+        };
+
+        let channels = if image.is_color() {
+            image
+                .data()
+                .axis_iter(Axis(0))
+                .map(|plane| bin_channel(&mut plane.iter().copied()))
+                .collect()
+        } else {
+            vec![bin_channel(&mut image.data().iter().copied())]
+        };
+
+        Self { min, max, channels }
+    }
+
+    /// Value at the center of a bin
+    pub fn bin_center(&self, bin: usize) -> f32 {
+        let bins = self.channels.first().map_or(1, Vec::len).max(1);
+        self.min + (bin as f32 + 0.5) * (self.max - self.min) / bins as f32
+    }
+}
+
+/// Draw the histograms as overlaid curves: red, green and blue for color images, gray for
+/// mono ones. Counts are plotted on a log scale so the faint tail stays visible.
+pub fn render_histogram(ui: &mut Ui, histograms: &ChannelHistograms) {
+    let colors: &[(Color32, &str)] = if histograms.channels.len() == 3 {
+        &[
+            (Color32::from_rgb(230, 80, 80), "Red"),
+            (Color32::from_rgb(80, 200, 80), "Green"),
+            (Color32::from_rgb(90, 130, 255), "Blue"),
+        ]
+    } else {
+        &[(Color32::GRAY, "Luminance")]
+    };
+
+    Plot::new("preview_histogram")
+        .height(HISTOGRAM_HEIGHT)
+        .show_y(false)
+        .allow_scroll(false)
+        .show(ui, |plot_ui| {
+            for (counts, &(color, name)) in histograms.channels.iter().zip(colors) {
+                let points: PlotPoints = counts
+                    .iter()
+                    .enumerate()
+                    .map(|(bin, &count)| {
+                        [
+                            histograms.bin_center(bin) as f64,
+                            (count as f64 + 1.0).log10(),
+                        ]
+                    })
+                    .collect();
+                plot_ui.line(Line::new(points).color(color).name(name));
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    #[test]
+    fn color_images_are_binned_per_channel_over_a_shared_range() {
+        // Red, green and blue planes; the NaN is left out
+        let data = [[0.0, 1.0, 1.0, 4.0], [2.0, 2.0, 2.0, f32::NAN], [3.0; 4]].concat();
+        let image = FitsImage::from_data(ArrayD::from_shape_vec(vec![3, 1, 4], data).unwrap());
+        let histograms = ChannelHistograms::compute(&image, 4);
+
+        assert_eq!((histograms.min, histograms.max), (0.0, 4.0));
+        assert_eq!(
+            histograms.channels,
+            [vec![1, 2, 0, 1], vec![0, 0, 3, 0], vec![0, 0, 0, 4]]
+        );
+        assert_eq!(histograms.bin_center(0), 0.5);
+    }
+
+    #[test]
+    fn mono_images_get_a_single_histogram() {
+        let data = vec![0.0, 1.0, 2.0, 3.0];
+        let image = FitsImage::from_data(ArrayD::from_shape_vec(vec![2, 2], data).unwrap());
+        let histograms = ChannelHistograms::compute(&image, 2);
+
+        assert_eq!(histograms.channels, [vec![2, 2]]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+
+use eframe::egui::Context;
+
+use crate::image::{FitsImage, FolderWatcher, FrameType, ImageError, watch_folder};
+
+/// Picks up light frames as capture software writes them to a folder, so a session can be
+/// reviewed while it's still being shot.
+///
+/// New frames are handed back through `poll`. Dropping the watch stops it.
+pub struct LiveWatch {
+    receiver: Receiver<FitsImage>,
+    // Only held to keep the watch running
+    _watcher: FolderWatcher,
+}
+
+impl LiveWatch {
+    pub fn spawn(ctx: &Context, folder: &Path) -> Result<Self, ImageError> {
+        let (sender, receiver) = mpsc::channel();
+
+        let ctx = ctx.clone();
+        let watcher = watch_folder(folder, FrameType::Light, move |image| {
+            // The receiver is gone once the watch is dropped
+            if sender.send(image).is_ok() {
+                ctx.request_repaint();
+            }
+        })?;
+
+        Ok(Self {
+            receiver,
+            _watcher: watcher,
+        })
+    }
+
+    /// Take the frames that arrived since the last call
+    pub fn poll(&self) -> Vec<FitsImage> {
+        self.receiver.try_iter().collect()
+    }
+}
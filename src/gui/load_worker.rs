@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use eframe::egui::Context;
+
+use crate::gui::registration::RegisteredFrame;
+use crate::image::FrameType;
+
+/// A frame read by a `LoadWorker`
+pub struct LoadedFrame {
+    pub frame_type: FrameType,
+    pub frame: RegisteredFrame,
+}
+
+/// Reads frames on a background thread so loading a large folder doesn't freeze the UI
+/// and can be cancelled.
+///
+/// Files are read one at a time in the given order and handed back over a channel, picked
+/// up with `poll`. Cancellation is checked between files, so frames already read are kept.
+/// Dropping the worker cancels it.
+pub struct LoadWorker {
+    /// Number of files the worker was asked to load
+    pub total: usize,
+    receiver: Receiver<LoadedFrame>,
+    cancelled: Arc<AtomicBool>,
+    done: bool,
+}
+
+impl LoadWorker {
+    pub fn spawn(ctx: &Context, batches: Vec<(FrameType, Vec<PathBuf>)>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let total = batches.iter().map(|(_, paths)| paths.len()).sum();
+
+        let ctx = ctx.clone();
+        let worker_cancelled = Arc::clone(&cancelled);
+        thread::spawn(move || {
+            for (frame_type, paths) in batches {
+                for path in paths {
+                    if worker_cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+
+                    let frame = RegisteredFrame::new(path, frame_type);
+                    // The receiver is gone once the worker is dropped
+                    if sender.send(LoadedFrame { frame_type, frame }).is_err() {
+                        return;
+                    }
+                    ctx.request_repaint();
+                }
+            }
+            ctx.request_repaint();
+        });
+
+        Self {
+            total,
+            receiver,
+            cancelled,
+            done: false,
+        }
+    }
+
+    /// Take the frames read since the last call
+    pub fn poll(&mut self) -> Vec<LoadedFrame> {
+        let mut frames = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(frame) => frames.push(frame),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        frames
+    }
+
+    /// Whether every frame has been read, or loading stopped after a cancel
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Stop after the file being read
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for LoadWorker {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
@@ -1,4 +1,12 @@
 pub mod app;
+pub mod drag_preview;
+pub mod histogram;
+pub mod live_watch;
+pub mod load_worker;
+pub mod preview_worker;
 pub mod registration;
+pub mod session_plot;
+pub mod stretch;
+pub mod task_worker;
 
-pub use app::EventideApp;
\ No newline at end of file
+pub use app::EventideApp;
@@ -0,0 +1,301 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use eframe::egui::{ColorImage, Context};
+
+use crate::analysis::{DEFAULT_DETECTION_SIGMA, FrameMetrics, Star, detect_stars};
+use crate::gui::histogram::{ChannelHistograms, HISTOGRAM_BINS};
+use crate::gui::registration::{StretchSettings, preview_image, thumbnail_image};
+use crate::image::{FitsImage, FrameType};
+
+/// A frame whose preview should be generated in the background
+pub struct PreviewJob {
+    /// Index of the frame in its tab
+    pub index: usize,
+    /// The frame's pixels. Held weakly so a queued job doesn't force a copy when the frame
+    /// is edited (`Arc::make_mut`); frames dropped or replaced meanwhile are skipped.
+    pub image: Weak<FitsImage>,
+    /// Whether to render a three-channel image in color
+    pub show_color: bool,
+}
+
+/// A finished preview, ready to be uploaded as a texture on the UI thread
+pub struct PreviewResult {
+    pub index: usize,
+    pub show_color: bool,
+    pub image: ColorImage,
+}
+
+/// Generates the previews for one tab on a background thread.
+///
+/// Textures can only be created on the UI thread, so finished `ColorImage`s are handed
+/// back over a channel and picked up with `poll`. No thread is started when there are no
+/// jobs. Dropping the worker cancels it.
+pub struct PreviewWorker {
+    pub frame_type: FrameType,
+    pub stretch: StretchSettings,
+    /// The view's color setting the jobs were created with
+    pub show_color: bool,
+    receiver: Receiver<PreviewResult>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl PreviewWorker {
+    pub fn spawn(
+        ctx: &Context,
+        frame_type: FrameType,
+        stretch: StretchSettings,
+        show_color: bool,
+        jobs: Vec<PreviewJob>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        if !jobs.is_empty() {
+            let ctx = ctx.clone();
+            let worker_cancelled = Arc::clone(&cancelled);
+            thread::spawn(move || {
+                use rayon::prelude::*;
+
+                jobs.into_par_iter().for_each_with(sender, |sender, job| {
+                    if worker_cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let Some(image) = job.image.upgrade() else {
+                        return;
+                    };
+
+                    match preview_image(&image, stretch, job.show_color) {
+                        Ok(image) => {
+                            let result = PreviewResult {
+                                index: job.index,
+                                show_color: job.show_color,
+                                image,
+                            };
+                            // The receiver is gone once the worker is dropped
+                            if sender.send(result).is_ok() {
+                                ctx.request_repaint();
+                            }
+                        }
+                        Err(e) => log::warn!("Failed to generate preview {}: {}", job.index, e),
+                    }
+                });
+            });
+        }
+
+        Self {
+            frame_type,
+            stretch,
+            show_color,
+            receiver,
+            cancelled,
+        }
+    }
+
+    /// Whether this worker is generating previews with the given settings
+    pub fn matches(
+        &self,
+        frame_type: FrameType,
+        stretch: StretchSettings,
+        show_color: bool,
+    ) -> bool {
+        self.frame_type == frame_type && self.stretch == stretch && self.show_color == show_color
+    }
+
+    /// Take the previews finished since the last call
+    pub fn poll(&self) -> Vec<PreviewResult> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl Drop for PreviewWorker {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A finished thumbnail, ready to be uploaded as a texture on the UI thread, with the
+/// frame's quality metrics, stars and histograms
+pub struct ThumbnailResult {
+    pub index: usize,
+    pub image: ColorImage,
+    pub metrics: FrameMetrics,
+    /// Stars for the preview's overlay
+    pub stars: Vec<Star>,
+    /// Per-channel histograms for the preview's histogram panel
+    pub histograms: ChannelHistograms,
+}
+
+/// Generates low resolution thumbnails for every frame of one tab on a background thread,
+/// so the frame table can show them without waiting for full previews. Each frame's
+/// quality metrics (FWHM, eccentricity, background), stars and histograms are measured
+/// along the way, so showing them in the preview doesn't stall the UI.
+///
+/// Thumbnails don't depend on the view's stretch settings, so a worker runs once per load.
+/// Dropping the worker cancels it.
+pub struct ThumbnailWorker {
+    pub frame_type: FrameType,
+    receiver: Receiver<ThumbnailResult>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ThumbnailWorker {
+    /// Start rendering thumbnails for `(index, image)` pairs. Images are held weakly, as
+    /// in `PreviewJob`, and no thread is started when there are none.
+    pub fn spawn(
+        ctx: &Context,
+        frame_type: FrameType,
+        jobs: Vec<(usize, Weak<FitsImage>)>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        if !jobs.is_empty() {
+            let ctx = ctx.clone();
+            let worker_cancelled = Arc::clone(&cancelled);
+            thread::spawn(move || {
+                use rayon::prelude::*;
+
+                jobs.into_par_iter()
+                    .for_each_with(sender, |sender, (index, image)| {
+                        if worker_cancelled.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        let Some(image) = image.upgrade() else {
+                            return;
+                        };
+
+                        match thumbnail_image(&image) {
+                            Ok(thumbnail) => {
+                                let result = ThumbnailResult {
+                                    index,
+                                    image: thumbnail,
+                                    metrics: FrameMetrics::measure(&image),
+                                    stars: detect_stars(&image, DEFAULT_DETECTION_SIGMA),
+                                    histograms: ChannelHistograms::compute(&image, HISTOGRAM_BINS),
+                                };
+                                // The receiver is gone once the worker is dropped
+                                if sender.send(result).is_ok() {
+                                    ctx.request_repaint();
+                                }
+                            }
+                            Err(e) => log::warn!("Failed to generate thumbnail {}: {}", index, e),
+                        }
+                    });
+            });
+        }
+
+        Self {
+            frame_type,
+            receiver,
+            cancelled,
+        }
+    }
+
+    /// Take the thumbnails finished since the last call
+    pub fn poll(&self) -> Vec<ThumbnailResult> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl Drop for ThumbnailWorker {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    fn image(value: f32) -> Arc<FitsImage> {
+        Arc::new(FitsImage::from_data(ArrayD::from_shape_fn(
+            vec![8, 8],
+            |index| value + index[1] as f32,
+        )))
+    }
+
+    /// Poll until `count` results arrived or a second passed
+    fn wait_for(worker: &PreviewWorker, count: usize) -> Vec<PreviewResult> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut results = Vec::new();
+        while results.len() < count && Instant::now() < deadline {
+            results.extend(worker.poll());
+            thread::sleep(Duration::from_millis(5));
+        }
+        results
+    }
+
+    #[test]
+    fn previews_are_rendered_off_the_ui_thread() {
+        let images = [image(0.0), image(10.0)];
+        let jobs = images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| PreviewJob {
+                index,
+                image: Arc::downgrade(image),
+                show_color: false,
+            })
+            .collect();
+
+        let stretch = StretchSettings::default();
+        let worker =
+            PreviewWorker::spawn(&Context::default(), FrameType::Light, stretch, false, jobs);
+        let mut indices: Vec<usize> = wait_for(&worker, 2).iter().map(|r| r.index).collect();
+        indices.sort();
+        assert_eq!(indices, [0, 1]);
+        assert!(worker.matches(FrameType::Light, stretch, false));
+    }
+
+    #[test]
+    fn frames_dropped_before_rendering_are_skipped() {
+        let kept = image(0.0);
+        let jobs = vec![
+            PreviewJob {
+                index: 0,
+                image: Arc::downgrade(&image(5.0)),
+                show_color: false,
+            },
+            PreviewJob {
+                index: 1,
+                image: Arc::downgrade(&kept),
+                show_color: false,
+            },
+        ];
+
+        let worker = PreviewWorker::spawn(
+            &Context::default(),
+            FrameType::Light,
+            StretchSettings::default(),
+            false,
+            jobs,
+        );
+        let results = wait_for(&worker, 2);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].index, 1);
+    }
+
+    #[test]
+    fn queued_jobs_dont_hold_the_frames() {
+        let mut frame = image(0.0);
+        let job = PreviewJob {
+            index: 0,
+            image: Arc::downgrade(&frame),
+            show_color: false,
+        };
+
+        // Editing a frame that's only queued doesn't copy its pixels; the job just loses
+        // track of it
+        let pixels = frame.data().as_ptr();
+        Arc::make_mut(&mut frame).data_mut().fill(1.0);
+        assert_eq!(frame.data().as_ptr(), pixels);
+        assert!(job.image.upgrade().is_none());
+    }
+}
@@ -1,23 +1,114 @@
-use eframe::egui::{self, ComboBox, Context, Grid, ScrollArea, Ui, Vec2};
-use egui::Widget;
-use std::path::PathBuf;
-
-use crate::image::{FitsImage, FrameType, ImageError};
-
-/// Represents different stretching methods to enhance image visualization
+use eframe::egui::{self, ComboBox, Context, Grid, Pos2, Rect, ScrollArea, Ui, Vec2};
+use ndarray::{Array2, ArrayD, ArrayView2, Axis, Ix2, s};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use crate::alignment::derotate;
+use crate::analysis::{DEFAULT_DETECTION_SIGMA, FrameMetrics, MAX_ECCENTRICITY, Star, csv_escape};
+use crate::calibration::{DarkMatchOptions, flat_level_warning, match_dark_with};
+use crate::gui::drag_preview::{DragPreview, PreviewQuality, draft_factor};
+use crate::gui::histogram::{ChannelHistograms, render_histogram};
+use crate::gui::live_watch::LiveWatch;
+use crate::gui::load_worker::LoadWorker;
+use crate::gui::preview_worker::{PreviewJob, PreviewWorker, ThumbnailWorker};
+use crate::gui::session_plot::render_session_plot;
+use crate::gui::stretch::{
+    ASINH_SOFTENING_RANGE, DEFAULT_ASINH_SOFTENING, stretch_to_rgba,
+    stretch_to_rgba_with_statistics,
+};
+use crate::image::{
+    FitsImage, FrameType, GradientModel, ImageError, LuminanceWeights, SaveOptions, median_of,
+};
+
+pub use crate::gui::stretch::{ColorMap, StretchMethod, StretchSettings};
+
+/// Which of the two compared frames the blink comparator is showing
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum StretchMethod {
-    /// Linear stretch - simple min/max normalization
-    Linear,
-    /// Logarithmic stretch - enhances dim features
-    Logarithmic,
-    /// Auto stretch - automatic histogram adjustment
-    AutoStretch,
+pub enum BlinkSide {
+    A,
+    B,
+}
+
+/// Blink comparator state: alternates the preview between two frames of the active tab
+#[derive(Debug, Clone)]
+pub struct BlinkComparator {
+    /// Whether blink mode replaces the normal preview
+    pub enabled: bool,
+    /// Index of the first frame being compared
+    pub frame_a: usize,
+    /// Index of the second frame being compared
+    pub frame_b: usize,
+    /// Switch automatically on a timer instead of only on manual toggles
+    pub auto_blink: bool,
+    /// Seconds between automatic switches
+    pub interval: f64,
+    /// The frame currently shown
+    showing: BlinkSide,
+    /// Time (in seconds) of the last switch
+    last_switch: f64,
 }
 
-impl Default for StretchMethod {
+impl Default for BlinkComparator {
     fn default() -> Self {
-        StretchMethod::Linear
+        Self {
+            enabled: false,
+            frame_a: 0,
+            frame_b: 1,
+            auto_blink: true,
+            interval: 0.5,
+            showing: BlinkSide::A,
+            last_switch: 0.0,
+        }
+    }
+}
+
+impl BlinkComparator {
+    /// Advance the timer to `now`, returning true when the shown frame changed
+    pub fn update(&mut self, now: f64) -> bool {
+        if !self.enabled || !self.auto_blink {
+            self.last_switch = now;
+            return false;
+        }
+
+        if now - self.last_switch >= self.interval {
+            self.toggle();
+            self.last_switch = now;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Seconds remaining until the next automatic switch
+    pub fn time_until_switch(&self, now: f64) -> f64 {
+        (self.interval - (now - self.last_switch)).max(0.0)
+    }
+
+    /// Switch to the other frame
+    pub fn toggle(&mut self) {
+        self.showing = match self.showing {
+            BlinkSide::A => BlinkSide::B,
+            BlinkSide::B => BlinkSide::A,
+        };
+    }
+
+    /// Show a specific side
+    pub fn show(&mut self, side: BlinkSide) {
+        self.showing = side;
+    }
+
+    /// The side currently shown
+    pub fn showing(&self) -> BlinkSide {
+        self.showing
+    }
+
+    /// Index of the frame currently shown
+    pub fn current_frame(&self) -> usize {
+        match self.showing {
+            BlinkSide::A => self.frame_a,
+            BlinkSide::B => self.frame_b,
+        }
     }
 }
 
@@ -26,118 +117,453 @@ impl Default for StretchMethod {
 pub struct RegisteredFrame {
     /// Path to the image file
     pub path: PathBuf,
-    /// Metadata extracted from the image
-    pub fits_image: FitsImage,
+    /// Metadata extracted from the image, shared with background preview generation
+    pub fits_image: Arc<FitsImage>,
     /// Whether this frame is selected for processing
     pub selected: bool,
     /// Thumbnail or preview data (will be loaded on demand)
     pub preview_data: Option<egui::TextureHandle>,
-    /// The stretch method used for the current preview
-    pub preview_stretch: Option<StretchMethod>,
+    /// Low resolution preview shown in the frame table, generated in the background on load
+    pub thumbnail: Option<egui::TextureHandle>,
+    /// The stretch settings used for the current preview
+    pub preview_stretch: Option<StretchSettings>,
+    /// Whether the current preview was rendered in color
+    pub preview_color: bool,
+    /// Why the frame was excluded from processing (e.g. "user rejected")
+    pub reject_reason: Option<String>,
+    /// Median star FWHM in pixels, measured in the background after the frame is loaded
+    pub fwhm: Option<f32>,
+    /// Median star eccentricity, measured in the background after the frame is loaded
+    pub eccentricity: Option<f32>,
+    /// Robust sky background level, measured in the background after the frame is loaded
+    pub background: Option<f32>,
+    /// Detected stars, found in the background after the frame is loaded
+    pub stars: Option<Vec<Star>>,
+    /// Per-channel histograms, computed in the background after the frame is loaded
+    pub histograms: Option<ChannelHistograms>,
 }
 
 impl RegisteredFrame {
+    /// Read a frame from disk. Its metrics are left unset until `set_metrics`, as measuring
+    /// them is slow; `ThumbnailWorker` does it along with the thumbnail.
     pub fn new(path: PathBuf, frame_type: FrameType) -> Self {
         let fits_image =
             FitsImage::from_file(&path, frame_type).unwrap_or_else(|_| FitsImage::new(0, 0));
+        Self::from_image(path, fits_image)
+    }
+
+    /// Register a frame that has already been read from `path`
+    pub fn from_image(path: PathBuf, fits_image: FitsImage) -> Self {
         Self {
             path,
-            fits_image,
+            fits_image: Arc::new(fits_image),
             selected: true, // Default to selected
             preview_data: None,
+            thumbnail: None,
             preview_stretch: None, // No preview generated yet
+            preview_color: false,
+            reject_reason: None,
+            fwhm: None,
+            eccentricity: None,
+            background: None,
+            stars: None,
+            histograms: None,
         }
     }
 
+    /// Store the quality metrics measured from the frame's pixels
+    pub fn set_metrics(&mut self, metrics: &FrameMetrics) {
+        self.fwhm = metrics.fwhm;
+        self.eccentricity = metrics.eccentricity;
+        self.background = Some(metrics.background);
+    }
+
+    /// Exclude the frame from processing, recording why
+    pub fn reject(&mut self, reason: impl Into<String>) {
+        self.selected = false;
+        self.reject_reason = Some(reason.into());
+    }
+
+    /// Median star FWHM in arcseconds, when both the FWHM and the pixel scale are known
+    pub fn fwhm_arcsec(&self) -> Option<f32> {
+        self.fits_image.metadata.pixels_to_arcsec(self.fwhm?)
+    }
+
+    /// Drop the previews and measurements derived from the pixels after they were edited.
+    /// The thumbnail worker measures the frame again when it renders the new thumbnail.
+    fn refresh_after_edit(&mut self) {
+        self.fwhm = None;
+        self.eccentricity = None;
+        self.background = None;
+        self.preview_data = None;
+        self.preview_stretch = None;
+        self.thumbnail = None;
+        self.stars = None;
+        self.histograms = None;
+    }
+
+    /// Whether the frame's stars are elongated enough to suggest tracking errors or wind
+    pub fn has_elongated_stars(&self) -> bool {
+        self.eccentricity.is_some_and(|e| e > MAX_ECCENTRICITY)
+    }
+
+    /// Reject the frame if its stars are elongated, giving the measured eccentricity as
+    /// the reason. Returns whether the frame was rejected.
+    pub fn reject_if_elongated(&mut self) -> bool {
+        match self.eccentricity {
+            Some(e) if e > MAX_ECCENTRICITY => {
+                self.reject(format!("eccentricity {:.2} > {:.2}", e, MAX_ECCENTRICITY));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Include the frame in processing, clearing any previous reject reason
+    pub fn accept(&mut self) {
+        self.selected = true;
+        self.reject_reason = None;
+    }
+
     /// Generate a preview image for display
+    ///
+    /// Three-channel images are shown in color when `show_color` is set, otherwise
+    /// their luminance is shown in grayscale.
     pub fn generate_preview(
         &mut self,
         ctx: &Context,
-        stretch_method: StretchMethod,
+        stretch_method: StretchSettings,
+        show_color: bool,
     ) -> Result<(), ImageError> {
         // If we already have a preview with the same stretch method, don't regenerate it
         // This improves performance when switching between tabs
-        if self.preview_data.is_some() && self.preview_stretch == Some(stretch_method) {
+        if self.has_preview(stretch_method, show_color) {
             return Ok(());
         }
 
-        // Scale image data to 8-bit for preview
-        let data = self.fits_image.data.clone();
-        let flat_data = data.iter().cloned().collect::<Vec<f32>>();
-
-        // Find min and max for scaling
-        let min_val = flat_data.iter().fold(f32::INFINITY, |a, &b| a.min(b));
-        let max_val = flat_data.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
-        let range = max_val - min_val;
-
-        // Calculate statistics needed for stretching
-        let mean = flat_data.iter().sum::<f32>() / flat_data.len() as f32;
-        let std_dev = (flat_data.iter().map(|&x| (x - mean).powi(2)).sum::<f32>()
-            / flat_data.len() as f32)
-            .sqrt();
-
-        // Create 8-bit RGB data for preview
-        let width = self.fits_image.metadata.dimensions.0;
-        let height = self.fits_image.metadata.dimensions.1;
-        let mut rgba_data = Vec::with_capacity(width * height * 4);
-
-        // Convert grayscale data to RGBA using the selected stretch method
-        for value in flat_data {
-            let normalized = if range > 0.0 {
-                match stretch_method {
-                    StretchMethod::Linear => {
-                        // Simple linear stretch
-                        ((value - min_val) / range * 255.0).clamp(0.0, 255.0) as u8
-                    }
-                    StretchMethod::Logarithmic => {
-                        // Logarithmic stretch - enhances dim features
-                        if value <= min_val {
-                            0
-                        } else {
-                            let epsilon = 0.001; // To avoid ln(0)
-                            ((value - min_val + epsilon).ln() / (max_val - min_val + epsilon).ln()
-                                * 255.0)
-                                .clamp(0.0, 255.0) as u8
-                        }
-                    }
-                    StretchMethod::AutoStretch => {
-                        // Automatic stretching based on mean and std dev
-                        // Using a simple algorithm that enhances contrast around the mean
-                        let shadow_clip = (mean - 2.0 * std_dev).max(min_val);
-                        let highlight_clip = (mean + 4.0 * std_dev).min(max_val);
-                        let auto_range = highlight_clip - shadow_clip;
-                        if auto_range > 0.0 {
-                            ((value - shadow_clip) / auto_range * 255.0).clamp(0.0, 255.0) as u8
-                        } else {
-                            0
-                        }
-                    }
-                }
-            } else {
-                0
-            };
+        let image = preview_image(&self.fits_image, stretch_method, show_color)?;
+        self.set_preview(ctx, image, stretch_method, show_color);
 
-            // Add RGB and alpha channels
-            rgba_data.push(normalized);
-            rgba_data.push(normalized);
-            rgba_data.push(normalized);
-            rgba_data.push(255); // Alpha
-        }
+        Ok(())
+    }
 
-        // Create egui texture
+    /// Whether the current preview was rendered with these settings
+    pub fn has_preview(&self, stretch_method: StretchSettings, show_color: bool) -> bool {
+        self.preview_data.is_some()
+            && self.preview_stretch == Some(stretch_method)
+            && self.preview_color == show_color
+    }
+
+    /// Upload a rendered preview as the frame's texture
+    pub fn set_preview(
+        &mut self,
+        ctx: &Context,
+        image: egui::ColorImage,
+        stretch_method: StretchSettings,
+        show_color: bool,
+    ) {
         let texture = ctx.load_texture(
             self.path.file_name().unwrap_or_default().to_string_lossy(),
-            egui::ColorImage::from_rgba_unmultiplied([width, height], &rgba_data),
+            image,
             egui::TextureOptions::default(),
         );
 
         self.preview_data = Some(texture);
         self.preview_stretch = Some(stretch_method);
+        self.preview_color = show_color;
+    }
+}
 
-        Ok(())
+/// Render a stretched preview of an image.
+///
+/// This does the CPU work of `generate_preview` without touching the egui context,
+/// so it can run on a background thread.
+pub fn preview_image(
+    image: &FitsImage,
+    stretch_method: StretchSettings,
+    show_color: bool,
+) -> Result<egui::ColorImage, ImageError> {
+    let derotated;
+    let image = if stretch_method.derotate && image.metadata.rotation.is_some() {
+        derotated = derotate(image)?;
+        &derotated
+    } else {
+        image
+    };
+
+    let (rgba_data, width, height) = if image.is_color() && !show_color {
+        let luminance = image.to_luminance_with(stretch_method.luminance_weights)?;
+        stretch_to_rgba(luminance.data(), stretch_method)
+    } else if image.is_color() {
+        stretch_to_rgba(image.data(), stretch_method)
+    } else {
+        // Mono frames reuse the image's cached statistics
+        stretch_to_rgba_with_statistics(
+            image.view2d()?,
+            &image.calculate_statistics(),
+            stretch_method,
+        )
+    };
+
+    Ok(egui::ColorImage::from_rgba_unmultiplied(
+        [width, height],
+        &rgba_data,
+    ))
+}
+
+/// A low resolution preview rendered while the stretch is being adjusted
+struct DraftPreview {
+    /// The tab and index of the frame it shows
+    frame: (FrameType, usize),
+    stretch: StretchSettings,
+    texture: egui::TextureHandle,
+}
+
+/// Render a preview downsampled so its longest side fits in `DRAFT_PREVIEW_SIZE`, fast
+/// enough to redo on every frame of a slider drag
+pub fn draft_preview_image(
+    image: &FitsImage,
+    stretch: StretchSettings,
+    show_color: bool,
+) -> Result<egui::ColorImage, ImageError> {
+    let (width, height) = image.metadata.dimensions;
+    let mut draft = FitsImage::new(0, 0);
+    draft.set_data(downsample(image.data(), draft_factor(width, height)));
+    let shape = draft.data().shape();
+    draft.metadata.dimensions = (shape[shape.len() - 1], shape[shape.len() - 2]);
+    draft.metadata.rotation = image.metadata.rotation;
+    preview_image(&draft, stretch, show_color)
+}
+
+/// Longest side of a thumbnail in pixels
+pub const THUMBNAIL_SIZE: usize = 128;
+
+/// Height thumbnails are drawn at in the frame table
+const THUMBNAIL_ROW_HEIGHT: f32 = 32.0;
+
+/// Render a small auto-stretched thumbnail of an image, downsampled so its longest side
+/// is at most `THUMBNAIL_SIZE`. Color images stay in color.
+pub fn thumbnail_image(image: &FitsImage) -> Result<egui::ColorImage, ImageError> {
+    let (width, height) = match *image.data().shape() {
+        [height, width] | [_, height, width] => (width, height),
+        _ => (0, 0),
+    };
+    let factor = width.max(height).div_ceil(THUMBNAIL_SIZE).max(1);
+    let stretch = StretchSettings {
+        method: StretchMethod::AutoStretch,
+        ..Default::default()
+    };
+
+    let (rgba_data, width, height) = stretch_to_rgba(&downsample(image.data(), factor), stretch);
+    Ok(egui::ColorImage::from_rgba_unmultiplied(
+        [width, height],
+        &rgba_data,
+    ))
+}
+
+/// Shrink `[height, width]` or `[channels, height, width]` data by averaging
+/// `factor` x `factor` blocks. Non-finite values are left out of each block's mean, and
+/// partial blocks at the right and bottom edges are averaged over the pixels they have.
+pub fn downsample(data: &ArrayD<f32>, factor: usize) -> ArrayD<f32> {
+    if factor <= 1 {
+        return data.clone();
+    }
+
+    let downsample_plane = |plane: ArrayView2<f32>| {
+        let (height, width) = plane.dim();
+        Array2::from_shape_fn(
+            (height.div_ceil(factor), width.div_ceil(factor)),
+            |(y, x)| {
+                let block = plane.slice(s![
+                    y * factor..((y + 1) * factor).min(height),
+                    x * factor..((x + 1) * factor).min(width)
+                ]);
+                let (sum, count) = block
+                    .iter()
+                    .filter(|v| v.is_finite())
+                    .fold((0.0, 0usize), |(sum, count), &v| (sum + v, count + 1));
+                if count > 0 {
+                    sum / count as f32
+                } else {
+                    f32::NAN
+                }
+            },
+        )
+    };
+
+    match data.ndim() {
+        2 => match data.view().into_dimensionality::<Ix2>() {
+            Ok(plane) => downsample_plane(plane).into_dyn(),
+            Err(_) => data.clone(),
+        },
+        3 => {
+            let planes: Vec<_> = data
+                .axis_iter(Axis(0))
+                .filter_map(|plane| plane.into_dimensionality::<Ix2>().ok())
+                .map(downsample_plane)
+                .collect();
+            let views: Vec<_> = planes.iter().map(|plane| plane.view()).collect();
+            ndarray::stack(Axis(0), &views)
+                .map(|stacked| stacked.into_dyn())
+                .unwrap_or_else(|_| data.clone())
+        }
+        _ => data.clone(),
+    }
+}
+
+/// Zoom and pan state of the preview, expressed as the visible part of the texture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreviewViewport {
+    /// Magnification relative to fitting the whole image (1.0 = whole image visible)
+    pub zoom: f32,
+    /// Center of the visible region in texture coordinates (0..1)
+    pub center: Vec2,
+}
+
+impl Default for PreviewViewport {
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            center: Vec2::splat(0.5),
+        }
     }
 }
 
+impl PreviewViewport {
+    const MAX_ZOOM: f32 = 32.0;
+
+    /// The visible part of the texture, in texture coordinates
+    pub fn uv_rect(&self) -> Rect {
+        let half = 0.5 / self.zoom;
+        Rect::from_center_size(self.center.to_pos2(), Vec2::splat(2.0 * half))
+    }
+
+    /// Multiply the zoom, keeping the view center fixed
+    pub fn zoom_by(&mut self, factor: f32) {
+        self.zoom = (self.zoom * factor).clamp(1.0, Self::MAX_ZOOM);
+        self.clamp_center();
+    }
+
+    /// Move the view by a drag of `delta` screen points over an image shown at `size`
+    pub fn pan_by(&mut self, delta: Vec2, size: Vec2) {
+        if size.x > 0.0 && size.y > 0.0 {
+            self.center -= delta / size / self.zoom;
+            self.clamp_center();
+        }
+    }
+
+    /// Keep the visible region inside the texture
+    fn clamp_center(&mut self) {
+        let half = 0.5 / self.zoom;
+        self.center.x = self.center.x.clamp(half, 1.0 - half);
+        self.center.y = self.center.y.clamp(half, 1.0 - half);
+    }
+}
+
+/// Map a position in image pixel coordinates to the screen, given the visible texture
+/// region `uv` and the screen rectangle it's drawn into
+pub fn image_to_screen(point: (f32, f32), image_size: (f32, f32), uv: Rect, screen: Rect) -> Pos2 {
+    // Pixel centers sit half a pixel in from the texture edge
+    let u = (point.0 + 0.5) / image_size.0;
+    let v = (point.1 + 0.5) / image_size.1;
+
+    Pos2::new(
+        screen.min.x + (u - uv.min.x) / uv.width() * screen.width(),
+        screen.min.y + (v - uv.min.y) / uv.height() * screen.height(),
+    )
+}
+
+/// Most frame previews kept as textures at once; older ones are regenerated when viewed again
+pub const MAX_RESIDENT_PREVIEWS: usize = 64;
+
+/// Least recently used order of the frames holding a preview texture, used to bound the
+/// GPU memory spent on previews in large sessions
+#[derive(Debug, Clone)]
+pub struct PreviewLru {
+    capacity: usize,
+    /// Frames with a preview, least recently used first
+    order: std::collections::VecDeque<(FrameType, usize)>,
+}
+
+impl PreviewLru {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            order: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// Mark a frame's preview as the most recently used, returning the frames whose
+    /// previews should be dropped to stay within capacity
+    pub fn touch(&mut self, frame: (FrameType, usize)) -> Vec<(FrameType, usize)> {
+        if let Some(position) = self.order.iter().position(|&entry| entry == frame) {
+            self.order.remove(position);
+        }
+        self.order.push_back(frame);
+
+        let excess = self.order.len().saturating_sub(self.capacity);
+        self.order.drain(..excess).collect()
+    }
+
+    /// Forget every frame of a tab, e.g. after its frames were replaced
+    pub fn remove_tab(&mut self, frame_type: FrameType) {
+        self.order
+            .retain(|&(entry_type, _)| entry_type != frame_type);
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Threshold used by the "Repair bad columns" bulk operation, in robust sigmas
+const BULK_COLUMN_DEFECT_SIGMA: f32 = 5.0;
+
+/// Radius in pixels star masks are grown by, so they also cover the faint star halos
+const STAR_MASK_DILATION: usize = 3;
+
+/// Appended to a frame's file stem to name its star mask
+const STAR_MASK_SUFFIX: &str = "_starmask";
+
+/// Default distance in image pixels between pixel grid lines
+pub const DEFAULT_GRID_SPACING: usize = 100;
+
+/// Image coordinates of the grid lines, every `spacing` pixels, that fall inside the
+/// visible range `[start, end)` along one axis
+pub fn grid_lines(start: f32, end: f32, spacing: usize) -> Vec<usize> {
+    if spacing == 0 || end <= start {
+        return Vec::new();
+    }
+
+    let first = (start.max(0.0) / spacing as f32).ceil() as usize * spacing;
+    (first..)
+        .step_by(spacing)
+        .take_while(|&line| (line as f32) < end)
+        .collect()
+}
+
+/// Where the star mask of the frame at `path` is saved: beside it, as a plain FITS file
+/// named after it with `STAR_MASK_SUFFIX`
+pub fn star_mask_path(path: &Path) -> PathBuf {
+    let mut stem = Path::new(path.file_stem().unwrap_or_default());
+    // Compressed frames keep their FITS extension in the stem, e.g. light.fits.gz
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+    {
+        stem = Path::new(stem.file_stem().unwrap_or_default());
+    }
+    path.with_file_name(format!(
+        "{}{}.fits",
+        stem.to_string_lossy(),
+        STAR_MASK_SUFFIX
+    ))
+}
+
 /// The registration view state
 pub struct RegistrationView {
     /// Currently selected tab
@@ -148,6 +574,64 @@ pub struct RegistrationView {
     pub selected_frame_indices: std::collections::HashMap<FrameType, Option<usize>>,
     /// Currently selected stretch method for image preview
     pub selected_stretch: StretchMethod,
+    /// Arcsinh softening of the Luminance stretch, as set on the slider
+    pub asinh_softening: f32,
+    /// Softening the full resolution previews use; follows the slider once it comes to rest
+    applied_softening: f32,
+    /// Drag state of the softening slider
+    softening_drag: DragPreview,
+    /// Low resolution preview shown while the softening slider moves
+    draft_preview: Option<DraftPreview>,
+    /// Show three-channel images in color rather than as luminance
+    pub show_color: bool,
+    /// Highlight the pixels the stretch clips to black or white
+    pub show_clipping: bool,
+    /// False-color palette for mono previews
+    pub color_map: ColorMap,
+    /// Channel weights color frames are collapsed to luminance with
+    pub luminance_weights: LuminanceWeights,
+    /// Show frames counter-rotated by their field rotation
+    pub derotate_preview: bool,
+    /// Filename filter for the frame table
+    pub search_query: String,
+    /// Blink comparison between two frames of the active tab
+    pub blink: BlinkComparator,
+    /// Keyword for the batch metadata editor
+    pub batch_key: String,
+    /// Value for the batch metadata editor
+    pub batch_value: String,
+    /// Whether batch keyword edits are also written to the files on disk
+    pub batch_save_to_disk: bool,
+    /// Outcome of the last batch keyword edit
+    pub batch_status: Option<String>,
+    /// Outcome of the last CSV export
+    pub export_status: Option<String>,
+    /// Outcome of the last operation applied to the selected frames
+    pub bulk_status: Option<String>,
+    /// Zoom and pan of the preview image
+    pub preview_viewport: PreviewViewport,
+    /// Draw circles around the detected stars on the preview
+    pub show_star_overlay: bool,
+    /// Draw a pixel coordinate grid over the preview
+    pub show_grid: bool,
+    /// Show the histogram of the previewed frame, per channel for color frames
+    pub show_histogram: bool,
+    /// Distance in image pixels between grid lines
+    pub grid_spacing: usize,
+    /// Background generation of the active tab's previews
+    preview_worker: Option<PreviewWorker>,
+    /// Which frames hold preview textures, evicting the least recently viewed
+    preview_lru: PreviewLru,
+    /// Background loading of the frames, while it runs
+    load_worker: Option<LoadWorker>,
+    /// Frames received from the current load
+    loaded_count: usize,
+    /// Watch of the lights folder for frames still being captured, while enabled
+    live_watch: Option<LiveWatch>,
+    /// Light frames added by the current watch
+    watched_count: usize,
+    /// Background thumbnail generation, one worker per loaded tab
+    thumbnail_workers: std::collections::HashMap<FrameType, ThumbnailWorker>,
 }
 
 impl Default for RegistrationView {
@@ -170,6 +654,35 @@ impl Default for RegistrationView {
             frames: std::collections::HashMap::new(),
             selected_frame_indices,
             selected_stretch: StretchMethod::default(),
+            asinh_softening: DEFAULT_ASINH_SOFTENING,
+            applied_softening: DEFAULT_ASINH_SOFTENING,
+            softening_drag: DragPreview::default(),
+            draft_preview: None,
+            show_color: true,
+            show_clipping: false,
+            color_map: ColorMap::default(),
+            luminance_weights: LuminanceWeights::default(),
+            derotate_preview: false,
+            search_query: String::new(),
+            blink: BlinkComparator::default(),
+            batch_key: String::new(),
+            batch_value: String::new(),
+            batch_save_to_disk: false,
+            batch_status: None,
+            export_status: None,
+            bulk_status: None,
+            preview_viewport: PreviewViewport::default(),
+            show_star_overlay: false,
+            show_grid: false,
+            show_histogram: false,
+            grid_spacing: DEFAULT_GRID_SPACING,
+            preview_worker: None,
+            preview_lru: PreviewLru::new(MAX_RESIDENT_PREVIEWS),
+            load_worker: None,
+            loaded_count: 0,
+            live_watch: None,
+            watched_count: 0,
+            thumbnail_workers: std::collections::HashMap::new(),
         }
     }
 }
@@ -207,6 +720,10 @@ impl RegistrationView {
         }
 
         self.frames.insert(frame_type, frames);
+        self.preview_lru.remove_tab(frame_type);
+
+        // Any running worker refers to the frames that were just replaced
+        self.stop_workers(frame_type);
 
         // Set the first frame as selected if there are frames
         if !self
@@ -219,29 +736,407 @@ impl RegistrationView {
         }
     }
 
-    fn ensure_preview(
+    /// Cancel the preview and thumbnail workers of a tab. They are started again on the
+    /// next frame, covering the tab's frames as they are then.
+    fn stop_workers(&mut self, frame_type: FrameType) {
+        if self
+            .preview_worker
+            .as_ref()
+            .is_some_and(|worker| worker.frame_type == frame_type)
+        {
+            self.preview_worker = None;
+        }
+        self.thumbnail_workers.remove(&frame_type);
+    }
+
+    /// Replace the frames of each listed tab, reading the files in the background. Frames
+    /// show up as they're read; `cancel_loading` stops early and keeps what was read.
+    pub fn start_loading(&mut self, ctx: &Context, batches: Vec<(FrameType, Vec<PathBuf>)>) {
+        // A watch would add frames from the previous folder to the new ones
+        self.stop_watching();
+        for (frame_type, _) in &batches {
+            self.load_frames_from_paths(*frame_type, Vec::new());
+            self.selected_frame_indices.insert(*frame_type, None);
+        }
+
+        self.loaded_count = 0;
+        self.load_worker = Some(LoadWorker::spawn(ctx, batches));
+    }
+
+    /// Whether frames are still being read in the background
+    pub fn is_loading(&self) -> bool {
+        self.load_worker.is_some()
+    }
+
+    /// Stop loading after the file being read, keeping the frames read so far
+    pub fn cancel_loading(&mut self) {
+        if let Some(worker) = &self.load_worker {
+            worker.cancel();
+        }
+    }
+
+    /// Add the frames read since the last frame, and restart the preview and thumbnail
+    /// workers once loading ends so they cover every frame
+    fn update_load_worker(&mut self) {
+        let Some(worker) = &mut self.load_worker else {
+            return;
+        };
+
+        let loaded = worker.poll();
+        let done = worker.is_done();
+        self.loaded_count += loaded.len();
+        for loaded in loaded {
+            let frames = self.frames.entry(loaded.frame_type).or_default();
+            frames.push(loaded.frame);
+            self.selected_frame_indices
+                .entry(loaded.frame_type)
+                .or_default()
+                .get_or_insert(0);
+        }
+
+        if done {
+            log::info!("Loaded {} frames", self.loaded_count);
+            self.load_worker = None;
+            self.preview_worker = None;
+            self.thumbnail_workers.clear();
+        }
+    }
+
+    /// Add the light frames written to `folder` from now on, as the capture software saves
+    /// them
+    pub fn start_watching(&mut self, ctx: &Context, folder: &Path) -> Result<(), ImageError> {
+        self.live_watch = Some(LiveWatch::spawn(ctx, folder)?);
+        self.watched_count = 0;
+        Ok(())
+    }
+
+    pub fn stop_watching(&mut self) {
+        self.live_watch = None;
+    }
+
+    /// Number of light frames the running watch has added, or `None` when not watching
+    pub fn watched_count(&self) -> Option<usize> {
+        self.live_watch.as_ref().map(|_| self.watched_count)
+    }
+
+    /// Add the frames that arrived in the watched folder since the last frame
+    fn update_live_watch(&mut self) {
+        let Some(watch) = &self.live_watch else {
+            return;
+        };
+
+        let frames = self.frames.entry(FrameType::Light).or_default();
+        let before = frames.len();
+        for image in watch.poll() {
+            let Some(path) = image.metadata.file_path.clone() else {
+                continue;
+            };
+            // Capture software may write a file more than once
+            if frames.iter().all(|frame| frame.path != path) {
+                frames.push(RegisteredFrame::from_image(path, image));
+            }
+        }
+
+        let added = frames.len() - before;
+        if added > 0 {
+            log::info!("Added {} new light frames", added);
+            self.watched_count += added;
+            self.selected_frame_indices
+                .entry(FrameType::Light)
+                .or_default()
+                .get_or_insert(0);
+            // Started again on the next frame, covering the new frames
+            self.stop_workers(FrameType::Light);
+        }
+    }
+
+    /// Run `op` on every selected frame of a tab in parallel, returning the frames it failed
+    /// on. Edited frames are measured again and their previews and thumbnails regenerated.
+    pub fn apply_to_selected<F>(
         &mut self,
         frame_type: FrameType,
-        index: usize,
-        ctx: &Context,
-    ) -> Result<(), ImageError> {
-        if let Some(frames) = self.frames.get_mut(&frame_type) {
-            if index < frames.len() {
-                // Generate the preview if needed or if stretch method changed
-                let stretch = self.selected_stretch;
-                if frames[index].preview_data.is_none()
-                    || frames[index].preview_stretch != Some(stretch)
+        op: F,
+    ) -> Vec<(PathBuf, ImageError)>
+    where
+        F: Fn(&mut FitsImage) -> Result<(), ImageError> + Sync,
+    {
+        use rayon::prelude::*;
+
+        // Stop the tab's workers first, so frames they aren't rendering right now can be
+        // edited in place
+        self.stop_workers(frame_type);
+
+        let Some(frames) = self.frames.get_mut(&frame_type) else {
+            return Vec::new();
+        };
+
+        let errors = frames
+            .par_iter_mut()
+            .filter(|frame| frame.selected)
+            .filter_map(|frame| {
+                // Copies the image only if a worker is rendering it at this moment
+                let result = op(Arc::make_mut(&mut frame.fits_image));
+                frame.refresh_after_edit();
+                result.err().map(|e| (frame.path.clone(), e))
+            })
+            .collect();
+
+        errors
+    }
+
+    /// Run an operation from the bulk operations menu on the active tab and report how it went
+    fn run_bulk_operation<F>(&mut self, name: &str, op: F)
+    where
+        F: Fn(&mut FitsImage) -> Result<(), ImageError> + Sync,
+    {
+        let count = self.selected_count(self.active_tab);
+        let errors = self.apply_to_selected(self.active_tab, op);
+        self.report_bulk_result(name, count, &errors);
+    }
+
+    /// Write a star mask next to every selected frame of the active tab, named after the
+    /// frame with `STAR_MASK_SUFFIX`, for protecting stars while stretching. Existing masks
+    /// are replaced.
+    fn save_star_masks(&mut self) {
+        use rayon::prelude::*;
+
+        let Some(frames) = self.frames.get(&self.active_tab) else {
+            return;
+        };
+        let selected: Vec<(PathBuf, Arc<FitsImage>)> = frames
+            .iter()
+            .filter(|frame| frame.selected)
+            .map(|frame| (frame.path.clone(), frame.fits_image.clone()))
+            .collect();
+
+        let errors: Vec<(PathBuf, ImageError)> = selected
+            .par_iter()
+            .filter_map(|(path, image)| {
+                let mask_path = star_mask_path(path);
+                let _ = std::fs::remove_file(&mask_path);
+                image
+                    .generate_star_mask(DEFAULT_DETECTION_SIGMA, STAR_MASK_DILATION)
+                    .to_file_with_options(&mask_path, &SaveOptions::processed())
+                    .err()
+                    .map(|e| (path.clone(), e))
+            })
+            .collect();
+
+        self.report_bulk_result("Save star masks", selected.len(), &errors);
+    }
+
+    /// Log a bulk operation's failures and summarize it in the bulk operations menu
+    fn report_bulk_result(&mut self, name: &str, count: usize, errors: &[(PathBuf, ImageError)]) {
+        for (path, error) in errors {
+            log::warn!("{} failed on {}: {}", name, path.display(), error);
+        }
+        self.bulk_status = Some(if errors.is_empty() {
+            format!("{}: applied to {} frames", name, count)
+        } else {
+            format!(
+                "{}: failed on {} of {} frames, first error: {}",
+                name,
+                errors.len(),
+                count,
+                errors[0].1
+            )
+        });
+    }
+
+    /// Operations that can be applied to every selected frame of the active tab at once
+    fn render_bulk_operations(&mut self, ui: &mut Ui) {
+        ui.collapsing("Apply to selected frames", |ui| {
+            ui.horizontal_wrapped(|ui| {
+                if ui.button("Flip vertically").clicked() {
+                    self.run_bulk_operation("Flip vertically", |image| {
+                        let data = image.data_mut();
+                        let axis = Axis(data.ndim() - 2);
+                        for mut column in data.lanes_mut(axis) {
+                            let len = column.len();
+                            for y in 0..len / 2 {
+                                column.swap(y, len - 1 - y);
+                            }
+                        }
+                        image.metadata.history.record("Flipped vertically");
+                        Ok(())
+                    });
+                }
+                if ui.button("Repair bad columns").clicked() {
+                    self.run_bulk_operation("Repair bad columns", |image| {
+                        image
+                            .correct_column_defects(BULK_COLUMN_DEFECT_SIGMA)
+                            .map(|_| ())
+                    });
+                }
+                if ui.button("Remove linear gradient").clicked() {
+                    self.run_bulk_operation("Remove linear gradient", |image| {
+                        image.remove_gradient(GradientModel::Linear)
+                    });
+                }
+                if ui
+                    .button("Save star masks")
+                    .on_hover_text(
+                        "Write a mask of the detected stars next to each frame, to protect \
+                         them when stretching",
+                    )
+                    .clicked()
                 {
-                    println!(
-                        "Generating preview for frame {} of type {:?} with {:?} stretch",
-                        frames[index].path.display(),
-                        frame_type,
-                        stretch
+                    self.save_star_masks();
+                }
+            });
+
+            if let Some(status) = &self.bulk_status {
+                ui.label(status);
+            }
+        });
+    }
+
+    /// Upload finished background previews and restart the worker when the active tab,
+    /// stretch or color setting changes
+    fn update_preview_worker(&mut self, ctx: &Context) {
+        let frame_type = self.active_tab;
+        let stretch = self.stretch_settings();
+
+        let mut finished = Vec::new();
+        if let Some(worker) = &self.preview_worker {
+            if let Some(frames) = self.frames.get_mut(&worker.frame_type) {
+                for result in worker.poll() {
+                    if let Some(frame) = frames.get_mut(result.index) {
+                        frame.set_preview(ctx, result.image, worker.stretch, result.show_color);
+                        finished.push((worker.frame_type, result.index));
+                    }
+                }
+            }
+        }
+        for (frame_type, index) in finished {
+            self.mark_preview_used(frame_type, index);
+        }
+
+        if self
+            .preview_worker
+            .as_ref()
+            .is_some_and(|worker| worker.matches(frame_type, stretch, self.show_color))
+        {
+            return;
+        }
+
+        let jobs: Vec<PreviewJob> = self
+            .frames
+            .get(&frame_type)
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .filter_map(|(index, frame)| {
+                let show_color = self.show_color && frame.fits_image.is_color();
+                (!frame.has_preview(stretch, show_color)).then(|| PreviewJob {
+                    index,
+                    image: Arc::downgrade(&frame.fits_image),
+                    show_color,
+                })
+            })
+            .collect();
+
+        // Replacing the previous worker cancels it
+        self.preview_worker = Some(PreviewWorker::spawn(
+            ctx,
+            frame_type,
+            stretch,
+            self.show_color,
+            jobs,
+        ));
+    }
+
+    /// Record that a frame's preview was used, dropping the least recently used previews
+    /// beyond `MAX_RESIDENT_PREVIEWS`
+    fn mark_preview_used(&mut self, frame_type: FrameType, index: usize) {
+        for (evicted_type, evicted_index) in self.preview_lru.touch((frame_type, index)) {
+            if let Some(frame) = self
+                .frames
+                .get_mut(&evicted_type)
+                .and_then(|frames| frames.get_mut(evicted_index))
+            {
+                frame.preview_data = None;
+                frame.preview_stretch = None;
+            }
+        }
+    }
+
+    /// Upload finished thumbnails and start generating them for newly loaded tabs
+    fn update_thumbnail_workers(&mut self, ctx: &Context) {
+        // Workers are started once per tab, so wait until every frame is loaded
+        if self.is_loading() {
+            return;
+        }
+
+        for (frame_type, frames) in &mut self.frames {
+            let Some(worker) = self.thumbnail_workers.get(frame_type) else {
+                let jobs: Vec<(usize, Weak<FitsImage>)> = frames
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, frame)| frame.thumbnail.is_none())
+                    .map(|(index, frame)| (index, Arc::downgrade(&frame.fits_image)))
+                    .collect();
+                self.thumbnail_workers
+                    .insert(*frame_type, ThumbnailWorker::spawn(ctx, *frame_type, jobs));
+                continue;
+            };
+
+            for result in worker.poll() {
+                if let Some(frame) = frames.get_mut(result.index) {
+                    let name = format!(
+                        "thumbnail_{}",
+                        frame.path.file_name().unwrap_or_default().to_string_lossy()
                     );
-                    return frames[index].generate_preview(ctx, stretch);
+                    frame.thumbnail =
+                        Some(ctx.load_texture(name, result.image, egui::TextureOptions::default()));
+                    frame.set_metrics(&result.metrics);
+                    frame.stars = Some(result.stars);
+                    frame.histograms = Some(result.histograms);
                 }
             }
         }
+    }
+
+    /// The stretch currently selected in the view
+    fn stretch_settings(&self) -> StretchSettings {
+        StretchSettings {
+            method: self.selected_stretch,
+            asinh_softening: self.applied_softening,
+            show_clipping: self.show_clipping,
+            color_map: self.color_map,
+            derotate: self.derotate_preview,
+            luminance_weights: self.luminance_weights,
+        }
+    }
+
+    fn ensure_preview(
+        &mut self,
+        frame_type: FrameType,
+        index: usize,
+        ctx: &Context,
+    ) -> Result<(), ImageError> {
+        let stretch = self.stretch_settings();
+        let Some(frame) = self
+            .frames
+            .get_mut(&frame_type)
+            .and_then(|frames| frames.get_mut(index))
+        else {
+            return Ok(());
+        };
+
+        // Generate the preview if needed or if stretch method changed
+        let show_color = self.show_color && frame.fits_image.is_color();
+        if !frame.has_preview(stretch, show_color) {
+            log::debug!(
+                "Generating preview for frame {} of type {:?} with {:?} stretch",
+                frame.path.display(),
+                frame_type,
+                stretch
+            );
+            frame.generate_preview(ctx, stretch, show_color)?;
+        }
+
+        self.mark_preview_used(frame_type, index);
         Ok(())
     }
 
@@ -253,19 +1148,26 @@ impl RegistrationView {
             .get(&frame_type)
             .unwrap_or(&None)
         {
+            // In blink mode the preview alternates between the two compared frames
+            let shown = if self.blink.enabled {
+                self.blink.current_frame()
+            } else {
+                *selected
+            };
+
             if let Some(frames) = self.frames.get(&frame_type) {
-                if *selected < frames.len() {
-                    let frame = &frames[*selected];
+                if shown < frames.len() {
+                    let frame = &frames[shown];
 
                     // Add stretch method dropdown
                     ui.label("Stretch method:");
-                    let current_stretch = self.selected_stretch;
-
-                    ComboBox::from_id_source("stretch_method_combo")
+                    ComboBox::from_id_salt("stretch_method_combo")
                         .selected_text(match self.selected_stretch {
                             StretchMethod::Linear => "Linear",
+                            StretchMethod::PercentileLinear => "Linear (0.1-99.9%)",
                             StretchMethod::Logarithmic => "Logarithmic",
                             StretchMethod::AutoStretch => "AutoStretch",
+                            StretchMethod::Luminance => "Luminance",
                         })
                         .show_ui(ui, |ui| {
                             ui.selectable_value(
@@ -273,6 +1175,14 @@ impl RegistrationView {
                                 StretchMethod::Linear,
                                 "Linear",
                             );
+                            ui.selectable_value(
+                                &mut self.selected_stretch,
+                                StretchMethod::PercentileLinear,
+                                "Linear (0.1-99.9%)",
+                            )
+                            .on_hover_text(
+                                "Linear stretch that ignores the brightest and darkest pixels",
+                            );
                             ui.selectable_value(
                                 &mut self.selected_stretch,
                                 StretchMethod::Logarithmic,
@@ -283,10 +1193,145 @@ impl RegistrationView {
                                 StretchMethod::AutoStretch,
                                 "AutoStretch",
                             );
+                            ui.selectable_value(
+                                &mut self.selected_stretch,
+                                StretchMethod::Luminance,
+                                "Luminance",
+                            )
+                            .on_hover_text("Stretch color images without washing out star colors");
                         });
 
-                    // If preview data is available, display it
-                    if let Some(texture) = &frame.preview_data {
+                    if self.selected_stretch == StretchMethod::Luminance {
+                        let response = ui.add(
+                            egui::Slider::new(&mut self.asinh_softening, ASINH_SOFTENING_RANGE)
+                                .logarithmic(true)
+                                .text("Strength"),
+                        );
+                        let now = Instant::now();
+                        let quality =
+                            self.softening_drag
+                                .update(response.dragged(), response.changed(), now);
+
+                        if quality == PreviewQuality::Full {
+                            // At rest: full resolution previews catch up with the slider
+                            self.applied_softening = self.asinh_softening;
+                            self.draft_preview = None;
+                        } else {
+                            if let Some(wait) = self.softening_drag.time_to_settle(now) {
+                                ui.ctx().request_repaint_after(wait);
+                            }
+                            let stretch = StretchSettings {
+                                asinh_softening: self.asinh_softening,
+                                ..self.stretch_settings()
+                            };
+                            let show_color = self.show_color && frame.fits_image.is_color();
+                            let current = self.draft_preview.as_ref().is_some_and(|draft| {
+                                draft.frame == (frame_type, shown) && draft.stretch == stretch
+                            });
+                            if !current {
+                                match draft_preview_image(&frame.fits_image, stretch, show_color) {
+                                    Ok(image) => {
+                                        self.draft_preview = Some(DraftPreview {
+                                            frame: (frame_type, shown),
+                                            stretch,
+                                            texture: ui.ctx().load_texture(
+                                                "draft_preview",
+                                                image,
+                                                egui::TextureOptions::default(),
+                                            ),
+                                        });
+                                    }
+                                    Err(e) => log::warn!("Failed to render draft preview: {}", e),
+                                }
+                            }
+                        }
+                    }
+
+                    if frame.fits_image.is_color() {
+                        ui.checkbox(&mut self.show_color, "Show as color");
+                    }
+
+                    if frame.fits_image.is_color()
+                        && (!self.show_color || self.selected_stretch == StretchMethod::Luminance)
+                    {
+                        ui.horizontal(|ui| {
+                            ui.label("Luminance weights:");
+                            let selected = LuminanceWeights::PRESETS
+                                .iter()
+                                .find(|(_, weights)| *weights == self.luminance_weights)
+                                .map_or("Custom", |(name, _)| *name);
+                            ComboBox::from_id_salt("luminance_weights_combo")
+                                .selected_text(selected)
+                                .show_ui(ui, |ui| {
+                                    for (name, weights) in LuminanceWeights::PRESETS {
+                                        ui.selectable_value(
+                                            &mut self.luminance_weights,
+                                            weights,
+                                            name,
+                                        );
+                                    }
+                                });
+                        });
+                    }
+
+                    if !(frame.fits_image.is_color() && self.show_color) {
+                        ui.horizontal(|ui| {
+                            ui.label("Color map:");
+                            ComboBox::from_id_salt("color_map_combo")
+                                .selected_text(self.color_map.name())
+                                .show_ui(ui, |ui| {
+                                    for color_map in ColorMap::ALL {
+                                        ui.selectable_value(
+                                            &mut self.color_map,
+                                            color_map,
+                                            color_map.name(),
+                                        );
+                                    }
+                                });
+                        });
+                    }
+
+                    if frame.fits_image.metadata.rotation.is_some() {
+                        ui.checkbox(&mut self.derotate_preview, "De-rotate preview")
+                            .on_hover_text(
+                                "Counter-rotate by the frame's CROTA2 so meridian-flipped \
+                                 frames match the rest",
+                            );
+                    }
+
+                    ui.checkbox(&mut self.show_clipping, "Show clipping")
+                        .on_hover_text("Shadows clipped to black in blue, highlights in red");
+
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.show_star_overlay, "Show detected stars");
+                        if let Some(stars) = frame.stars.as_ref().filter(|_| self.show_star_overlay)
+                        {
+                            ui.label(format!("{} stars", stars.len()));
+                        }
+                        if self.preview_viewport.zoom > 1.0 && ui.button("Reset zoom").clicked() {
+                            self.preview_viewport = PreviewViewport::default();
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.show_grid, "Show pixel grid");
+                        if self.show_grid {
+                            ui.add(
+                                egui::DragValue::new(&mut self.grid_spacing)
+                                    .range(1..=10_000)
+                                    .suffix(" px"),
+                            );
+                        }
+                    });
+
+                    // If preview data is available, display it. While the stretch slider
+                    // moves, the low resolution draft stands in for it.
+                    let draft = self
+                        .draft_preview
+                        .as_ref()
+                        .filter(|draft| draft.frame == (frame_type, shown))
+                        .map(|draft| &draft.texture);
+                    if let Some(texture) = draft.or(frame.preview_data.as_ref()) {
                         // Calculate image size to fit the available space
                         let available_width = ui.available_width();
                         let available_height = ui.available_height() - 200.0; // Reserve space for metadata below
@@ -295,16 +1340,15 @@ impl RegistrationView {
                         let image_width = frame.fits_image.metadata.dimensions.0 as f32;
                         let image_height = frame.fits_image.metadata.dimensions.1 as f32;
 
-                        // Calculate scale factor to fit in the available space
-                        let scale_w = available_width / image_width;
-                        let scale_h = available_height / image_height;
-                        let scale = scale_w.min(scale_h);
-
-                        // Calculate the displayed size
-                        let display_width = image_width * scale;
-                        let display_height = image_height * scale;
+                        // Calculate the displayed size, correcting for non-square pixels
+                        let (display_width, display_height) = display_size(
+                            (image_width, image_height),
+                            frame.fits_image.metadata.pixel_aspect_ratio() as f32,
+                            (available_width, available_height),
+                        );
+                        let scale = display_height / image_height;
 
-                        println!(
+                        log::trace!(
                             "Displaying preview for frame {}: {}x{} at scale {:.2}",
                             frame.path.display(),
                             display_width,
@@ -312,18 +1356,113 @@ impl RegistrationView {
                             scale
                         );
 
-                        ui.centered_and_justified(|ui| {
-                            ui.add(
-                                egui::Image::new(texture)
-                                    .fit_to_exact_size(Vec2::new(display_width, display_height))
-                                    .corner_radius(4.0)
-                                    .sense(egui::Sense::click()),
-                            )
+                        ui.vertical_centered(|ui| {
+                            let (rect, response) = ui.allocate_exact_size(
+                                Vec2::new(display_width, display_height),
+                                egui::Sense::click_and_drag(),
+                            );
+
+                            // Scroll to zoom, drag to pan, double click to fit again
+                            if response.hovered() {
+                                let scroll = ui.input(|i| i.smooth_scroll_delta.y);
+                                if scroll != 0.0 {
+                                    self.preview_viewport.zoom_by((scroll * 0.002).exp());
+                                }
+                            }
+                            if response.dragged() {
+                                self.preview_viewport
+                                    .pan_by(response.drag_delta(), rect.size());
+                            }
+                            if response.double_clicked() {
+                                self.preview_viewport = PreviewViewport::default();
+                            }
+
+                            let uv = self.preview_viewport.uv_rect();
+                            let painter = ui.painter_at(rect);
+                            painter.image(texture.id(), rect, uv, egui::Color32::WHITE);
+
+                            if self.show_grid {
+                                let stroke = egui::Stroke::new(
+                                    1.0,
+                                    egui::Color32::from_rgba_unmultiplied(255, 255, 0, 96),
+                                );
+                                let font = egui::FontId::monospace(10.0);
+                                let color = egui::Color32::YELLOW;
+                                // Lines sit on pixel edges, half a pixel before the centers
+                                let to_screen = |x: f32, y: f32| {
+                                    image_to_screen(
+                                        (x - 0.5, y - 0.5),
+                                        (image_width, image_height),
+                                        uv,
+                                        rect,
+                                    )
+                                };
+
+                                for x in grid_lines(
+                                    uv.min.x * image_width,
+                                    uv.max.x * image_width,
+                                    self.grid_spacing,
+                                ) {
+                                    let screen_x = to_screen(x as f32, 0.0).x;
+                                    painter.vline(screen_x, rect.y_range(), stroke);
+                                    painter.text(
+                                        Pos2::new(screen_x + 2.0, rect.min.y + 2.0),
+                                        egui::Align2::LEFT_TOP,
+                                        x,
+                                        font.clone(),
+                                        color,
+                                    );
+                                }
+                                for y in grid_lines(
+                                    uv.min.y * image_height,
+                                    uv.max.y * image_height,
+                                    self.grid_spacing,
+                                ) {
+                                    let screen_y = to_screen(0.0, y as f32).y;
+                                    painter.hline(rect.x_range(), screen_y, stroke);
+                                    painter.text(
+                                        Pos2::new(rect.min.x + 2.0, screen_y + 2.0),
+                                        egui::Align2::LEFT_TOP,
+                                        y,
+                                        font.clone(),
+                                        color,
+                                    );
+                                }
+                            }
+
+                            if let Some(stars) =
+                                frame.stars.as_ref().filter(|_| self.show_star_overlay)
+                            {
+                                let stroke = egui::Stroke::new(1.0, egui::Color32::GREEN);
+                                let pixel_scale = rect.height() / (image_height * uv.height());
+                                for star in stars {
+                                    let center = image_to_screen(
+                                        (star.x, star.y),
+                                        (image_width, image_height),
+                                        uv,
+                                        rect,
+                                    );
+                                    if rect.contains(center) {
+                                        let radius = (star.area as f32 / std::f32::consts::PI)
+                                            .sqrt()
+                                            * pixel_scale
+                                            + 3.0;
+                                        painter.circle_stroke(center, radius, stroke);
+                                    }
+                                }
+                            }
                         });
                     } else {
                         ui.label("Preview not available");
                     }
 
+                    ui.checkbox(&mut self.show_histogram, "Show histogram");
+                    if let Some(histograms) =
+                        frame.histograms.as_ref().filter(|_| self.show_histogram)
+                    {
+                        render_histogram(ui, histograms);
+                    }
+
                     // Display some basic metadata
                     ui.label(format!(
                         "File: {}",
@@ -335,6 +1474,10 @@ impl RegistrationView {
                         frame.fits_image.metadata.dimensions.1
                     ));
 
+                    if let Some(object) = &frame.fits_image.metadata.object {
+                        ui.label(format!("Object: {}", object));
+                    }
+
                     if let Some(exposure) = frame.fits_image.metadata.exposure_time {
                         ui.label(format!("Exposure: {:.2} seconds", exposure));
                     }
@@ -347,10 +1490,22 @@ impl RegistrationView {
                         ui.label(format!("Gain: {}", gain));
                     }
 
+                    if let Some(offset) = frame.fits_image.metadata.offset {
+                        ui.label(format!("Offset: {}", offset));
+                    }
+
                     if let Some(temp) = frame.fits_image.metadata.temperature {
                         ui.label(format!("Temperature: {:.1}°C", temp));
                     }
 
+                    if let Some(scale) = frame.fits_image.metadata.pixel_scale() {
+                        ui.label(format!("Pixel scale: {:.2}\"/px", scale));
+                    }
+
+                    if let Some(reason) = &frame.reject_reason {
+                        ui.label(format!("Rejected: {}", reason));
+                    }
+
                     ui.label(format!(
                         "Pixel Type: {}",
                         match frame.fits_image.metadata.pixel_type {
@@ -361,6 +1516,7 @@ impl RegistrationView {
                             crate::image::PixelType::U32 => "U32",
                             crate::image::PixelType::I16 => "I16",
                             crate::image::PixelType::I32 => "I32",
+                            crate::image::PixelType::I64 => "I64",
                         }
                     ));
                 }
@@ -382,36 +1538,75 @@ impl RegistrationView {
                 .min_scrolled_height(600.0)
                 .show(ui, |ui| {
                     Grid::new(format!("frames_table_{:?}", frame_type))
-                        .num_columns(6)
+                        .num_columns(12)
                         .striped(true)
                         .min_col_width(60.0)
                         .show(ui, |ui| {
                             // Header row
                             ui.strong("Use");
+                            ui.strong("");
                             ui.strong("Filename");
+                            ui.strong("Object");
                             ui.strong("Exposure");
                             ui.strong("Filter");
                             ui.strong("Gain");
                             ui.strong("Temperature");
+                            ui.strong("Airmass");
+                            ui.strong("FWHM");
+                            ui.strong("Eccentricity");
                             ui.strong("Preview");
                             ui.end_row();
 
-                            // Data rows
-                            for (idx, frame) in frames.iter_mut().enumerate() {
-                                // Checkbox for selection
-                                let mut selected = frame.selected;
-                                if ui.checkbox(&mut selected, "").changed() {
-                                    frame.selected = selected;
+                            // Data rows
+                            for (idx, frame) in frames.iter_mut().enumerate() {
+                                // File name
+                                let file_name = frame
+                                    .path
+                                    .file_name()
+                                    .unwrap_or_default()
+                                    .to_string_lossy()
+                                    .to_string();
+
+                                // Hide rows that don't match the search, keeping their selection
+                                if !matches_filename_query(&file_name, &self.search_query) {
+                                    continue;
+                                }
+
+                                // Checkbox for selection
+                                let mut selected = frame.selected;
+                                let mut checkbox = ui.checkbox(&mut selected, "");
+                                if let Some(reason) = &frame.reject_reason {
+                                    checkbox = checkbox.on_hover_text(reason.as_str());
+                                }
+                                if checkbox.changed() {
+                                    if selected {
+                                        frame.accept();
+                                    } else {
+                                        frame.reject("user rejected");
+                                    }
+                                }
+
+                                // Thumbnail, once the background worker has rendered it
+                                if let Some(thumbnail) = &frame.thumbnail {
+                                    ui.add(
+                                        egui::Image::new(thumbnail)
+                                            .max_height(THUMBNAIL_ROW_HEIGHT),
+                                    );
+                                } else {
+                                    ui.label("-");
+                                }
+
+                                let name_label = ui.label(&file_name);
+                                if let Some(reason) = &frame.reject_reason {
+                                    name_label.on_hover_text(format!("Rejected: {}", reason));
                                 }
 
-                                // File name
-                                let file_name = frame
-                                    .path
-                                    .file_name()
-                                    .unwrap_or_default()
-                                    .to_string_lossy()
-                                    .to_string();
-                                ui.label(&file_name);
+                                // Target name
+                                if let Some(object) = &frame.fits_image.metadata.object {
+                                    ui.label(object);
+                                } else {
+                                    ui.label("-");
+                                }
 
                                 // Exposure time
                                 if let Some(exposure) = frame.fits_image.metadata.exposure_time {
@@ -441,6 +1636,38 @@ impl RegistrationView {
                                     ui.label("-");
                                 }
 
+                                // Airmass
+                                if let Some(airmass) = frame.fits_image.metadata.airmass {
+                                    ui.label(format!("{:.2}", airmass));
+                                } else {
+                                    ui.label("-");
+                                }
+
+                                // FWHM
+                                if let (Some(fwhm), Some(arcsec)) =
+                                    (frame.fwhm, frame.fwhm_arcsec())
+                                {
+                                    ui.label(format!("{:.2}\"", arcsec))
+                                        .on_hover_text(format!("{:.2}px", fwhm));
+                                } else if let Some(fwhm) = frame.fwhm {
+                                    ui.label(format!("{:.2}px", fwhm));
+                                } else {
+                                    ui.label("-");
+                                }
+
+                                // Eccentricity, highlighted when the stars are elongated
+                                if let Some(eccentricity) = frame.eccentricity {
+                                    let text = format!("{:.2}", eccentricity);
+                                    if frame.has_elongated_stars() {
+                                        ui.colored_label(egui::Color32::YELLOW, text)
+                                            .on_hover_text("Elongated stars");
+                                    } else {
+                                        ui.label(text);
+                                    }
+                                } else {
+                                    ui.label("-");
+                                }
+
                                 // Preview button with different styling for currently selected image
                                 let is_selected = self.selected_frame_indices.get(&frame_type)
                                     == Some(&Some(idx));
@@ -464,8 +1691,21 @@ impl RegistrationView {
 
     /// Render the registration view UI
     pub fn ui(&mut self, ctx: &Context, ui: &mut Ui) {
-        println!("Available width: {}", ui.available_width());
-        println!("Available height: {}", ui.available_height());
+        log::trace!("Available width: {}", ui.available_width());
+        log::trace!("Available height: {}", ui.available_height());
+
+        self.update_load_worker();
+        self.update_live_watch();
+        if let Some(worker) = &self.load_worker {
+            let total = worker.total;
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(format!("Loading frames {}/{}", self.loaded_count, total));
+                if ui.button("Cancel loading").clicked() {
+                    self.cancel_loading();
+                }
+            });
+        }
 
         // Tab bar for different frame types
         ui.horizontal(|ui| {
@@ -515,16 +1755,37 @@ impl RegistrationView {
 
         ui.add_space(8.0);
 
+        self.handle_selection_shortcuts(ctx);
+
+        // Pre-generate the rest of the tab's previews in the background
+        self.update_preview_worker(ctx);
+        self.update_thumbnail_workers(ctx);
+
         // If there are frames for this type, ensure preview for the selected frame
         if let Some(selected) = self
             .selected_frame_indices
             .get(&self.active_tab)
-            .unwrap_or(&None)
+            .copied()
+            .flatten()
         {
-            let _ = self.ensure_preview(self.active_tab, *selected, ctx);
+            let _ = self.ensure_preview(self.active_tab, selected, ctx);
+        }
+
+        // Keep both blink frames ready and schedule the next switch
+        if self.blink.enabled {
+            let _ = self.ensure_preview(self.active_tab, self.blink.frame_a, ctx);
+            let _ = self.ensure_preview(self.active_tab, self.blink.frame_b, ctx);
+
+            let now = ctx.input(|i| i.time);
+            self.blink.update(now);
+            if self.blink.auto_blink {
+                ctx.request_repaint_after(Duration::from_secs_f64(
+                    self.blink.time_until_switch(now),
+                ));
+            }
         }
 
-        println!(
+        log::trace!(
             "Available height before horizontal: {}",
             ui.available_height()
         );
@@ -533,7 +1794,7 @@ impl RegistrationView {
 
         // Use a horizontal layout with controlled sizing for preview and table
         ui.horizontal(|ui| {
-            println!(
+            log::trace!(
                 "Available size horizontal: {}x{}, horiontal_ui_height: {}",
                 ui.available_width(),
                 ui.available_height(),
@@ -543,7 +1804,7 @@ impl RegistrationView {
 
             // Left side: Preview section with fixed width
             ui.vertical(|ui| {
-                println!(
+                log::trace!(
                     "Available size for preview: {}x{}",
                     ui.available_width(),
                     ui.available_height()
@@ -554,8 +1815,9 @@ impl RegistrationView {
                 ui.group(|ui| {
                     ui.heading("Preview");
                     ui.set_width(half_available_width);
+                    self.render_blink_controls(ui);
                     ui.set_height(ui.available_height());
-                    println!(
+                    log::trace!(
                         "Preview section size: {}x{}",
                         ui.available_width(),
                         ui.available_height()
@@ -573,6 +1835,16 @@ impl RegistrationView {
 
                     ui.add_space(8.0);
 
+                    ui.horizontal(|ui| {
+                        ui.label("Search:");
+                        ui.text_edit_singleline(&mut self.search_query);
+                        if !self.search_query.is_empty() && ui.button("Clear").clicked() {
+                            self.search_query.clear();
+                        }
+                    });
+
+                    ui.add_space(8.0);
+
                     self.render_frame_table(ui, self.active_tab);
 
                     ui.add_space(8.0);
@@ -582,23 +1854,293 @@ impl RegistrationView {
                         if ui.button("Select All").clicked() {
                             if let Some(frames) = self.frames.get_mut(&self.active_tab) {
                                 for frame in frames {
-                                    frame.selected = true;
+                                    frame.accept();
                                 }
                             }
                         }
                         if ui.button("Deselect All").clicked() {
                             if let Some(frames) = self.frames.get_mut(&self.active_tab) {
                                 for frame in frames {
-                                    frame.selected = false;
+                                    frame.reject("user rejected");
+                                }
+                            }
+                        }
+                        if ui
+                            .button("Reject elongated")
+                            .on_hover_text(format!(
+                                "Reject frames with a median star eccentricity above {:.2}",
+                                MAX_ECCENTRICITY
+                            ))
+                            .clicked()
+                        {
+                            if let Some(frames) = self.frames.get_mut(&self.active_tab) {
+                                for frame in frames {
+                                    frame.reject_if_elongated();
                                 }
                             }
                         }
+                        if ui.button("Export CSV").clicked() {
+                            self.export_csv();
+                        }
+                    });
+
+                    if let Some(status) = &self.export_status {
+                        ui.label(status);
+                    }
+
+                    ui.add_space(8.0);
+
+                    self.render_batch_keyword_editor(ui);
+                    self.render_bulk_operations(ui);
+
+                    ui.add_space(8.0);
+
+                    egui::CollapsingHeader::new("Session quality").show(ui, |ui| {
+                        let frames = self.frames.get(&self.active_tab).map(Vec::as_slice);
+                        render_session_plot(ui, frames.unwrap_or_default());
                     });
                 });
             });
         });
     }
 
+    /// Ask for a destination and write the active tab's frame table to it as CSV
+    fn export_csv(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Export frame table")
+            .add_filter("CSV", &["csv"])
+            .set_file_name("frames.csv")
+            .save_file()
+        else {
+            return;
+        };
+
+        let mut csv = String::from(FRAME_CSV_HEADER);
+        csv.push('\n');
+        for frame in self.frames.get(&self.active_tab).into_iter().flatten() {
+            csv.push_str(&frame_csv_row(frame));
+            csv.push('\n');
+        }
+
+        self.export_status = Some(match std::fs::write(&path, csv) {
+            Ok(()) => format!("Exported to {}", path.display()),
+            Err(e) => format!("Export failed: {}", e),
+        });
+    }
+
+    /// Space or X toggles the previewed frame, Delete rejects it and moves to the next one.
+    /// Ignored while a text field has focus.
+    fn handle_selection_shortcuts(&mut self, ctx: &Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+
+        let (toggle, reject) = ctx.input_mut(|input| {
+            let toggle = input.consume_key(egui::Modifiers::NONE, egui::Key::Space)
+                | input.consume_key(egui::Modifiers::NONE, egui::Key::X);
+            let reject = input.consume_key(egui::Modifiers::NONE, egui::Key::Delete);
+            (toggle, reject)
+        });
+
+        if toggle {
+            self.toggle_current_frame();
+        }
+        if reject {
+            self.reject_current_and_advance();
+        }
+    }
+
+    /// Flip whether the previewed frame of the active tab is used for processing
+    fn toggle_current_frame(&mut self) {
+        let Some(index) = self
+            .selected_frame_indices
+            .get(&self.active_tab)
+            .copied()
+            .flatten()
+        else {
+            return;
+        };
+        if let Some(frame) = self
+            .frames
+            .get_mut(&self.active_tab)
+            .and_then(|frames| frames.get_mut(index))
+        {
+            if frame.selected {
+                frame.reject("user rejected");
+            } else {
+                frame.accept();
+            }
+        }
+    }
+
+    /// Reject the previewed frame of the active tab and preview the next one, staying on
+    /// the last frame at the end of the list
+    fn reject_current_and_advance(&mut self) {
+        let Some(index) = self
+            .selected_frame_indices
+            .get(&self.active_tab)
+            .copied()
+            .flatten()
+        else {
+            return;
+        };
+        let Some(frames) = self.frames.get_mut(&self.active_tab) else {
+            return;
+        };
+        let Some(frame) = frames.get_mut(index) else {
+            return;
+        };
+
+        frame.reject("user rejected");
+        let next = (index + 1).min(frames.len() - 1);
+        self.selected_frame_indices
+            .insert(self.active_tab, Some(next));
+    }
+
+    fn render_blink_controls(&mut self, ui: &mut Ui) {
+        let file_names: Vec<String> = self
+            .frames
+            .get(&self.active_tab)
+            .map(|frames| {
+                frames
+                    .iter()
+                    .map(|f| {
+                        f.path
+                            .file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if file_names.len() < 2 {
+            self.blink.enabled = false;
+            return;
+        }
+
+        // Keep the compared frames valid when the active tab changes
+        self.blink.frame_a = self.blink.frame_a.min(file_names.len() - 1);
+        self.blink.frame_b = self.blink.frame_b.min(file_names.len() - 1);
+
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.blink.enabled, "Blink compare");
+
+            if !self.blink.enabled {
+                return;
+            }
+
+            for (label, index) in [
+                ("A", &mut self.blink.frame_a),
+                ("B", &mut self.blink.frame_b),
+            ] {
+                ComboBox::from_id_salt(format!("blink_frame_{}", label))
+                    .selected_text(format!("{}: {}", label, file_names[*index]))
+                    .show_ui(ui, |ui| {
+                        for (i, name) in file_names.iter().enumerate() {
+                            ui.selectable_value(index, i, name);
+                        }
+                    });
+            }
+        });
+
+        if self.blink.enabled {
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.blink.auto_blink, "Auto");
+                ui.add(egui::Slider::new(&mut self.blink.interval, 0.1..=3.0).text("Interval (s)"));
+
+                let showing = self.blink.showing();
+                if ui.selectable_label(showing == BlinkSide::A, "A").clicked() {
+                    self.blink.show(BlinkSide::A);
+                }
+                if ui.selectable_label(showing == BlinkSide::B, "B").clicked() {
+                    self.blink.show(BlinkSide::B);
+                }
+            });
+        }
+    }
+
+    fn render_batch_keyword_editor(&mut self, ui: &mut Ui) {
+        ui.collapsing("Set keyword for selected frames", |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Keyword:");
+                ui.add(egui::TextEdit::singleline(&mut self.batch_key).desired_width(80.0));
+                ui.label("Value:");
+                ui.add(egui::TextEdit::singleline(&mut self.batch_value).desired_width(120.0));
+            });
+
+            ui.checkbox(
+                &mut self.batch_save_to_disk,
+                "Also update the files on disk",
+            );
+
+            if ui.button("Set keyword for all selected frames").clicked() {
+                let key = self.batch_key.clone();
+                let value = self.batch_value.clone();
+                let save = self.batch_save_to_disk;
+                self.batch_status = Some(
+                    match self.set_keyword_for_selected(self.active_tab, &key, &value, save) {
+                        Ok(count) => format!("Set {} on {} frame(s)", key.to_uppercase(), count),
+                        Err(errors) => {
+                            format!("{} frame(s) failed: {}", errors.len(), errors.join("; "))
+                        }
+                    },
+                );
+            }
+
+            if let Some(status) = &self.batch_status {
+                ui.label(status);
+            }
+        });
+    }
+
+    /// Set a FITS keyword on every selected frame of a type, optionally updating the files.
+    ///
+    /// Returns the number of frames updated, or the error message for each frame that failed.
+    pub fn set_keyword_for_selected(
+        &mut self,
+        frame_type: FrameType,
+        key: &str,
+        value: &str,
+        save_to_disk: bool,
+    ) -> Result<usize, Vec<String>> {
+        let mut updated = 0;
+        let mut errors = Vec::new();
+
+        // Queued jobs lose track of edited frames
+        self.stop_workers(frame_type);
+
+        if let Some(frames) = self.frames.get_mut(&frame_type) {
+            for frame in frames.iter_mut().filter(|frame| frame.selected) {
+                let result = Arc::make_mut(&mut frame.fits_image)
+                    .set_metadata_key(key, value)
+                    .and_then(|_| {
+                        if save_to_disk {
+                            FitsImage::update_header_key(&frame.path, key, value)
+                        } else {
+                            Ok(())
+                        }
+                    });
+
+                match result {
+                    Ok(()) => updated += 1,
+                    Err(e) => errors.push(format!(
+                        "{}: {}",
+                        frame.path.file_name().unwrap_or_default().to_string_lossy(),
+                        e
+                    )),
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(updated)
+        } else {
+            Err(errors)
+        }
+    }
+
     /// Get all selected frames of a specific type
     pub fn get_selected_frames(&self, frame_type: FrameType) -> Vec<PathBuf> {
         self.frames
@@ -612,4 +2154,572 @@ impl RegistrationView {
             })
             .unwrap_or_default()
     }
+
+    /// Number of selected frames of a specific type
+    pub fn selected_count(&self, frame_type: FrameType) -> usize {
+        self.frames.get(&frame_type).map_or(0, |frames| {
+            frames.iter().filter(|frame| frame.selected).count()
+        })
+    }
+
+    /// Paths and images of the selected frames of a specific type
+    pub fn selected_images(&self, frame_type: FrameType) -> Vec<(PathBuf, Arc<FitsImage>)> {
+        self.frames
+            .get(&frame_type)
+            .map(|frames| {
+                frames
+                    .iter()
+                    .filter(|frame| frame.selected)
+                    .map(|frame| (frame.path.clone(), Arc::clone(&frame.fits_image)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Median exposure time of the selected frames of a specific type
+    pub fn selected_median_exposure(&self, frame_type: FrameType) -> Option<f64> {
+        let frames = self.frames.get(&frame_type)?;
+        let selected: Vec<&RegisteredFrame> = frames.iter().filter(|f| f.selected).collect();
+        median_exposure(&selected)
+    }
+
+    /// Check that calibration frames match the exposure of the frames they calibrate, and
+    /// that flats are exposed within the sensor's linear range
+    pub fn exposure_warnings(&self) -> Vec<String> {
+        let pairs = [
+            (FrameType::Dark, "Darks", FrameType::Light, "lights"),
+            (FrameType::DarkFlat, "Dark flats", FrameType::Flat, "flats"),
+        ];
+
+        let flats = self.selected_images(FrameType::Flat);
+        let flat_level = flat_level_warning(flats.iter().map(|(_, image)| image.as_ref()));
+
+        pairs
+            .iter()
+            .filter_map(|&(calib_type, calib_name, target_type, target_name)| {
+                let warning = exposure_mismatch_warning(
+                    calib_name,
+                    self.selected_median_exposure(calib_type),
+                    target_name,
+                    self.selected_median_exposure(target_type),
+                )?;
+                // Only darks can be scaled to the exposure of the lights
+                Some(match calib_type {
+                    FrameType::Dark => format!("{} — enable dark scaling", warning),
+                    _ => warning,
+                })
+            })
+            .chain(flat_level)
+            .collect()
+    }
+
+    /// Warn when some selected lights have no selected dark that matches them
+    pub fn dark_match_warning(&self, options: &DarkMatchOptions) -> Option<String> {
+        // Matching only reads the metadata, so skip copying the pixels
+        let darks: Vec<FitsImage> = self
+            .selected_images(FrameType::Dark)
+            .into_iter()
+            .map(|(_, image)| {
+                let mut dark = FitsImage::new(0, 0);
+                dark.metadata = image.metadata.clone();
+                dark
+            })
+            .collect();
+        if darks.is_empty() {
+            return None;
+        }
+
+        let lights = self.selected_images(FrameType::Light);
+        let unmatched = lights
+            .iter()
+            .filter(|(_, light)| match_dark_with(light, &darks, options).is_none())
+            .count();
+        (unmatched > 0).then(|| {
+            format!(
+                "{} of {} lights have no dark matching them ({})",
+                unmatched,
+                lights.len(),
+                options.strategy.name().to_lowercase()
+            )
+        })
+    }
+
+    /// Warn when the selected lights likely won't fit in memory for stacking
+    pub fn memory_warning(&self) -> Option<String> {
+        let frames = self.frames.get(&FrameType::Light)?;
+        let selected: Vec<&RegisteredFrame> = frames.iter().filter(|f| f.selected).collect();
+        let dimensions = selected.first()?.fits_image.dimensions();
+        crate::calibration::memory_warning(selected.len(), dimensions)
+    }
+}
+
+/// Size at which to draw an image so it fits the available space.
+///
+/// `pixel_aspect` is the width of a pixel relative to its height, so that images from
+/// sensors or binning modes with non-square pixels are shown with their true proportions.
+pub fn display_size(
+    image_size: (f32, f32),
+    pixel_aspect: f32,
+    available: (f32, f32),
+) -> (f32, f32) {
+    let (image_width, image_height) = image_size;
+    let (available_width, available_height) = available;
+
+    // Physical width of the image measured in pixel heights
+    let physical_width = image_width * pixel_aspect;
+
+    // Calculate scale factor to fit in the available space
+    let scale_w = available_width / physical_width;
+    let scale_h = available_height / image_height;
+    let scale = scale_w.min(scale_h);
+
+    (physical_width * scale, image_height * scale)
+}
+
+/// Case-insensitive substring match of a filename against a search query
+pub fn matches_filename_query(file_name: &str, query: &str) -> bool {
+    let query = query.trim();
+    query.is_empty() || file_name.to_lowercase().contains(&query.to_lowercase())
+}
+
+/// Median exposure time of the given frames, ignoring frames without an EXPTIME
+pub fn median_exposure(frames: &[&RegisteredFrame]) -> Option<f64> {
+    let mut exposures: Vec<f64> = frames
+        .iter()
+        .filter_map(|frame| frame.fits_image.metadata.exposure_time)
+        .collect();
+
+    if exposures.is_empty() {
+        return None;
+    }
+
+    Some(median_of(&mut exposures))
+}
+
+/// Build a warning when a calibration set's exposure differs from its target set.
+///
+/// Returns `None` when either exposure is unknown or both agree within 1%.
+pub fn exposure_mismatch_warning(
+    calib_name: &str,
+    calib_exposure: Option<f64>,
+    target_name: &str,
+    target_exposure: Option<f64>,
+) -> Option<String> {
+    let calib = calib_exposure?;
+    let target = target_exposure?;
+
+    let tolerance = 0.01 * calib.abs().max(target.abs());
+    if (calib - target).abs() <= tolerance {
+        return None;
+    }
+
+    Some(format!(
+        "{} are {}s but {} are {}s",
+        calib_name, calib, target_name, target
+    ))
+}
+
+/// Column names of the CSV written by the "Export CSV" button
+pub const FRAME_CSV_HEADER: &str = "filename,selected,exposure,filter,gain,temperature,fwhm";
+
+/// Format a frame as a row of the exported CSV, leaving unknown values empty
+pub fn frame_csv_row(frame: &RegisteredFrame) -> String {
+    let metadata = &frame.fits_image.metadata;
+    let file_name = frame.path.file_name().unwrap_or_default().to_string_lossy();
+
+    format!(
+        "{},{},{},{},{},{},{}",
+        csv_escape(&file_name),
+        frame.selected,
+        metadata
+            .exposure_time
+            .map(|v| format!("{:.3}", v))
+            .unwrap_or_default(),
+        csv_escape(metadata.filter.as_deref().unwrap_or_default()),
+        metadata.iso_gain.map(|v| v.to_string()).unwrap_or_default(),
+        metadata
+            .temperature
+            .map(|v| format!("{:.1}", v))
+            .unwrap_or_default(),
+        frame.fwhm.map(|v| format!("{:.3}", v)).unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame at `path` holding `image`, as if it had been read from there
+    fn frame(path: &str, image: FitsImage) -> RegisteredFrame {
+        let mut frame = RegisteredFrame::new(PathBuf::from(path), image.frame_type);
+        frame.fits_image = Arc::new(image);
+        frame
+    }
+
+    fn image() -> FitsImage {
+        FitsImage::from_data(ArrayD::from_elem(vec![4, 4], 100.0))
+    }
+
+    #[test]
+    fn csv_row_formats_the_table_columns() {
+        let mut image = image();
+        image.metadata.exposure_time = Some(300.0);
+        image.metadata.filter = Some("Ha, 7nm".to_string());
+        image.metadata.iso_gain = Some(120.0);
+        image.metadata.temperature = Some(-10.04);
+        let mut frame = frame("/data/light_001.fits", image);
+        frame.fwhm = Some(2.5);
+
+        assert_eq!(
+            frame_csv_row(&frame),
+            "light_001.fits,true,300.000,\"Ha, 7nm\",120,-10.0,2.500"
+        );
+    }
+
+    #[test]
+    fn csv_row_leaves_unknown_values_empty() {
+        let mut frame = frame("light_002.fits", image());
+        frame.reject("user rejected");
+
+        assert_eq!(frame_csv_row(&frame), "light_002.fits,false,,,,,");
+        assert_eq!(FRAME_CSV_HEADER.split(',').count(), 7);
+    }
+
+    #[test]
+    fn metrics_arrive_after_the_frame_is_read() {
+        let mut frame = frame("light_003.fits", image());
+        assert_eq!((frame.fwhm, frame.background), (None, None));
+
+        frame.set_metrics(&FrameMetrics::measure(&frame.fits_image));
+        assert_eq!(frame.background, Some(100.0));
+
+        frame.refresh_after_edit();
+        assert_eq!(frame.background, None);
+    }
+
+    #[test]
+    fn auto_reject_gives_the_eccentricity_as_the_reason() {
+        let mut elongated = frame("light_004.fits", image());
+        elongated.eccentricity = Some(0.82);
+        let mut round = frame("light_005.fits", image());
+        round.eccentricity = Some(0.2);
+
+        assert!(elongated.reject_if_elongated());
+        assert!(!elongated.selected);
+        assert_eq!(
+            elongated.reject_reason.as_deref(),
+            Some("eccentricity 0.82 > 0.60")
+        );
+
+        assert!(!round.reject_if_elongated());
+        assert!(round.selected);
+        assert_eq!(round.reject_reason, None);
+    }
+
+    #[test]
+    fn exposure_mismatch_flags_differing_exposures_only() {
+        assert_eq!(
+            exposure_mismatch_warning("Darks", Some(60.0), "lights", Some(120.0)).as_deref(),
+            Some("Darks are 60s but lights are 120s")
+        );
+        assert_eq!(
+            exposure_mismatch_warning("Darks", Some(120.0), "lights", Some(120.5)),
+            None
+        );
+        assert_eq!(
+            exposure_mismatch_warning("Darks", None, "lights", Some(120.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn median_exposure_skips_frames_without_exptime() {
+        let frames: Vec<RegisteredFrame> = [Some(60.0), None, Some(120.0), Some(300.0)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, exposure)| {
+                let mut image = image();
+                image.metadata.exposure_time = exposure;
+                frame(&format!("dark_{}.fits", i), image)
+            })
+            .collect();
+        let frames: Vec<&RegisteredFrame> = frames.iter().collect();
+
+        assert_eq!(median_exposure(&frames), Some(120.0));
+        assert_eq!(median_exposure(&frames[1..2]), None);
+    }
+
+    #[test]
+    fn filename_query_matches_substrings_ignoring_case() {
+        let names = ["Light_M31_001.fits", "light_m31_002.fits", "Dark_300s.fits"];
+        let matching = |query: &str| -> Vec<&str> {
+            names
+                .into_iter()
+                .filter(|name| matches_filename_query(name, query))
+                .collect()
+        };
+
+        assert_eq!(
+            matching("m31"),
+            ["Light_M31_001.fits", "light_m31_002.fits"]
+        );
+        assert_eq!(matching(" DARK "), ["Dark_300s.fits"]);
+        assert_eq!(matching(""), names);
+        assert!(matching("flat").is_empty());
+    }
+
+    #[test]
+    fn display_size_widens_images_of_wide_pixels() {
+        let mut image = image();
+        image.metadata.pixel_size_x = Some(7.52);
+        image.metadata.pixel_size_y = Some(3.76);
+        let aspect = image.metadata.pixel_aspect_ratio() as f32;
+        assert_eq!(aspect, 2.0);
+
+        assert_eq!(
+            display_size((100.0, 100.0), aspect, (400.0, 400.0)),
+            (400.0, 200.0)
+        );
+        assert_eq!(
+            display_size((100.0, 100.0), 1.0, (400.0, 300.0)),
+            (300.0, 300.0)
+        );
+
+        image.metadata.pixel_size_y = None;
+        assert_eq!(image.metadata.pixel_aspect_ratio(), 1.0);
+    }
+
+    #[test]
+    fn blink_switches_sides_once_per_interval() {
+        let mut blink = BlinkComparator {
+            enabled: true,
+            frame_a: 2,
+            frame_b: 5,
+            ..BlinkComparator::default()
+        };
+        blink.update(10.0);
+        assert_eq!((blink.showing(), blink.current_frame()), (BlinkSide::B, 5));
+
+        assert!(!blink.update(10.3));
+        assert!((blink.time_until_switch(10.3) - 0.2).abs() < 1e-9);
+        assert!(blink.update(10.5));
+        assert_eq!((blink.showing(), blink.current_frame()), (BlinkSide::A, 2));
+
+        // Without auto blink only manual toggles switch, and the timer restarts
+        blink.auto_blink = false;
+        assert!(!blink.update(20.0));
+        blink.toggle();
+        assert_eq!(blink.showing(), BlinkSide::B);
+        blink.auto_blink = true;
+        assert!(!blink.update(20.4));
+        assert!(blink.update(20.5));
+    }
+
+    #[test]
+    fn star_positions_follow_zoom_and_pan() {
+        // A 100 x 50 image drawn at twice its size
+        let image_size = (100.0, 50.0);
+        let screen = Rect::from_min_size(Pos2::new(10.0, 20.0), Vec2::new(200.0, 100.0));
+        let close = |a: Pos2, b: Pos2| (a - b).length() < 1e-3;
+        let mut viewport = PreviewViewport::default();
+
+        let at = |viewport: &PreviewViewport, point| {
+            image_to_screen(point, image_size, viewport.uv_rect(), screen)
+        };
+        assert!(close(at(&viewport, (0.0, 0.0)), Pos2::new(11.0, 21.0)));
+        assert!(close(at(&viewport, (49.5, 24.5)), screen.center()));
+
+        // Zooming keeps the center fixed and spreads everything else out from it
+        viewport.zoom_by(2.0);
+        assert!(close(at(&viewport, (49.5, 24.5)), screen.center()));
+        assert!(close(at(&viewport, (24.5, 12.0)), screen.min));
+
+        // Dragging right by half the view shows the left half of the image
+        viewport.pan_by(Vec2::new(100.0, 0.0), screen.size());
+        assert!(close(at(&viewport, (24.5, 12.0)), Pos2::new(110.0, 20.0)));
+    }
+
+    #[test]
+    fn thumbnails_are_downsampled_to_the_thumbnail_size() {
+        let mono = FitsImage::from_data(ArrayD::from_shape_fn(vec![200, 300], |index| {
+            (index[0] + index[1]) as f32
+        }));
+        let thumbnail = thumbnail_image(&mono).unwrap();
+        // 300 pixels need a factor of 3 to fit in 128; the partial last row is kept
+        assert_eq!(thumbnail.size, [100, 67]);
+
+        let color = FitsImage::from_data(ArrayD::from_shape_fn(vec![3, 64, 96], |index| {
+            (index[0] * 100 + index[2]) as f32
+        }));
+        assert_eq!(thumbnail_image(&color).unwrap().size, [96, 64]);
+
+        // Blocks average their finite pixels, edge blocks over the pixels they have
+        let data = ArrayD::from_shape_vec(
+            vec![3, 3],
+            vec![1.0, 3.0, 10.0, f32::NAN, 5.0, 20.0, 7.0, 8.0, 9.0],
+        )
+        .unwrap();
+        let small = downsample(&data, 2);
+        assert_eq!(small.shape(), [2, 2]);
+        assert_eq!(
+            small.iter().copied().collect::<Vec<_>>(),
+            [3.0, 15.0, 7.5, 9.0]
+        );
+    }
+
+    #[test]
+    fn shortcuts_toggle_and_reject_the_previewed_frame() {
+        let mut view = RegistrationView::new();
+        let frames = (0..3)
+            .map(|i| frame(&format!("light_{i:03}.fits"), image()))
+            .collect();
+        view.frames.insert(FrameType::Light, frames);
+        view.selected_frame_indices
+            .insert(FrameType::Light, Some(1));
+        let selected = |view: &RegistrationView| -> Vec<bool> {
+            view.frames[&FrameType::Light]
+                .iter()
+                .map(|frame| frame.selected)
+                .collect()
+        };
+
+        view.toggle_current_frame();
+        assert_eq!(selected(&view), [true, false, true]);
+        view.toggle_current_frame();
+        assert_eq!(selected(&view), [true, true, true]);
+        assert_eq!(view.frames[&FrameType::Light][1].reject_reason, None);
+
+        view.reject_current_and_advance();
+        assert_eq!(selected(&view), [true, false, true]);
+        assert_eq!(view.selected_frame_indices[&FrameType::Light], Some(2));
+
+        // Rejecting the last frame stays on it
+        view.reject_current_and_advance();
+        assert_eq!(selected(&view), [true, false, false]);
+        assert_eq!(view.selected_frame_indices[&FrameType::Light], Some(2));
+    }
+
+    #[test]
+    fn only_darks_are_told_to_enable_dark_scaling() {
+        let exposed = |name: &str, exposure: f64| {
+            let mut image = image();
+            image.metadata.exposure_time = Some(exposure);
+            // Without a full scale, the flat's level isn't checked
+            image.metadata.pixel_type = crate::image::PixelType::F32;
+            frame(name, image)
+        };
+        let mut view = RegistrationView::new();
+        view.frames
+            .insert(FrameType::Light, vec![exposed("light.fits", 120.0)]);
+        view.frames
+            .insert(FrameType::Dark, vec![exposed("dark.fits", 60.0)]);
+        view.frames
+            .insert(FrameType::Flat, vec![exposed("flat.fits", 2.0)]);
+        view.frames
+            .insert(FrameType::DarkFlat, vec![exposed("darkflat.fits", 1.0)]);
+
+        assert_eq!(
+            view.exposure_warnings(),
+            [
+                "Darks are 60s but lights are 120s — enable dark scaling",
+                "Dark flats are 1s but flats are 2s",
+            ]
+        );
+    }
+
+    #[test]
+    fn star_masks_are_saved_beside_their_frame() {
+        assert_eq!(
+            star_mask_path(Path::new("/data/light_001.fits")),
+            PathBuf::from("/data/light_001_starmask.fits")
+        );
+        assert_eq!(
+            star_mask_path(Path::new("/data/M31.2024-10-01.fit.gz")),
+            PathBuf::from("/data/M31.2024-10-01_starmask.fits")
+        );
+    }
+
+    #[test]
+    fn grid_lines_cover_the_visible_region() {
+        assert_eq!(grid_lines(0.0, 350.0, 100), [0, 100, 200, 300]);
+        assert_eq!(grid_lines(-20.0, 150.0, 50), [0, 50, 100]);
+        assert!(grid_lines(0.0, 350.0, 0).is_empty());
+
+        // A 1000 x 600 image zoomed in 4x around its center shows x 375..625, y 225..375
+        let mut viewport = PreviewViewport::default();
+        viewport.zoom_by(4.0);
+        let uv = viewport.uv_rect();
+        assert_eq!(
+            grid_lines(uv.min.x * 1000.0, uv.max.x * 1000.0, 100),
+            [400, 500, 600]
+        );
+        assert_eq!(grid_lines(uv.min.y * 600.0, uv.max.y * 600.0, 100), [300]);
+    }
+
+    #[test]
+    fn preview_lru_evicts_the_least_recently_viewed_frames() {
+        let light = |index| (FrameType::Light, index);
+        let mut lru = PreviewLru::new(3);
+        for index in 0..3 {
+            assert!(lru.touch(light(index)).is_empty());
+        }
+
+        // Viewing frame 0 again makes frame 1 the least recently used
+        assert!(lru.touch(light(0)).is_empty());
+        assert_eq!(lru.touch(light(3)), [light(1)]);
+        assert_eq!(lru.touch(light(4)), [light(2)]);
+        assert_eq!(lru.len(), 3);
+
+        lru.touch((FrameType::Dark, 0));
+        lru.remove_tab(FrameType::Light);
+        assert_eq!(lru.len(), 1);
+        assert!(lru.touch(light(5)).is_empty());
+    }
+
+    #[test]
+    fn bulk_operations_only_touch_selected_frames() {
+        let mut view = RegistrationView::new();
+        let mut frames: Vec<RegisteredFrame> = (0..3)
+            .map(|i| frame(&format!("light_{i:03}.fits"), image()))
+            .collect();
+        frames[1].reject("user rejected");
+        Arc::make_mut(&mut frames[2].fits_image)
+            .data_mut()
+            .fill(50.0);
+        frames[0].fwhm = Some(2.5);
+        view.frames.insert(FrameType::Light, frames);
+
+        let errors = view.apply_to_selected(FrameType::Light, |image| {
+            if image.data()[[0, 0]] < 60.0 {
+                return Err(ImageError::FormatError("too dim".to_string()));
+            }
+            *image.data_mut() += 10.0;
+            Ok(())
+        });
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, PathBuf::from("light_002.fits"));
+        let frames = &view.frames[&FrameType::Light];
+        let values: Vec<f32> = frames
+            .iter()
+            .map(|frame| frame.fits_image.data()[[0, 0]])
+            .collect();
+        assert_eq!(values, [110.0, 100.0, 50.0]);
+        // Edited frames are measured again
+        assert_eq!(frames[0].fwhm, None);
+    }
+
+    #[test]
+    fn fwhm_is_converted_to_arcseconds_when_the_scale_is_known() {
+        let mut frame = frame("light_001.fits", image());
+        frame.fwhm = Some(2.5);
+        assert_eq!(frame.fwhm_arcsec(), None);
+
+        // 3.76 um pixels at 250 mm give 3.1022"/px
+        let metadata = &mut Arc::make_mut(&mut frame.fits_image).metadata;
+        metadata.pixel_size_x = Some(3.76);
+        metadata.focal_length = Some(250.0);
+        let arcsec = frame.fwhm_arcsec().unwrap();
+        assert!((arcsec - 7.7556).abs() < 1e-3, "{}", arcsec);
+    }
 }
@@ -0,0 +1,98 @@
+use eframe::egui::{self, Ui};
+use egui_plot::{Line, Plot, PlotPoints, Points};
+
+use crate::gui::registration::RegisteredFrame;
+
+/// Height of each metric plot in the session quality section
+const PLOT_HEIGHT: f32 = 120.0;
+
+/// `[hours since the first frame, value]` points of a per-frame metric, in acquisition
+/// order. Frames without a DATE-OBS or without a value for the metric are left out.
+pub fn time_series<F>(frames: &[RegisteredFrame], metric: F) -> Vec<[f64; 2]>
+where
+    F: Fn(&RegisteredFrame) -> Option<f64>,
+{
+    let mut points: Vec<(f64, f64)> = frames
+        .iter()
+        .filter_map(|frame| {
+            let time = frame.fits_image.metadata.observation_time()?;
+            Some((time, metric(frame)?))
+        })
+        .filter(|(_, value)| value.is_finite())
+        .collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let start = points.first().map_or(0.0, |&(time, _)| time);
+    points
+        .into_iter()
+        .map(|(time, value)| [(time - start) / 3600.0, value])
+        .collect()
+}
+
+/// Plot the frames' background level and FWHM against acquisition time, so clouds,
+/// dew or focus drift show up as a trend. The plots share their time axis.
+pub fn render_session_plot(ui: &mut Ui, frames: &[RegisteredFrame]) {
+    let background = time_series(frames, |frame| frame.background.map(f64::from));
+    let fwhm = time_series(frames, |frame| frame.fwhm.map(f64::from));
+
+    if background.is_empty() {
+        ui.label("No frames with a DATE-OBS to plot");
+        return;
+    }
+
+    for (id, name, points) in [
+        ("session_background_plot", "Background", background),
+        ("session_fwhm_plot", "FWHM (px)", fwhm),
+    ] {
+        ui.label(name);
+        Plot::new(id)
+            .height(PLOT_HEIGHT)
+            .link_axis("session_plot", [true, false])
+            .x_axis_label("Hours since first frame")
+            .allow_scroll(false)
+            .show(ui, |plot_ui| {
+                plot_ui.line(Line::new(PlotPoints::from(points.clone())).name(name));
+                plot_ui.points(
+                    Points::new(PlotPoints::from(points))
+                        .radius(2.0)
+                        .color(egui::Color32::LIGHT_BLUE),
+                );
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::FitsImage;
+
+    fn frame(date_obs: Option<&str>, background: Option<f32>) -> RegisteredFrame {
+        let mut image = FitsImage::from_data(ArrayD::from_elem(vec![2, 2], 100.0));
+        image.metadata.date_obs = date_obs.map(str::to_string);
+        let mut frame = RegisteredFrame::from_image(PathBuf::from("light.fits"), image);
+        frame.background = background;
+        frame
+    }
+
+    #[test]
+    fn time_series_is_sorted_by_acquisition_time() {
+        let frames = [
+            frame(Some("2024-03-01T23:30:00"), Some(300.0)),
+            frame(Some("2024-03-01T22:00:00"), Some(100.0)),
+            frame(None, Some(999.0)),
+            frame(Some("2024-03-02T00:15:00"), Some(400.0)),
+            frame(Some("2024-03-01T23:00:00"), None),
+            frame(Some("2024-03-01T22:45:00"), Some(200.0)),
+        ];
+
+        let points = time_series(&frames, |frame| frame.background.map(f64::from));
+        assert_eq!(
+            points,
+            [[0.0, 100.0], [0.75, 200.0], [1.5, 300.0], [2.25, 400.0]]
+        );
+    }
+}
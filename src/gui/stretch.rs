@@ -0,0 +1,671 @@
+use ndarray::{ArrayD, ArrayView2, ArrayViewD, Axis};
+
+use crate::image::{ImageStatistics, LuminanceWeights};
+
+/// Default softening of the arcsinh curve used by `StretchMethod::Luminance`; higher
+/// values brighten faint detail more
+pub const DEFAULT_ASINH_SOFTENING: f32 = 10.0;
+
+/// Range of the arcsinh softening offered in the view
+pub const ASINH_SOFTENING_RANGE: std::ops::RangeInclusive<f32> = 1.0..=200.0;
+
+/// Fraction of pixels `StretchMethod::PercentileLinear` clips to black, and to white
+const PERCENTILE_CLIP: f32 = 0.001;
+
+/// Histogram resolution used to find the percentile clip points
+const PERCENTILE_BINS: usize = 65536;
+
+/// Border, in pixels, left out of the `StretchMethod::AutoStretch` background estimate so
+/// vignetting and amp glow at the frame edges don't skew it
+const AUTO_STRETCH_EDGE_MARGIN: usize = 32;
+
+/// Represents different stretching methods to enhance image visualization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StretchMethod {
+    /// Linear stretch - simple min/max normalization
+    Linear,
+    /// Linear stretch between the 0.1% and 99.9% percentiles, so a few hot pixels
+    /// don't set the white point
+    PercentileLinear,
+    /// Logarithmic stretch - enhances dim features
+    Logarithmic,
+    /// Auto stretch - automatic histogram adjustment
+    AutoStretch,
+    /// Arcsinh stretch of the luminance, applied by the same factor to every channel so
+    /// color images keep their hue (bright stars stay colored instead of turning white)
+    Luminance,
+}
+
+impl Default for StretchMethod {
+    fn default() -> Self {
+        StretchMethod::PercentileLinear
+    }
+}
+
+/// False-color palette mono previews are mapped through after stretching
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMap {
+    #[default]
+    Gray,
+    Viridis,
+    Inferno,
+    /// Black through red and yellow to white
+    Heat,
+}
+
+impl ColorMap {
+    pub const ALL: [ColorMap; 4] = [
+        ColorMap::Gray,
+        ColorMap::Viridis,
+        ColorMap::Inferno,
+        ColorMap::Heat,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMap::Gray => "Gray",
+            ColorMap::Viridis => "Viridis",
+            ColorMap::Inferno => "Inferno",
+            ColorMap::Heat => "Heat",
+        }
+    }
+
+    /// Evenly spaced colors the palette interpolates between, or `None` for plain gray
+    fn stops(&self) -> Option<&'static [[u8; 3]]> {
+        match self {
+            ColorMap::Gray => None,
+            ColorMap::Viridis => Some(&[
+                [68, 1, 84],
+                [59, 82, 139],
+                [33, 145, 140],
+                [94, 201, 98],
+                [253, 231, 37],
+            ]),
+            ColorMap::Inferno => Some(&[
+                [0, 0, 4],
+                [87, 16, 110],
+                [188, 55, 84],
+                [249, 142, 9],
+                [252, 255, 164],
+            ]),
+            ColorMap::Heat => Some(&[
+                [0, 0, 0],
+                [128, 0, 0],
+                [255, 64, 0],
+                [255, 192, 0],
+                [255, 255, 255],
+            ]),
+        }
+    }
+
+    /// Color of a stretched 8-bit value
+    pub fn rgb(&self, value: u8) -> [u8; 3] {
+        let Some(stops) = self.stops() else {
+            return [value; 3];
+        };
+
+        let position = value as f32 / 255.0 * (stops.len() - 1) as f32;
+        let index = (position as usize).min(stops.len() - 2);
+        let t = position - index as f32;
+        let (from, to) = (stops[index], stops[index + 1]);
+        std::array::from_fn(|c| {
+            (from[c] as f32 + (to[c] as f32 - from[c] as f32) * t).round() as u8
+        })
+    }
+}
+
+/// Everything a preview is stretched with: the method plus display options
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StretchSettings {
+    pub method: StretchMethod,
+    /// Softening of the arcsinh curve of `StretchMethod::Luminance`
+    pub asinh_softening: f32,
+    /// Mark pixels the stretch clips: shadows in `SHADOW_CLIP_COLOR`, highlights in
+    /// `HIGHLIGHT_CLIP_COLOR`
+    pub show_clipping: bool,
+    /// Palette for mono previews; color previews ignore it
+    pub color_map: ColorMap,
+    /// Counter-rotate frames by their CROTA2 field rotation before stretching
+    pub derotate: bool,
+    /// How color frames are collapsed to luminance, for mono previews and the
+    /// `Luminance` stretch
+    pub luminance_weights: LuminanceWeights,
+}
+
+impl Default for StretchSettings {
+    fn default() -> Self {
+        Self {
+            method: StretchMethod::default(),
+            asinh_softening: DEFAULT_ASINH_SOFTENING,
+            show_clipping: false,
+            color_map: ColorMap::default(),
+            derotate: false,
+            luminance_weights: LuminanceWeights::default(),
+        }
+    }
+}
+
+/// Color of pixels below the black point when showing clipping
+pub const SHADOW_CLIP_COLOR: [u8; 4] = [0, 0, 255, 255];
+/// Color of pixels above the white point when showing clipping
+pub const HIGHLIGHT_CLIP_COLOR: [u8; 4] = [255, 0, 0, 255];
+
+/// Statistics a stretch needs from the plane being stretched
+#[derive(Debug, Clone, Copy)]
+pub struct StretchParams {
+    min: f32,
+    max: f32,
+    mean: f32,
+    std_dev: f32,
+    /// Values at the `PERCENTILE_CLIP` and `1 - PERCENTILE_CLIP` percentiles
+    percentiles: (f32, f32),
+}
+
+impl StretchParams {
+    /// Compute the parameters directly from a plane of values
+    pub fn from_values(values: &[f32]) -> Self {
+        if values.is_empty() {
+            return Self {
+                min: 0.0,
+                max: 0.0,
+                mean: 0.0,
+                std_dev: 0.0,
+                percentiles: (0.0, 0.0),
+            };
+        }
+
+        // Find min and max for scaling
+        let min = values.iter().fold(f32::INFINITY, |a, &b| a.min(b));
+        let max = values.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+
+        // Calculate statistics needed for stretching
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let std_dev =
+            (values.iter().map(|&x| (x - mean).powi(2)).sum::<f32>() / values.len() as f32).sqrt();
+
+        Self {
+            min,
+            max,
+            mean,
+            std_dev,
+            percentiles: percentiles(values, min, max),
+        }
+    }
+
+    /// Reuse (possibly cached) image statistics. These hold no percentiles, so
+    /// `PercentileLinear` falls back to the full range; use `from_values` for it.
+    pub fn from_statistics(stats: &ImageStatistics) -> Self {
+        Self {
+            min: stats.min,
+            max: stats.max,
+            mean: stats.mean,
+            std_dev: stats.std_dev,
+            percentiles: (stats.min, stats.max),
+        }
+    }
+
+    /// Take the mean and standard deviation (the background estimate) from `background`
+    fn with_background(self, background: &ImageStatistics) -> Self {
+        Self {
+            mean: background.mean,
+            std_dev: background.std_dev,
+            ..self
+        }
+    }
+
+    /// Black and white points of a stretch: values below the first become pure black and
+    /// values above the second pure white
+    pub fn clip_bounds(&self, stretch_method: StretchMethod) -> (f32, f32) {
+        match stretch_method {
+            StretchMethod::Linear | StretchMethod::Logarithmic => (self.min, self.max),
+            StretchMethod::PercentileLinear => self.percentiles,
+            StretchMethod::AutoStretch | StretchMethod::Luminance => (
+                (self.mean - 2.0 * self.std_dev).max(self.min),
+                (self.mean + 4.0 * self.std_dev).min(self.max),
+            ),
+        }
+    }
+}
+
+/// Stretch a single plane of pixel values to 8-bit using the given stretch
+pub fn stretch_plane(values: &[f32], params: &StretchParams, stretch: StretchSettings) -> Vec<u8> {
+    let StretchParams {
+        min: min_val,
+        max: max_val,
+        ..
+    } = *params;
+    let range = max_val - min_val;
+
+    values
+        .iter()
+        .map(|&value| {
+            if range <= 0.0 {
+                return 0;
+            }
+
+            match stretch.method {
+                StretchMethod::Linear => {
+                    // Simple linear stretch
+                    ((value - min_val) / range * 255.0).clamp(0.0, 255.0) as u8
+                }
+                StretchMethod::PercentileLinear => {
+                    let (black, white) = params.clip_bounds(StretchMethod::PercentileLinear);
+                    let range = white - black;
+                    if range > 0.0 {
+                        ((value - black) / range * 255.0).clamp(0.0, 255.0) as u8
+                    } else {
+                        0
+                    }
+                }
+                StretchMethod::Logarithmic => {
+                    // Logarithmic stretch - enhances dim features
+                    if value <= min_val {
+                        0
+                    } else {
+                        let epsilon = 0.001; // To avoid ln(0)
+                        ((value - min_val + epsilon).ln() / (max_val - min_val + epsilon).ln()
+                            * 255.0)
+                            .clamp(0.0, 255.0) as u8
+                    }
+                }
+                StretchMethod::Luminance => {
+                    let (black, white) = params.clip_bounds(StretchMethod::Luminance);
+                    let range = white - black;
+                    if range > 0.0 {
+                        let x = ((value - black) / range).clamp(0.0, 1.0);
+                        (asinh_curve(x, stretch.asinh_softening) * 255.0).clamp(0.0, 255.0) as u8
+                    } else {
+                        0
+                    }
+                }
+                StretchMethod::AutoStretch => {
+                    // Automatic stretching based on mean and std dev
+                    // Using a simple algorithm that enhances contrast around the mean
+                    let (shadow_clip, highlight_clip) =
+                        params.clip_bounds(StretchMethod::AutoStretch);
+                    let auto_range = highlight_clip - shadow_clip;
+                    if auto_range > 0.0 {
+                        ((value - shadow_clip) / auto_range * 255.0).clamp(0.0, 255.0) as u8
+                    } else {
+                        0
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+/// Interleave three 8-bit planes into RGBA pixels with full opacity
+pub fn pack_rgb_planes(red: &[u8], green: &[u8], blue: &[u8]) -> Vec<u8> {
+    let mut rgba_data = Vec::with_capacity(red.len() * 4);
+
+    for ((&r, &g), &b) in red.iter().zip(green).zip(blue) {
+        rgba_data.push(r);
+        rgba_data.push(g);
+        rgba_data.push(b);
+        rgba_data.push(255); // Alpha
+    }
+
+    rgba_data
+}
+
+/// Paint the pixels of `rgba` whose value in `values` reaches the black or white point in
+/// `bounds` with the clip colors. The bounds are inclusive: for `Linear` and `Logarithmic`
+/// they are the data's own min and max, so pixels sitting there (e.g. saturated star cores)
+/// are the clipped ones. Highlights win over shadows, so a pixel already marked as a
+/// highlight by another channel stays that way.
+fn overlay_clipping(rgba: &mut [u8], values: &[f32], (black, white): (f32, f32)) {
+    if white <= black {
+        // A flat plane has nothing to clip
+        return;
+    }
+    for (pixel, &value) in rgba.chunks_exact_mut(4).zip(values) {
+        if value >= white {
+            pixel.copy_from_slice(&HIGHLIGHT_CLIP_COLOR);
+        } else if value <= black && pixel != HIGHLIGHT_CLIP_COLOR {
+            pixel.copy_from_slice(&SHADOW_CLIP_COLOR);
+        }
+    }
+}
+
+/// Stretch image data to 8-bit RGBA, returning the pixels with the width and height.
+///
+/// Mono `[height, width]` data is stretched to gray, three-channel `[3, height, width]`
+/// data is stretched per channel into color. Other shapes produce an empty image.
+pub fn stretch_to_rgba(data: &ArrayD<f32>, stretch: StretchSettings) -> (Vec<u8>, usize, usize) {
+    match *data.shape() {
+        [height, width] => {
+            let values: Vec<f32> = data.iter().copied().collect();
+            let params = plane_params(data.view(), &values, stretch.method);
+            (stretch_gray(&values, &params, stretch), width, height)
+        }
+        [3, height, width] if stretch.method == StretchMethod::Luminance => {
+            (stretch_color_preserving(data, stretch), width, height)
+        }
+        [3, height, width] => {
+            // Stretch each channel independently and map it to its RGB channel
+            let planes: Vec<(Vec<f32>, StretchParams)> = data
+                .axis_iter(Axis(0))
+                .map(|plane| {
+                    let values: Vec<f32> = plane.iter().copied().collect();
+                    let params = plane_params(plane.into_dyn(), &values, stretch.method);
+                    (values, params)
+                })
+                .collect();
+            let stretched: Vec<Vec<u8>> = planes
+                .iter()
+                .map(|(values, params)| stretch_plane(values, params, stretch))
+                .collect();
+            let mut rgba = pack_rgb_planes(&stretched[0], &stretched[1], &stretched[2]);
+
+            if stretch.show_clipping {
+                for (values, params) in &planes {
+                    overlay_clipping(&mut rgba, values, params.clip_bounds(stretch.method));
+                }
+            }
+
+            (rgba, width, height)
+        }
+        _ => (Vec::new(), 0, 0),
+    }
+}
+
+/// Stretch parameters for one plane whose pixels are `values`. `AutoStretch` estimates the
+/// background without a border of `AUTO_STRETCH_EDGE_MARGIN` pixels.
+fn plane_params(plane: ArrayViewD<f32>, values: &[f32], method: StretchMethod) -> StretchParams {
+    let params = StretchParams::from_values(values);
+    if method == StretchMethod::AutoStretch {
+        params.with_background(&ImageStatistics::excluding_edges(
+            plane,
+            AUTO_STRETCH_EDGE_MARGIN,
+        ))
+    } else {
+        params
+    }
+}
+
+/// Stretch `[3, height, width]` data by its luminance: the luminance is stretched with
+/// `asinh_curve` and every channel is scaled by the same factor, keeping the ratios
+/// between channels (the hue) intact.
+fn stretch_color_preserving(data: &ArrayD<f32>, stretch: StretchSettings) -> Vec<u8> {
+    let weights = stretch.luminance_weights;
+    let channels: Vec<Vec<f32>> = data
+        .axis_iter(Axis(0))
+        .map(|plane| plane.iter().copied().collect())
+        .collect();
+    let luminance: Vec<f32> = channels[0]
+        .iter()
+        .zip(&channels[1])
+        .zip(&channels[2])
+        .map(|((&r, &g), &b)| weights.r * r + weights.g * g + weights.b * b)
+        .collect();
+
+    let params = StretchParams::from_values(&luminance);
+    let (black, white) = params.clip_bounds(StretchMethod::Luminance);
+    let range = white - black;
+
+    let mut rgba = Vec::with_capacity(luminance.len() * 4);
+    for (i, &lum) in luminance.iter().enumerate() {
+        let x = if range > 0.0 {
+            ((lum - black) / range).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let factor = if x > 0.0 {
+            asinh_curve(x, stretch.asinh_softening) / x
+        } else {
+            0.0
+        };
+
+        for channel in &channels {
+            let normalized = if range > 0.0 {
+                (channel[i] - black) / range
+            } else {
+                0.0
+            };
+            rgba.push((normalized * factor * 255.0).clamp(0.0, 255.0) as u8);
+        }
+        rgba.push(255); // Alpha
+    }
+
+    if stretch.show_clipping {
+        overlay_clipping(&mut rgba, &luminance, (black, white));
+    }
+
+    rgba
+}
+
+/// Values at the low and high clip percentiles of the finite `values`, read from a
+/// histogram spanning `[min, max]`
+fn percentiles(values: &[f32], min: f32, max: f32) -> (f32, f32) {
+    let range = max - min;
+    if !range.is_finite() || range <= 0.0 {
+        return (min, max);
+    }
+
+    let scale = PERCENTILE_BINS as f32 / range;
+    let mut histogram = vec![0usize; PERCENTILE_BINS];
+    let mut count = 0;
+    for &value in values.iter().filter(|v| v.is_finite()) {
+        let bin = (((value - min) * scale) as usize).min(PERCENTILE_BINS - 1);
+        histogram[bin] += 1;
+        count += 1;
+    }
+
+    let low_rank = (count as f32 * PERCENTILE_CLIP) as usize;
+    let high_rank = (count as f32 * (1.0 - PERCENTILE_CLIP)) as usize;
+    let mut low = min;
+    let mut cumulative = 0;
+    for (bin, &bin_count) in histogram.iter().enumerate() {
+        if cumulative <= low_rank && cumulative + bin_count > low_rank {
+            low = min + bin as f32 / scale;
+        }
+        cumulative += bin_count;
+        if cumulative > high_rank {
+            return (low, min + (bin + 1) as f32 / scale);
+        }
+    }
+
+    (low, max)
+}
+
+/// Arcsinh stretch of a value normalized to [0, 1], mapping 0 to 0 and 1 to 1
+fn asinh_curve(x: f32, softening: f32) -> f32 {
+    (softening * x).asinh() / softening.asinh()
+}
+
+/// Stretch a mono plane to gray RGBA using precomputed (e.g. cached) statistics.
+///
+/// `AutoStretch` still estimates its background from `plane`, without the edges.
+pub fn stretch_to_rgba_with_statistics(
+    plane: ArrayView2<f32>,
+    stats: &ImageStatistics,
+    stretch: StretchSettings,
+) -> (Vec<u8>, usize, usize) {
+    let (height, width) = plane.dim();
+    let values: Vec<f32> = plane.iter().copied().collect();
+    let params = match stretch.method {
+        StretchMethod::PercentileLinear => StretchParams::from_values(&values),
+        StretchMethod::AutoStretch => StretchParams::from_statistics(stats).with_background(
+            &ImageStatistics::excluding_edges(plane.into_dyn(), AUTO_STRETCH_EDGE_MARGIN),
+        ),
+        _ => StretchParams::from_statistics(stats),
+    };
+    (stretch_gray(&values, &params, stretch), width, height)
+}
+
+/// Stretch one plane to RGBA through the color map, marking clipped pixels if requested
+fn stretch_gray(values: &[f32], params: &StretchParams, stretch: StretchSettings) -> Vec<u8> {
+    let gray = stretch_plane(values, params, stretch);
+    let mut rgba: Vec<u8> = gray
+        .iter()
+        .flat_map(|&value| {
+            let [r, g, b] = stretch.color_map.rgb(value);
+            [r, g, b, 255]
+        })
+        .collect();
+    if stretch.show_clipping {
+        overlay_clipping(&mut rgba, values, params.clip_bounds(stretch.method));
+    }
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_METHODS: [StretchMethod; 5] = [
+        StretchMethod::Linear,
+        StretchMethod::PercentileLinear,
+        StretchMethod::Logarithmic,
+        StretchMethod::AutoStretch,
+        StretchMethod::Luminance,
+    ];
+
+    /// A mono ramp brightening from the first pixel to the last
+    fn ramp(height: usize, width: usize) -> ArrayD<f32> {
+        ArrayD::from_shape_fn(vec![height, width], |index| {
+            100.0 + (index[0] * width + index[1]) as f32
+        })
+    }
+
+    #[test]
+    fn every_method_stretches_a_ramp_to_opaque_rising_gray() {
+        let data = ramp(48, 80);
+        for method in ALL_METHODS {
+            let stretch = StretchSettings {
+                method,
+                ..StretchSettings::default()
+            };
+            let (rgba, width, height) = stretch_to_rgba(&data, stretch);
+            assert_eq!((width, height), (80, 48), "{:?}", method);
+            assert_eq!(rgba.len(), 80 * 48 * 4, "{:?}", method);
+
+            let gray: Vec<u8> = rgba.chunks_exact(4).map(|pixel| pixel[0]).collect();
+            assert!(
+                rgba.chunks_exact(4)
+                    .all(|pixel| pixel[3] == 255 && pixel[0] == pixel[1] && pixel[1] == pixel[2]),
+                "{:?}",
+                method
+            );
+            assert!(gray.windows(2).all(|w| w[0] <= w[1]), "{:?}", method);
+            assert!(gray[0] < gray[gray.len() - 1], "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn unsupported_shapes_give_an_empty_image() {
+        let data = ArrayD::zeros(vec![2, 3, 4, 5]);
+        let (rgba, _, _) = stretch_to_rgba(&data, StretchSettings::default());
+        assert!(rgba.is_empty());
+    }
+
+    #[test]
+    fn rgb_planes_are_packed_into_opaque_pixels() {
+        let rgba = pack_rgb_planes(&[10, 20], &[30, 40], &[50, 60]);
+        assert_eq!(rgba, [10, 30, 50, 255, 20, 40, 60, 255]);
+    }
+
+    #[test]
+    fn color_cubes_map_each_plane_to_its_channel() {
+        // Red brightens left to right, green the other way, blue stays in between
+        let data = ArrayD::from_shape_fn(vec![3, 4, 16], |index| match index[0] {
+            0 => index[2] as f32,
+            1 => 15.0 - index[2] as f32,
+            _ => 7.5 + (index[1] as f32 - 1.5),
+        });
+        let stretch = StretchSettings {
+            method: StretchMethod::Linear,
+            ..StretchSettings::default()
+        };
+        let (rgba, width, height) = stretch_to_rgba(&data, stretch);
+        assert_eq!((width, height), (16, 4));
+
+        let first = &rgba[..4];
+        let last = &rgba[15 * 4..16 * 4];
+        assert!(first[0] < first[1] && last[0] > last[1]);
+        assert!(rgba.chunks_exact(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn clipped_pixels_get_the_clip_colors() {
+        let is_gray = |pixel: &[u8]| pixel[0] == pixel[1] && pixel[1] == pixel[2];
+        let data = ramp(48, 80);
+        for method in [StretchMethod::Linear, StretchMethod::PercentileLinear] {
+            let stretch = StretchSettings {
+                method,
+                show_clipping: true,
+                ..StretchSettings::default()
+            };
+            let (rgba, _, _) = stretch_to_rgba(&data, stretch);
+            let pixels: Vec<&[u8]> = rgba.chunks_exact(4).collect();
+            let (first, last) = (pixels[0], pixels[pixels.len() - 1]);
+
+            assert_eq!(first, SHADOW_CLIP_COLOR, "{:?}", method);
+            assert_eq!(last, HIGHLIGHT_CLIP_COLOR, "{:?}", method);
+            assert!(is_gray(pixels[pixels.len() / 2]), "{:?}", method);
+
+            let plain = StretchSettings {
+                show_clipping: false,
+                ..stretch
+            };
+            let (rgba, _, _) = stretch_to_rgba(&data, plain);
+            assert!(rgba.chunks_exact(4).all(is_gray), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn luminance_stretch_keeps_channel_ratios() {
+        // One hue, brightening from black at the first pixel to full at the last
+        let hue = [1.0, 0.8, 0.6];
+        let data = ArrayD::from_shape_fn(vec![3, 1, 101], |index| {
+            hue[index[0]] * index[2] as f32 / 100.0
+        });
+        let stretch = StretchSettings {
+            method: StretchMethod::Luminance,
+            ..StretchSettings::default()
+        };
+        let (rgba, _, _) = stretch_to_rgba(&data, stretch);
+
+        // Away from black and from saturation, where u8 rounding dominates
+        for pixel in rgba.chunks_exact(4).skip(5).take(46) {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+            assert!((g - hue[1] * r).abs() <= 2.0, "{:?}", pixel);
+            assert!((b - hue[2] * r).abs() <= 2.0, "{:?}", pixel);
+        }
+        // Faint pixels are brightened well beyond a linear scaling of the red channel
+        assert!(rgba[10 * 4] as f32 > 2.0 * 0.1 * 255.0);
+    }
+
+    #[test]
+    fn palettes_map_known_values_to_their_colors() {
+        assert_eq!(ColorMap::Gray.rgb(37), [37, 37, 37]);
+        assert_eq!(ColorMap::Viridis.rgb(0), [68, 1, 84]);
+        assert_eq!(ColorMap::Viridis.rgb(255), [253, 231, 37]);
+        assert_eq!(ColorMap::Inferno.rgb(255), [252, 255, 164]);
+        // 0.2 of the way: 80% along from black to dark red
+        assert_eq!(ColorMap::Heat.rgb(51), [102, 0, 0]);
+        // Just past the middle stop
+        assert_eq!(ColorMap::Heat.rgb(128), [255, 65, 0]);
+    }
+
+    #[test]
+    fn percentile_linear_ignores_a_single_hot_pixel() {
+        let mut data = ramp(48, 80);
+        data[[0, 0]] = 1_000_000.0;
+        let middle = |method| {
+            let stretch = StretchSettings {
+                method,
+                ..StretchSettings::default()
+            };
+            let (rgba, _, _) = stretch_to_rgba(&data, stretch);
+            (rgba[0], rgba[(24 * 80 + 40) * 4])
+        };
+
+        // The hot pixel sets the white point of a plain linear stretch, crushing the ramp
+        assert_eq!(middle(StretchMethod::Linear), (255, 0));
+        let (hot, gray) = middle(StretchMethod::PercentileLinear);
+        assert_eq!(hot, 255);
+        assert!((100..160).contains(&gray), "{}", gray);
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use eframe::egui::Context;
+
+/// Runs one slow computation (a measurement, an image operation) on a background thread so
+/// the UI keeps drawing, and hands its result back through `poll`.
+///
+/// Dropping the worker discards the result; the computation itself runs to completion.
+pub struct TaskWorker<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T: Send + 'static> TaskWorker<T> {
+    pub fn spawn(ctx: &Context, task: impl FnOnce() -> T + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            // The receiver is gone once the worker is dropped
+            if sender.send(task()).is_ok() {
+                ctx.request_repaint();
+            }
+        });
+
+        Self { receiver }
+    }
+
+    /// The result, once the task has finished
+    pub fn poll(&self) -> Option<T> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn result_arrives_through_poll() {
+        let ctx = Context::default();
+        let (release, wait) = mpsc::channel::<()>();
+        let worker = TaskWorker::spawn(&ctx, move || {
+            wait.recv().unwrap();
+            6 * 7
+        });
+
+        // Still running until released
+        assert_eq!(worker.poll(), None);
+        release.send(()).unwrap();
+
+        let result = std::iter::repeat_with(|| worker.poll())
+            .find_map(|result| {
+                thread::yield_now();
+                result
+            })
+            .unwrap();
+        assert_eq!(result, 42);
+    }
+}
@@ -0,0 +1,427 @@
+use std::str::FromStr;
+
+use ndarray::{Array2, ArrayViewMut2};
+
+use super::{FitsImage, ImageError, for_each_plane_mut, median_of};
+
+/// Rejection threshold used when estimating a tile's background level
+const TILE_CLIP_SIGMA: f32 = 2.5;
+/// Number of clipping passes per tile
+const TILE_CLIP_ITERATIONS: usize = 5;
+/// Approximate number of samples per axis used when fitting a global gradient
+const GRADIENT_SAMPLES_PER_AXIS: usize = 128;
+/// Number of fit-and-reject passes when fitting a global gradient
+const GRADIENT_FIT_ITERATIONS: usize = 3;
+/// Tile size used for the tiled model when none is given, in pixels
+pub const DEFAULT_BACKGROUND_TILE: usize = 64;
+
+/// Shape of the background model fitted when removing a gradient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradientModel {
+    /// A tilted plane: a + bx + cy
+    Linear,
+    /// A second order surface: a + bx + cy + dx² + exy + fy²
+    Quadratic,
+    /// Interpolated grid of tile backgrounds, see `remove_background_tiled`
+    Tiled { tile: usize },
+}
+
+impl GradientModel {
+    /// All models, the tiled one with the default tile size, in display order
+    pub fn all() -> [GradientModel; 3] {
+        [
+            GradientModel::Linear,
+            GradientModel::Quadratic,
+            GradientModel::Tiled {
+                tile: DEFAULT_BACKGROUND_TILE,
+            },
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GradientModel::Linear => "Linear",
+            GradientModel::Quadratic => "Quadratic",
+            GradientModel::Tiled { .. } => "Tiled",
+        }
+    }
+}
+
+/// Parses `linear`, `quadratic`, `tiled` or `tiled:<pixels>`, as taken on the command line
+impl FromStr for GradientModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, tile) = match s.split_once(':') {
+            Some((name, tile)) => (name, Some(tile)),
+            None => (s, None),
+        };
+        match (name.to_lowercase().as_str(), tile) {
+            ("linear", None) => Ok(GradientModel::Linear),
+            ("quadratic", None) => Ok(GradientModel::Quadratic),
+            ("tiled", None) => Ok(GradientModel::Tiled {
+                tile: DEFAULT_BACKGROUND_TILE,
+            }),
+            ("tiled", Some(tile)) => match tile.parse() {
+                Ok(tile) if tile > 0 => Ok(GradientModel::Tiled { tile }),
+                _ => Err(format!("Invalid tile size '{}'", tile)),
+            },
+            _ => Err(format!(
+                "Unknown background model '{}', expected linear, quadratic, tiled or tiled:<pixels>",
+                s
+            )),
+        }
+    }
+}
+
+impl FitsImage {
+    /// Fit the given background model to the image and subtract it.
+    ///
+    /// Global models are fitted by least squares to a grid of samples, rejecting
+    /// outlying samples (stars, nebulosity) between passes.
+    pub fn remove_gradient(&mut self, model: GradientModel) -> Result<(), ImageError> {
+        let terms = match model {
+            GradientModel::Linear => 3,
+            GradientModel::Quadratic => 6,
+            GradientModel::Tiled { tile } => return self.remove_background_tiled(tile),
+        };
+        self.metadata
+            .history
+            .record(format!("Background removed: {:?} model", model));
+
+        for_each_plane_mut(self.data_mut(), |plane| {
+            subtract_polynomial_background(plane, terms)
+        })?;
+
+        Ok(())
+    }
+
+    /// Remove a smoothly varying background using a grid of tiles.
+    ///
+    /// The image is divided into `tile` x `tile` pixel tiles, each tile's background is
+    /// estimated with a sigma-clipped median so stars don't bias it, and a surface
+    /// bilinearly interpolated between the tile centers is subtracted. Unlike a global
+    /// plane or polynomial fit this follows strong, non-linear gradients.
+    pub fn remove_background_tiled(&mut self, tile: usize) -> Result<(), ImageError> {
+        if tile == 0 {
+            return Err(ImageError::UnsupportedOperation(
+                "Background tile size must be greater than zero".to_string(),
+            ));
+        }
+
+        self.metadata
+            .history
+            .record(format!("Background removed: {} px tiles", tile));
+
+        for_each_plane_mut(self.data_mut(), |plane| {
+            subtract_tiled_background(plane, tile)
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Estimate and subtract the tiled background model of a single plane
+fn subtract_tiled_background(mut plane: ArrayViewMut2<f32>, tile: usize) {
+    let (height, width) = plane.dim();
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let tiles_x = width.div_ceil(tile);
+    let tiles_y = height.div_ceil(tile);
+
+    // Robust background level and center position of every tile
+    let mut levels = Array2::<f32>::zeros((tiles_y, tiles_x));
+    let centers_x: Vec<f32> = (0..tiles_x)
+        .map(|tx| tile_center(tx, tile, width))
+        .collect();
+    let centers_y: Vec<f32> = (0..tiles_y)
+        .map(|ty| tile_center(ty, tile, height))
+        .collect();
+
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let y0 = ty * tile;
+            let x0 = tx * tile;
+            let mut values: Vec<f32> = plane
+                .slice(ndarray::s![
+                    y0..(y0 + tile).min(height),
+                    x0..(x0 + tile).min(width)
+                ])
+                .iter()
+                .copied()
+                .collect();
+            levels[[ty, tx]] = clipped_median(&mut values);
+        }
+    }
+
+    // Subtract the surface interpolated between tile centers
+    for y in 0..height {
+        let (ty0, ty1, fy) = interpolation_weights(&centers_y, y as f32);
+        for x in 0..width {
+            let (tx0, tx1, fx) = interpolation_weights(&centers_x, x as f32);
+            let top = levels[[ty0, tx0]] * (1.0 - fx) + levels[[ty0, tx1]] * fx;
+            let bottom = levels[[ty1, tx0]] * (1.0 - fx) + levels[[ty1, tx1]] * fx;
+            plane[[y, x]] -= top * (1.0 - fy) + bottom * fy;
+        }
+    }
+}
+
+/// Fit a polynomial surface with the first `terms` terms of `polynomial_terms` and subtract it
+fn subtract_polynomial_background(mut plane: ArrayViewMut2<f32>, terms: usize) {
+    let (height, width) = plane.dim();
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let step_x = (width / GRADIENT_SAMPLES_PER_AXIS).max(1);
+    let step_y = (height / GRADIENT_SAMPLES_PER_AXIS).max(1);
+    let mut samples: Vec<(f64, f64, f64)> = Vec::new();
+    for y in (0..height).step_by(step_y) {
+        for x in (0..width).step_by(step_x) {
+            let value = plane[[y, x]];
+            if value.is_finite() {
+                let (nx, ny) = normalized_coords(x, y, width, height);
+                samples.push((nx, ny, value as f64));
+            }
+        }
+    }
+
+    let mut coefficients = vec![0.0; terms];
+    for _ in 0..GRADIENT_FIT_ITERATIONS {
+        let Some(fit) = fit_polynomial(&samples, terms) else {
+            return;
+        };
+        coefficients = fit;
+
+        // Reject samples far from the surface so stars and nebulosity don't pull it around
+        let residuals: Vec<f64> = samples
+            .iter()
+            .map(|&(x, y, v)| v - evaluate_polynomial(&coefficients, x, y))
+            .collect();
+        let std_dev =
+            (residuals.iter().map(|r| r * r).sum::<f64>() / residuals.len() as f64).sqrt();
+        let before = samples.len();
+        let mut kept = residuals.iter();
+        samples.retain(|_| {
+            kept.next()
+                .is_some_and(|&r| r.abs() <= TILE_CLIP_SIGMA as f64 * std_dev)
+        });
+        if samples.len() == before || samples.len() < terms {
+            break;
+        }
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            let (nx, ny) = normalized_coords(x, y, width, height);
+            plane[[y, x]] -= evaluate_polynomial(&coefficients, nx, ny) as f32;
+        }
+    }
+}
+
+/// Map pixel coordinates into [-1, 1] to keep the least squares system well conditioned
+fn normalized_coords(x: usize, y: usize, width: usize, height: usize) -> (f64, f64) {
+    let nx = if width > 1 {
+        2.0 * x as f64 / (width - 1) as f64 - 1.0
+    } else {
+        0.0
+    };
+    let ny = if height > 1 {
+        2.0 * y as f64 / (height - 1) as f64 - 1.0
+    } else {
+        0.0
+    };
+    (nx, ny)
+}
+
+/// Terms of a second order polynomial in x and y, constant and linear terms first
+fn polynomial_terms(x: f64, y: f64) -> [f64; 6] {
+    [1.0, x, y, x * x, x * y, y * y]
+}
+
+fn evaluate_polynomial(coefficients: &[f64], x: f64, y: f64) -> f64 {
+    coefficients
+        .iter()
+        .zip(polynomial_terms(x, y))
+        .map(|(c, t)| c * t)
+        .sum()
+}
+
+/// Least squares fit of the first `terms` polynomial terms via the normal equations
+fn fit_polynomial(samples: &[(f64, f64, f64)], terms: usize) -> Option<Vec<f64>> {
+    let weighted = samples.iter().map(|&(x, y, value)| (x, y, value, 1.0));
+    fit_weighted_polynomial(weighted, terms)
+}
+
+/// Weighted least squares fit of the first `terms` polynomial terms to
+/// `(x, y, value, weight)` samples, via the normal equations
+pub(crate) fn fit_weighted_polynomial(
+    samples: impl IntoIterator<Item = (f64, f64, f64, f64)>,
+    terms: usize,
+) -> Option<Vec<f64>> {
+    // Build the augmented normal matrix [AᵀWA | AᵀWb]
+    let mut matrix = vec![vec![0.0; terms + 1]; terms];
+    let mut count = 0;
+    for (x, y, value, weight) in samples {
+        let t = polynomial_terms(x, y);
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, cell) in row[..terms].iter_mut().enumerate() {
+                *cell += weight * t[i] * t[j];
+            }
+            row[terms] += weight * t[i] * value;
+        }
+        count += 1;
+    }
+    if count < terms {
+        return None;
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..terms {
+        let pivot = (col..terms).max_by(|&a, &b| {
+            matrix[a][col]
+                .abs()
+                .partial_cmp(&matrix[b][col].abs())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+        if matrix[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        matrix.swap(col, pivot);
+
+        let (upper, lower) = matrix.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for row in lower {
+            let factor = row[col] / pivot_row[col];
+            for (cell, &p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *cell -= factor * p;
+            }
+        }
+    }
+
+    let mut solution = vec![0.0; terms];
+    for row in (0..terms).rev() {
+        let sum: f64 = ((row + 1)..terms)
+            .map(|k| matrix[row][k] * solution[k])
+            .sum();
+        solution[row] = (matrix[row][terms] - sum) / matrix[row][row];
+    }
+
+    Some(solution)
+}
+
+/// Center coordinate of tile `index` along an axis of length `len` (the last tile may be partial)
+fn tile_center(index: usize, tile: usize, len: usize) -> f32 {
+    let start = index * tile;
+    let end = (start + tile).min(len);
+    (start + end) as f32 / 2.0 - 0.5
+}
+
+/// The two neighbouring tile indices for `pos` and the weight of the second one.
+/// Positions outside the outermost centers are clamped to the edge tile.
+fn interpolation_weights(centers: &[f32], pos: f32) -> (usize, usize, f32) {
+    let last = centers.len() - 1;
+    if pos <= centers[0] {
+        return (0, 0, 0.0);
+    }
+    if pos >= centers[last] {
+        return (last, last, 0.0);
+    }
+
+    let upper = centers.partition_point(|&c| c <= pos).min(last);
+    let lower = upper - 1;
+    let span = centers[upper] - centers[lower];
+    (lower, upper, (pos - centers[lower]) / span)
+}
+
+/// Median of the values after iteratively rejecting outliers around the median
+fn clipped_median(values: &mut Vec<f32>) -> f32 {
+    sigma_clip(values, TILE_CLIP_SIGMA, TILE_CLIP_ITERATIONS);
+    if values.is_empty() {
+        return 0.0;
+    }
+    median_of(values)
+}
+
+/// Drop non-finite values, then repeatedly reject values further than `sigma` standard
+/// deviations from the median until none are rejected or `iterations` passes have run.
+///
+/// A pass that would reject every value is not applied.
+pub(super) fn sigma_clip(values: &mut Vec<f32>, sigma: f32, iterations: usize) {
+    values.retain(|v| v.is_finite());
+
+    for _ in 0..iterations {
+        if values.is_empty() {
+            return;
+        }
+        let median = median_of(values);
+        let variance =
+            values.iter().map(|&v| (v - median).powi(2)).sum::<f32>() / values.len() as f32;
+        let std_dev = variance.sqrt();
+        if std_dev == 0.0 {
+            return;
+        }
+
+        let threshold = sigma * std_dev;
+        if values.iter().all(|&v| (v - median).abs() > threshold) {
+            return;
+        }
+        let before = values.len();
+        values.retain(|&v| (v - median).abs() <= threshold);
+        if values.len() == before {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// A sky brightening towards the center, as from light pollution overhead
+    fn radial_gradient() -> FitsImage {
+        FitsImage::from_data(ArrayD::from_shape_fn(vec![96, 128], |index| {
+            let (y, x) = (index[0] as f32 - 47.5, index[1] as f32 - 63.5);
+            let r = (x * x + y * y).sqrt() / 80.0;
+            1000.0 + 400.0 * (-4.0 * r * r).exp()
+        }))
+    }
+
+    #[test]
+    fn tiled_background_flattens_a_radial_gradient_better_than_a_plane() {
+        let mut linear = radial_gradient();
+        linear.remove_gradient(GradientModel::Linear).unwrap();
+        let mut tiled = radial_gradient();
+        tiled.remove_background_tiled(16).unwrap();
+
+        let linear_spread = linear.calculate_statistics().std_dev;
+        let tiled_spread = tiled.calculate_statistics().std_dev;
+        assert!(
+            tiled_spread < linear_spread / 5.0,
+            "tiled {} vs linear {}",
+            tiled_spread,
+            linear_spread
+        );
+        assert!(tiled.remove_background_tiled(0).is_err());
+    }
+
+    #[test]
+    fn models_parse_from_their_command_line_names() {
+        assert_eq!("linear".parse(), Ok(GradientModel::Linear));
+        assert_eq!("Quadratic".parse(), Ok(GradientModel::Quadratic));
+        assert_eq!(
+            "tiled".parse(),
+            Ok(GradientModel::Tiled {
+                tile: DEFAULT_BACKGROUND_TILE
+            })
+        );
+        assert_eq!("tiled:32".parse(), Ok(GradientModel::Tiled { tile: 32 }));
+        assert!("tiled:0".parse::<GradientModel>().is_err());
+        assert!("linear:32".parse::<GradientModel>().is_err());
+        assert!("spline".parse::<GradientModel>().is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use ndarray::{ArrayViewMut1, ArrayViewMut2, Axis};
+
+use super::{FitsImage, ImageError, for_each_plane_mut};
+
+/// Number of successive box blurs used to approximate a Gaussian
+const BOX_PASSES: usize = 3;
+
+impl FitsImage {
+    /// Blur the image with a Gaussian of the given standard deviation in pixels.
+    ///
+    /// The Gaussian is approximated by three box blurs, so the cost doesn't grow with
+    /// `sigma`. Non-finite pixels are left out of their neighbours' averages and stay
+    /// missing. Color planes are blurred separately.
+    pub fn gaussian_blur(&mut self, sigma: f32) -> Result<(), ImageError> {
+        if sigma.is_nan() || sigma <= 0.0 {
+            return Err(ImageError::UnsupportedOperation(
+                "Blur sigma must be greater than zero".to_string(),
+            ));
+        }
+
+        let radii = box_radii(sigma);
+        for_each_plane_mut(self.data_mut(), |plane| blur_plane(plane, &radii))?;
+
+        Ok(())
+    }
+}
+
+/// Radii of the box blurs whose combination has the variance of a Gaussian of `sigma`
+fn box_radii(sigma: f32) -> [usize; BOX_PASSES] {
+    let passes = BOX_PASSES as f32;
+    let variance = 12.0 * sigma * sigma;
+
+    // Widths must be odd; use the two odd widths around the ideal one
+    let ideal = (variance / passes + 1.0).sqrt();
+    let mut lower = ideal.floor() as usize;
+    if lower % 2 == 0 {
+        lower = lower.saturating_sub(1).max(1);
+    }
+    let lower_f = lower as f32;
+    let lower_passes =
+        ((variance - passes * lower_f * lower_f - 4.0 * passes * lower_f - 3.0 * passes)
+            / (-4.0 * lower_f - 4.0))
+            .round()
+            .max(0.0) as usize;
+
+    std::array::from_fn(|pass| {
+        let width = if pass < lower_passes {
+            lower
+        } else {
+            lower + 2
+        };
+        (width - 1) / 2
+    })
+}
+
+/// Apply each box blur along rows and then columns
+fn blur_plane(mut plane: ArrayViewMut2<f32>, radii: &[usize]) {
+    let mut sums = Vec::new();
+    let mut counts = Vec::new();
+    for &radius in radii {
+        for axis in [Axis(1), Axis(0)] {
+            for line in plane.lanes_mut(axis) {
+                box_blur_line(line, radius, &mut sums, &mut counts);
+            }
+        }
+    }
+}
+
+/// Replace every finite value of a line by the mean of the finite values within `radius`.
+/// The window shrinks at the ends of the line. `sums` and `counts` are scratch buffers.
+fn box_blur_line(
+    mut line: ArrayViewMut1<f32>,
+    radius: usize,
+    sums: &mut Vec<f64>,
+    counts: &mut Vec<u32>,
+) {
+    let len = line.len();
+
+    // Prefix sums, so every window is a difference of two entries
+    sums.clear();
+    counts.clear();
+    sums.push(0.0);
+    counts.push(0);
+    for &value in line.iter() {
+        let (sum, count) = if value.is_finite() {
+            (value as f64, 1)
+        } else {
+            (0.0, 0)
+        };
+        sums.push(sums[sums.len() - 1] + sum);
+        counts.push(counts[counts.len() - 1] + count);
+    }
+
+    for (i, value) in line.iter_mut().enumerate() {
+        if !value.is_finite() {
+            continue;
+        }
+        let start = i.saturating_sub(radius);
+        let end = (i + radius + 1).min(len);
+        let count = counts[end] - counts[start];
+        *value = ((sums[end] - sums[start]) / count as f64) as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let mut image = FitsImage::from_data(ArrayD::from_elem(vec![12, 9], 250.0));
+        image.gaussian_blur(3.0).unwrap();
+        // The windows shrink at the borders, which must not darken them
+        for &value in image.data() {
+            assert!((value - 250.0).abs() < 1e-3, "{} != 250", value);
+        }
+    }
+
+    #[test]
+    fn impulse_spreads_symmetrically_with_the_requested_sigma() {
+        let (size, center) = (31, 15);
+        let mut data = ArrayD::zeros(vec![size, size]);
+        data[[center, center]] = 1000.0;
+        let mut image = FitsImage::from_data(data);
+        let sigma = 2.5;
+        image.gaussian_blur(sigma).unwrap();
+
+        let data = image.data();
+        for offset in 1..=6 {
+            let right = data[[center, center + offset]];
+            assert!(right > 0.0);
+            for value in [
+                data[[center, center - offset]],
+                data[[center + offset, center]],
+                data[[center - offset, center]],
+            ] {
+                assert!((value - right).abs() < 1e-3, "{} != {}", value, right);
+            }
+        }
+
+        // The flux is kept and spread with the variance of the Gaussian along each axis
+        let total: f32 = data.iter().sum();
+        assert!((total - 1000.0).abs() < 0.1, "total {}", total);
+        for axis in 0..2 {
+            let variance: f32 = data
+                .indexed_iter()
+                .map(|(index, &value)| value * (index[axis] as f32 - center as f32).powi(2))
+                .sum::<f32>()
+                / total;
+            let measured = variance.sqrt();
+            assert!(
+                (measured - sigma).abs() < 0.1,
+                "sigma {} != {}",
+                measured,
+                sigma
+            );
+        }
+    }
+}
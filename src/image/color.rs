@@ -0,0 +1,115 @@
+use ndarray::Axis;
+
+use super::{FitsImage, ImageError};
+
+/// Per-channel weights used to collapse RGB data into a single luminance plane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuminanceWeights {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl LuminanceWeights {
+    /// ITU-R BT.709 coefficients
+    pub const REC_709: Self = Self {
+        r: 0.2126,
+        g: 0.7152,
+        b: 0.0722,
+    };
+
+    /// Equal weighting of the three channels
+    pub const EQUAL: Self = Self {
+        r: 1.0 / 3.0,
+        g: 1.0 / 3.0,
+        b: 1.0 / 3.0,
+    };
+
+    /// The weightings offered to users, with their display names
+    pub const PRESETS: [(&'static str, Self); 2] =
+        [("Rec. 709", Self::REC_709), ("Equal", Self::EQUAL)];
+}
+
+impl Default for LuminanceWeights {
+    fn default() -> Self {
+        Self::REC_709
+    }
+}
+
+impl FitsImage {
+    /// Collapse a three-channel image to a single Rec. 709 luminance plane
+    pub fn to_luminance(&self) -> Result<FitsImage, ImageError> {
+        self.to_luminance_with(LuminanceWeights::default())
+    }
+
+    /// Collapse a three-channel image to a single luminance plane using custom weights
+    pub fn to_luminance_with(&self, weights: LuminanceWeights) -> Result<FitsImage, ImageError> {
+        if !self.is_color() {
+            return Err(ImageError::UnsupportedOperation(format!(
+                "Luminance extraction requires a 3-channel image, got {} channel(s)",
+                self.channels()
+            )));
+        }
+
+        let r = self.data.index_axis(Axis(0), 0);
+        let g = self.data.index_axis(Axis(0), 1);
+        let b = self.data.index_axis(Axis(0), 2);
+        let luminance = ndarray::Zip::from(&r)
+            .and(&g)
+            .and(&b)
+            .map_collect(|&r, &g, &b| weights.r * r + weights.g * g + weights.b * b);
+
+        let mut result = FitsImage::new(0, 0);
+        result.metadata = self.metadata.clone();
+        result.frame_type = self.frame_type;
+        *result.data_mut() = luminance;
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    /// A 2 x 2 color image whose channels are flat at `r`, `g` and `b`
+    fn rgb(r: f32, g: f32, b: f32) -> FitsImage {
+        FitsImage::from_data(ArrayD::from_shape_fn(vec![3, 2, 2], |index| {
+            [r, g, b][index[0]]
+        }))
+    }
+
+    #[test]
+    fn luminance_weights_each_channel() {
+        let image = rgb(100.0, 200.0, 1000.0);
+
+        let luminance = image.to_luminance().unwrap();
+        assert_eq!(luminance.data().shape(), [2, 2]);
+        let expected = 0.2126 * 100.0 + 0.7152 * 200.0 + 0.0722 * 1000.0;
+        assert!(
+            luminance
+                .data()
+                .iter()
+                .all(|&v| (v - expected).abs() < 1e-3)
+        );
+
+        let equal = image.to_luminance_with(LuminanceWeights::EQUAL).unwrap();
+        assert!(
+            equal
+                .data()
+                .iter()
+                .all(|&v| (v - 1300.0 / 3.0).abs() < 1e-3)
+        );
+    }
+
+    #[test]
+    fn luminance_of_a_mono_image_is_unsupported() {
+        let mono = FitsImage::from_data(ArrayD::zeros(vec![2, 2]));
+        assert!(matches!(
+            mono.to_luminance(),
+            Err(ImageError::UnsupportedOperation(_))
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use ndarray::{ArrayViewMut2, Axis};
+
+use super::{FitsImage, ImageError, for_each_plane_mut, median_of};
+
+/// Columns on each side whose levels a column is compared against
+const NEIGHBOR_COLUMNS: usize = 5;
+
+impl FitsImage {
+    /// Repair defective sensor columns, returning how many were replaced.
+    ///
+    /// A column is defective when its mean level deviates from the median of its
+    /// neighbouring columns by more than `threshold_sigma` robust standard deviations of
+    /// those deviations across the frame. Defective columns are replaced row by row by
+    /// linear interpolation between the nearest good columns on either side. Unlike hot
+    /// pixel removal this catches whole columns that are uniformly bright or dark.
+    pub fn correct_column_defects(&mut self, threshold_sigma: f32) -> Result<usize, ImageError> {
+        let mut corrected = 0;
+        for_each_plane_mut(self.data_mut(), |plane| {
+            corrected += correct_plane_columns(plane, threshold_sigma)
+        })?;
+
+        if corrected > 0 {
+            self.metadata
+                .history
+                .record(format!("Interpolated {} defective columns", corrected));
+        }
+        Ok(corrected)
+    }
+}
+
+/// Find and interpolate the defective columns of one plane
+fn correct_plane_columns(mut plane: ArrayViewMut2<f32>, threshold_sigma: f32) -> usize {
+    let width = plane.ncols();
+    if width < 3 {
+        return 0;
+    }
+
+    let levels: Vec<f32> = plane
+        .axis_iter(Axis(1))
+        .map(|column| {
+            let (sum, count) = column
+                .iter()
+                .filter(|v| v.is_finite())
+                .fold((0.0f64, 0usize), |(sum, count), &v| {
+                    (sum + v as f64, count + 1)
+                });
+            if count > 0 {
+                (sum / count as f64) as f32
+            } else {
+                f32::NAN
+            }
+        })
+        .collect();
+
+    // How far each column sits from its neighbourhood. Near the edges the neighbours all
+    // lie on one side, where their median would be off by any gradient across the frame,
+    // so a line through them is extrapolated instead.
+    let deviations: Vec<f32> = (0..width)
+        .map(|x| {
+            let start = x.saturating_sub(NEIGHBOR_COLUMNS);
+            let end = (x + NEIGHBOR_COLUMNS + 1).min(width);
+            let neighbors: Vec<(f32, f32)> = (start..end)
+                .filter(|&n| n != x)
+                .map(|n| (n as f32, levels[n]))
+                .filter(|(_, v)| v.is_finite())
+                .collect();
+            if neighbors.is_empty() || !levels[x].is_finite() {
+                return 0.0;
+            }
+            let expected = if x - start == end - x - 1 {
+                median_of(&mut neighbors.iter().map(|&(_, v)| v).collect::<Vec<_>>())
+            } else {
+                line_at(&neighbors, x as f32)
+            };
+            levels[x] - expected
+        })
+        .collect();
+
+    // Robust spread of the deviations, so the defects themselves don't inflate it. On a
+    // frame without any texture it is zero, and there's nothing to compare against.
+    let mut absolute: Vec<f32> = deviations.iter().map(|d| d.abs()).collect();
+    let spread = 1.4826 * median_of(&mut absolute);
+    if spread <= 0.0 {
+        return 0;
+    }
+    let defective: Vec<bool> = deviations
+        .iter()
+        .map(|d| d.abs() > threshold_sigma * spread)
+        .collect();
+
+    let mut corrected = 0;
+    for x in (0..width).filter(|&x| defective[x]) {
+        let left = (0..x).rev().find(|&n| !defective[n]);
+        let right = (x + 1..width).find(|&n| !defective[n]);
+        let (left, right, t) = match (left, right) {
+            (Some(l), Some(r)) => (l, r, (x - l) as f32 / (r - l) as f32),
+            (Some(l), None) => (l, l, 0.0),
+            (None, Some(r)) => (r, r, 0.0),
+            (None, None) => continue,
+        };
+
+        for mut row in plane.rows_mut() {
+            row[x] = row[left] * (1.0 - t) + row[right] * t;
+        }
+        corrected += 1;
+    }
+
+    corrected
+}
+
+/// Value at `x` of a line through the `(x, y)` points, fitted robustly so that one
+/// defective neighbour doesn't tilt it: the slope is the median of the pairwise slopes
+/// (Theil-Sen), the offset the median of the points' offsets from it
+fn line_at(points: &[(f32, f32)], x: f32) -> f32 {
+    let mut slopes: Vec<f32> = points
+        .iter()
+        .enumerate()
+        .flat_map(|(i, &(x0, y0))| {
+            points[i + 1..]
+                .iter()
+                .map(move |&(x1, y1)| (y1 - y0) / (x1 - x0))
+        })
+        .collect();
+    let slope = if slopes.is_empty() {
+        0.0
+    } else {
+        median_of(&mut slopes)
+    };
+    let mut levels: Vec<f32> = points
+        .iter()
+        .map(|&(px, py)| py + slope * (x - px))
+        .collect();
+    median_of(&mut levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+
+    #[test]
+    fn bright_column_is_interpolated_away() {
+        // A gentle gradient with a little texture, and one hot column
+        let clean = |y: usize, x: usize| 100.0 + x as f32 * 0.5 + ((y * 7 + x * 3) % 5) as f32;
+        let data = ArrayD::from_shape_fn(vec![20, 30], |index| {
+            let (y, x) = (index[0], index[1]);
+            clean(y, x) + if x == 12 { 500.0 } else { 0.0 }
+        });
+        let mut image = FitsImage::from_data(data);
+
+        assert_eq!(image.correct_column_defects(5.0).unwrap(), 1);
+        for y in 0..20 {
+            let expected = (clean(y, 11) + clean(y, 13)) / 2.0;
+            assert!((image.data()[[y, 12]] - expected).abs() < 1e-3);
+            // Good columns are untouched
+            assert_eq!(image.data()[[y, 11]], clean(y, 11));
+        }
+        assert!(
+            image.metadata.history.entries()[0].contains("1 defective column"),
+            "{:?}",
+            image.metadata.history.entries()
+        );
+    }
+
+    #[test]
+    fn clean_frame_is_left_alone() {
+        let textured = ArrayD::from_shape_fn(vec![10, 10], |index| {
+            100.0 + ((index[0] * 7 + index[1] * 3) % 5) as f32
+        });
+        // Edge columns only have neighbours on one side, which a gradient puts off level
+        let gradient = ArrayD::from_shape_fn(vec![10, 100], |index| 100.0 + index[1] as f32 * 2.0);
+
+        for data in [textured, gradient] {
+            let mut image = FitsImage::from_data(data.clone());
+            assert_eq!(image.correct_column_defects(5.0).unwrap(), 0);
+            assert_eq!(image.data(), &data);
+        }
+    }
+}
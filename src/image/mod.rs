@@ -1,12 +1,35 @@
+mod background;
+mod blur;
+mod color;
+mod cosmetic;
+mod organize;
+mod watch;
+
 use std::error::Error;
 use std::fmt;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use fitsio::FitsFile;
 use fitsio::images::ImageDescription;
 use fitsio::images::ImageType;
-use ndarray::{ArrayD, IxDyn};
+use ndarray::{
+    ArrayD, ArrayView2, ArrayViewD, ArrayViewMut2, ArrayViewMutD, Axis, Ix2, IxDyn, Slice,
+};
+
+pub use background::GradientModel;
+pub(crate) use background::fit_weighted_polynomial;
+pub use color::LuminanceWeights;
+pub use organize::{DEFAULT_ORGANIZE_TEMPLATE, organize_frames};
+pub use watch::{FolderWatcher, watch_folder};
+
+/// File extensions recognized as FITS images, including gzip and Rice (.fz) compressed files
+pub const FITS_EXTENSIONS: &[&str] = &["fit", "fits", "fts", "fit.gz", "fits.gz", "fts.gz", "fz"];
+
+/// Magic bytes at the start of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 /// Possible pixel data types in FITS images
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -16,6 +39,7 @@ pub enum PixelType {
     U32,
     I16,
     I32,
+    I64,
     F32,
     F64,
 }
@@ -29,10 +53,57 @@ impl PixelType {
             PixelType::U32 => 4,
             PixelType::I16 => 2,
             PixelType::I32 => 4,
+            PixelType::I64 => 8,
             PixelType::F32 => 4,
             PixelType::F64 => 8,
         }
     }
+
+    /// The FITS image type pixels of this type are stored as
+    pub fn image_type(&self) -> ImageType {
+        match self {
+            PixelType::U8 => ImageType::UnsignedByte,
+            PixelType::U16 => ImageType::UnsignedShort,
+            PixelType::U32 => ImageType::UnsignedLong,
+            PixelType::I16 => ImageType::Short,
+            PixelType::I32 => ImageType::Long,
+            PixelType::I64 => ImageType::LongLong,
+            PixelType::F32 => ImageType::Float,
+            PixelType::F64 => ImageType::Double,
+        }
+    }
+
+    /// BLANK keyword used to mark missing pixels when saving integer data, as the raw
+    /// stored value and the pixel value it reads back as. `None` for floating point types,
+    /// which use NaN instead.
+    ///
+    /// Unsigned 16 and 32-bit data is stored signed with a BZERO offset, so its raw minimum
+    /// reads back as 0. Unsigned types have no value to spare, so the BLANK value is a
+    /// real value too; `to_file_with_options` stores float when a pixel would collide.
+    pub fn blank_value(&self) -> Option<(i64, f32)> {
+        match self {
+            PixelType::U8 => Some((0, 0.0)),
+            PixelType::U16 => Some((i16::MIN as i64, 0.0)),
+            PixelType::U32 => Some((i32::MIN as i64, 0.0)),
+            PixelType::I16 => Some((i16::MIN as i64, i16::MIN as f32)),
+            PixelType::I32 => Some((i32::MIN as i64, i32::MIN as f32)),
+            PixelType::I64 => Some((i64::MIN, i64::MIN as f32)),
+            PixelType::F32 | PixelType::F64 => None,
+        }
+    }
+
+    /// Largest value an integer pixel type can hold, or `None` for floating point types
+    pub fn max_value(&self) -> Option<f32> {
+        match self {
+            PixelType::U8 => Some(u8::MAX as f32),
+            PixelType::U16 => Some(u16::MAX as f32),
+            PixelType::U32 => Some(u32::MAX as f32),
+            PixelType::I16 => Some(i16::MAX as f32),
+            PixelType::I32 => Some(i32::MAX as f32),
+            PixelType::I64 => Some(i64::MAX as f32),
+            PixelType::F32 | PixelType::F64 => None,
+        }
+    }
 }
 
 /// Metadata associated with a FITS image
@@ -46,17 +117,162 @@ pub struct ImageMetadata {
     pub exposure_time: Option<f64>,
     /// Image temperature in degrees Celsius
     pub temperature: Option<f64>,
-    /// ISO/Gain setting
-    pub iso_gain: Option<u32>,
+    /// ISO/Gain setting (GAIN, ISOSPEED or ISO). Fractional for cameras that report gain
+    /// in e-/ADU.
+    pub iso_gain: Option<f64>,
+    /// Sensor offset setting (OFFSET)
+    pub offset: Option<u32>,
     /// Filter used (if any)
     pub filter: Option<String>,
+    /// Name of the target (OBJECT)
+    pub object: Option<String>,
+    /// Relative air mass the frame was taken through (AIRMASS, 1.0 at zenith)
+    pub airmass: Option<f64>,
+    /// Field rotation in degrees (CROTA2), e.g. 180 after a meridian flip
+    pub rotation: Option<f64>,
+    /// Start of the exposure (DATE-OBS), as written by the capture software
+    pub date_obs: Option<String>,
+    /// Focal length of the optics in millimeters (FOCALLEN)
+    pub focal_length: Option<f64>,
+    /// Pixel width in microns (XPIXSZ)
+    pub pixel_size_x: Option<f64>,
+    /// Pixel height in microns (YPIXSZ)
+    pub pixel_size_y: Option<f64>,
     /// Original file path
     pub file_path: Option<PathBuf>,
     /// Additional key-value metadata
     pub extra: std::collections::HashMap<String, String>,
+    /// Processing applied to the image, saved as HISTORY cards
+    pub history: ProcessingHistory,
+}
+
+/// Processing steps applied to an image, in order. Every step is written as a HISTORY
+/// card when the image is saved, so a stack records how it was made.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessingHistory {
+    entries: Vec<String>,
+}
+
+impl ProcessingHistory {
+    /// Append a processing step
+    pub fn record(&mut self, entry: impl Into<String>) {
+        self.entries.push(entry.into());
+    }
+
+    /// The recorded steps, oldest first
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+}
+
+impl ImageMetadata {
+    /// Width of a pixel relative to its height, defaulting to square pixels
+    pub fn pixel_aspect_ratio(&self) -> f64 {
+        match (self.pixel_size_x, self.pixel_size_y) {
+            (Some(x), Some(y)) if x > 0.0 && y > 0.0 => x / y,
+            _ => 1.0,
+        }
+    }
+
+    /// Start of the exposure in seconds since the Unix epoch, parsed from a DATE-OBS of the
+    /// form `YYYY-MM-DDThh:mm:ss[.sss]` (UTC). `None` if it's missing or malformed.
+    pub fn observation_time(&self) -> Option<f64> {
+        let date_obs = self.date_obs.as_deref()?.trim();
+        let (date, time) = date_obs.split_once('T').unwrap_or((date_obs, "00:00:00"));
+
+        let mut date_parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+        let (year, month, day) = (
+            date_parts.next()??,
+            date_parts.next()??,
+            date_parts.next()??,
+        );
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+
+        let mut time_parts = time.trim_end_matches('Z').splitn(3, ':');
+        let hours: f64 = time_parts.next()?.parse().ok()?;
+        let minutes: f64 = time_parts.next().unwrap_or("0").parse().ok()?;
+        let seconds: f64 = time_parts.next().unwrap_or("0").parse().ok()?;
+
+        let days = days_from_civil(year, month, day) as f64;
+        Some(days * 86400.0 + hours * 3600.0 + minutes * 60.0 + seconds)
+    }
+
+    /// Image scale in arcseconds per pixel from the pixel width and the focal length, or
+    /// `None` unless both XPIXSZ and FOCALLEN are known. XPIXSZ already includes binning.
+    pub fn pixel_scale(&self) -> Option<f32> {
+        let pixel_size = self.pixel_size_x.filter(|&size| size > 0.0)?;
+        let focal_length = self.focal_length.filter(|&length| length > 0.0)?;
+        Some((ARCSEC_PER_RADIAN_MICRON_PER_MM * pixel_size / focal_length) as f32)
+    }
+
+    /// Convert an angular size measured in pixels to arcseconds, or `None` when the pixel
+    /// scale is unknown
+    pub fn pixels_to_arcsec(&self, pixels: f32) -> Option<f32> {
+        Some(pixels * self.pixel_scale()?)
+    }
+
+    /// Fill in an output file name template for a stack of `frame_count` frames.
+    ///
+    /// Supported placeholders are `{object}`, `{filter}`, `{count}`, `{exposure}` (seconds
+    /// per frame) and `{date}` (the day of DATE-OBS). Missing values become `unknown`, and
+    /// characters that are awkward in file names are replaced with `_`.
+    pub fn resolve_template(&self, template: &str, frame_count: usize) -> String {
+        let exposure = self
+            .exposure_time
+            .map(|seconds| format!("{}", (seconds * 1000.0).round() / 1000.0));
+        let date = self
+            .date_obs
+            .as_deref()
+            .and_then(|date| date.split('T').next());
+
+        template
+            .replace("{object}", &template_value(self.object.as_deref()))
+            .replace("{filter}", &template_value(self.filter.as_deref()))
+            .replace("{count}", &frame_count.to_string())
+            .replace("{exposure}", &template_value(exposure.as_deref()))
+            .replace("{date}", &template_value(date))
+    }
+}
+
+/// Days between 1970-01-01 and a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Arcseconds per radian scaled from microns over millimeters: the 206.265 in
+/// `scale = 206.265 * pixel size (µm) / focal length (mm)`
+const ARCSEC_PER_RADIAN_MICRON_PER_MM: f64 = 206.265;
+
+/// Default output file name for stacked images
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "{object}_{filter}_{count}x{exposure}s_stacked.fits";
+
+/// A metadata value made safe to use as part of a file name
+pub(crate) fn template_value(value: Option<&str>) -> String {
+    let value = value.map(str::trim).filter(|v| !v.is_empty());
+    match value {
+        Some(value) => value
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || matches!(c, '-' | '.') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+        None => "unknown".to_string(),
+    }
 }
 
 /// Image statistics
+#[derive(Debug, Clone)]
 pub struct ImageStatistics {
     /// Minimum pixel value
     pub min: f32,
@@ -70,6 +286,65 @@ pub struct ImageStatistics {
     pub std_dev: f32,
 }
 
+impl ImageStatistics {
+    /// Statistics of an image without any finite pixels
+    fn empty() -> Self {
+        Self {
+            min: 0.0,
+            max: 0.0,
+            mean: 0.0,
+            median: 0.0,
+            std_dev: 0.0,
+        }
+    }
+
+    /// Statistics of the finite pixels of `data` that lie at least `edge_margin` pixels
+    /// from the edges of their plane (the last two axes), so vignetting and amp glow
+    /// along the border don't skew the result.
+    ///
+    /// The whole plane is used when the margin would leave no pixels.
+    pub fn excluding_edges(mut data: ArrayViewD<f32>, edge_margin: usize) -> Self {
+        let ndim = data.ndim();
+        let plane_axes = [Axis(ndim.saturating_sub(2)), Axis(ndim.saturating_sub(1))];
+        let fits = ndim >= 2
+            && plane_axes
+                .iter()
+                .all(|&axis| data.len_of(axis) > 2 * edge_margin);
+        if fits {
+            for axis in plane_axes {
+                let len = data.len_of(axis);
+                data.slice_axis_inplace(axis, Slice::from(edge_margin..len - edge_margin));
+            }
+        }
+
+        Self::of_values(data.iter().copied().filter(|v| v.is_finite()).collect())
+    }
+
+    /// Statistics of finite `values`, in any order
+    fn of_values(mut values: Vec<f32>) -> Self {
+        if values.is_empty() {
+            return Self::empty();
+        }
+
+        let (min, max) = values.iter().fold((f32::MAX, f32::MIN), |(min, max), &v| {
+            (min.min(v), max.max(v))
+        });
+        let count = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / count;
+        let std_dev = (values.iter().map(|&v| (v - mean).powi(2)).sum::<f32>() / count).sqrt();
+
+        let median = median_of(&mut values);
+
+        Self {
+            min,
+            max,
+            mean,
+            median,
+            std_dev,
+        }
+    }
+}
+
 impl Default for ImageMetadata {
     fn default() -> Self {
         Self {
@@ -78,13 +353,81 @@ impl Default for ImageMetadata {
             exposure_time: None,
             temperature: None,
             iso_gain: None,
+            offset: None,
             filter: None,
+            object: None,
+            airmass: None,
+            rotation: None,
+            date_obs: None,
+            focal_length: None,
+            pixel_size_x: None,
+            pixel_size_y: None,
             file_path: None,
             extra: std::collections::HashMap::new(),
+            history: ProcessingHistory::default(),
+        }
+    }
+}
+
+/// How the median is computed when calculating statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MedianMethod {
+    /// Sort every pixel value: exact, but O(n log n)
+    #[default]
+    Exact,
+    /// Bin the values into a histogram: O(n), accurate to one bin width
+    Histogram { bins: usize },
+}
+
+/// Options controlling how an image is written by `to_file_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    /// Constant added to every pixel before casting, so values that went slightly negative
+    /// (e.g. after dark subtraction) aren't clipped by unsigned pixel types. Recorded in the
+    /// PEDESTAL keyword and subtracted again when the file is loaded. Following MaxIm DL,
+    /// the keyword holds the value to add to the stored pixels, i.e. `-pedestal`.
+    pub pedestal: f32,
+    /// Write integer images as 32-bit float instead of casting back to their original type,
+    /// keeping the fractional values produced by stacking and calibration
+    pub force_float: bool,
+}
+
+impl SaveOptions {
+    /// Options for processed results (stacks, calibrated frames): always saved as float
+    pub fn processed() -> Self {
+        Self {
+            force_float: true,
+            ..Self::default()
         }
     }
 }
 
+/// How stacked results are arranged in the output folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputLayout {
+    /// Everything is written directly into the output folder
+    #[default]
+    Flat,
+    /// Each filter's stack goes into its own subfolder named after the filter
+    ByFilter,
+}
+
+impl OutputLayout {
+    /// Folder a stack taken with `filter` is written to, created if it doesn't exist yet
+    pub fn directory(
+        &self,
+        output_dir: &Path,
+        filter: Option<&str>,
+    ) -> Result<PathBuf, ImageError> {
+        let directory = match self {
+            OutputLayout::Flat => output_dir.to_path_buf(),
+            OutputLayout::ByFilter => output_dir.join(template_value(filter)),
+        };
+        std::fs::create_dir_all(&directory)?;
+        Ok(directory)
+    }
+}
+
 /// Calibration frame type
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FrameType {
@@ -95,6 +438,50 @@ pub enum FrameType {
     DarkFlat,
 }
 
+impl FrameType {
+    /// Parse the value of a FRAME keyword (case-insensitive)
+    pub fn from_keyword(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "light" => Some(FrameType::Light),
+            "dark" => Some(FrameType::Dark),
+            "flat" => Some(FrameType::Flat),
+            "bias" => Some(FrameType::Bias),
+            "darkflat" => Some(FrameType::DarkFlat),
+            _ => None,
+        }
+    }
+
+    /// Parse the value of an IMAGETYP keyword as written by capture software, e.g.
+    /// "Light Frame", "Dark Frame", "Flat Field", "Bias Frame" or "Dark Flat"
+    pub fn from_image_type(value: &str) -> Option<Self> {
+        let normalized: String = value
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        let normalized = normalized
+            .strip_suffix("frame")
+            .or_else(|| normalized.strip_suffix("field"))
+            .unwrap_or(&normalized);
+        match normalized {
+            "offset" => Some(FrameType::Bias),
+            "flatdark" => Some(FrameType::DarkFlat),
+            other => Self::from_keyword(other),
+        }
+    }
+
+    /// The value written to the FRAME keyword for this frame type
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            FrameType::Light => "LIGHT",
+            FrameType::Dark => "DARK",
+            FrameType::Flat => "FLAT",
+            FrameType::Bias => "BIAS",
+            FrameType::DarkFlat => "DARKFLAT",
+        }
+    }
+}
+
 /// Error types for image operations
 #[derive(Debug)]
 pub enum ImageError {
@@ -136,10 +523,12 @@ impl From<fitsio::errors::Error> for ImageError {
 pub struct FitsImage {
     /// Metadata for the image
     pub metadata: ImageMetadata,
-    /// The actual pixel data
-    pub data: ArrayD<f32>,
+    /// The actual pixel data, written only through `data_mut`/`set_data`
+    data: ArrayD<f32>,
     /// The frame type
     pub frame_type: FrameType,
+    /// Cached result of `calculate_statistics`, cleared by `data_mut`/`set_data`
+    stats_cache: OnceLock<ImageStatistics>,
 }
 
 impl FitsImage {
@@ -151,55 +540,309 @@ impl FitsImage {
             metadata: ImageMetadata::default(),
             data,
             frame_type: FrameType::Light,
+            stats_cache: OnceLock::new(),
         }
     }
 
-    pub fn from_folder<P: AsRef<Path>>(
+    /// Load every file in a folder whose name ends with one of `extensions`.
+    ///
+    /// A missing folder is an `IoError` naming the path, and a folder without any
+    /// matching file is a `FormatError`, so callers never get an empty set of frames.
+    pub fn from_folder<P: AsRef<Path>, S: AsRef<str>>(
         path: P,
         frame_type: FrameType,
+        extensions: &[S],
     ) -> Result<Vec<Self>, ImageError> {
-        let path = path.as_ref();
-        let mut images = Vec::new();
+        Self::from_folder_with_cancel(path, frame_type, extensions, &AtomicBool::new(false))
+    }
 
-        // Iterate over all files in the directory
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            let file_path = entry.path();
+    /// `from_folder` that stops before the next file once `cancelled` is set, returning the
+    /// frames read until then (possibly none) instead of an error
+    pub fn from_folder_with_cancel<P: AsRef<Path>, S: AsRef<str>>(
+        path: P,
+        frame_type: FrameType,
+        extensions: &[S],
+        cancelled: &AtomicBool,
+    ) -> Result<Vec<Self>, ImageError> {
+        let mut images = Vec::new();
 
-            // Check if the file is a FITS file
-            if file_path
-                .extension()
-                .map_or(false, |ext| ext == "fits" || ext == "fit" || ext == "fts")
-            {
-                println!("Loading FITS file: {:?}", file_path);
-                let image = FitsImage::from_file(&file_path, frame_type)?;
-                println!("Loaded FITS file: {:?}", file_path);
-                images.push(image);
+        for file_path in Self::list_folder(path, extensions)? {
+            if cancelled.load(Ordering::Relaxed) {
+                log::info!("Loading cancelled after {} frames", images.len());
+                return Ok(images);
             }
+
+            log::debug!("Loading FITS file: {:?}", file_path);
+            let image = FitsImage::from_file(&file_path, frame_type)?;
+            log::debug!("Loaded FITS file: {:?}", file_path);
+            images.push(image);
         }
 
         Ok(images)
     }
 
-    /// Load a FITS image from a file
-    pub fn from_file<P: AsRef<Path>>(path: P, frame_type: FrameType) -> Result<Self, ImageError> {
+    /// Lazily load the FITS files of a folder in file name order, one frame per item.
+    ///
+    /// Only the listing is read up front; each frame is read when the iterator reaches it,
+    /// and a file that fails to load yields an `Err` without ending the iteration. A folder
+    /// that is missing or holds no FITS files yields a single `Err`, as in `from_folder`.
+    pub fn iter_folder<P: AsRef<Path>>(
+        path: P,
+        frame_type: FrameType,
+    ) -> impl Iterator<Item = Result<Self, ImageError>> {
+        let (paths, error) = match Self::list_folder(path, FITS_EXTENSIONS) {
+            Ok(paths) => (paths, None),
+            Err(e) => (Vec::new(), Some(Err(e))),
+        };
+        error.into_iter().chain(
+            paths
+                .into_iter()
+                .map(move |path| Self::from_file(&path, frame_type)),
+        )
+    }
+
+    /// The files in a folder with one of `extensions`, in file name order.
+    ///
+    /// Fails when the folder doesn't exist or holds no matching files.
+    pub fn list_folder<P: AsRef<Path>, S: AsRef<str>>(
+        path: P,
+        extensions: &[S],
+    ) -> Result<Vec<PathBuf>, ImageError> {
+        let path = path.as_ref();
+        let entries = std::fs::read_dir(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("directory {} not found", path.display()),
+            ),
+            _ => e,
+        })?;
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let file_path = entry?.path();
+            if file_path.is_file() && Self::has_extension(&file_path, extensions) {
+                paths.push(file_path);
+            }
+        }
+        paths.sort();
+
+        if paths.is_empty() {
+            return Err(ImageError::FormatError(format!(
+                "no FITS files found in {}",
+                path.display()
+            )));
+        }
+
+        Ok(paths)
+    }
+
+    /// Whether a path has one of the FITS file extensions (including compressed ones)
+    pub fn is_fits_file<P: AsRef<Path>>(path: P) -> bool {
+        Self::has_extension(path, FITS_EXTENSIONS)
+    }
+
+    /// Whether a file name ends with one of `extensions` (without the leading dot,
+    /// compared case-insensitively), so multi-part extensions like `fits.gz` work
+    pub fn has_extension<P: AsRef<Path>, S: AsRef<str>>(path: P, extensions: &[S]) -> bool {
+        let file_name = path
+            .as_ref()
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        extensions.iter().any(|ext| {
+            let ext = ext.as_ref().trim().trim_start_matches('.').to_lowercase();
+            !ext.is_empty() && file_name.ends_with(&format!(".{}", ext))
+        })
+    }
+
+    /// Width and height of a FITS file's image, read from its header without loading the
+    /// pixels. Compressed files report the size of the image they hold.
+    pub fn read_dimensions<P: AsRef<Path>>(path: P) -> Result<(usize, usize), ImageError> {
+        let mut fitsfile = FitsFile::open(path.as_ref())?;
+        let mut hdu = fitsfile.primary_hdu()?;
+        if matches!(&hdu.info, fitsio::hdu::HduInfo::ImageInfo { shape, .. } if shape.is_empty()) {
+            hdu = fitsfile.hdu(1)?;
+        }
+
+        match &hdu.info {
+            fitsio::hdu::HduInfo::ImageInfo { shape, .. } if shape.len() >= 2 => {
+                Ok((shape[shape.len() - 1], shape[shape.len() - 2]))
+            }
+            _ => Err(ImageError::UnsupportedOperation(
+                "Only image HDUs are supported".to_string(),
+            )),
+        }
+    }
+
+    /// Check that a FITS file's declared image size matches the data actually present.
+    ///
+    /// Reads only the primary header, so truncated captures are reported with a
+    /// descriptive `FormatError` instead of failing later while reshaping the pixels.
+    /// Gzipped files are decompressed on the fly and checked as the file they hold, which
+    /// also catches a corrupt or cut off gzip stream.
+    pub fn validate<P: AsRef<Path>>(path: P) -> Result<(), ImageError> {
+        use std::io::{Read, Seek};
+
+        const BLOCK_SIZE: usize = 2880;
+        const CARD_SIZE: usize = 80;
+
         let path = path.as_ref();
+        let mut file = std::fs::File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+
+        let mut bitpix: Option<i64> = None;
+        let mut naxes: Vec<(usize, usize)> = Vec::new();
+        let mut header_len = 0;
+        let mut found_end = false;
+        let mut block = [0u8; BLOCK_SIZE];
+
+        let mut magic = [0u8; 2];
+        let gzipped = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+        file.seek(std::io::SeekFrom::Start(0))?;
+        let mut reader: Box<dyn Read> = if gzipped {
+            Box::new(flate2::read::GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let corrupt_gzip = |e: io::Error| {
+            ImageError::FormatError(format!("{}: corrupt gzip stream: {}", path.display(), e))
+        };
+
+        while !found_end {
+            match reader.read_exact(&mut block) {
+                Ok(()) => {}
+                Err(e) if gzipped && e.kind() != io::ErrorKind::UnexpectedEof => {
+                    return Err(corrupt_gzip(e));
+                }
+                Err(_) => {
+                    return Err(ImageError::FormatError(format!(
+                        "{}: header is truncated (no END card found)",
+                        path.display()
+                    )));
+                }
+            }
+
+            if header_len == 0 && !block.starts_with(b"SIMPLE") {
+                return Err(ImageError::FormatError(format!(
+                    "{}: not a FITS file",
+                    path.display()
+                )));
+            }
+            header_len += BLOCK_SIZE;
+
+            for card in block.chunks(CARD_SIZE) {
+                // Header cards are plain ASCII; anything else can't be a keyword we need
+                let key = std::str::from_utf8(&card[..8]).unwrap_or("").trim();
+
+                if key == "END" {
+                    found_end = true;
+                    break;
+                }
+
+                if &card[8..10] != b"= " {
+                    continue;
+                }
+
+                // Numeric values never contain '/', so everything after it is a comment
+                let value = std::str::from_utf8(&card[10..])
+                    .unwrap_or("")
+                    .split('/')
+                    .next()
+                    .unwrap_or("")
+                    .trim();
+
+                if key == "BITPIX" {
+                    bitpix = value.parse().ok();
+                } else if let Some(axis) = key.strip_prefix("NAXIS") {
+                    if let (Ok(axis), Ok(length)) = (axis.parse::<usize>(), value.parse()) {
+                        naxes.push((axis, length));
+                    }
+                }
+            }
+        }
+
+        let bitpix = bitpix.ok_or_else(|| {
+            ImageError::FormatError(format!("{}: missing BITPIX keyword", path.display()))
+        })?;
+        naxes.sort_by_key(|&(axis, _)| axis);
+
+        let bytes_per_pixel = (bitpix.unsigned_abs() / 8) as usize;
+        let declared_pixels: usize = if naxes.is_empty() {
+            0
+        } else {
+            naxes.iter().map(|&(_, length)| length).product()
+        };
+
+        // The data of a gzipped file is only known once the whole stream is decompressed
+        let file_len = if gzipped {
+            let data_len = io::copy(&mut reader, &mut io::sink()).map_err(corrupt_gzip)?;
+            header_len + data_len as usize
+        } else {
+            file_len
+        };
+        let available_bytes = file_len.saturating_sub(header_len);
+        if bytes_per_pixel == 0 || declared_pixels * bytes_per_pixel <= available_bytes {
+            return Ok(());
+        }
+
+        let declared = naxes
+            .iter()
+            .map(|(_, length)| length.to_string())
+            .collect::<Vec<_>>()
+            .join("x");
+
+        Err(ImageError::FormatError(format!(
+            "{}: declared {} but only {} pixels present",
+            path.display(),
+            declared,
+            available_bytes / bytes_per_pixel
+        )))
+    }
+
+    /// Load a FITS image from a file.
+    ///
+    /// A FRAME or IMAGETYP keyword in the header decides the frame type; `frame_type` is
+    /// used for files that have neither.
+    pub fn from_file<P: AsRef<Path>>(path: P, frame_type: FrameType) -> Result<Self, ImageError> {
+        Self::read_file(path.as_ref(), Some(frame_type))
+    }
+
+    /// Load a FITS image whose frame type isn't known up front. Files without a FRAME or
+    /// IMAGETYP keyword get a type inferred from their pixels (see `infer_frame_type`).
+    pub fn from_file_detect_type<P: AsRef<Path>>(path: P) -> Result<Self, ImageError> {
+        Self::read_file(path.as_ref(), None)
+    }
+
+    fn read_file(path: &Path, frame_type: Option<FrameType>) -> Result<Self, ImageError> {
         let mut fitsfile = FitsFile::open(path)?;
 
         // Access the primary HDU (Header Data Unit)
-        let hdu = fitsfile.primary_hdu()?;
+        let mut hdu = fitsfile.primary_hdu()?;
+
+        // Tile-compressed (.fz) files leave the primary HDU empty and store the image in the
+        // first extension; cfitsio decompresses it (and gzipped files) transparently on read
+        if matches!(&hdu.info, fitsio::hdu::HduInfo::ImageInfo { shape, .. } if shape.is_empty()) {
+            hdu = fitsfile.hdu(1)?;
+        }
 
         match &hdu.info {
             fitsio::hdu::HduInfo::ImageInfo { shape, image_type } => {
-                // Check if the image is 2D
-                if shape.len() != 2 {
-                    return Err(ImageError::UnsupportedOperation(
-                        "Only 2D images are supported".to_string(),
-                    ));
-                }
-
-                let height = shape[0] as usize;
-                let width = shape[1] as usize;
+                // Mono images are 2D; color images are a cube of three planes, which is
+                // told apart from other cubes (e.g. time series) by CTYPE3
+                let pixel_shape = match *shape.as_slice() {
+                    [height, width] => vec![height, width],
+                    [3, height, width] if is_color_cube(&hdu, &mut fitsfile) => {
+                        vec![3, height, width]
+                    }
+                    _ => {
+                        return Err(ImageError::UnsupportedOperation(
+                            "Only 2D images and 3-plane color cubes are supported".to_string(),
+                        ));
+                    }
+                };
+                let height = pixel_shape[pixel_shape.len() - 2];
+                let width = pixel_shape[pixel_shape.len() - 1];
 
                 // Initialize metadata
                 let mut metadata = ImageMetadata {
@@ -221,44 +864,94 @@ impl FitsImage {
                     metadata.filter = Some(filter);
                 }
 
+                if let Ok(object) = hdu.read_key::<String>(&mut fitsfile, "OBJECT") {
+                    metadata.object = Some(object);
+                }
+
+                metadata.airmass = read_numeric_key(&hdu, &mut fitsfile, "AIRMASS");
+                metadata.rotation = read_numeric_key(&hdu, &mut fitsfile, "CROTA2");
+
+                if let Ok(date_obs) = hdu.read_key::<String>(&mut fitsfile, "DATE-OBS") {
+                    metadata.date_obs = Some(date_obs);
+                }
+
+                metadata.focal_length = read_numeric_key(&hdu, &mut fitsfile, "FOCALLEN");
+
+                if let Ok(xpixsz) = hdu.read_key::<f64>(&mut fitsfile, "XPIXSZ") {
+                    metadata.pixel_size_x = Some(xpixsz);
+                }
+
+                if let Ok(ypixsz) = hdu.read_key::<f64>(&mut fitsfile, "YPIXSZ") {
+                    metadata.pixel_size_y = Some(ypixsz);
+                }
+
+                // Gain is GAIN on CMOS/CCD cameras and ISOSPEED or ISO on DSLRs
+                metadata.iso_gain = ["GAIN", "ISOSPEED", "ISO"]
+                    .iter()
+                    .find_map(|key| read_numeric_key(&hdu, &mut fitsfile, key));
+
+                metadata.offset = read_numeric_key(&hdu, &mut fitsfile, "OFFSET")
+                    .map(|offset| offset.round().max(0.0) as u32);
+
+                // Keep any other user keywords (e.g. set with set_metadata_key) so they're
+                // written back on save
+                metadata.extra = read_extra_keys(&mut fitsfile)?;
+
+                // Integer pixels equal to BLANK are undefined. BLANK is a raw stored value, so
+                // scale it like the pixels are (e.g. unsigned 16-bit data is stored with BZERO)
+                let blank = hdu.read_key::<i64>(&mut fitsfile, "BLANK").ok().map(|raw| {
+                    let bzero = read_numeric_key(&hdu, &mut fitsfile, "BZERO").unwrap_or(0.0);
+                    let bscale = read_numeric_key(&hdu, &mut fitsfile, "BSCALE").unwrap_or(1.0);
+                    raw as f64 * bscale + bzero
+                });
+
                 // Read the pixel data into an ndarray
-                let data: ArrayD<f32> = match image_type {
+                let mut data: ArrayD<f32> = match image_type {
+                    // Signed bytes don't fit U8, widen them to I16 instead
                     fitsio::images::ImageType::Byte => {
-                        metadata.pixel_type = PixelType::U8;
-                        let pixels: Vec<u8> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<u8, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        metadata.pixel_type = PixelType::I16;
+                        let pixels: Vec<i16> = hdu.read_image(&mut fitsfile)?;
+                        ndarray::Array::<i16, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
-                            .mapv(|x| x as f32)
+                            .mapv(|x| blank_to_nan(x as f64, blank))
                             .into_dyn()
                     }
-                    fitsio::images::ImageType::LongLong => {
+                    fitsio::images::ImageType::Long => {
                         metadata.pixel_type = PixelType::I32;
+                        let pixels: Vec<i32> = hdu.read_image(&mut fitsfile)?;
+                        ndarray::Array::<i32, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
+                            .map_err(|e| ImageError::DimensionError(e.to_string()))?
+                            .mapv(|x| blank_to_nan(x as f64, blank))
+                            .into_dyn()
+                    }
+                    fitsio::images::ImageType::LongLong => {
+                        metadata.pixel_type = PixelType::I64;
                         let pixels: Vec<i64> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<i64, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<i64, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
-                            .mapv(|x| x as f32)
+                            .mapv(|x| blank_to_nan(x as f64, blank))
                             .into_dyn()
                     }
                     fitsio::images::ImageType::UnsignedByte => {
                         metadata.pixel_type = PixelType::U8;
                         let pixels: Vec<u8> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<u8, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<u8, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
-                            .mapv(|x| x as f32)
+                            .mapv(|x| blank_to_nan(x as f64, blank))
                             .into_dyn()
                     }
                     fitsio::images::ImageType::UnsignedLong => {
                         metadata.pixel_type = PixelType::U32;
                         let pixels: Vec<u32> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<u32, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<u32, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
-                            .mapv(|x| x as f32)
+                            .mapv(|x| blank_to_nan(x as f64, blank))
                             .into_dyn()
                     }
                     fitsio::images::ImageType::Double => {
                         metadata.pixel_type = PixelType::F64;
                         let pixels: Vec<f64> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<f64, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<f64, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
                             .mapv(|x| x as f32)
                             .into_dyn()
@@ -266,53 +959,68 @@ impl FitsImage {
                     fitsio::images::ImageType::Float => {
                         metadata.pixel_type = PixelType::F32;
                         let pixels: Vec<f32> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<f32, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<f32, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
                             .into_dyn()
                     }
                     fitsio::images::ImageType::Short => {
                         metadata.pixel_type = PixelType::I16;
                         let pixels: Vec<i16> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<i16, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<i16, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
-                            .mapv(|x| x as f32)
+                            .mapv(|x| blank_to_nan(x as f64, blank))
                             .into_dyn()
                     }
                     fitsio::images::ImageType::UnsignedShort => {
                         metadata.pixel_type = PixelType::U16;
                         let pixels: Vec<u16> = hdu.read_image(&mut fitsfile)?;
-                        ndarray::Array::<u16, _>::from_shape_vec(IxDyn(&[height, width]), pixels)
+                        ndarray::Array::<u16, _>::from_shape_vec(IxDyn(&pixel_shape), pixels)
                             .map_err(|e| ImageError::DimensionError(e.to_string()))?
-                            .mapv(|x| x as f32)
+                            .mapv(|x| blank_to_nan(x as f64, blank))
                             .into_dyn()
                     }
-                    _ => {
-                        return Err(ImageError::UnsupportedOperation(
-                            "Unsupported FITS data type".to_string(),
-                        ));
-                    }
                 };
 
-                // Determine frame type based on FITS header if available
-                let frame_type =
-                    if let Ok(frametype) = hdu.read_key::<String>(&mut fitsfile, "FRAME") {
-                        match frametype.to_lowercase().as_str() {
-                            "light" => FrameType::Light,
-                            "dark" => FrameType::Dark,
-                            "flat" => FrameType::Flat,
-                            "bias" => FrameType::Bias,
-                            "darkflat" => FrameType::DarkFlat,
-                            _ => FrameType::Light, // Default to light frame
-                        }
-                    } else {
-                        frame_type
-                    };
-
-                Ok(Self {
+                // Reverse any pedestal that was added when the file was saved. As in MaxIm DL,
+                // PEDESTAL is the value to add to the stored pixels (negative when an offset
+                // was added)
+                if let Ok(pedestal) = hdu.read_key::<f64>(&mut fitsfile, "PEDESTAL") {
+                    let pedestal = pedestal as f32;
+                    data.mapv_inplace(|v| v + pedestal);
+                }
+
+                let mut image = Self {
                     metadata,
                     data,
-                    frame_type,
-                })
+                    frame_type: frame_type.unwrap_or(FrameType::Light),
+                    stats_cache: OnceLock::new(),
+                };
+
+                // Determine frame type from the FITS header if available. Without one the
+                // caller's type is kept, and only when there is none is it guessed from
+                // the pixel data.
+                let header_type = match hdu.read_key::<String>(&mut fitsfile, "FRAME") {
+                    // Default to light frame for unknown values
+                    Ok(frametype) => {
+                        Some(FrameType::from_keyword(&frametype).unwrap_or(FrameType::Light))
+                    }
+                    Err(_) => hdu
+                        .read_key::<String>(&mut fitsfile, "IMAGETYP")
+                        .ok()
+                        .and_then(|imagetyp| FrameType::from_image_type(&imagetyp)),
+                };
+                if let Some(header_type) = header_type {
+                    image.frame_type = header_type;
+                } else if frame_type.is_none() {
+                    image.frame_type = crate::analysis::infer_frame_type(&image);
+                    log::info!(
+                        "{:?} has no FRAME or IMAGETYP keyword and looks like a {:?} frame",
+                        path,
+                        image.frame_type
+                    );
+                }
+
+                Ok(image)
             }
             _ => {
                 return Err(ImageError::UnsupportedOperation(
@@ -324,12 +1032,64 @@ impl FitsImage {
 
     /// Save the image to a FITS file
     pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        self.to_file_with_options(path, &SaveOptions::default())
+    }
+
+    /// Save the image to a FITS file with explicit save options
+    pub fn to_file_with_options<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: &SaveOptions,
+    ) -> Result<(), ImageError> {
         let path = path.as_ref();
+        let pedestal = options.pedestal;
+        let pixel_type = match self.metadata.pixel_type {
+            PixelType::F32 | PixelType::F64 => self.metadata.pixel_type,
+            _ if options.force_float => PixelType::F32,
+            original => original,
+        };
+
+        // Integer types can't hold NaN, so missing pixels are written as the BLANK value.
+        // That only works if no real pixel is stored as BLANK too; when one is (e.g. real
+        // zeros in unsigned data, whose only possible BLANK reads back as 0) store float.
+        let has_missing = self.data.iter().any(|v| v.is_nan());
+        let pixel_type = match pixel_type.blank_value() {
+            Some((_, blank))
+                if has_missing
+                    && self
+                        .data
+                        .iter()
+                        .any(|&v| v.is_finite() && v + pedestal < blank + 1.0) =>
+            {
+                PixelType::F32
+            }
+            _ => pixel_type,
+        };
+
+        // The header is written from the metadata but the pixels from the data, so a mismatch
+        // would silently produce a corrupt file
+        let (width, height) = self.metadata.dimensions;
+        let shape = self.data.shape();
+        let channels = if shape.len() == 3 { shape[0] } else { 1 };
+        if self.data.len() != channels * width * height {
+            return Err(ImageError::DimensionError(format!(
+                "Image data has {} values but metadata dimensions are {}x{}",
+                self.data.len(),
+                width,
+                height
+            )));
+        }
 
-        // Create a new FITS file
+        // Create a new FITS file, stored with the image's own pixel type. Axes are given
+        // slowest-varying first, matching the row-major data.
+        let axes = if shape.len() == 3 {
+            vec![channels, height, width]
+        } else {
+            vec![height, width]
+        };
         let description = ImageDescription {
-            data_type: ImageType::Double,
-            dimensions: &[self.metadata.dimensions.0, self.metadata.dimensions.1],
+            data_type: pixel_type.image_type(),
+            dimensions: &axes,
         };
         let mut fitsfile = FitsFile::create(path)
             .with_custom_primary(&description)
@@ -350,51 +1110,107 @@ impl FitsImage {
             hdu.write_key(&mut fitsfile, "FILTER", filter.as_str())?;
         }
 
-        // Write frame type
-        match self.frame_type {
-            FrameType::Light => hdu.write_key(&mut fitsfile, "FRAME", "LIGHT")?,
-            FrameType::Dark => hdu.write_key(&mut fitsfile, "FRAME", "DARK")?,
-            FrameType::Flat => hdu.write_key(&mut fitsfile, "FRAME", "FLAT")?,
-            FrameType::Bias => hdu.write_key(&mut fitsfile, "FRAME", "BIAS")?,
-            FrameType::DarkFlat => hdu.write_key(&mut fitsfile, "FRAME", "DARKFLAT")?,
+        if let Some(ref object) = self.metadata.object {
+            hdu.write_key(&mut fitsfile, "OBJECT", object.as_str())?;
         }
 
-        // Write extra metadata
-        for (key, value) in &self.metadata.extra {
-            // FITS keys are limited to 8 characters
-            let key = if key.len() > 8 { &key[0..8] } else { key };
+        if let Some(airmass) = self.metadata.airmass {
+            hdu.write_key(&mut fitsfile, "AIRMASS", airmass)?;
+        }
 
-            hdu.write_key(&mut fitsfile, key, value.as_str())?;
+        if let Some(rotation) = self.metadata.rotation {
+            hdu.write_key(&mut fitsfile, "CROTA2", rotation)?;
         }
 
-        // Write the pixel data based on the original pixel type
-        match self.metadata.pixel_type {
-            PixelType::U8 => {
-                let data: Vec<u8> = self.data.iter().map(|&x| x as u8).collect();
-                hdu.write_image(&mut fitsfile, &data)?;
-            }
-            PixelType::I16 => {
-                let data: Vec<i16> = self.data.iter().map(|&x| x as i16).collect();
-                hdu.write_image(&mut fitsfile, &data)?;
-            }
-            PixelType::U16 => {
-                let data: Vec<u16> = self.data.iter().map(|&x| x as u16).collect();
-                hdu.write_image(&mut fitsfile, &data)?;
+        if let Some(ref date_obs) = self.metadata.date_obs {
+            hdu.write_key(&mut fitsfile, "DATE-OBS", date_obs.as_str())?;
+        }
+
+        if let Some(focal_length) = self.metadata.focal_length {
+            hdu.write_key(&mut fitsfile, "FOCALLEN", focal_length)?;
+        }
+
+        if let Some(xpixsz) = self.metadata.pixel_size_x {
+            hdu.write_key(&mut fitsfile, "XPIXSZ", xpixsz)?;
+        }
+
+        if let Some(ypixsz) = self.metadata.pixel_size_y {
+            hdu.write_key(&mut fitsfile, "YPIXSZ", ypixsz)?;
+        }
+
+        // Whole gains (ISO, camera gain settings) stay integer cards
+        if let Some(gain) = self.metadata.iso_gain {
+            if gain.fract() == 0.0 {
+                hdu.write_key(&mut fitsfile, "GAIN", gain as i64)?;
+            } else {
+                hdu.write_key(&mut fitsfile, "GAIN", gain)?;
+            }
+        }
+
+        if let Some(offset) = self.metadata.offset {
+            hdu.write_key(&mut fitsfile, "OFFSET", offset as i64)?;
+        }
+
+        // Write frame type
+        hdu.write_key(&mut fitsfile, "FRAME", self.frame_type.keyword())?;
+
+        // Record the pedestal so it can be subtracted again on load, with MaxIm DL's sign:
+        // the value that brings the stored pixels back to their original level
+        if pedestal != 0.0 {
+            hdu.write_key(&mut fitsfile, "PEDESTAL", -pedestal as f64)?;
+        }
+
+        // Write extra metadata
+        for (key, value) in &self.metadata.extra {
+            // FITS keys are limited to 8 characters
+            let key = if key.len() > 8 { &key[0..8] } else { key };
+
+            hdu.write_key(&mut fitsfile, key, value.as_str())?;
+        }
+
+        for entry in self.metadata.history.entries() {
+            write_history(&mut fitsfile, entry)?;
+        }
+
+        let blank = pixel_type.blank_value();
+        if let Some((raw, _)) = blank.filter(|_| has_missing) {
+            hdu.write_key(&mut fitsfile, "BLANK", raw)?;
+        }
+        let blank_physical = blank.map_or(0.0, |(_, physical)| physical);
+        let value = |x: f32| {
+            if x.is_nan() {
+                blank_physical
+            } else {
+                x + pedestal
+            }
+        };
+
+        // Write the pixel data based on the original pixel type, unless forced to float
+        match pixel_type {
+            PixelType::U8 => {
+                hdu.write_image(&mut fitsfile, &cast_clamped::<u8>(&self.data, value))?
+            }
+            PixelType::I16 => {
+                hdu.write_image(&mut fitsfile, &cast_clamped::<i16>(&self.data, value))?
+            }
+            PixelType::U16 => {
+                hdu.write_image(&mut fitsfile, &cast_clamped::<u16>(&self.data, value))?
             }
             PixelType::U32 => {
-                let data: Vec<u32> = self.data.iter().map(|&x| x as u32).collect();
-                hdu.write_image(&mut fitsfile, &data)?;
+                hdu.write_image(&mut fitsfile, &cast_clamped::<u32>(&self.data, value))?
             }
             PixelType::I32 => {
-                let data: Vec<i32> = self.data.iter().map(|&x| x as i32).collect();
-                hdu.write_image(&mut fitsfile, &data)?;
+                hdu.write_image(&mut fitsfile, &cast_clamped::<i32>(&self.data, value))?
+            }
+            PixelType::I64 => {
+                hdu.write_image(&mut fitsfile, &cast_clamped::<i64>(&self.data, value))?
             }
             PixelType::F32 => {
-                let data: Vec<f32> = self.data.iter().cloned().collect();
+                let data: Vec<f32> = self.data.iter().map(|&x| x + pedestal).collect();
                 hdu.write_image(&mut fitsfile, &data)?;
             }
             PixelType::F64 => {
-                let data: Vec<f64> = self.data.iter().map(|&x| x as f64).collect();
+                let data: Vec<f64> = self.data.iter().map(|&x| (x + pedestal) as f64).collect();
                 hdu.write_image(&mut fitsfile, &data)?;
             }
         }
@@ -402,30 +1218,308 @@ impl FitsImage {
         Ok(())
     }
 
+    /// Set a FITS keyword on the image.
+    ///
+    /// Keywords with a dedicated metadata field (see `MAPPED_KEYS`) update that field;
+    /// anything else is stored in `metadata.extra`, which is saved and read back with the
+    /// image. Keywords describing the file's structure or pixel encoding are rejected.
+    pub fn set_metadata_key(&mut self, key: &str, value: &str) -> Result<(), ImageError> {
+        let key = key.trim().to_uppercase();
+        let value = value.trim();
+        check_writable_key(&key)?;
+
+        let parse_number = |value: &str| {
+            value.parse::<f64>().map_err(|_| {
+                ImageError::FormatError(format!("{} expects a number, got '{}'", key, value))
+            })
+        };
+
+        match key.as_str() {
+            "EXPTIME" => self.metadata.exposure_time = Some(parse_number(value)?),
+            "CCD-TEMP" => self.metadata.temperature = Some(parse_number(value)?),
+            "FILTER" => self.metadata.filter = Some(value.to_string()),
+            "OBJECT" => self.metadata.object = Some(value.to_string()),
+            "AIRMASS" => self.metadata.airmass = Some(parse_number(value)?),
+            "CROTA2" => self.metadata.rotation = Some(parse_number(value)?),
+            "DATE-OBS" => self.metadata.date_obs = Some(value.to_string()),
+            "FOCALLEN" => self.metadata.focal_length = Some(parse_number(value)?),
+            "XPIXSZ" => self.metadata.pixel_size_x = Some(parse_number(value)?),
+            "YPIXSZ" => self.metadata.pixel_size_y = Some(parse_number(value)?),
+            "GAIN" | "ISOSPEED" | "ISO" => self.metadata.iso_gain = Some(parse_number(value)?),
+            "OFFSET" => self.metadata.offset = Some(parse_number(value)?.round().max(0.0) as u32),
+            "FRAME" | "IMAGETYP" => {
+                self.frame_type = FrameType::from_image_type(value).ok_or_else(|| {
+                    ImageError::FormatError(format!("Unknown frame type '{}'", value))
+                })?;
+            }
+            _ => {
+                self.metadata.extra.insert(key, value.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Update (or add) a keyword in the primary header of an existing FITS file.
+    ///
+    /// The header is edited in place, so pixel data and every other card are kept as-is.
+    /// Integer and float values are written as numeric cards, anything else as a string.
+    pub fn update_header_key<P: AsRef<Path>>(
+        path: P,
+        key: &str,
+        value: &str,
+    ) -> Result<(), ImageError> {
+        use std::ffi::CString;
+
+        let key = key.trim().to_uppercase();
+        let value = value.trim();
+        check_writable_key(&key)?;
+
+        let to_cstring = |s: &str| {
+            CString::new(s)
+                .map_err(|_| ImageError::FormatError(format!("'{}' contains a NUL character", s)))
+        };
+        let c_key = to_cstring(&key)?;
+        let c_comment = to_cstring("")?;
+
+        let mut fitsfile = FitsFile::edit(path.as_ref())?;
+        fitsfile.primary_hdu()?;
+
+        let mut status = 0;
+        // fitsio's write_key appends a new card, so use cfitsio's update calls instead.
+        // SAFETY: the handle is valid while `fitsfile` is borrowed, every string passed is a
+        // NUL-terminated CString that outlives the call, and `status` is a live local
+        unsafe {
+            let fptr = fitsfile.as_raw();
+            if let Ok(int_value) = value.parse::<i64>() {
+                fitsio::sys::ffukyj(
+                    fptr,
+                    c_key.as_ptr(),
+                    int_value,
+                    c_comment.as_ptr(),
+                    &mut status,
+                );
+            } else if let Ok(float_value) = value.parse::<f64>() {
+                fitsio::sys::ffukyd(
+                    fptr,
+                    c_key.as_ptr(),
+                    float_value,
+                    -15,
+                    c_comment.as_ptr(),
+                    &mut status,
+                );
+            } else {
+                let c_value = to_cstring(value)?;
+                fitsio::sys::ffukys(
+                    fptr,
+                    c_key.as_ptr(),
+                    c_value.as_ptr(),
+                    c_comment.as_ptr(),
+                    &mut status,
+                );
+            }
+        }
+
+        if status != 0 {
+            return Err(ImageError::FitsError(format!(
+                "Failed to update {} (cfitsio status {})",
+                key, status
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Get a reference to the image data
     pub fn data(&self) -> &ArrayD<f32> {
         &self.data
     }
 
     /// Get a mutable reference to the image data
+    ///
+    /// This clears the cached statistics, since the caller may change the pixels.
     pub fn data_mut(&mut self) -> &mut ArrayD<f32> {
+        self.stats_cache = OnceLock::new();
         &mut self.data
     }
 
+    /// Replace the image data, clearing the cached statistics
+    pub fn set_data(&mut self, data: ArrayD<f32>) {
+        *self.data_mut() = data;
+    }
+
+    /// Divide the image pixel by pixel by another image of the same shape, e.g. a flat.
+    ///
+    /// Pixels where the divisor isn't a positive finite value become missing (NaN).
+    pub fn divide(&mut self, divisor: &FitsImage) -> Result<(), ImageError> {
+        if self.data.shape() != divisor.data.shape() {
+            return Err(ImageError::DimensionError(format!(
+                "Cannot divide image of shape {:?} by image of shape {:?}",
+                self.data.shape(),
+                divisor.data.shape()
+            )));
+        }
+
+        ndarray::Zip::from(self.data_mut())
+            .and(&divisor.data)
+            .for_each(|value, &d| {
+                *value = if d.is_finite() && d > 0.0 {
+                    *value / d
+                } else {
+                    f32::NAN
+                };
+            });
+        self.metadata
+            .history
+            .record(format!("Divided by {:?} frame", divisor.frame_type));
+
+        Ok(())
+    }
+
+    /// Subtract `scale` times another image of the same shape pixel by pixel, e.g. a dark.
+    pub fn subtract(&mut self, other: &FitsImage, scale: f32) -> Result<(), ImageError> {
+        if self.data.shape() != other.data.shape() {
+            return Err(ImageError::DimensionError(format!(
+                "Cannot subtract image of shape {:?} from image of shape {:?}",
+                other.data.shape(),
+                self.data.shape()
+            )));
+        }
+
+        ndarray::Zip::from(self.data_mut())
+            .and(&other.data)
+            .for_each(|value, &o| *value -= scale * o);
+        let entry = if scale == 1.0 {
+            format!("Subtracted {:?} frame", other.frame_type)
+        } else {
+            format!(
+                "Subtracted {:?} frame scaled by {}",
+                other.frame_type, scale
+            )
+        };
+        self.metadata.history.record(entry);
+
+        Ok(())
+    }
+
     /// Get the dimensions of the image
     pub fn dimensions(&self) -> (usize, usize) {
         self.metadata.dimensions
     }
 
+    /// Copy out a `width` x `height` region starting at (`x`, `y`), across all channels
+    pub fn crop(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<FitsImage, ImageError> {
+        let (image_width, image_height) = self.dimensions();
+        if x + width > image_width || y + height > image_height {
+            return Err(ImageError::DimensionError(format!(
+                "Crop {}x{} at ({}, {}) exceeds the {}x{} image",
+                width, height, x, y, image_width, image_height
+            )));
+        }
+
+        // Height and width are the last two axes for both mono and color data
+        let ndim = self.data.ndim();
+        let data = self
+            .data
+            .slice_axis(Axis(ndim - 2), Slice::from(y..y + height))
+            .slice_axis(Axis(ndim - 1), Slice::from(x..x + width))
+            .to_owned();
+
+        let mut cropped = FitsImage::new(0, 0);
+        cropped.metadata = self.metadata.clone();
+        cropped.metadata.dimensions = (width, height);
+        cropped
+            .metadata
+            .history
+            .record(format!("Cropped to {}x{} at ({}, {})", width, height, x, y));
+        cropped.frame_type = self.frame_type;
+        *cropped.data_mut() = data;
+
+        Ok(cropped)
+    }
+
+    /// Crop equally from opposite edges down to `width` x `height`
+    pub fn crop_center(&self, width: usize, height: usize) -> Result<FitsImage, ImageError> {
+        let (image_width, image_height) = self.dimensions();
+        let x = image_width.saturating_sub(width) / 2;
+        let y = image_height.saturating_sub(height) / 2;
+        self.crop(x, y, width, height)
+    }
+
+    /// The pixels as a `[y, x]` plane, with the shape taken from the data itself rather
+    /// than the metadata. Fails for color cubes; use `to_luminance` or index a plane first.
+    pub fn view2d(&self) -> Result<ArrayView2<'_, f32>, ImageError> {
+        self.data.view().into_dimensionality::<Ix2>().map_err(|_| {
+            ImageError::DimensionError(format!(
+                "Expected a 2D image, got {} dimensions",
+                self.data.ndim()
+            ))
+        })
+    }
+
+    /// Number of channels: 1 for mono images, or the leading axis length for cubes
+    pub fn channels(&self) -> usize {
+        if self.data.ndim() == 3 {
+            self.data.shape()[0]
+        } else {
+            1
+        }
+    }
+
+    /// Whether the image holds three color planes laid out as `[channel, y, x]`
+    pub fn is_color(&self) -> bool {
+        self.channels() == 3
+    }
+
     /// Calculate basic image statistics: mean, median, min, max, and standard deviation
+    ///
+    /// The result is cached until the data is mutated through `data_mut`.
     pub fn calculate_statistics(&self) -> ImageStatistics {
+        self.stats_cache
+            .get_or_init(|| self.compute_statistics(MedianMethod::Exact))
+            .clone()
+    }
+
+    /// Calculate image statistics, choosing how the median is computed.
+    ///
+    /// `MedianMethod::Exact` goes through the statistics cache; the histogram
+    /// approximation is computed fresh each call.
+    pub fn calculate_statistics_with(&self, median_method: MedianMethod) -> ImageStatistics {
+        match median_method {
+            MedianMethod::Exact => self.calculate_statistics(),
+            MedianMethod::Histogram { .. } => self.compute_statistics(median_method),
+        }
+    }
+
+    /// Calculate image statistics over the pixels that survive iterative sigma clipping.
+    ///
+    /// Stars, hot pixels and other outliers are rejected first, so the result describes the
+    /// sky background. See `calculate_statistics` for the unclipped values.
+    pub fn calculate_statistics_clipped(&self, sigma: f32, iterations: usize) -> ImageStatistics {
+        let mut values: Vec<f32> = self.data.iter().copied().collect();
+        background::sigma_clip(&mut values, sigma, iterations);
+        ImageStatistics::of_values(values)
+    }
+
+    fn compute_statistics(&self, median_method: MedianMethod) -> ImageStatistics {
+        // NaN and infinite pixels (e.g. blank pixels) are treated as missing
+        let finite = || self.data.iter().copied().filter(|v| v.is_finite());
+
         let mut min = f32::MAX;
         let mut max = f32::MIN;
         let mut sum = 0.0;
+        let mut count = 0usize;
 
         // Calculate min, max, and sum
-        for &value in self.data.iter() {
+        for value in finite() {
             sum += value;
+            count += 1;
             if value < min {
                 min = value;
             }
@@ -434,28 +1528,25 @@ impl FitsImage {
             }
         }
 
-        let count = self.data.len() as f32;
+        if count == 0 {
+            return ImageStatistics::empty();
+        }
+
+        let count = count as f32;
         let mean = sum / count;
 
         // Calculate variance and standard deviation
         let mut variance_sum = 0.0;
-        for &value in self.data.iter() {
+        for value in finite() {
             variance_sum += (value - mean).powi(2);
         }
 
         let std_dev = (variance_sum / count).sqrt();
 
         // Calculate median
-        let mut values: Vec<f32> = self.data.iter().cloned().collect();
-        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-
-        let median = if values.is_empty() {
-            0.0
-        } else if values.len() % 2 == 0 {
-            let mid = values.len() / 2;
-            (values[mid - 1] + values[mid]) / 2.0
-        } else {
-            values[values.len() / 2]
+        let median = match median_method {
+            MedianMethod::Exact => median_of(&mut finite().collect::<Vec<f32>>()),
+            MedianMethod::Histogram { bins } => histogram_median(&self.data, min, max, bins),
         };
 
         ImageStatistics {
@@ -467,3 +1558,1050 @@ impl FitsImage {
         }
     }
 }
+
+#[cfg(test)]
+impl FitsImage {
+    /// Image holding `data`, a `[height, width]` or `[3, height, width]` array, with
+    /// matching metadata dimensions
+    pub(crate) fn from_data(data: ArrayD<f32>) -> Self {
+        let shape = data.shape();
+        let dimensions = (shape[shape.len() - 1], shape[shape.len() - 2]);
+        let mut image = Self::new(0, 0);
+        image.metadata.dimensions = dimensions;
+        image.set_data(data);
+        image
+    }
+}
+
+/// Median of a non-empty set of values, sorting them in place. Even counts give the mean
+/// of the middle two.
+pub(crate) fn median_of<T>(values: &mut [T]) -> T
+where
+    T: Copy + PartialOrd + std::ops::Add<Output = T> + std::ops::Div<Output = T> + From<u8>,
+{
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / T::from(2)
+    } else {
+        values[mid]
+    }
+}
+
+/// Run `f` on each 2D plane of `data`: the whole image, or each color plane of a cube
+fn for_each_plane_mut(
+    data: &mut ArrayD<f32>,
+    mut f: impl FnMut(ArrayViewMut2<f32>),
+) -> Result<(), ImageError> {
+    fn plane(view: ArrayViewMutD<'_, f32>) -> Result<ArrayViewMut2<'_, f32>, ImageError> {
+        view.into_dimensionality::<Ix2>()
+            .map_err(|e| ImageError::DimensionError(e.to_string()))
+    }
+
+    if data.ndim() == 3 {
+        for view in data.outer_iter_mut() {
+            f(plane(view)?);
+        }
+    } else {
+        f(plane(data.view_mut())?);
+    }
+    Ok(())
+}
+
+/// A path in the system temp directory, unique to this process, for a test to write to
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("eventide-test-{}-{}", std::process::id(), name))
+}
+
+/// Approximate the median of `data` from a histogram spanning `[min, max]`.
+///
+/// The result is interpolated within the bin holding the middle value, so the error is
+/// at most one bin width, `(max - min) / bins`.
+fn histogram_median(data: &ArrayD<f32>, min: f32, max: f32, bins: usize) -> f32 {
+    let count = data.iter().filter(|v| v.is_finite()).count();
+    if count == 0 {
+        return 0.0;
+    }
+
+    let range = max - min;
+    if range <= 0.0 || bins == 0 {
+        return min;
+    }
+
+    let scale = bins as f32 / range;
+    let mut histogram = vec![0usize; bins];
+    for &value in data.iter().filter(|v| v.is_finite()) {
+        let bin = (((value - min) * scale) as usize).min(bins - 1);
+        histogram[bin] += 1;
+    }
+
+    // Rank of the middle value
+    let target = count / 2;
+    let mut cumulative = 0;
+    for (bin, &bin_count) in histogram.iter().enumerate() {
+        if cumulative + bin_count > target {
+            let fraction = ((target - cumulative) as f32 + 0.5) / bin_count as f32;
+            return min + (bin as f32 + fraction) / scale;
+        }
+        cumulative += bin_count;
+    }
+
+    max
+}
+
+/// Whether a 3-plane cube holds color channels. Cubes without CTYPE3 are assumed to be
+/// color, as that's how processing software saves RGB images; otherwise CTYPE3 must name
+/// RGB planes.
+fn is_color_cube(hdu: &fitsio::hdu::FitsHdu, fitsfile: &mut FitsFile) -> bool {
+    match hdu.read_key::<String>(fitsfile, "CTYPE3") {
+        Ok(ctype) => ctype.trim().to_uppercase().contains("RGB"),
+        Err(_) => true,
+    }
+}
+
+/// An integer pixel value as f32, or NaN if it matches the BLANK value
+fn blank_to_nan(value: f64, blank: Option<f64>) -> f32 {
+    if blank == Some(value) {
+        f32::NAN
+    } else {
+        value as f32
+    }
+}
+
+/// Integer pixel types that f32 values are saved as
+trait IntegerPixel: Copy {
+    const MIN: f32;
+    const MAX: f32;
+    /// Convert a value already rounded and clamped to `MIN..=MAX`
+    fn from_f32(value: f32) -> Self;
+}
+
+macro_rules! integer_pixel {
+    ($($t:ty),*) => {
+        $(impl IntegerPixel for $t {
+            const MIN: f32 = <$t>::MIN as f32;
+            const MAX: f32 = <$t>::MAX as f32;
+            fn from_f32(value: f32) -> Self {
+                value as $t
+            }
+        })*
+    };
+}
+
+integer_pixel!(u8, i16, u16, u32, i32, i64);
+
+/// Convert pixel values for saving as an integer type: each is passed through `value`,
+/// rounded to the nearest integer and clamped to the type's range, so out of range pixels
+/// saturate instead of wrapping
+fn cast_clamped<T: IntegerPixel>(data: &ArrayD<f32>, value: impl Fn(f32) -> f32) -> Vec<T> {
+    data.iter()
+        .map(|&x| T::from_f32(value(x).round().clamp(T::MIN, T::MAX)))
+        .collect()
+}
+
+/// Append a HISTORY card to the current HDU. cfitsio continues long text on further cards.
+fn write_history(fitsfile: &mut FitsFile, text: &str) -> Result<(), ImageError> {
+    let c_text = std::ffi::CString::new(text)
+        .map_err(|_| ImageError::FormatError(format!("'{}' contains a NUL character", text)))?;
+
+    let mut status = 0;
+    // fitsio has no call for commentary cards, so go through cfitsio directly.
+    // SAFETY: the handle is valid while `fitsfile` is borrowed, and `c_text` is a
+    // NUL-terminated string that outlives the call
+    unsafe {
+        fitsio::sys::ffphis(fitsfile.as_raw(), c_text.as_ptr(), &mut status);
+    }
+
+    if status != 0 {
+        return Err(ImageError::FitsError(format!(
+            "Failed to write HISTORY (cfitsio status {})",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Keywords read into a dedicated metadata field, or written by `to_file` itself
+const MAPPED_KEYS: &[&str] = &[
+    "EXPTIME", "CCD-TEMP", "FILTER", "OBJECT", "AIRMASS", "CROTA2", "DATE-OBS", "FOCALLEN",
+    "XPIXSZ", "YPIXSZ", "GAIN", "ISOSPEED", "ISO", "OFFSET", "FRAME", "IMAGETYP", "PEDESTAL",
+];
+
+/// Keywords describing the file's structure or how its pixels are stored. `to_file` writes
+/// them from the data, and changing them in a header corrupts the file or changes how its
+/// pixels are read. PEDESTAL is applied to the pixels on load and set through `SaveOptions`.
+const RESERVED_KEYS: &[&str] = &[
+    "SIMPLE", "BITPIX", "NAXIS", "EXTEND", "XTENSION", "PCOUNT", "GCOUNT", "BZERO", "BSCALE",
+    "BLANK", "END", "PEDESTAL",
+];
+
+/// Check that a keyword (already trimmed and uppercased) can be set by the user
+fn check_writable_key(key: &str) -> Result<(), ImageError> {
+    if key.is_empty() || key.len() > 8 {
+        return Err(ImageError::FormatError(format!(
+            "Invalid FITS keyword '{}': must be 1 to 8 characters",
+            key
+        )));
+    }
+
+    // NAXIS1, NAXIS2, ... give the length of each axis
+    let is_axis_length = key
+        .strip_prefix("NAXIS")
+        .is_some_and(|axis| !axis.is_empty() && axis.bytes().all(|b| b.is_ascii_digit()));
+    if RESERVED_KEYS.contains(&key) || is_axis_length {
+        return Err(ImageError::FormatError(format!(
+            "{} describes the file's structure and can't be set",
+            key
+        )));
+    }
+
+    Ok(())
+}
+
+/// Read the user keywords of the current HDU that have no metadata field of their own.
+/// Structural, scaling, WCS and commentary cards are left out, as are the keywords in
+/// `MAPPED_KEYS`. String values are unquoted, others kept as written.
+fn read_extra_keys(
+    fitsfile: &mut FitsFile,
+) -> Result<std::collections::HashMap<String, String>, ImageError> {
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+
+    const TYP_USER_KEY: i32 = 150;
+    // FLEN_CARD, FLEN_KEYWORD, FLEN_VALUE and FLEN_COMMENT from fitsio.h
+    let mut card = [0 as c_char; 81];
+    let mut name = [0 as c_char; 75];
+    let mut value = [0 as c_char; 71];
+    let mut comment = [0 as c_char; 73];
+
+    let mut status = 0;
+    let mut count = 0;
+    // SAFETY: the raw handle stays valid while `fitsfile` is mutably borrowed by this
+    // function, and the out pointers are to live locals
+    let fptr = unsafe {
+        let fptr = fitsfile.as_raw();
+        fitsio::sys::ffghsp(fptr, &mut count, std::ptr::null_mut(), &mut status);
+        fptr
+    };
+
+    let mut extra = std::collections::HashMap::new();
+    for index in 1..=count {
+        if status != 0 {
+            break;
+        }
+        // SAFETY: each buffer is at least the size cfitsio writes into it (the FLEN_*
+        // constants above) and cfitsio NUL-terminates them, so reading them as C strings
+        // stays within bounds
+        let (class, key, raw_value) = unsafe {
+            fitsio::sys::ffgrec(fptr, index, card.as_mut_ptr(), &mut status);
+            let class = fitsio::sys::ffgkcl(card.as_mut_ptr());
+            fitsio::sys::ffgkyn(
+                fptr,
+                index,
+                name.as_mut_ptr(),
+                value.as_mut_ptr(),
+                comment.as_mut_ptr(),
+                &mut status,
+            );
+            (
+                class,
+                CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned(),
+                CStr::from_ptr(value.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        };
+
+        if status != 0 || class != TYP_USER_KEY || key.is_empty() {
+            continue;
+        }
+        if MAPPED_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        extra.insert(key, unquote_key_value(&raw_value));
+    }
+
+    if status != 0 {
+        return Err(ImageError::FitsError(format!(
+            "Failed to read header keywords (cfitsio status {})",
+            status
+        )));
+    }
+    Ok(extra)
+}
+
+/// The text of a header value: quoted strings lose their quotes, escaped quotes and
+/// trailing padding, anything else is returned trimmed
+fn unquote_key_value(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        Some(quoted) => quoted.replace("''", "'").trim_end().to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// Read a keyword that may be written as an integer, a float, or a quoted number
+fn read_numeric_key(hdu: &fitsio::hdu::FitsHdu, fitsfile: &mut FitsFile, key: &str) -> Option<f64> {
+    hdu.read_key::<f64>(fitsfile, key).ok().or_else(|| {
+        hdu.read_key::<String>(fitsfile, key)
+            .ok()
+            .and_then(|value| value.trim().parse::<f64>().ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> FitsImage {
+        FitsImage::from_data(ArrayD::from_shape_vec(vec![2, 3], vec![1.0; 6]).unwrap())
+    }
+
+    #[test]
+    fn set_metadata_key_updates_mapped_fields_and_extra() {
+        let mut image = image();
+        image.set_metadata_key("exptime", "120").unwrap();
+        image.set_metadata_key("FRAME", "Dark").unwrap();
+        image.set_metadata_key("TELESCOP", "RedCat 51").unwrap();
+
+        assert_eq!(image.metadata.exposure_time, Some(120.0));
+        assert_eq!(image.frame_type, FrameType::Dark);
+        assert_eq!(image.metadata.extra["TELESCOP"], "RedCat 51");
+        assert!(image.set_metadata_key("EXPTIME", "long").is_err());
+        assert!(image.set_metadata_key("TOOLONGKEY", "1").is_err());
+
+        // Mapped keywords beyond the core ones update their field rather than `extra`
+        image.set_metadata_key("AIRMASS", "1.8").unwrap();
+        assert_eq!(image.metadata.airmass, Some(1.8));
+        assert!(!image.metadata.extra.contains_key("AIRMASS"));
+
+        for key in ["NAXIS", "naxis2", "BITPIX", "BZERO", "PEDESTAL"] {
+            assert!(
+                matches!(
+                    image.set_metadata_key(key, "16"),
+                    Err(ImageError::FormatError(_))
+                ),
+                "{} was accepted",
+                key
+            );
+        }
+        assert_eq!(image.metadata.extra.len(), 1);
+    }
+
+    #[test]
+    fn set_metadata_key_survives_save_and_reload() {
+        let mut image = image();
+        image.set_metadata_key("FRAME", "FLAT").unwrap();
+        image.set_metadata_key("TELESCOP", "It's a RedCat").unwrap();
+        image.set_metadata_key("SITELAT", "43.25").unwrap();
+
+        let path = temp_path("set-key.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        let reloaded = FitsImage::from_file_detect_type(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.frame_type, FrameType::Flat);
+        assert_eq!(reloaded.metadata.extra["TELESCOP"], "It's a RedCat");
+        assert_eq!(reloaded.metadata.extra["SITELAT"], "43.25");
+        // Structural and mapped keywords don't end up in `extra`
+        for key in ["SIMPLE", "BITPIX", "NAXIS1", "FRAME"] {
+            assert!(
+                !reloaded.metadata.extra.contains_key(key),
+                "{} in extra",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn gain_keywords_are_read_as_numbers() {
+        let path = temp_path("gain.fits");
+        for (gain, expected) in [("120", 120.0), ("0.25", 0.25)] {
+            let _ = std::fs::remove_file(&path);
+            image().to_file(&path).unwrap();
+            FitsImage::update_header_key(&path, "GAIN", gain).unwrap();
+            FitsImage::update_header_key(&path, "OFFSET", "30").unwrap();
+
+            let reloaded = FitsImage::from_file_detect_type(&path).unwrap();
+            assert_eq!(reloaded.metadata.iso_gain, Some(expected));
+            assert_eq!(reloaded.metadata.offset, Some(30));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fractional_gain_survives_save_and_reload() {
+        let mut image = image();
+        image.set_metadata_key("GAIN", "1.67").unwrap();
+
+        let path = temp_path("fractional-gain.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        let reloaded = FitsImage::from_file_detect_type(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.metadata.iso_gain, Some(1.67));
+    }
+
+    #[test]
+    fn iso_speed_is_read_as_gain() {
+        let path = temp_path("iso.fits");
+        let _ = std::fs::remove_file(&path);
+        image().to_file(&path).unwrap();
+        FitsImage::update_header_key(&path, "ISOSPEED", "800").unwrap();
+        let reloaded = FitsImage::from_file_detect_type(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.metadata.iso_gain, Some(800.0));
+    }
+
+    #[test]
+    fn read_dimensions_reads_only_the_header() {
+        let path = temp_path("dimensions.fits");
+        let _ = std::fs::remove_file(&path);
+        FitsImage::from_data(ArrayD::zeros(vec![3, 5, 7]))
+            .to_file(&path)
+            .unwrap();
+        let dimensions = FitsImage::read_dimensions(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(dimensions.unwrap(), (7, 5));
+        assert!(FitsImage::read_dimensions(temp_path("missing.fits")).is_err());
+    }
+
+    #[test]
+    fn update_header_key_edits_a_saved_file() {
+        let path = temp_path("update-key.fits");
+        let _ = std::fs::remove_file(&path);
+        image().to_file(&path).unwrap();
+
+        FitsImage::update_header_key(&path, "EXPTIME", "30").unwrap();
+        FitsImage::update_header_key(&path, "OBSERVER", "Me").unwrap();
+        assert!(FitsImage::update_header_key(&path, "NAXIS1", "2").is_err());
+        let reloaded = FitsImage::from_file_detect_type(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.dimensions(), (3, 2));
+        assert_eq!(reloaded.metadata.exposure_time, Some(30.0));
+        assert_eq!(reloaded.metadata.extra["OBSERVER"], "Me");
+    }
+
+    #[test]
+    fn validate_reports_a_truncated_data_unit() {
+        let image = FitsImage::from_data(ArrayD::from_elem(vec![30, 40], 1.0));
+        let path = temp_path("truncated.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        assert!(FitsImage::validate(&path).is_ok());
+
+        // Keep the header and a few hundred bytes of the data unit
+        let bytes = std::fs::read(&path).unwrap();
+        let end_card = bytes
+            .chunks(80)
+            .position(|card| card.starts_with(b"END "))
+            .unwrap();
+        let header_len = ((end_card + 1) * 80).div_ceil(2880) as u64 * 2880;
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(header_len + 400).unwrap();
+        drop(file);
+        let result = FitsImage::validate(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(ImageError::FormatError(message)) => {
+                assert!(message.contains("declared 40x30 but only"), "{}", message);
+                assert!(message.ends_with("pixels present"), "{}", message);
+            }
+            other => panic!("expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn statistics_are_cached_until_the_data_changes() {
+        let mut image = image();
+        assert_eq!(image.calculate_statistics().mean, 1.0);
+
+        image.data_mut().fill(5.0);
+        assert_eq!(image.calculate_statistics().mean, 5.0);
+
+        image.set_data(ArrayD::from_elem(vec![2, 3], 3.0));
+        assert_eq!(image.calculate_statistics().mean, 3.0);
+    }
+
+    #[test]
+    fn histogram_median_is_within_one_bin_of_the_exact_median() {
+        // Skewed towards dark values, like a sky background with a few bright stars
+        let data = ArrayD::from_shape_fn(vec![100, 100], |index| {
+            let hash = ((index[0] * 100 + index[1]) as u32).wrapping_mul(2_654_435_761) >> 16;
+            let u = (hash % 1000) as f32 / 1000.0;
+            200.0 + 3000.0 * u * u * u
+        });
+        let image = FitsImage::from_data(data);
+
+        let exact = image.calculate_statistics();
+        let bins = 256;
+        let approximate = image.calculate_statistics_with(MedianMethod::Histogram { bins });
+        let bin_width = (exact.max - exact.min) / bins as f32;
+        assert!(
+            (approximate.median - exact.median).abs() <= bin_width,
+            "{} vs {}",
+            approximate.median,
+            exact.median
+        );
+        assert_eq!(approximate.mean, exact.mean);
+    }
+
+    #[test]
+    fn gzipped_file_loads_like_the_uncompressed_original() {
+        let data = ArrayD::from_shape_fn(vec![12, 20], |index| (index[0] * 20 + index[1]) as f32);
+        let mut image = FitsImage::from_data(data);
+        image.metadata.exposure_time = Some(30.0);
+
+        let plain = temp_path("original.fits");
+        let gzipped = temp_path("compressed.fits.gz");
+        for path in [&plain, &gzipped] {
+            let _ = std::fs::remove_file(path);
+            image.to_file(path).unwrap();
+        }
+        // cfitsio compresses files whose name ends in .gz as it writes them
+        let magic = std::fs::read(&gzipped).unwrap()[..2].to_vec();
+        assert!(FitsImage::is_fits_file(&gzipped));
+        assert!(FitsImage::validate(&gzipped).is_ok());
+        let original = FitsImage::from_file(&plain, FrameType::Light).unwrap();
+        let decompressed = FitsImage::from_file(&gzipped, FrameType::Light).unwrap();
+        std::fs::remove_file(&plain).unwrap();
+        std::fs::remove_file(&gzipped).unwrap();
+
+        assert_eq!(magic, GZIP_MAGIC);
+        assert_eq!(decompressed.data, original.data);
+        assert_eq!(decompressed.metadata.exposure_time, Some(30.0));
+    }
+
+    #[test]
+    fn cut_off_gzip_stream_fails_validation() {
+        let data = ArrayD::from_shape_fn(vec![64, 64], |index| (index[0] * index[1]) as f32);
+        let gzipped = temp_path("cut-off.fits.gz");
+        let _ = std::fs::remove_file(&gzipped);
+        FitsImage::from_data(data).to_file(&gzipped).unwrap();
+        let bytes = std::fs::read(&gzipped).unwrap();
+        std::fs::write(&gzipped, &bytes[..bytes.len() / 2]).unwrap();
+
+        let result = FitsImage::validate(&gzipped);
+        std::fs::remove_file(&gzipped).unwrap();
+        assert!(
+            matches!(result, Err(ImageError::FormatError(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn pedestal_keeps_negative_values_of_unsigned_frames() {
+        let data = ArrayD::from_shape_vec(vec![1, 4], vec![-12.0, -1.0, 0.0, 250.0]).unwrap();
+        let mut image = FitsImage::from_data(data);
+        image.metadata.pixel_type = PixelType::U16;
+
+        let clipped_path = temp_path("no-pedestal.fits");
+        let pedestal_path = temp_path("pedestal.fits");
+        let options = SaveOptions {
+            pedestal: 100.0,
+            ..SaveOptions::default()
+        };
+        for path in [&clipped_path, &pedestal_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        image.to_file(&clipped_path).unwrap();
+        image
+            .to_file_with_options(&pedestal_path, &options)
+            .unwrap();
+        let clipped = FitsImage::from_file(&clipped_path, FrameType::Light).unwrap();
+        let reloaded = FitsImage::from_file(&pedestal_path, FrameType::Light).unwrap();
+        std::fs::remove_file(&clipped_path).unwrap();
+        std::fs::remove_file(&pedestal_path).unwrap();
+
+        assert_eq!(
+            clipped.data.iter().copied().collect::<Vec<_>>(),
+            [0.0, 0.0, 0.0, 250.0]
+        );
+        assert_eq!(reloaded.metadata.pixel_type, PixelType::U16);
+        assert_eq!(
+            reloaded.data.iter().copied().collect::<Vec<_>>(),
+            [-12.0, -1.0, 0.0, 250.0]
+        );
+    }
+
+    #[test]
+    fn iter_folder_yields_each_frame_and_per_file_errors() {
+        let folder = temp_path("iter-folder");
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        for name in ["a.fits", "c.fits"] {
+            image().to_file(folder.join(name)).unwrap();
+        }
+        std::fs::write(folder.join("b.fits"), b"not a FITS file").unwrap();
+        std::fs::write(folder.join("notes.txt"), b"ignored").unwrap();
+
+        let results: Vec<_> = FitsImage::iter_folder(&folder, FrameType::Light).collect();
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        // The broken file doesn't stop the frames after it
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn iter_folder_reports_missing_and_empty_folders() {
+        let missing: Vec<_> =
+            FitsImage::iter_folder(temp_path("no-such-folder"), FrameType::Light).collect();
+        assert_eq!(missing.len(), 1);
+        assert!(matches!(missing[0], Err(ImageError::IoError(_))));
+
+        let folder = temp_path("empty-folder");
+        std::fs::create_dir_all(&folder).unwrap();
+        let empty: Vec<_> = FitsImage::iter_folder(&folder, FrameType::Light).collect();
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(empty.len(), 1);
+        assert!(matches!(empty[0], Err(ImageError::FormatError(_))));
+    }
+
+    #[test]
+    fn extension_list_decides_which_files_are_read() {
+        let folder = temp_path("extensions");
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        for name in ["a.fits", "b.FITS.gz", "c.xisf"] {
+            std::fs::write(folder.join(name), b"").unwrap();
+        }
+
+        let names = |extensions: &[&str]| -> Vec<String> {
+            FitsImage::list_folder(&folder, extensions)
+                .unwrap()
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        let plain = names(&["fit", "fits", "fts"]);
+        let custom = names(&["fits", ".fits.gz"]);
+        let default = names(FITS_EXTENSIONS);
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(plain, ["a.fits"]);
+        assert_eq!(custom, ["a.fits", "b.FITS.gz"]);
+        assert_eq!(default, ["a.fits", "b.FITS.gz"]);
+    }
+
+    #[test]
+    fn object_name_survives_save_and_reload() {
+        let mut image = image();
+        image.metadata.object = Some("M 31".to_string());
+
+        let path = temp_path("object.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        let reloaded = FitsImage::from_file(&path, FrameType::Light).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.metadata.object.as_deref(), Some("M 31"));
+        assert!(!reloaded.metadata.extra.contains_key("OBJECT"));
+    }
+
+    #[test]
+    fn median_of_handles_odd_and_even_counts() {
+        assert_eq!(median_of(&mut [3.0f32, 1.0, 2.0]), 2.0);
+        assert_eq!(median_of(&mut [4.0f32, 1.0, 3.0, 2.0]), 2.5);
+        assert_eq!(median_of(&mut [120.0f64, 60.0]), 90.0);
+    }
+
+    #[test]
+    fn output_template_is_resolved_from_metadata() {
+        let metadata = ImageMetadata {
+            object: Some("M 31".to_string()),
+            filter: Some("Ha".to_string()),
+            exposure_time: Some(300.0),
+            date_obs: Some("2024-09-14T21:03:11".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            metadata.resolve_template(DEFAULT_OUTPUT_TEMPLATE, 24),
+            "M_31_Ha_24x300s_stacked.fits"
+        );
+        assert_eq!(
+            metadata.resolve_template("{date}/{object}.fits", 1),
+            "2024-09-14/M_31.fits"
+        );
+        assert_eq!(
+            ImageMetadata::default().resolve_template(DEFAULT_OUTPUT_TEMPLATE, 3),
+            "unknown_unknown_3xunknowns_stacked.fits"
+        );
+    }
+
+    #[test]
+    fn blank_pixels_are_masked_out_of_the_mean() {
+        let data = ArrayD::from_shape_vec(vec![1, 4], vec![100.0, f32::NAN, 300.0, f32::NAN]);
+        let mut image = FitsImage::from_data(data.unwrap());
+        image.metadata.pixel_type = PixelType::I16;
+
+        let path = temp_path("blank.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        let header = String::from_utf8_lossy(&std::fs::read(&path).unwrap()).into_owned();
+        let reloaded = FitsImage::from_file(&path, FrameType::Light).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(header.contains("BLANK   =               -32768"));
+        assert_eq!(reloaded.metadata.pixel_type, PixelType::I16);
+        let missing: Vec<bool> = reloaded.data.iter().map(|v| v.is_nan()).collect();
+        assert_eq!(missing, [false, true, false, true]);
+        assert_eq!(reloaded.calculate_statistics().mean, 200.0);
+    }
+
+    #[test]
+    fn every_bitpix_is_read_back_as_its_pixel_type() {
+        let values = vec![0.0, 1.0, 7.0, 100.0, 127.0, 250.0];
+        for pixel_type in [
+            PixelType::U8,
+            PixelType::U16,
+            PixelType::U32,
+            PixelType::I16,
+            PixelType::I32,
+            PixelType::I64,
+            PixelType::F32,
+            PixelType::F64,
+        ] {
+            let data = ArrayD::from_shape_vec(vec![2, 3], values.clone()).unwrap();
+            let mut image = FitsImage::from_data(data);
+            image.metadata.pixel_type = pixel_type;
+
+            let path = temp_path(&format!("bitpix-{:?}.fits", pixel_type));
+            let _ = std::fs::remove_file(&path);
+            image.to_file(&path).unwrap();
+            let reloaded = FitsImage::from_file(&path, FrameType::Light).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(reloaded.metadata.pixel_type, pixel_type);
+            assert_eq!(reloaded.data.iter().copied().collect::<Vec<_>>(), values);
+        }
+    }
+
+    #[test]
+    fn signed_byte_files_are_widened_to_i16() {
+        let path = temp_path("bitpix-signed-byte.fits");
+        let _ = std::fs::remove_file(&path);
+        let description = ImageDescription {
+            data_type: ImageType::Byte,
+            dimensions: &[1, 4],
+        };
+        let mut fitsfile = FitsFile::create(&path)
+            .with_custom_primary(&description)
+            .open()
+            .unwrap();
+        let hdu = fitsfile.primary_hdu().unwrap();
+        let pixels: Vec<i16> = vec![-128, -1, 0, 127];
+        hdu.write_image(&mut fitsfile, &pixels).unwrap();
+        drop(fitsfile);
+
+        let reloaded = FitsImage::from_file(&path, FrameType::Light).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.metadata.pixel_type, PixelType::I16);
+        assert_eq!(
+            reloaded.data.iter().copied().collect::<Vec<_>>(),
+            [-128.0, -1.0, 0.0, 127.0]
+        );
+    }
+
+    #[test]
+    fn long_files_are_read_as_i32() {
+        let path = temp_path("bitpix-long.fits");
+        let _ = std::fs::remove_file(&path);
+        let description = ImageDescription {
+            data_type: ImageType::Long,
+            dimensions: &[2, 2],
+        };
+        let mut fitsfile = FitsFile::create(&path)
+            .with_custom_primary(&description)
+            .open()
+            .unwrap();
+        let hdu = fitsfile.primary_hdu().unwrap();
+        // Beyond the 16-bit range, and exactly representable as f32
+        let pixels: Vec<i32> = vec![-2_000_000, 0, 70_000, 16_777_216];
+        hdu.write_image(&mut fitsfile, &pixels).unwrap();
+        drop(fitsfile);
+
+        let reloaded = FitsImage::from_file(&path, FrameType::Light).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.metadata.pixel_type, PixelType::I32);
+        assert_eq!(reloaded.dimensions(), (2, 2));
+        assert_eq!(
+            reloaded.data.iter().copied().collect::<Vec<_>>(),
+            [-2_000_000.0, 0.0, 70_000.0, 16_777_216.0]
+        );
+    }
+
+    #[test]
+    fn i64_images_survive_save_and_reload() {
+        // Outside the 32-bit range, plus a missing pixel stored as BLANK
+        let values = vec![2f32.powi(40), -(2f32.powi(35)), 3.0, f32::NAN];
+        let data = ArrayD::from_shape_vec(vec![2, 2], values.clone()).unwrap();
+        let mut image = FitsImage::from_data(data);
+        image.metadata.pixel_type = PixelType::I64;
+
+        let path = temp_path("bitpix-longlong.fits");
+        let _ = std::fs::remove_file(&path);
+        image.to_file(&path).unwrap();
+        let reloaded = FitsImage::from_file(&path, FrameType::Light).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(PixelType::I64.bytes_per_pixel(), 8);
+        assert_eq!(reloaded.metadata.pixel_type, PixelType::I64);
+        let data: Vec<f32> = reloaded.data.iter().copied().collect();
+        assert_eq!(data[..3], values[..3]);
+        assert!(data[3].is_nan());
+    }
+
+    #[test]
+    fn saving_with_mismatched_dimensions_is_an_error() {
+        let mut image = image();
+        // A crop that forgot to update the metadata
+        image.metadata.dimensions = (3, 3);
+
+        let path = temp_path("mismatched.fits");
+        let _ = std::fs::remove_file(&path);
+        let result = image.to_file(&path);
+
+        assert!(matches!(result, Err(ImageError::DimensionError(_))));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn integer_casts_round_and_saturate() {
+        let data = ArrayD::from_shape_vec(vec![1, 4], vec![-1e20, -1.6, 2.5, 1e20]).unwrap();
+        let same = |v: f32| v;
+
+        assert_eq!(cast_clamped::<u8>(&data, same), [0, 0, 3, u8::MAX]);
+        assert_eq!(
+            cast_clamped::<i16>(&data, same),
+            [i16::MIN, -2, 3, i16::MAX]
+        );
+        assert_eq!(cast_clamped::<u16>(&data, same), [0, 0, 3, u16::MAX]);
+        assert_eq!(cast_clamped::<u32>(&data, same), [0, 0, 3, u32::MAX]);
+        assert_eq!(
+            cast_clamped::<i32>(&data, same),
+            [i32::MIN, -2, 3, i32::MAX]
+        );
+        assert_eq!(
+            cast_clamped::<i64>(&data, same),
+            [i64::MIN, -2, 3, i64::MAX]
+        );
+
+        // The value function (e.g. a pedestal) is applied before clamping
+        assert_eq!(cast_clamped::<u8>(&data, |v| v + 10.0), [0, 8, 13, u8::MAX]);
+    }
+
+    #[test]
+    fn keep_float_saves_the_fractional_values_of_a_calibrated_frame() {
+        let light = ArrayD::from_shape_vec(vec![1, 3], vec![1000.0, 1001.0, 1003.0]).unwrap();
+        let mut calibrated = FitsImage::from_data(light);
+        calibrated.metadata.pixel_type = PixelType::U16;
+        let flat = ArrayD::from_shape_vec(vec![1, 3], vec![0.8, 1.6, 3.0]).unwrap();
+        calibrated.divide(&FitsImage::from_data(flat)).unwrap();
+
+        let float_path = temp_path("keep-float.fits");
+        let integer_path = temp_path("keep-integer.fits");
+        for path in [&float_path, &integer_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        calibrated
+            .to_file_with_options(&float_path, &SaveOptions::processed())
+            .unwrap();
+        calibrated.to_file(&integer_path).unwrap();
+        let float = FitsImage::from_file(&float_path, FrameType::Light).unwrap();
+        let integer = FitsImage::from_file(&integer_path, FrameType::Light).unwrap();
+        std::fs::remove_file(&float_path).unwrap();
+        std::fs::remove_file(&integer_path).unwrap();
+
+        assert_eq!(float.metadata.pixel_type, PixelType::F32);
+        let expected = [1250.0, 625.625, 1003.0 / 3.0];
+        for (&value, expected) in float.data.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-3, "{} vs {}", value, expected);
+        }
+        assert_eq!(integer.metadata.pixel_type, PixelType::U16);
+        assert_eq!(
+            integer.data.iter().copied().collect::<Vec<_>>(),
+            [1250.0, 626.0, 334.0]
+        );
+    }
+
+    #[test]
+    fn clipped_statistics_describe_the_background_not_the_stars() {
+        let mut image = FitsImage::from_data(ArrayD::from_shape_fn(vec![64, 64], |index| {
+            100.0 + ((index[0] * 13 + index[1] * 7) % 9) as f32 - 4.0
+        }));
+        for (y, x) in [
+            (5, 5),
+            (10, 40),
+            (20, 20),
+            (30, 55),
+            (40, 12),
+            (50, 33),
+            (58, 58),
+        ] {
+            image
+                .data_mut()
+                .slice_mut(ndarray::s![y - 1..=y + 1, x - 1..=x + 1])
+                .fill(20000.0);
+        }
+
+        let global = image.calculate_statistics();
+        let clipped = image.calculate_statistics_clipped(3.0, 5);
+        assert!(global.mean > 300.0, "global mean {}", global.mean);
+        assert!(
+            (clipped.mean - 100.0).abs() < 1.0,
+            "clipped mean {}",
+            clipped.mean
+        );
+        assert!(clipped.max <= 104.0);
+        assert_eq!(clipped.median, 100.0);
+    }
+
+    #[test]
+    fn view2d_rejects_color_cubes() {
+        let mono = FitsImage::from_data(ArrayD::from_shape_fn(vec![2, 3], |index| {
+            (index[0] * 3 + index[1]) as f32
+        }));
+        let view = mono.view2d().unwrap();
+        assert_eq!(view.dim(), (2, 3));
+        assert_eq!(view[[1, 2]], 5.0);
+
+        let cube = FitsImage::from_data(ArrayD::zeros(vec![3, 2, 3]));
+        assert!(matches!(cube.view2d(), Err(ImageError::DimensionError(_))));
+    }
+
+    #[test]
+    fn missing_and_empty_folders_are_distinct_errors() {
+        let missing = temp_path("missing-folder");
+        let _ = std::fs::remove_dir_all(&missing);
+        let result = FitsImage::from_folder(&missing, FrameType::Light, FITS_EXTENSIONS);
+        let Err(ImageError::IoError(e)) = result else {
+            panic!("expected an IO error for a missing folder");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("missing-folder"), "{}", e);
+
+        // A folder with files, just none of them FITS
+        let empty = temp_path("empty-folder");
+        let _ = std::fs::remove_dir_all(&empty);
+        std::fs::create_dir_all(&empty).unwrap();
+        std::fs::write(empty.join("notes.txt"), "no frames here").unwrap();
+        let result = FitsImage::from_folder(&empty, FrameType::Light, FITS_EXTENSIONS);
+        std::fs::remove_dir_all(&empty).unwrap();
+
+        assert!(matches!(result, Err(ImageError::FormatError(_))));
+    }
+
+    #[test]
+    fn three_plane_cubes_load_as_rgb_color() {
+        let path = temp_path("rgb-cube.fits");
+        for ctype in [None, Some("RGB"), Some("TIME")] {
+            let _ = std::fs::remove_file(&path);
+            let description = ImageDescription {
+                data_type: ImageType::Float,
+                dimensions: &[3, 2, 4],
+            };
+            let mut fitsfile = FitsFile::create(&path)
+                .with_custom_primary(&description)
+                .open()
+                .unwrap();
+            let hdu = fitsfile.primary_hdu().unwrap();
+            // Red planes hold 100s, green 200s and blue 300s
+            let pixels: Vec<f32> = (0..24)
+                .map(|i| (i / 8 + 1) as f32 * 100.0 + (i % 8) as f32)
+                .collect();
+            hdu.write_image(&mut fitsfile, &pixels).unwrap();
+            if let Some(ctype) = ctype {
+                hdu.write_key(&mut fitsfile, "CTYPE3", ctype).unwrap();
+            }
+            drop(fitsfile);
+
+            let result = FitsImage::from_file(&path, FrameType::Light);
+            if ctype == Some("TIME") {
+                assert!(matches!(result, Err(ImageError::UnsupportedOperation(_))));
+                continue;
+            }
+            let image = result.unwrap();
+            assert!(image.is_color(), "{:?}", ctype);
+            assert_eq!(image.dimensions(), (4, 2));
+            assert_eq!(image.data.shape(), [3, 2, 4]);
+            for (channel, base) in [(0, 100.0), (1, 200.0), (2, 300.0)] {
+                assert_eq!(image.data[[channel, 1, 3]], base + 7.0, "{:?}", ctype);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cancelled_folder_loads_stop_without_an_error() {
+        let folder = temp_path("cancel-folder");
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        for i in 0..3 {
+            image()
+                .to_file(folder.join(format!("light_{i}.fits")))
+                .unwrap();
+        }
+
+        let load = |cancelled: bool| {
+            let cancelled = AtomicBool::new(cancelled);
+            FitsImage::from_folder_with_cancel(
+                &folder,
+                FrameType::Light,
+                FITS_EXTENSIONS,
+                &cancelled,
+            )
+        };
+        let all = load(false).unwrap();
+        // The flag is checked before each file, so nothing more is read once it's set
+        let cancelled = load(true).unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(all.len(), 3);
+        assert!(cancelled.is_empty());
+    }
+
+    #[test]
+    fn pixel_scale_comes_from_pixel_size_and_focal_length() {
+        let mut metadata = ImageMetadata::default();
+        assert_eq!(metadata.pixel_scale(), None);
+
+        // A 3.76 um pixel behind a 250 mm lens
+        metadata.pixel_size_x = Some(3.76);
+        metadata.focal_length = Some(250.0);
+        let scale = metadata.pixel_scale().unwrap();
+        assert!((scale - 3.1022).abs() < 1e-3, "{}", scale);
+
+        metadata.focal_length = Some(0.0);
+        assert_eq!(metadata.pixel_scale(), None);
+    }
+
+    #[test]
+    fn edge_margin_leaves_a_bright_border_out_of_the_background() {
+        // A 10 x 10 sky at 100 inside a 2 pixel border of amp glow at 1000
+        let data = ArrayD::from_shape_fn(vec![10, 10], |index| {
+            let edge = [index[0], index[1]].iter().any(|&i| !(2..8).contains(&i));
+            if edge { 1000.0 } else { 100.0 }
+        });
+
+        let full = ImageStatistics::excluding_edges(data.view(), 0);
+        assert_eq!((full.mean, full.median), (676.0, 1000.0));
+        let inner = ImageStatistics::excluding_edges(data.view(), 2);
+        assert_eq!((inner.mean, inner.median, inner.max), (100.0, 100.0, 100.0));
+        // A margin that would leave nothing falls back to the whole plane
+        let all = ImageStatistics::excluding_edges(data.view(), 5);
+        assert_eq!(all.mean, 676.0);
+    }
+}
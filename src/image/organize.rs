@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{FitsImage, ImageError, template_value};
+
+/// Default layout for `organize_frames`: one folder per target and filter
+pub const DEFAULT_ORGANIZE_TEMPLATE: &str = "{object}/{filter}/{frametype}_{date}.fits";
+
+/// Copy frames into `dest`, naming each from its own metadata.
+///
+/// `template` is a path relative to `dest` and supports the placeholders of
+/// `ImageMetadata::resolve_template` plus `{frametype}`; `/` separates folders, which are
+/// created as needed. Frames that would land on the same name are numbered (`_1`, `_2`, ...)
+/// rather than overwritten. The originals are left in place. Returns the new paths in the
+/// order of `paths`.
+pub fn organize_frames(
+    paths: &[PathBuf],
+    dest: &Path,
+    template: &str,
+) -> Result<Vec<PathBuf>, ImageError> {
+    let mut organized = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        // The FRAME or IMAGETYP keyword (or the pixel data) decides the type
+        let image = FitsImage::from_file_detect_type(path)?;
+        let frame_type = image.frame_type.keyword().to_lowercase();
+        let relative = image
+            .metadata
+            .resolve_template(template, 1)
+            .replace("{frametype}", &template_value(Some(&frame_type)));
+
+        let target = unique_path(&dest.join(relative));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(path, &target)?;
+        log::debug!("Organized {} as {}", path.display(), target.display());
+        organized.push(target);
+    }
+
+    Ok(organized)
+}
+
+/// `path` itself if it's free, otherwise the first free `stem_N.ext` next to it
+fn unique_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|counter| path.with_file_name(format!("{}_{}{}", stem, counter, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::temp_path;
+
+    fn write_frame(path: &Path, keys: &[(&str, &str)]) {
+        let mut image = FitsImage::from_data(ArrayD::zeros(vec![2, 2]));
+        for &(key, value) in keys {
+            image.set_metadata_key(key, value).unwrap();
+        }
+        image.to_file(path).unwrap();
+    }
+
+    #[test]
+    fn frames_are_copied_into_the_template_layout() {
+        let source = temp_path("organize-source");
+        let dest = temp_path("organize-dest");
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&source).unwrap();
+
+        let light = [
+            ("OBJECT", "M31"),
+            ("FILTER", "Ha"),
+            ("FRAME", "Light"),
+            ("DATE-OBS", "2024-09-01T22:10:00"),
+        ];
+        let paths = vec![
+            source.join("a.fits"),
+            source.join("b.fits"),
+            source.join("c.fits"),
+        ];
+        write_frame(&paths[0], &light);
+        write_frame(&paths[1], &light);
+        write_frame(
+            &paths[2],
+            &[("FRAME", "Dark"), ("DATE-OBS", "2024-09-02T08:00:00")],
+        );
+
+        let organized = organize_frames(&paths, &dest, DEFAULT_ORGANIZE_TEMPLATE).unwrap();
+        let relative: Vec<PathBuf> = organized
+            .iter()
+            .map(|path| path.strip_prefix(&dest).unwrap().to_path_buf())
+            .collect();
+        let all_exist = organized.iter().all(|path| path.is_file());
+        let originals_kept = paths.iter().all(|path| path.is_file());
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&dest).unwrap();
+
+        assert_eq!(
+            relative,
+            [
+                PathBuf::from("M31/Ha/light_2024-09-01.fits"),
+                // Same name as the first frame, so it's numbered
+                PathBuf::from("M31/Ha/light_2024-09-01_1.fits"),
+                PathBuf::from("unknown/unknown/dark_2024-09-02.fits"),
+            ]
+        );
+        assert!(all_exist);
+        assert!(originals_kept);
+    }
+}
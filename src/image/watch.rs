@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::{FitsImage, FrameType, ImageError};
+
+/// How often pending files are checked for a stable size
+const SETTLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keeps a folder watch running. Watching stops when this is dropped.
+pub struct FolderWatcher {
+    // Dropping the watcher closes the event channel, which ends the worker thread
+    watcher: Option<RecommendedWatcher>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for FolderWatcher {
+    fn drop(&mut self) {
+        self.watcher.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Watch a folder for new FITS files and call `on_new` with each one once it has been
+/// completely written.
+///
+/// A file is considered complete when its size is non-zero and unchanged between two
+/// checks `SETTLE_INTERVAL` apart, so frames still being written by capture software
+/// are not loaded half-finished. Files that fail to load are logged and skipped.
+pub fn watch_folder<P, F>(
+    path: P,
+    frame_type: FrameType,
+    on_new: F,
+) -> Result<FolderWatcher, ImageError>
+where
+    P: AsRef<Path>,
+    F: Fn(FitsImage) + Send + 'static,
+{
+    let path = path.as_ref();
+    let (sender, receiver) = mpsc::channel();
+
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                for path in event.paths {
+                    if FitsImage::is_fits_file(&path) {
+                        let _ = sender.send(path);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Folder watch error: {}", e),
+        })
+        .map_err(|e| ImageError::IoError(io::Error::other(e)))?;
+
+    watcher
+        .watch(path, RecursiveMode::NonRecursive)
+        .map_err(|e| ImageError::IoError(io::Error::other(e)))?;
+
+    log::info!("Watching {:?} for new {:?} frames", path, frame_type);
+
+    let worker = thread::spawn(move || {
+        // Last observed size of each file that has not been loaded yet
+        let mut pending: HashMap<PathBuf, Option<u64>> = HashMap::new();
+        let mut next_check = Instant::now() + SETTLE_INTERVAL;
+
+        loop {
+            // Wait for events only until the next check is due, so a steady stream of
+            // events (a file being written) can't postpone the check indefinitely
+            let timeout = next_check.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(path) => {
+                    pending.entry(path).or_insert(None);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if Instant::now() < next_check {
+                continue;
+            }
+            next_check = Instant::now() + SETTLE_INTERVAL;
+
+            for path in take_settled(&mut pending) {
+                match FitsImage::from_file(&path, frame_type) {
+                    Ok(image) => {
+                        log::debug!("Loaded new frame: {:?}", path);
+                        on_new(image);
+                    }
+                    Err(e) => log::warn!("Skipping new file {:?}: {}", path, e),
+                }
+            }
+        }
+    });
+
+    Ok(FolderWatcher {
+        watcher: Some(watcher),
+        worker: Some(worker),
+    })
+}
+
+/// Remove and return the pending files whose size is non-zero and the same as at the
+/// last check, recording the current size of the others
+fn take_settled(pending: &mut HashMap<PathBuf, Option<u64>>) -> Vec<PathBuf> {
+    let mut settled = Vec::new();
+    for (path, last_size) in pending.iter_mut() {
+        let size = std::fs::metadata(path).map(|m| m.len()).ok();
+        if size.is_some_and(|size| size > 0) && size == *last_size {
+            settled.push(path.clone());
+        } else {
+            *last_size = size;
+        }
+    }
+    for path in &settled {
+        pending.remove(path);
+    }
+    settled
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayD;
+
+    use super::*;
+    use crate::image::temp_path;
+
+    #[test]
+    fn files_settle_once_their_size_stops_changing() {
+        let path = temp_path("settle.fits");
+        std::fs::write(&path, [0u8; 10]).unwrap();
+        let mut pending = HashMap::from([(path.clone(), None)]);
+
+        // First sighting only records the size
+        assert!(take_settled(&mut pending).is_empty());
+        std::fs::write(&path, [0u8; 20]).unwrap();
+        assert!(take_settled(&mut pending).is_empty());
+        assert_eq!(take_settled(&mut pending), vec![path.clone()]);
+        assert!(pending.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn empty_files_never_settle() {
+        let path = temp_path("empty.fits");
+        std::fs::write(&path, []).unwrap();
+        let mut pending = HashMap::from([(path.clone(), None)]);
+
+        assert!(take_settled(&mut pending).is_empty());
+        assert!(take_settled(&mut pending).is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn new_file_in_watched_folder_triggers_the_callback() {
+        let directory = temp_path("watched");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let watcher = watch_folder(&directory, FrameType::Light, move |image| {
+            let _ = sender.send(image.dimensions());
+        })
+        .unwrap();
+
+        FitsImage::from_data(ArrayD::zeros(vec![4, 6]))
+            .to_file(directory.join("light_001.fits"))
+            .unwrap();
+
+        let loaded = receiver.recv_timeout(SETTLE_INTERVAL * 10);
+        drop(watcher);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(loaded, Ok((6, 4)));
+    }
+}
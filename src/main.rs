@@ -1,9 +1,157 @@
+mod alignment;
+mod analysis;
+#[cfg(feature = "plate-solve")]
+mod astrometry;
 mod calibration;
 mod commands;
+mod fft;
 mod gui;
 mod image;
 
+use clap::{Parser, Subcommand};
+
+/// Astrophotography image processing. Launches the GUI when no command is given.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Stack on the GPU when built with the `gpu` feature
+    #[arg(long, global = true)]
+    gpu: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Stack the light frames in a folder
+    Stack {
+        /// Folder containing the light frames
+        lights: String,
+        /// Folder containing the dark frames
+        #[arg(long)]
+        darks: Option<String>,
+        /// Folder containing the flat frames
+        #[arg(long)]
+        flats: Option<String>,
+        /// Folder containing the bias frames
+        #[arg(long)]
+        bias: Option<String>,
+        /// Folder the stacked image is written to
+        #[arg(long, default_value = ".")]
+        output: String,
+        /// Output file name; {object}, {filter}, {count} and {exposure} are filled in from the frames
+        #[arg(long, default_value = image::DEFAULT_OUTPUT_TEMPLATE)]
+        name_template: String,
+        /// Stack each filter separately into its own subfolder, as {filter}/<name template>
+        #[arg(long)]
+        split_by_filter: bool,
+        /// Register the lights onto the first one before stacking; the transforms are cached
+        /// in the lights folder so re-stacking skips the registration
+        #[arg(long)]
+        align: bool,
+        /// Leave out frames whose background or star count stands out from the rest, e.g.
+        /// from clouds, headlights or dew
+        #[arg(long)]
+        reject_outliers: bool,
+        /// Remove a background gradient from every light before stacking: linear, quadratic,
+        /// tiled or tiled:<pixels>
+        #[arg(long, value_name = "MODEL")]
+        background: Option<image::GradientModel>,
+        /// Number of worker threads
+        #[arg(long)]
+        threads: Option<usize>,
+    },
+    /// Print per-frame quality metrics for the FITS files in a folder
+    Analyze {
+        /// Folder containing the frames to analyze
+        folder: String,
+        /// Also write the metrics to this CSV file
+        #[arg(long)]
+        csv: Option<String>,
+    },
+    /// Copy the FITS files in a folder into a layout named from their metadata
+    Organize {
+        /// Folder containing the frames to organize
+        folder: String,
+        /// Folder the organized copies are written to
+        dest: String,
+        /// Path of each copy relative to the destination; {object}, {filter}, {frametype},
+        /// {date} and {exposure} are filled in from the frame
+        #[arg(long, default_value = image::DEFAULT_ORGANIZE_TEMPLATE)]
+        template: String,
+    },
+    /// Plate-solve a FITS file with a locally installed ASTAP or astrometry.net and print
+    /// the sky position of its center
+    #[cfg(feature = "plate-solve")]
+    Solve {
+        /// FITS file to solve
+        file: String,
+        /// Solver to run: astap or astrometry-net
+        #[arg(long, default_value = "astap")]
+        solver: astrometry::SolverKind,
+        /// Path of the solver binary, when it isn't on PATH as astap or solve-field
+        #[arg(long)]
+        binary: Option<String>,
+    },
+}
+
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let cli = Cli::parse();
+
+    if cli.gpu {
+        calibration::set_backend(calibration::Backend::Gpu);
+    }
+
+    match cli.command {
+        Some(Command::Stack {
+            lights,
+            darks,
+            flats,
+            bias,
+            output,
+            name_template,
+            split_by_filter,
+            align,
+            reject_outliers,
+            background,
+            threads,
+        }) => {
+            let layout = if split_by_filter {
+                image::OutputLayout::ByFilter
+            } else {
+                image::OutputLayout::Flat
+            };
+            let output = commands::StackOutput {
+                folder: output,
+                name_template,
+                layout,
+            };
+            let steps = commands::StackSteps {
+                align,
+                reject_outliers,
+                subtract_background: background,
+            };
+            commands::run_stack_command(lights, darks, flats, bias, output, steps, threads)
+        }
+        Some(Command::Analyze { folder, csv }) => commands::run_analyze_command(folder, csv),
+        Some(Command::Organize {
+            folder,
+            dest,
+            template,
+        }) => commands::run_organize_command(folder, dest, template),
+        #[cfg(feature = "plate-solve")]
+        Some(Command::Solve {
+            file,
+            solver,
+            binary,
+        }) => commands::run_solve_command(file, solver, binary),
+        None => run_gui(),
+    }
+}
+
+fn run_gui() {
     let options = eframe::NativeOptions {
         viewport: eframe::egui::ViewportBuilder::default().with_inner_size([800.0, 600.0]),
         ..Default::default()